@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::breaker::{BreakerError, BreakerSettings, BreakerStatus, CircuitBreaker};
+use crate::detector::ProfileKey;
+use crate::models::{AnomalyScore, BehaviorPattern};
+
+// ==========================================
+// ALERTAS VÍA WEBHOOK
+// ==========================================
+
+// Tiempo máximo que esperamos al receptor del webhook
+const WEBHOOK_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Serialize)]
+struct CriticalAlert<'a> {
+    tenant_id: &'a str,
+    client_id: &'a str,
+    score: f64,
+    detected_patterns: &'a [BehaviorPattern],
+    timestamp: DateTime<Utc>,
+}
+
+/// Notifica a un webhook externo cuando un perfil pasa a `Critical`.
+///
+/// El envío es fire-and-forget (tarea tokio con timeout) y se aplica un debounce
+/// por (tenant_id, client_id) para no saturar al on-call.
+pub struct WebhookAlerter {
+    client: reqwest::Client,
+    url: String,
+    debounce: Duration,
+    last_sent: DashMap<ProfileKey, DateTime<Utc>>,
+    // Con el receptor caído las alertas se descartan al instante (sin tareas esperando)
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl WebhookAlerter {
+    pub fn new(url: String, debounce: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(StdDuration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            url,
+            debounce,
+            last_sent: DashMap::new(),
+            breaker: Arc::new(CircuitBreaker::new("alert_webhook", Self::breaker_settings(BreakerSettings::default()))),
+        }
+    }
+
+    /// Umbrales del circuit breaker del webhook. El timeout sigue siendo el del cliente
+    /// HTTP: el envío es en segundo plano y su latencia no frena el scoring.
+    pub fn with_breaker(mut self, settings: BreakerSettings) -> Self {
+        self.breaker = Arc::new(CircuitBreaker::new("alert_webhook", Self::breaker_settings(settings)));
+        self
+    }
+
+    fn breaker_settings(settings: BreakerSettings) -> BreakerSettings {
+        BreakerSettings {
+            timeout_ms: WEBHOOK_TIMEOUT_SECS * 1000,
+            ..settings
+        }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    /// Programa el envío de la alerta. Nunca bloquea al llamador.
+    /// Debe invocarse dentro de un runtime de tokio.
+    pub fn notify(&self, score: &AnomalyScore) {
+        let key = (score.tenant_id.clone(), score.client_id.clone());
+        let now = Utc::now();
+
+        // Debounce atómico: solo el primero dentro de la ventana envía
+        let mut should_send = false;
+        self.last_sent
+            .entry(key)
+            .and_modify(|last| {
+                if now - *last >= self.debounce {
+                    *last = now;
+                    should_send = true;
+                }
+            })
+            .or_insert_with(|| {
+                should_send = true;
+                now
+            });
+        if !should_send {
+            return;
+        }
+
+        let body = match serde_json::to_vec(&CriticalAlert {
+            tenant_id: &score.tenant_id,
+            client_id: &score.client_id,
+            score: score.score,
+            detected_patterns: &score.detected_patterns,
+            timestamp: score.timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("[ALERT] No se pudo serializar la alerta: {}", e);
+                return;
+            }
+        };
+
+        let request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        let target = format!("{}:{}", score.tenant_id, score.client_id);
+        let breaker = self.breaker.clone();
+        tokio::spawn(async move {
+            // Una respuesta no-2xx también cuenta como fallo del receptor
+            let send = async {
+                let resp = request.send().await.map_err(|e| e.to_string())?;
+                match resp.status().is_success() {
+                    true => Ok(()),
+                    false => Err(format!("respondió {}", resp.status())),
+                }
+            };
+            match breaker.call(send).await {
+                Ok(()) => log::info!("[ALERT] Alerta crítica enviada para {}", target),
+                Err(BreakerError::Open) => log::warn!("[ALERT] Webhook en circuito abierto: alerta descartada para {}", target),
+                Err(e) => log::warn!("[ALERT] Falló el webhook para {}: {}", target, e),
+            }
+        });
+    }
+
+    /// Olvida entradas de debounce ya expiradas (acota la memoria).
+    pub fn purge_expired(&self) {
+        let now = Utc::now();
+        self.last_sent.retain(|_, last| now - *last < self.debounce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::breaker::BreakerState;
+    use crate::models::ThreatLevel;
+
+    fn critical(client_id: &str) -> AnomalyScore {
+        AnomalyScore {
+            client_id: client_id.to_string(),
+            tenant_id: "acme".to_string(),
+            score: 1.0,
+            level: ThreatLevel::Critical,
+            detected_patterns: vec![BehaviorPattern::PayloadInjection],
+            reasons: vec![],
+            pattern_contributions: HashMap::new(),
+            timestamp: Utc::now(),
+            recommendation: "ISOLATE_SESSION".to_string(),
+        }
+    }
+
+    // Puerto sin nadie escuchando: conexión rechazada al instante
+    fn refused_url() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/alerts", listener.local_addr().unwrap())
+    }
+
+    async fn wait_for(alerter: &WebhookAlerter, done: impl Fn(&BreakerStatus) -> bool) -> BreakerStatus {
+        tokio::time::timeout(StdDuration::from_secs(5), async {
+            loop {
+                let status = alerter.breaker_status();
+                if done(&status) {
+                    return status;
+                }
+                tokio::time::sleep(StdDuration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("el breaker no llegó al estado esperado")
+    }
+
+    #[tokio::test]
+    async fn failing_webhook_opens_the_breaker() {
+        let settings = BreakerSettings { failure_threshold: 2, cooldown_secs: 60, ..BreakerSettings::default() };
+        let alerter = WebhookAlerter::new(refused_url(), Duration::minutes(10)).with_breaker(settings);
+        assert_eq!(alerter.breaker_status().name, "alert_webhook");
+
+        alerter.notify(&critical("1"));
+        wait_for(&alerter, |status| status.consecutive_failures == 1).await;
+        // Debounce: el mismo cliente no vuelve a enviar dentro de la ventana
+        alerter.notify(&critical("1"));
+        alerter.notify(&critical("2"));
+        let status = wait_for(&alerter, |status| status.state == BreakerState::Open).await;
+        assert_eq!(status.consecutive_failures, 2);
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+
+// ==========================================
+// ALLOWLIST DE REDES DE CONFIANZA
+// ==========================================
+
+/// Redes (IPv4/IPv6) cuyo tráfico no se puntúa: egress de oficina, sondas de monitoreo...
+#[derive(Debug, Clone, Default)]
+pub struct IpAllowlist {
+    networks: Vec<IpNet>,
+}
+
+impl IpAllowlist {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        Self { networks }
+    }
+
+    /// Parsea una lista separada por comas de CIDRs o IPs sueltas (`10.0.0.0/8, 2001:db8::/32, 1.2.3.4`).
+    pub fn parse_networks(raw: &str) -> Result<Vec<IpNet>, String> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Entrada de allowlist inválida: {:?}", entry))
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, ip: &str) -> bool {
+        match ip.trim().parse::<IpAddr>() {
+            Ok(addr) => self.networks.iter().any(|net| net.contains(&addr)),
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cidrs_and_single_addresses() {
+        let networks = IpAllowlist::parse_networks(" 10.0.0.0/8, 2001:db8::/32 ,1.2.3.4,, ").unwrap();
+        let allowlist = IpAllowlist::new(networks);
+        assert_eq!(allowlist.len(), 3);
+        assert!(allowlist.contains("10.255.0.1"));
+        assert!(allowlist.contains(" 2001:db8:1::5 "));
+        assert!(allowlist.contains("1.2.3.4"));
+        assert!(!allowlist.contains("1.2.3.5"));
+        assert!(!allowlist.contains("11.0.0.1"));
+        // Lo que no es una IP nunca está en la allowlist
+        assert!(!allowlist.contains("UNKNOWN"));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let err = IpAllowlist::parse_networks("10.0.0.0/8, 10.0.0.0/33").unwrap_err();
+        assert!(err.contains("10.0.0.0/33"), "{}", err);
+        assert!(IpAllowlist::parse_networks("office").is_err());
+        assert!(IpAllowlist::parse_networks("").unwrap().is_empty());
+    }
+}
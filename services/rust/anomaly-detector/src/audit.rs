@@ -0,0 +1,52 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use serde::Serialize;
+
+// ==========================================
+// AUDIT TRAIL (JSON LINES)
+// ==========================================
+
+/// Destino de los registros de auditoría: una línea JSON por decisión,
+/// pensada para ser ingerida por el SIEM.
+pub enum AuditLog {
+    Stdout,
+    File(Mutex<File>),
+}
+
+impl AuditLog {
+    /// Abre (en modo append) el fichero indicado, o usa stdout si es `None`.
+    pub fn open(path: Option<&str>) -> io::Result<Self> {
+        match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Self::File(Mutex::new(file)))
+            }
+            None => Ok(Self::Stdout),
+        }
+    }
+
+    /// Serializa `record` y lo escribe como una única línea.
+    /// Los fallos se reportan por el log normal: la auditoría nunca tumba una petición.
+    pub fn write<T: Serialize>(&self, record: &T) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("[AUDIT] No se pudo serializar el registro: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let result = match self {
+            Self::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Self::File(file) => match file.lock() {
+                Ok(mut file) => file.write_all(line.as_bytes()),
+                Err(poisoned) => poisoned.into_inner().write_all(line.as_bytes()),
+            },
+        };
+        if let Err(e) = result {
+            log::error!("[AUDIT] No se pudo escribir el registro: {}", e);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use subtle::{Choice, ConstantTimeEq};
+
+// ==========================================
+// API KEYS (ROTACIÓN SIN DOWNTIME)
+// ==========================================
+
+/// Conjunto de API keys válidas a la vez.
+///
+/// Rotación: se añade la key nueva, se migran los clientes y después se retira la antigua.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeySet {
+    keys: Vec<String>,
+}
+
+impl ApiKeySet {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+
+    /// Parsea una lista separada por comas (`key-nueva,key-antigua`), ignorando entradas vacías.
+    pub fn parse(raw: &str) -> Self {
+        Self::new(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    /// Comprueba `candidate` contra todas las keys sin cortocircuitar,
+    /// para no revelar por tiempos cuál coincide ni en qué byte difiere.
+    pub fn accepts(&self, candidate: &str) -> bool {
+        let mut matched = Choice::from(0);
+        for key in &self.keys {
+            matched |= constant_time_eq(key.as_bytes(), candidate.as_bytes());
+        }
+        matched.into()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+/// Igualdad en tiempo constante respecto al contenido de ambos lados.
+///
+/// `ct_eq` sobre slices retorna antes si las longitudes difieren; aquí siempre se
+/// recorre la key esperada completa, así el tiempo depende solo de su longitud y
+/// un atacante no aprende ni el prefijo ni la longitud correcta.
+fn constant_time_eq(expected: &[u8], candidate: &[u8]) -> Choice {
+    let mut equal = (expected.len() as u64).ct_eq(&(candidate.len() as u64));
+    for (i, byte) in expected.iter().enumerate() {
+        let other = candidate.get(i).copied().unwrap_or(!byte);
+        equal &= byte.ct_eq(&other);
+    }
+    equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trims_and_skips_empty_entries() {
+        let keys = ApiKeySet::parse(" key-nueva , ,key-antigua,");
+        assert_eq!(keys.len(), 2);
+        assert!(keys.accepts("key-nueva") && keys.accepts("key-antigua"));
+        assert!(ApiKeySet::parse(" , ").is_empty());
+    }
+
+    #[test]
+    fn rotation_accepts_every_configured_key_and_nothing_else() {
+        let rotating = ApiKeySet::parse("key-nueva,key-antigua");
+        assert!(rotating.accepts("key-nueva"));
+        assert!(rotating.accepts("key-antigua"));
+        // Retirada la antigua, deja de valer
+        let retired = ApiKeySet::parse("key-nueva");
+        assert!(!retired.accepts("key-antigua"));
+        // Sin keys configuradas no se acepta nada, tampoco la cadena vacía
+        assert!(!ApiKeySet::default().accepts(""));
+        assert!(!ApiKeySet::default().accepts("key-nueva"));
+    }
+
+    // La propiedad de tiempo constante no es medible de forma fiable en un test:
+    // aquí se fija el comportamiento, incluidos prefijos y longitudes distintas
+    #[test]
+    fn constant_time_eq_matches_only_identical_bytes() {
+        let eq = |a: &str, b: &str| bool::from(constant_time_eq(a.as_bytes(), b.as_bytes()));
+        assert!(eq("s3cret-key", "s3cret-key"));
+        assert!(eq("", ""));
+        assert!(!eq("s3cret-key", "s3cret-kex"));
+        assert!(!eq("s3cret-key", "s3cret"), "un prefijo correcto no basta");
+        assert!(!eq("s3cret-key", "s3cret-key-and-more"), "la key correcta seguida de basura tampoco");
+        assert!(!eq("s3cret-key", ""));
+        assert!(!eq("", "s3cret-key"));
+    }
+
+    #[test]
+    fn missing_candidate_bytes_never_compare_equal() {
+        // El relleno usado para bytes ausentes (!byte) nunca iguala al esperado,
+        // ni siquiera con valores extremos
+        for expected in [[0x00u8], [0xFF], [0x7F]] {
+            assert!(!bool::from(constant_time_eq(&expected, &[])));
+        }
+    }
+}
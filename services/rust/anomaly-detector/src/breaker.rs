@@ -0,0 +1,299 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// ==========================================
+// CIRCUIT BREAKER PARA DEPENDENCIAS EXTERNAS
+// ==========================================
+
+/// Umbrales de un `CircuitBreaker` (ver `SecurityConfig::circuit_breaker`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BreakerSettings {
+    /// Fallos consecutivos (errores o timeouts) que abren el circuito.
+    pub failure_threshold: u32,
+    /// Segundos abierto antes de dejar pasar una llamada de prueba.
+    pub cooldown_secs: u64,
+    /// Tiempo máximo por llamada: una dependencia colgada cuenta como fallo.
+    pub timeout_ms: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_secs: 30,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+impl BreakerSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.failure_threshold == 0 {
+            return Err("circuit_breaker.failure_threshold must be at least 1".to_string());
+        }
+        if self.timeout_ms == 0 {
+            return Err("circuit_breaker.timeout_ms must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Normal: todas las llamadas pasan.
+    Closed,
+    /// Dependencia caída: las llamadas fallan al instante sin esperar.
+    Open,
+    /// Enfriamiento cumplido: una única llamada de prueba decide si se cierra.
+    HalfOpen,
+}
+
+/// Estado visible en `/health`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+    pub name: &'static str,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// Solo abierto: segundos hasta la siguiente llamada de prueba.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum BreakerError<E> {
+    /// Circuito abierto: no se llegó a llamar.
+    Open,
+    Timeout,
+    Failed(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for BreakerError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BreakerError::Open => f.write_str("circuit open"),
+            BreakerError::Timeout => f.write_str("timed out"),
+            BreakerError::Failed(e) => e.fmt(f),
+        }
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Instant,
+    // Llamada de prueba en curso (semiabierto); caduca con el timeout si se cancela
+    probe_started: Option<Instant>,
+}
+
+/// Corta las llamadas a una dependencia que falla: tras `failure_threshold` fallos
+/// seguidos se abre y las peticiones siguen sin ella (heurísticas locales) en lugar
+/// de acumularse esperando. Pasado el enfriamiento deja pasar una llamada de prueba:
+/// si va bien se cierra, si falla vuelve a abrirse.
+pub struct CircuitBreaker {
+    name: &'static str,
+    settings: BreakerSettings,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, settings: BreakerSettings) -> Self {
+        Self {
+            name,
+            settings,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+                probe_started: None,
+            }),
+        }
+    }
+
+    /// Ejecuta `call` si el circuito lo permite, con el timeout configurado.
+    pub async fn call<T, E, F>(&self, call: F) -> Result<T, BreakerError<E>>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        if !self.allow() {
+            return Err(BreakerError::Open);
+        }
+        match tokio::time::timeout(self.timeout(), call).await {
+            Ok(Ok(value)) => {
+                self.record_success();
+                Ok(value)
+            }
+            Ok(Err(e)) => {
+                self.record_failure();
+                Err(BreakerError::Failed(e))
+            }
+            Err(_) => {
+                self.record_failure();
+                Err(BreakerError::Timeout)
+            }
+        }
+    }
+
+    /// `true` si la siguiente llamada puede pasar (en semiabierto, solo una a la vez).
+    pub fn allow(&self) -> bool {
+        let mut inner = self.lock();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if inner.opened_at.elapsed() >= self.cooldown() => {
+                log::info!("[BREAKER] {}: semiabierto, llamada de prueba", self.name);
+                inner.state = BreakerState::HalfOpen;
+                inner.probe_started = Some(Instant::now());
+                true
+            }
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => {
+                let stale = inner.probe_started.is_none_or(|at| at.elapsed() >= self.timeout());
+                if stale {
+                    inner.probe_started = Some(Instant::now());
+                }
+                stale
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.lock();
+        if inner.state != BreakerState::Closed {
+            log::info!("[BREAKER] {}: cerrado, la dependencia vuelve a responder", self.name);
+        }
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+        inner.probe_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.lock();
+        inner.failures = inner.failures.saturating_add(1);
+        let trip = match inner.state {
+            BreakerState::HalfOpen => true,
+            BreakerState::Closed => inner.failures >= self.settings.failure_threshold,
+            BreakerState::Open => false,
+        };
+        if trip {
+            log::warn!(
+                "[BREAKER] {}: abierto tras {} fallos seguidos, reintento en {}s",
+                self.name,
+                inner.failures,
+                self.settings.cooldown_secs
+            );
+            inner.state = BreakerState::Open;
+            inner.opened_at = Instant::now();
+            inner.probe_started = None;
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.lock().state
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.lock();
+        BreakerStatus {
+            name: self.name,
+            state: inner.state,
+            consecutive_failures: inner.failures,
+            retry_in_secs: (inner.state == BreakerState::Open)
+                .then(|| self.cooldown().saturating_sub(inner.opened_at.elapsed()).as_secs()),
+        }
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.settings.cooldown_secs)
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.timeout_ms)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failure_threshold: u32, cooldown_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new("test", BreakerSettings { failure_threshold, cooldown_secs, timeout_ms: 50 })
+    }
+
+    async fn fail(breaker: &CircuitBreaker) -> BreakerError<&'static str> {
+        breaker.call(async { Err::<(), _>("caído") }).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures_and_then_fails_fast() {
+        let breaker = breaker(3, 60);
+        for _ in 0..2 {
+            assert!(matches!(fail(&breaker).await, BreakerError::Failed("caído")));
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(matches!(fail(&breaker).await, BreakerError::Failed(_)));
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Abierto: la dependencia ni siquiera se llama
+        let mut called = false;
+        let result = breaker.call(async { called = true; Ok::<_, &str>(()) }).await;
+        assert!(matches!(result, Err(BreakerError::Open)));
+        assert!(!called);
+        let status = breaker.status();
+        assert_eq!(status.consecutive_failures, 3);
+        assert!(status.retry_in_secs.is_some_and(|secs| secs <= 60));
+    }
+
+    #[tokio::test]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = breaker(2, 60);
+        fail(&breaker).await;
+        breaker.call(async { Ok::<_, &str>(()) }).await.unwrap();
+        fail(&breaker).await;
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn timeouts_count_as_failures() {
+        let breaker = breaker(1, 60);
+        let hung = breaker.call(async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, &str>(())
+        });
+        assert!(matches!(hung.await, Err(BreakerError::Timeout)));
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[tokio::test]
+    async fn half_open_probe_closes_on_success_and_reopens_on_failure() {
+        // Enfriamiento 0: el siguiente intento tras abrir ya es la llamada de prueba
+        let breaker = breaker(1, 0);
+        fail(&breaker).await;
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow(), "solo una llamada de prueba a la vez");
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        breaker.call(async { Ok::<_, &str>(()) }).await.unwrap();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+        assert!(breaker.status().retry_in_secs.is_none());
+    }
+
+    #[test]
+    fn settings_validation() {
+        assert!(BreakerSettings::default().validate().is_ok());
+        assert!(BreakerSettings { failure_threshold: 0, ..BreakerSettings::default() }.validate().is_err());
+        assert!(BreakerSettings { timeout_ms: 0, ..BreakerSettings::default() }.validate().is_err());
+    }
+}
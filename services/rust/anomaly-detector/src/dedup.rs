@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// ==========================================
+// IDEMPOTENCIA (CACHÉ DE RESPUESTAS POR EVENT_ID)
+// ==========================================
+
+/// Caché acotada con TTL de respuestas ya emitidas.
+///
+/// Un reintento con el mismo `event_id` dentro de la ventana recibe la respuesta
+/// original sin volver a mutar perfiles ni contadores. Al llenarse se expulsa la
+/// entrada más antigua.
+///
+/// El TTL es el mismo para todas las entradas, así que el orden de inserción es
+/// también el de caducidad: una cola con ese orden hace que expirar y expulsar
+/// sean O(1) por entrada, sin recorrer el mapa en cada `/detect`.
+pub struct ResponseCache<V: Clone> {
+    entries: DashMap<String, (Instant, V)>,
+    // (instante de inserción, clave), de la más antigua a la más reciente. Una clave
+    // reinsertada deja atrás su posición anterior, que se descarta al llegar al frente
+    order: Mutex<VecDeque<(Instant, String)>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl<V: Clone> ResponseCache<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            order: Mutex::new(VecDeque::new()),
+            ttl,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Respuesta guardada para `key`, si aún no expiró.
+    pub fn get(&self, key: &str) -> Option<V> {
+        let entry = self.entries.get(key)?;
+        let (stored_at, value) = entry.value();
+        (stored_at.elapsed() <= self.ttl).then(|| value.clone())
+    }
+
+    pub fn insert(&self, key: String, value: V) {
+        let now = Instant::now();
+        let mut order = self.order.lock().unwrap_or_else(|e| e.into_inner());
+        // Caducadas primero; después, las más antiguas hasta dejar sitio
+        while let Some((stored_at, _)) = order.front() {
+            let expired = now.duration_since(*stored_at) > self.ttl;
+            if !expired && order.len() < self.capacity {
+                break;
+            }
+            let (stored_at, oldest) = order.pop_front().expect("front() ya comprobó que no está vacía");
+            self.entries.remove_if(&oldest, |_, (at, _)| *at == stored_at);
+        }
+        order.push_back((now, key.clone()));
+        self.entries.insert(key, (now, value));
+    }
+
+    /// Elimina las entradas cuyo TTL ya venció.
+    pub fn purge_expired(&self) {
+        let mut order = self.order.lock().unwrap_or_else(|e| e.into_inner());
+        while order.front().is_some_and(|(stored_at, _)| stored_at.elapsed() > self.ttl) {
+            if let Some((stored_at, key)) = order.pop_front() {
+                self.entries.remove_if(&key, |_, (at, _)| *at == stored_at);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_retry_within_the_ttl_gets_the_original_response() {
+        let cache = ResponseCache::new(Duration::from_secs(300), 10);
+        cache.insert("evt-1".to_string(), "BLOCK");
+        assert_eq!(cache.get("evt-1"), Some("BLOCK"));
+        assert_eq!(cache.get("evt-2"), None);
+    }
+
+    #[test]
+    fn a_full_cache_evicts_the_oldest_entry() {
+        let cache = ResponseCache::new(Duration::from_secs(300), 3);
+        for id in 0..10 {
+            cache.insert(format!("evt-{}", id), id);
+        }
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get("evt-6"), None);
+        assert_eq!(cache.get("evt-7"), Some(7));
+        assert_eq!(cache.get("evt-9"), Some(9));
+    }
+
+    #[test]
+    fn a_reinserted_key_is_not_evicted_by_its_old_position() {
+        let cache = ResponseCache::new(Duration::from_secs(300), 3);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        cache.insert("a".to_string(), 3);
+        // Sale la posición antigua de "a", no la entrada reinsertada
+        cache.insert("c".to_string(), 4);
+        assert_eq!(cache.get("a"), Some(3));
+        assert_eq!(cache.get("b"), Some(2));
+        assert_eq!(cache.get("c"), Some(4));
+    }
+
+    #[test]
+    fn expired_entries_are_purged() {
+        let cache = ResponseCache::new(Duration::from_millis(1), 10);
+        cache.insert("evt-1".to_string(), 1);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.get("evt-1"), None);
+        cache.purge_expired();
+        assert!(cache.is_empty());
+    }
+}
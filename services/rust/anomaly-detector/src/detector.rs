@@ -1,83 +1,70 @@
 use std::sync::Arc;
-use tokio::sync::RwLock; // Solo para configs globales
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{watch, RwLock}; // RwLock solo para configs globales
 use chrono::{DateTime, Utc, Duration};
 use dashmap::DashMap; // MEJORA: Mapa concurrente sin bloqueos globales
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use crate::{SecurityConfig, MAX_EVENT_AGE_SECS};
+use crate::alerts::WebhookAlerter;
+use crate::breaker::{BreakerError, BreakerStatus, CircuitBreaker};
+use crate::enrich::{run_enrichers, EventEnricher};
+use crate::error::DetectorError;
+use crate::history::{ScoreRecorder, ScoreSink, SCORE_QUEUE_CAPACITY};
+use crate::keys::composite_key;
+use crate::models::{Action, AnomalyScore, BehaviorEvent, BehaviorPattern, ClientProfile, CompromisedClient, EventSummary, MetadataRule, TenantStats, ThreatLevel, VipEntry};
+use crate::patterns::{PatternMatcher, KEY_IP_REPUTATION, KEY_NEW_DEVICE, KEY_TIMING_VARIANCE};
+use crate::scoring::{apply_sensitivity, RiskThresholds};
+use crate::storage::{InMemoryProfileStore, ProfileStore};
 
 // ==========================================
-// MOCK MODELS (Para que el código compile completo)
+// ANOMALY DETECTOR MEJORADO
 // ==========================================
-#[derive(Clone, Debug)]
-pub struct BehaviorEvent {
-    pub tenant_id: String, // AGREGADO: Vital para multi-tenancy
-    pub client_id: String,
-    pub timestamp: DateTime<Utc>,
-    pub indicators: HashMap<String, f64>,
-    pub confidence: f64,
-}
 
-#[derive(Clone, Debug)]
-pub struct ClientProfile {
-    pub tenant_id: String,
-    pub client_id: String,
-    pub first_seen: DateTime<Utc>,
-    pub last_seen: DateTime<Utc>,
-    pub total_events: u64,
-    pub risk_score: f64,
-    pub is_compromised: bool,
-    pub threat_level: ThreatLevel,
-}
+// Clave compuesta para separar usuarios por organización
+// Evita que un ataque en la Org A afecte al usuario en la Org B
+pub type ProfileKey = (String, String); // (tenant_id, client_id)
 
-#[derive(Clone, Debug)]
-pub struct AnomalyScore {
-    pub client_id: String,
-    pub tenant_id: String,
-    pub score: f64,
-    pub level: ThreatLevel,
-    pub detected_patterns: Vec<BehaviorPattern>,
-    pub recommendation: String,
-    pub timestamp: DateTime<Utc>,
-}
+// Ventana tras la cual un perfil inactivo se considera obsoleto
+pub const STALE_PROFILE_HOURS: i64 = 24;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub enum BehaviorPattern {
-    Normal,
-    RapidFailures,
-    Enumeration,
-    PayloadInjection,
-    TimingAttack,
-    ResourceAbuse,
-    DeviceChange,
-    AnomalousLocation,
-    CredentialSpray,
-}
+// Razón devuelta mientras un cliente sigue bloqueado
+pub const COMPROMISED_REASON: &str = "Client Flagged as Compromised";
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum ThreatLevel {
-    Safe,
-    Low,
-    Medium,
-    High,
-    Critical,
-}
+// Namespace de los VIP en el ProfileStore (clave composite_key(tenant, client))
+const VIP_NAMESPACE: &str = "vips";
+
+// Fracción de max_profiles a la que se baja al expulsar por capacidad
+const EVICTION_LOW_WATER: f64 = 0.9;
+
+/// Claves de umbral admitidas (globales y por tenant).
+/// `score_multiplier` escala el score de cada patrón (1.0 = sin cambio).
+pub const THRESHOLD_KEYS: [&str; 2] = ["rate_limit", "score_multiplier"];
 
-// Estructura para PatternMatcher (Mock)
-pub struct PatternMatcher;
-impl PatternMatcher {
-    pub fn new() -> Self { Self }
-    pub fn detect(&self, _event: &BehaviorEvent) -> Vec<BehaviorPattern> {
-        // Simulación: En producción esto tendría lógica real
-        vec![] 
+/// Valida una clave de umbral y su rango: `rate_limit` en [1, 100000] peticiones/minuto,
+/// `score_multiplier` en [0, 10].
+pub fn validate_threshold(key: &str, value: f64) -> Result<(), String> {
+    let (min, max) = match key {
+        "rate_limit" => (1.0, 100_000.0),
+        "score_multiplier" => (0.0, 10.0),
+        _ => return Err(format!("Unknown threshold '{}' (expected one of {:?})", key, THRESHOLD_KEYS)),
+    };
+    if !value.is_finite() || value < min || value > max {
+        return Err(format!("Invalid value for '{}': {} (expected {}..={})", key, value, min, max));
     }
+    Ok(())
 }
 
-// ==========================================
-// ANOMALY DETECTOR MEJORADO
-// ==========================================
+// Ring buffer de intervalos por cliente y mínimo de muestras para estimar su dispersión
+const TIMING_BUFFER_SIZE: usize = 32;
+const TIMING_MIN_SAMPLES: usize = 8;
+// Suelo de la dispersión: un intervalo perfectamente fijo (0 ms) es lo más robótico posible
+const TIMING_VARIANCE_FLOOR: f64 = 0.001;
 
-// Clave compuesta para separar usuarios por organización
-// Evita que un ataque en la Org A afecte al usuario en la Org B
-type ProfileKey = (String, String); // (tenant_id, client_id)
+// Dispositivos (device_id) recordados por cliente
+const MAX_KNOWN_DEVICES: usize = 10;
+
+// Escalera de fricción para clientes desafiados (de menor a mayor)
+const CHALLENGE_LADDER: [&str; 3] = ["THROTTLE_REQUESTS", "REQUIRE_MFA", "ISOLATE_SESSION"];
 
 pub struct AnomalyDetector {
     // MEJORA: DashMap permite acceso concurrente ultra-rápido sin bloquear todo el sistema
@@ -85,21 +72,180 @@ pub struct AnomalyDetector {
     pattern_matcher: Arc<PatternMatcher>,
     // Configuración global (rara vez cambia, RwLock está bien)
     thresholds: Arc<RwLock<HashMap<String, f64>>>,
+    // Overrides por tenant (tenant_id -> clave -> valor); si falta, se usa el global
+    tenant_thresholds: Arc<RwLock<HashMap<String, HashMap<String, f64>>>>,
     // Configuración de limpieza
     max_profiles: usize,
+    // Sensibilidad global (0.0 a 1.0) tomada de SecurityConfig
+    sensitivity: f64,
+    // Vida media del risk_score: tras este tiempo sin actividad el riesgo se reduce a la mitad
+    risk_half_life: Duration,
+    // Tiempo tras el cual se levanta el flag is_compromised (None = permanente)
+    compromise_ttl: Option<Duration>,
+    // Suelo del risk_score tras una liberación automática y cuánto dura (None = sin suelo)
+    min_risk_after_incident: f64,
+    incident_watch: Option<Duration>,
+    // Persistencia intercambiable (memoria por defecto, backend durable opcional)
+    store: Box<dyn ProfileStore>,
+    // Perfiles expulsados por capacidad (métrica)
+    evictions: AtomicU64,
+    // Eventos procesados, global y por tenant (Relaxed: basta con un conteo aproximado)
+    events_processed: AtomicU64,
+    tenant_events: DashMap<String, AtomicU64>,
+    // Webhook opcional para transiciones a Critical
+    alerter: Option<WebhookAlerter>,
+    // Corta las llamadas al store desde analyze() si deja de responder
+    store_breaker: CircuitBreaker,
+    // Histórico duradero de cada score (escritura en segundo plano)
+    score_history: Option<ScoreRecorder>,
+    // Fuentes externas consultadas antes del scoring, con un plazo total común
+    enrichers: Vec<Arc<dyn EventEnricher>>,
+    enrichment_timeout: std::time::Duration,
+    // Cortes score → ThreatLevel (los mismos que usa la API HTTP); recargables en caliente.
+    // std::sync: se leen desde `evaluate`, que no es async
+    risk_thresholds: std::sync::RwLock<RiskThresholds>,
+    // Peso base por patrón (SecurityConfig::pattern_weights)
+    pattern_weights: HashMap<BehaviorPattern, f64>,
+    // Tamaño del historial forense de cada perfil
+    event_history_size: usize,
+    // Antigüedad máxima aceptada en BehaviorEvent.timestamp (ver clamp_timestamp)
+    max_event_age: Duration,
+    // Reincidencia: cada N challenges previos se sube un escalón de fricción
+    challenge_escalation_step: u32,
+    // Periodo limpio tras el cual se olvida la reincidencia
+    challenge_reset: Duration,
+    // Clientes de confianza por identidad y su acción máxima (ver VipEntry)
+    vips: DashMap<ProfileKey, VipEntry>,
+    vip_max_action: Action,
+    // Señal de parada para las tareas en segundo plano
+    shutdown: watch::Sender<bool>,
 }
 
 impl AnomalyDetector {
     pub async fn new() -> Self {
+        Self::with_config(SecurityConfig::default())
+    }
+
+    /// Construye el detector aplicando los límites de `SecurityConfig`.
+    pub fn with_config(config: SecurityConfig) -> Self {
+        let profiles = Arc::new(DashMap::new());
+        let store = Box::new(InMemoryProfileStore::from_map(profiles.clone()));
+        Self::build(config, profiles, store)
+    }
+
+    /// Construye el detector con un backend de persistencia propio.
+    /// Llamar a `restore()` después para recuperar los perfiles guardados.
+    pub fn with_store(config: SecurityConfig, store: Box<dyn ProfileStore>) -> Self {
+        Self::build(config, Arc::new(DashMap::new()), store)
+    }
+
+    fn build(
+        config: SecurityConfig,
+        profiles: Arc<DashMap<ProfileKey, ClientProfile>>,
+        store: Box<dyn ProfileStore>,
+    ) -> Self {
         Self {
-            profiles: Arc::new(DashMap::new()),
+            profiles,
             pattern_matcher: Arc::new(PatternMatcher::new()),
-            thresholds: Arc::new(RwLock::new(Self::default_thresholds())),
-            max_profiles: 100_000, // Límite para evitar Memory Exhaustion (DoS)
+            thresholds: Arc::new(RwLock::new(Self::default_thresholds(&config))),
+            tenant_thresholds: Arc::new(RwLock::new(HashMap::new())),
+            max_profiles: config.max_active_profiles, // Límite para evitar Memory Exhaustion (DoS)
+            sensitivity: config.sensitivity,
+            risk_half_life: Duration::seconds((config.risk_half_life_hours * 3600.0) as i64),
+            compromise_ttl: (config.compromise_ttl_hours > 0.0)
+                .then(|| Duration::seconds((config.compromise_ttl_hours * 3600.0) as i64)),
+            min_risk_after_incident: config.min_risk_after_incident,
+            incident_watch: (config.min_risk_after_incident > 0.0 && config.incident_watch_hours > 0.0)
+                .then(|| Duration::seconds((config.incident_watch_hours * 3600.0) as i64)),
+            store,
+            evictions: AtomicU64::new(0),
+            events_processed: AtomicU64::new(0),
+            tenant_events: DashMap::new(),
+            alerter: config.alert_webhook_url.clone().map(|url| {
+                WebhookAlerter::new(url, Duration::minutes(config.alert_debounce_minutes))
+                    .with_breaker(config.circuit_breaker)
+            }),
+            store_breaker: CircuitBreaker::new("profile_store", config.circuit_breaker),
+            score_history: None,
+            enrichers: Vec::new(),
+            enrichment_timeout: std::time::Duration::from_millis(config.enrichment_timeout_ms),
+            risk_thresholds: std::sync::RwLock::new(config.risk_thresholds),
+            pattern_weights: config.pattern_weights,
+            event_history_size: config.event_history_size,
+            max_event_age: Duration::seconds(config.max_event_age_secs.min(MAX_EVENT_AGE_SECS) as i64),
+            challenge_escalation_step: config.challenge_escalation_step.max(1),
+            challenge_reset: Duration::seconds((config.challenge_reset_hours * 3600.0) as i64),
+            vips: DashMap::new(),
+            vip_max_action: config.vip_max_action,
+            shutdown: watch::channel(false).0,
         }
     }
 
-    pub async fn analyze(&self, event: &BehaviorEvent) -> Result<AnomalyScore, String> {
+    /// Registra cada score de `analyze()` en `sink` sin bloquear el scoring.
+    /// Debe invocarse dentro de un runtime de tokio.
+    pub fn with_score_sink(mut self, sink: Arc<dyn ScoreSink>) -> Self {
+        self.score_history = Some(ScoreRecorder::spawn(sink, SCORE_QUEUE_CAPACITY));
+        self
+    }
+
+    /// Sustituye el matcher por defecto (p.ej. con firmas cargadas desde fichero).
+    pub fn with_pattern_matcher(mut self, matcher: PatternMatcher) -> Self {
+        self.pattern_matcher = Arc::new(matcher);
+        self
+    }
+
+    /// Añade una fuente de enriquecimiento (p.ej. reputación de IP) consultada antes de
+    /// puntuar cada evento en `analyze()`/`simulate()`.
+    pub fn with_enricher(mut self, enricher: Arc<dyn EventEnricher>) -> Self {
+        self.enrichers.push(enricher);
+        self
+    }
+
+    /// `true` si hay enriquecedores: un evento sin indicadores propios puede ganarlos.
+    pub fn has_enrichers(&self) -> bool {
+        !self.enrichers.is_empty()
+    }
+
+    /// Persiste todos los perfiles en memoria en el store. Devuelve cuántos se guardaron.
+    /// Se toma un snapshot primero para no mantener guards del DashMap durante los awaits.
+    pub async fn flush(&self) -> usize {
+        let snapshot: Vec<ClientProfile> = self.profiles.iter().map(|r| r.value().clone()).collect();
+        let mut saved = 0;
+        for profile in &snapshot {
+            match self.store.save(profile).await {
+                Ok(()) => saved += 1,
+                Err(e) => log::warn!("[SECURITY] Flush falló para {}:{}: {}", profile.tenant_id, profile.client_id, e),
+            }
+        }
+        // Después, lo que quede en la cola del histórico (los perfiles tienen prioridad)
+        if let Some(history) = &self.score_history {
+            history.flush().await;
+        }
+        saved
+    }
+
+    /// Rehidrata el mapa en memoria desde el store. Devuelve cuántos perfiles se cargaron.
+    pub async fn restore(&self) -> usize {
+        let loaded = self.store.load_all().await;
+        let count = loaded.len();
+        for profile in loaded {
+            let key = (profile.tenant_id.clone(), profile.client_id.clone());
+            self.profiles.insert(key, profile);
+        }
+        count
+    }
+
+    /// Analiza un evento, actualiza el perfil del cliente y devuelve su score.
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "anomaly.analyze", skip_all, fields(tenant_id = %event.tenant_id))
+    )]
+    pub async fn analyze(&self, event: &BehaviorEvent) -> Result<AnomalyScore, DetectorError> {
+        validate_event(event)?;
+        self.count_event(&event.tenant_id);
+        let prepared = self.prepare(event).await;
+        let event = prepared.as_ref().unwrap_or(event);
+
         // 1. Protección Anti-DoS de Memoria
         if self.profiles.len() >= self.max_profiles {
             self.cleanup_stale_profiles();
@@ -108,124 +254,350 @@ impl AnomalyDetector {
         // 2. Clave Compuesta (Tenant Isolation)
         let key = (event.tenant_id.clone(), event.client_id.clone());
 
-        // 3. Obtener o Crear Perfil (Operación Atómica con DashMap)
-        let mut profile = self.profiles.entry(key.clone()).or_insert_with(|| ClientProfile {
-            tenant_id: event.tenant_id.clone(),
-            client_id: event.client_id.clone(),
-            first_seen: Utc::now(),
-            last_seen: Utc::now(),
-            total_events: 0,
-            risk_score: 0.0,
-            is_compromised: false,
-            threat_level: ThreatLevel::Safe,
-        });
+        // Si la purga no liberó hueco (todo comprometido) no se crean perfiles nuevos
+        if self.profiles.len() >= self.max_profiles && !self.profiles.contains_key(&key) {
+            return Err(DetectorError::CapacityExceeded { max_profiles: self.max_profiles });
+        }
+
+        // 3. Cache miss local: intentar recuperar el perfil desde el store
+        //    (p.ej. otra réplica pudo haberlo marcado como comprometido).
+        //    Con el store caído (breaker abierto) se sigue solo con la memoria local
+        if !self.profiles.contains_key(&key) {
+            let load = self.store.load(&event.tenant_id, &event.client_id);
+            if let Ok(Some(stored)) = self.store_breaker.call(async { Ok::<_, String>(load.await) }).await {
+                self.profiles.entry(key.clone()).or_insert(stored);
+            }
+        }
+
+        // La copia local puede estar desfasada: otra réplica pudo comprometer al cliente
+        // después. La marca compartida se consulta en cada evento (solo stores durables)
+        let mark = if self.store.is_durable() {
+            let load = self.store.load_compromise(&event.tenant_id, &event.client_id);
+            self.store_breaker.call(async { Ok::<_, String>(load.await) }).await.ok().flatten()
+        } else {
+            None
+        };
+
+        // Lo asíncrono se resuelve antes de tomar el guard (ver `evaluate`)
+        let tenant_multiplier = self.tenant_multiplier(&event.tenant_id).await;
+
+        // 4. Obtener o Crear Perfil (Operación Atómica con DashMap)
+        let mut profile = self.profiles.entry(key.clone()).or_insert_with(|| new_profile(event));
+        if let Some(at) = mark {
+            apply_compromise_mark(&mut profile, at);
+        }
+
+        // 5–9. Evaluación sobre el perfil vivo, síncrona: las llamadas concurrentes para la
+        // misma clave se serializan en el shard y ninguna actualización se pierde
+        let (result, scored) = self.evaluate(&mut profile, event, tenant_multiplier, self.tuning());
+        if let Some(history) = &self.score_history {
+            history.record(&result);
+        }
+        if !scored {
+            return Ok(result);
+        }
+
+        // 10. Persistencia: liberar el guard del DashMap antes de esperar al store
+        // Con el breaker abierto no se espera: el perfil sigue en memoria y lo persiste `flush()`
+        let snapshot = profile.clone();
+        drop(profile);
+        match self.store_breaker.call(self.store.save(&snapshot)).await {
+            Ok(()) | Err(BreakerError::Open) => {}
+            Err(e) => log::warn!("[SECURITY] No se pudo persistir el perfil {}:{}: {}", snapshot.tenant_id, snapshot.client_id, e),
+        }
+
+        // Solo llegamos aquí con perfiles no comprometidos: Critical es siempre una transición
+        if result.level == ThreatLevel::Critical {
+            if let Some(alerter) = &self.alerter {
+                alerter.notify(&result);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Calcula el score que obtendría `event` sin modificar ningún perfil,
+    /// sin persistir y sin disparar alertas (evaluación sobre una copia).
+    pub async fn simulate(&self, event: &BehaviorEvent) -> Result<AnomalyScore, DetectorError> {
+        self.simulate_with(event, self.sensitivity, self.risk_thresholds()).await
+    }
 
-        // 4. Actualización de Metadatos
+    /// Como `simulate`, pero con otra sensibilidad y otros cortes de nivel (what-if):
+    /// la configuración del detector no cambia.
+    pub async fn simulate_with(
+        &self,
+        event: &BehaviorEvent,
+        sensitivity: f64,
+        risk_thresholds: RiskThresholds,
+    ) -> Result<AnomalyScore, DetectorError> {
+        validate_event(event)?;
+        let prepared = self.prepare(event).await;
+        let event = prepared.as_ref().unwrap_or(event);
+        let key = (event.tenant_id.clone(), event.client_id.clone());
+        let local = self.profiles.get(&key).map(|r| r.value().clone());
+        let mut profile = match local {
+            Some(profile) => profile,
+            None => self
+                .store
+                .load(&event.tenant_id, &event.client_id)
+                .await
+                .unwrap_or_else(|| new_profile(event)),
+        };
+
+        let tenant_multiplier = self.tenant_multiplier(&event.tenant_id).await;
+        let (result, _) = self.evaluate(&mut profile, event, tenant_multiplier, Tuning { sensitivity, risk_thresholds });
+        Ok(result)
+    }
+
+    // Timestamp acotado y enriquecimiento externo, antes de tomar ningún guard.
+    // None = el evento se usa tal cual (sin copia)
+    async fn prepare(&self, event: &BehaviorEvent) -> Option<BehaviorEvent> {
+        let clamped = clamp_timestamp(event, Utc::now(), self.max_event_age);
+        if self.enrichers.is_empty() {
+            return clamped;
+        }
+        let mut enriched = clamped.unwrap_or_else(|| event.clone());
+        run_enrichers(&self.enrichers, &mut enriched, self.enrichment_timeout).await;
+        Some(enriched)
+    }
+
+    /// Aplica un evento sobre `profile` (metadatos, patrones, riesgo, recomendación).
+    /// Devuelve el score y si hubo análisis (`false` = perfil ya comprometido).
+    ///
+    /// Síncrona a propósito: `analyze` la llama con el guard del shard de DashMap tomado.
+    /// Un `.await` aquí dejaría el shard bloqueado mientras la tarea está suspendida, y
+    /// cualquier otra tarea del mismo hilo que tocara ese shard lo bloquearía (deadlock con
+    /// pocos workers). Todo lo asíncrono (umbrales del tenant) se resuelve antes.
+    ///
+    /// Dos relojes: el estado del perfil (last_seen, intervalos, decay, TTL) usa siempre la
+    /// hora del servidor, que el llamador no controla; `event.timestamp` (ya acotado) solo
+    /// fecha el resultado y el historial forense.
+    fn evaluate(
+        &self,
+        profile: &mut ClientProfile,
+        event: &BehaviorEvent,
+        tenant_multiplier: f64,
+        tuning: Tuning,
+    ) -> (AnomalyScore, bool) {
+        // 5. Actualización de Metadatos
+        // Guardamos el last_seen previo: el decay depende del tiempo de inactividad
+        let previous_seen = profile.last_seen;
         profile.last_seen = Utc::now();
         profile.total_events += 1;
+        // Media móvil de la confianza que el emisor declara en sus eventos
+        let confidence = event.confidence.clamp(0.0, 1.0);
+        profile.average_confidence += (confidence - profile.average_confidence) / profile.total_events as f64;
+        if profile.total_events > 1 {
+            let interval_ms = (profile.last_seen - previous_seen).num_microseconds().unwrap_or(i64::MAX) as f64 / 1000.0;
+            if profile.request_intervals_ms.len() >= TIMING_BUFFER_SIZE {
+                profile.request_intervals_ms.pop_front();
+            }
+            profile.request_intervals_ms.push_back(interval_ms);
+        }
+
+        // Un bloqueo expirado se levanta y el evento se analiza con normalidad,
+        // pero el cliente queda vigilado (ver incident_floor)
+        if profile.is_compromised && self.compromise_expired(profile) {
+            release_profile(profile);
+            profile.released_at = Some(profile.last_seen);
+        }
 
         // Si ya está comprometido, bloquear inmediatamente sin gastar CPU en análisis
         if profile.is_compromised {
-            return Ok(AnomalyScore {
+            let blocked = AnomalyScore {
                 client_id: event.client_id.clone(),
                 tenant_id: event.tenant_id.clone(),
                 score: 1.0,
                 level: ThreatLevel::Critical,
                 detected_patterns: vec![], // Ya no importa
-                timestamp: Utc::now(),
+                reasons: vec![COMPROMISED_REASON.to_string()],
+                pattern_contributions: HashMap::new(),
+                timestamp: event.timestamp,
                 recommendation: "BLOCK_PERMANENTLY".to_string(),
-            });
+            };
+            self.record_event(profile, &blocked);
+            return (blocked, false);
         }
 
-        // 5. Detección de Patrones
-        let detected_patterns = self.pattern_matcher.detect(event);
+        // 6. Detección de Patrones
+        // Indicadores derivados del perfil: timing_variance (si el llamador no lo aporta)
+        // de los intervalos observados y new_device de los dispositivos conocidos
+        let mut derived = HashMap::new();
+        if let Some(dispersion) = timing_dispersion(&profile.request_intervals_ms) {
+            if !event.indicators.contains_key(KEY_TIMING_VARIANCE) {
+                derived.insert(KEY_TIMING_VARIANCE.to_string(), dispersion);
+            }
+        }
+        if let Some(device_id) = event.device_id.as_deref().filter(|d| !d.is_empty()) {
+            if record_device(profile, device_id) {
+                derived.insert(KEY_NEW_DEVICE.to_string(), 1.0);
+            }
+        }
+        let enriched;
+        let event = if derived.is_empty() {
+            event
+        } else {
+            let mut indicators = event.indicators.clone();
+            indicators.extend(derived);
+            enriched = BehaviorEvent { indicators, ..event.clone() };
+            &enriched
+        };
+        let detection = self.pattern_matcher.detect_detailed(event);
+        let detected_patterns = detection.patterns;
 
-        // 6. Cálculo de Score (Corregido)
+        // 7. Cálculo de Score: todos los patrones suman y cada aporte queda registrado
         let mut score = 0.0;
         let mut critical_trigger = false;
+        let mut pattern_contributions = HashMap::new();
 
         for pattern in &detected_patterns {
-            let p_score = self.calculate_pattern_score(pattern, &event.indicators).await;
+            // Las reglas del tenant traen su propio peso (suma de las cumplidas)
+            let p_score = match pattern {
+                BehaviorPattern::TenantRule => (detection.rule_weight * tenant_multiplier).min(1.0),
+                _ => self.calculate_pattern_score(pattern, &event.indicators, tenant_multiplier),
+            };
             score += p_score;
-            
-            // Si hay inyección de payload, es CRÍTICO inmediatamente
+            pattern_contributions.insert(pattern.clone(), p_score);
+
+            // Si hay inyección de payload, es CRÍTICO pase lo que pase con los pesos
             if *pattern == BehaviorPattern::PayloadInjection {
                 critical_trigger = true;
-                score = 1.0; 
             }
         }
 
-        // Normalización inteligente (Acumulación con techo)
-        score = score.min(1.0).max(0.0);
+        // Un evento poco fiable (p.ej. un sensor heurístico) aporta en proporción a su confianza
+        score = combine_score(score * confidence, critical_trigger, tuning.sensitivity);
 
-        // 7. Determinación de Nivel de Amenaza
-        let level = match score {
-            _ if critical_trigger => ThreatLevel::Critical, // Prioridad máxima
-            s if s >= 0.9 => ThreatLevel::Critical,
-            s if s >= 0.75 => ThreatLevel::High,
-            s if s >= 0.5 => ThreatLevel::Medium,
-            s if s >= 0.25 => ThreatLevel::Low,
-            _ => ThreatLevel::Safe,
+        // 8. Determinación de Nivel de Amenaza
+        let level = if critical_trigger {
+            ThreatLevel::Critical // Prioridad máxima
+        } else {
+            tuning.risk_thresholds.level(score)
         };
 
-        // 8. Actualización de Riesgo en el Perfil (Con memoria)
-        // Usamos una media ponderada que da más peso al nuevo evento si es alto riesgo
-        if score > profile.risk_score {
-            // El riesgo sube rápido
-            profile.risk_score = score;
-        } else {
-            // El riesgo baja lento (decay)
-            profile.risk_score = (profile.risk_score * 0.9) + (score * 0.1);
-        }
+        // 9. Actualización de Riesgo en el Perfil (Con memoria)
+        // El riesgo baja con el tiempo de inactividad (no con el número de eventos)
+        // y sube de inmediato si el evento actual es más grave
+        let elapsed = profile.last_seen - previous_seen;
+        let decayed = decay_risk(profile.risk_score, elapsed, self.risk_half_life);
+        profile.risk_score = decayed.max(score).max(self.incident_floor(profile));
         
-        profile.threat_level = level.clone();
+        profile.threat_level = level;
 
+        // Un VIP nunca queda comprometido: el nivel Critical se conserva para la auditoría
+        let vip = self.vips.contains_key(&(event.tenant_id.clone(), event.client_id.clone()));
         if level == ThreatLevel::Critical {
-            profile.is_compromised = true;
+            if vip {
+                log::warn!(
+                    "[SECURITY] VIP {}:{} con score crítico {:.2}: no se marca como comprometido",
+                    event.tenant_id, event.client_id, score
+                );
+            } else {
+                profile.is_compromised = true;
+                profile.compromised_at = Some(profile.last_seen);
+                profile.incident_count += 1;
+            }
         }
 
-        // Recomendación de Seguridad para el Frontend/Gateway
+        // Recomendación de Seguridad para el Frontend/Gateway.
+        // Los reincidentes suben en la escalera de fricción más rápido
         let recommendation = match level {
             ThreatLevel::Critical => "ISOLATE_SESSION".to_string(),
-            ThreatLevel::High => "REQUIRE_MFA".to_string(),
-            ThreatLevel::Medium => "THROTTLE_REQUESTS".to_string(),
+            ThreatLevel::High | ThreatLevel::Medium => self.escalate_challenge(profile, level),
             ThreatLevel::Low => "LOG_WARNING".to_string(),
             ThreatLevel::Safe => "ALLOW".to_string(),
         };
+        let recommendation = if vip { self.cap_vip_recommendation(level, recommendation) } else { recommendation };
 
-        Ok(AnomalyScore {
+        let reasons = detected_patterns.iter().map(|p| p.human_reason().to_string()).collect();
+
+        let result = AnomalyScore {
             client_id: event.client_id.clone(),
             tenant_id: event.tenant_id.clone(),
             score,
             level,
             detected_patterns,
-            timestamp: Utc::now(),
+            reasons,
+            pattern_contributions,
+            timestamp: event.timestamp,
             recommendation,
-        })
+        };
+        self.record_event(profile, &result);
+        (result, true)
     }
 
-    async fn calculate_pattern_score(
-        &self,
-        pattern: &BehaviorPattern,
-        indicators: &HashMap<String, f64>,
-    ) -> f64 {
-        let base_score = match pattern {
-            BehaviorPattern::PayloadInjection => 1.0, // Instakill
-            BehaviorPattern::CredentialSpray => 0.9,
-            BehaviorPattern::Enumeration => 0.8,
-            BehaviorPattern::ResourceAbuse => 0.7,
-            BehaviorPattern::RapidFailures => 0.6,
-            BehaviorPattern::TimingAttack => 0.5,
-            BehaviorPattern::DeviceChange => 0.4,
-            BehaviorPattern::AnomalousLocation => 0.3,
-            BehaviorPattern::Normal => 0.0,
-        };
+    // "Once burned, watch closely": tras una liberación automática el riesgo no baja de
+    // min_risk_after_incident durante incident_watch (0 fuera de esa ventana)
+    fn incident_floor(&self, profile: &ClientProfile) -> f64 {
+        match (self.incident_watch, profile.released_at) {
+            (Some(watch), Some(released)) if profile.last_seen - released < watch => self.min_risk_after_incident,
+            _ => 0.0,
+        }
+    }
+
+    // Tope de la recomendación de un VIP: nunca se aísla la sesión, y con ALLOW ni challenge
+    fn cap_vip_recommendation(&self, level: ThreatLevel, recommendation: String) -> String {
+        match self.vip_max_action {
+            Action::Allow if level >= ThreatLevel::Medium => "LOG_WARNING".to_string(),
+            _ if recommendation == "ISOLATE_SESSION" => "REQUIRE_MFA".to_string(),
+            _ => recommendation,
+        }
+    }
+
+    /// Añade el resultado al historial forense del perfil (ring buffer acotado).
+    fn record_event(&self, profile: &mut ClientProfile, result: &AnomalyScore) {
+        if self.event_history_size == 0 {
+            return;
+        }
+        while profile.recent_events.len() >= self.event_history_size {
+            profile.recent_events.pop_front();
+        }
+        profile.recent_events.push_back(EventSummary {
+            timestamp: result.timestamp,
+            patterns: result.detected_patterns.clone(),
+            score: result.score,
+            level: result.level,
+        });
+    }
+
+    /// Registra un challenge y devuelve la recomendación escalada según la reincidencia.
+    fn escalate_challenge(&self, profile: &mut ClientProfile, level: ThreatLevel) -> String {
+        let now = profile.last_seen;
+        if let Some(last) = profile.last_challenged_at {
+            if now - last > self.challenge_reset {
+                profile.challenge_count = 0;
+            }
+        }
+
+        let base = if level == ThreatLevel::High { 1 } else { 0 };
+        let steps = (profile.challenge_count / self.challenge_escalation_step) as usize;
+        let rung = (base + steps).min(CHALLENGE_LADDER.len() - 1);
+
+        profile.challenge_count = profile.challenge_count.saturating_add(1);
+        profile.last_challenged_at = Some(now);
+
+        CHALLENGE_LADDER[rung].to_string()
+    }
+
+    // Apetito de riesgo del tenant (p.ej. 1.5 = más agresivo, 0.5 = más permisivo)
+    async fn tenant_multiplier(&self, tenant_id: &str) -> f64 {
+        self.tenant_threshold(tenant_id, "score_multiplier").await.unwrap_or(1.0)
+    }
+
+    fn calculate_pattern_score(&self, pattern: &BehaviorPattern, indicators: &HashMap<String, f64>, tenant_multiplier: f64) -> f64 {
+        let base_score = self.pattern_weights.get(pattern).copied().unwrap_or(0.0);
 
         let mut multiplier = 1.0;
         if let Some(&failure_rate) = indicators.get("failure_rate") {
             // Si la tasa de fallo es alta, el patrón es más peligroso
             multiplier += failure_rate; 
         }
+        // La reputación pesa según su certeza (0.6 = sospechosa, 1.0 = maliciosa conocida)
+        if *pattern == BehaviorPattern::BadReputation {
+            multiplier *= indicators.get(KEY_IP_REPUTATION).copied().unwrap_or(0.0).clamp(0.0, 1.0);
+        }
+
+        multiplier *= tenant_multiplier;
 
         (base_score * multiplier).min(1.0)
     }
@@ -233,26 +605,1088 @@ impl AnomalyDetector {
     // MEJORA: Función para prevenir desbordamiento de memoria (DoS)
     // Elimina perfiles inactivos por más de 24 horas
     fn cleanup_stale_profiles(&self) {
-        // En DashMap, retain escanea y elimina eficientemente
-        let threshold_time = Utc::now() - Duration::hours(24);
+        // En DashMap, retain escanea y elimina eficientemente.
+        // Los perfiles comprometidos nunca se eliminan: borrarlos "perdonaría" al atacante
+        let threshold_time = Utc::now() - Duration::hours(STALE_PROFILE_HOURS);
         self.profiles.retain(|_, profile| {
-            profile.last_seen > threshold_time && !profile.is_compromised
+            profile.last_seen > threshold_time || profile.is_compromised
         });
-        
-        // Si aún estamos llenos (ataque activo), purga aleatoria de seguridad
+
+        if let Some(alerter) = &self.alerter {
+            alerter.purge_expired();
+        }
+
+        // Si aún estamos llenos (ataque activo), purga parcial tipo LRU
         if self.profiles.len() >= self.max_profiles {
-            self.profiles.clear(); // Drastic but safe fallback
+            self.evict_least_recently_seen();
         }
     }
 
+    /// Lanza una tarea Tokio que barre perfiles obsoletos cada `every`,
+    /// alcance o no el límite de capacidad. Termina al llamar a `shutdown()`.
+    pub fn spawn_cleanup(self: &Arc<Self>, every: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        let detector = Arc::clone(self);
+        let mut stop = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            tick.tick().await; // El primer tick es inmediato
+            loop {
+                tokio::select! {
+                    _ = tick.tick() => {
+                        let before = detector.profiles.len();
+                        detector.cleanup_stale_profiles();
+                        log::debug!("[SECURITY] Barrido periódico: {} -> {} perfiles", before, detector.profiles.len());
+                    }
+                    _ = stop.changed() => break,
+                }
+            }
+        })
+    }
+
+    /// Detiene las tareas en segundo plano del detector.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    // Expulsa los perfiles no comprometidos con el last_seen más antiguo hasta
+    // bajar al EVICTION_LOW_WATER del límite (así no se ordena en cada evento)
+    fn evict_least_recently_seen(&self) {
+        let target = (self.max_profiles as f64 * EVICTION_LOW_WATER) as usize;
+        let excess = self.profiles.len().saturating_sub(target);
+        if excess == 0 {
+            return;
+        }
+
+        let mut candidates: Vec<(ProfileKey, DateTime<Utc>)> = self
+            .profiles
+            .iter()
+            .filter(|r| !r.value().is_compromised)
+            .map(|r| (r.key().clone(), r.value().last_seen))
+            .collect();
+        candidates.sort_unstable_by_key(|(_, last_seen)| *last_seen);
+
+        let mut evicted = 0;
+        for (key, _) in candidates.into_iter().take(excess) {
+            // remove_if: no expulsar un perfil que se comprometió mientras ordenábamos
+            if self.profiles.remove_if(&key, |_, p| !p.is_compromised).is_some() {
+                evicted += 1;
+            }
+        }
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    // Escala y cortes vigentes del detector
+    fn tuning(&self) -> Tuning {
+        Tuning {
+            sensitivity: self.sensitivity,
+            risk_thresholds: self.risk_thresholds(),
+        }
+    }
+
+    /// Score de `result` sin el aporte de los patrones `exclude` y **sin** la escala de
+    /// sensibilidad (aportes sumados con techo 1.0, o 1.0 con una inyección). Sirve para
+    /// no sumar dos veces una señal que el llamador ya puntuó; quien combina este valor
+    /// con otros aplica `apply_sensitivity` una sola vez al total.
+    pub fn score_without(&self, result: &AnomalyScore, exclude: &[BehaviorPattern]) -> f64 {
+        // Sin patrones: limpio (0) o perfil ya comprometido (1.0, fuera de la sensibilidad)
+        if result.detected_patterns.is_empty() {
+            return result.score;
+        }
+        let kept: Vec<&BehaviorPattern> = result.detected_patterns.iter().filter(|p| !exclude.contains(p)).collect();
+        if kept.contains(&&BehaviorPattern::PayloadInjection) {
+            return 1.0;
+        }
+        kept.iter().filter_map(|p| result.pattern_contributions.get(*p)).sum::<f64>().clamp(0.0, 1.0)
+    }
+
+    /// Cuenta un evento procesado. `analyze()` ya lo hace; el servicio HTTP lo llama
+    /// para las peticiones que no pasan por el motor de perfiles.
+    pub fn count_event(&self, tenant_id: &str) {
+        self.events_processed.fetch_add(1, Ordering::Relaxed);
+        // Camino rápido con lock de lectura del shard; solo un tenant nuevo escribe
+        match self.tenant_events.get(tenant_id) {
+            Some(counter) => counter.fetch_add(1, Ordering::Relaxed),
+            None => self.tenant_events.entry(tenant_id.to_string()).or_default().fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn events_processed(&self) -> u64 {
+        self.events_processed.load(Ordering::Relaxed)
+    }
+
+    /// Total de perfiles expulsados por presión de capacidad.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Scores descartados por tener la cola del histórico llena (None = sin histórico).
+    pub fn score_history_dropped(&self) -> Option<u64> {
+        self.score_history.as_ref().map(ScoreRecorder::dropped)
+    }
+
+    fn compromise_expired(&self, profile: &ClientProfile) -> bool {
+        match (self.compromise_ttl, profile.compromised_at) {
+            (Some(ttl), Some(at)) => Utc::now() - at >= ttl,
+            _ => false,
+        }
+    }
+
+    /// Desbloqueo administrativo: limpia el flag de compromiso y reinicia el riesgo.
+    /// Devuelve `false` si el perfil no existe.
+    pub async fn unblock(&self, tenant_id: &str, client_id: &str) -> bool {
+        let snapshot = {
+            let key = (tenant_id.to_string(), client_id.to_string());
+            let Some(mut profile) = self.profiles.get_mut(&key) else {
+                return false;
+            };
+            release_profile(&mut profile);
+            profile.clone()
+        };
+
+        if let Err(e) = self.store.clear_compromise(tenant_id, client_id).await {
+            log::warn!("[SECURITY] No se pudo levantar la marca de compromiso de {}:{}: {}", tenant_id, client_id, e);
+        }
+        if let Err(e) = self.store.save(&snapshot).await {
+            log::warn!("[SECURITY] No se pudo persistir el desbloqueo de {}:{}: {}", tenant_id, client_id, e);
+        }
+        log::info!("[SECURITY] Perfil {}:{} desbloqueado manualmente", tenant_id, client_id);
+        true
+    }
+
+    /// Marca un cliente como VIP de su tenant. Se persiste antes de aplicarse: un VIP
+    /// que no sobrevive a un reinicio no se da por bueno.
+    pub async fn add_vip(&self, entry: VipEntry) -> Result<(), DetectorError> {
+        let raw = serde_json::to_string(&entry).map_err(|e| DetectorError::StoreUnavailable(e.to_string()))?;
+        self.store
+            .save_record(VIP_NAMESPACE, &composite_key(&entry.tenant_id, &entry.client_id), &raw)
+            .await
+            .map_err(DetectorError::StoreUnavailable)?;
+        log::info!("[SECURITY] VIP {}:{} añadido", entry.tenant_id, entry.client_id);
+        self.vips.insert((entry.tenant_id.clone(), entry.client_id.clone()), entry);
+        Ok(())
+    }
+
+    /// Quita un VIP. `false` si no lo era.
+    pub async fn remove_vip(&self, tenant_id: &str, client_id: &str) -> bool {
+        if self.vips.remove(&(tenant_id.to_string(), client_id.to_string())).is_none() {
+            return false;
+        }
+        if let Err(e) = self.store.remove_record(VIP_NAMESPACE, &composite_key(tenant_id, client_id)).await {
+            log::warn!("[SECURITY] No se pudo borrar el VIP {}:{} del store: {}", tenant_id, client_id, e);
+        }
+        log::info!("[SECURITY] VIP {}:{} retirado", tenant_id, client_id);
+        true
+    }
+
+    /// VIP de un tenant, ordenados por client_id.
+    pub fn vips(&self, tenant_id: &str) -> Vec<VipEntry> {
+        let mut vips: Vec<VipEntry> = self
+            .vips
+            .iter()
+            .filter(|r| r.key().0 == tenant_id)
+            .map(|r| r.value().clone())
+            .collect();
+        vips.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        vips
+    }
+
+    pub fn is_vip(&self, tenant_id: &str, client_id: &str) -> bool {
+        self.vips.contains_key(&(tenant_id.to_string(), client_id.to_string()))
+    }
+
+    /// Acción máxima de un VIP (ver `SecurityConfig::vip_max_action`).
+    pub fn vip_max_action(&self) -> Action {
+        self.vip_max_action
+    }
+
+    /// Rehidrata los VIP persistidos. Devuelve cuántos se cargaron.
+    pub async fn restore_vips(&self) -> usize {
+        for (key, raw) in self.store.load_records(VIP_NAMESPACE).await {
+            match serde_json::from_str::<VipEntry>(&raw) {
+                Ok(entry) => {
+                    self.vips.insert((entry.tenant_id.clone(), entry.client_id.clone()), entry);
+                }
+                Err(e) => log::warn!("[SECURITY] VIP corrupto ignorado {}: {}", key, e),
+            }
+        }
+        self.vips.len()
+    }
+
+    /// Borra todos los perfiles de un tenant (baja del cliente, GDPR), en memoria y en el store,
+    /// incluidos los que solo quedan persistidos. Devuelve cuántos se eliminaron.
+    pub async fn purge_tenant(&self, tenant_id: &str) -> usize {
+        let mut removed = BTreeSet::new();
+        // Igualdad exacta sobre el tenant: nunca toca claves de otro tenant con el mismo prefijo
+        self.profiles.retain(|(tenant, client), _| {
+            if tenant == tenant_id {
+                removed.insert(client.clone());
+                return false;
+            }
+            true
+        });
+        for profile in self.store.load_all().await {
+            if profile.tenant_id == tenant_id {
+                removed.insert(profile.client_id);
+            }
+        }
+
+        for client_id in &removed {
+            if let Err(e) = self.store.remove(tenant_id, client_id).await {
+                log::warn!("[SECURITY] No se pudo borrar del store {}:{}: {}", tenant_id, client_id, e);
+            }
+        }
+        self.tenant_events.remove(tenant_id);
+        let vips: Vec<String> = self.vips(tenant_id).into_iter().map(|v| v.client_id).collect();
+        for client_id in &vips {
+            self.remove_vip(tenant_id, client_id).await;
+        }
+        log::info!("[SECURITY] Tenant {} purgado: {} perfiles", tenant_id, removed.len());
+        removed.len()
+    }
+
+    /// Estado de los circuit breakers de las dependencias externas (para /health).
+    pub fn breakers(&self) -> Vec<BreakerStatus> {
+        let mut breakers = vec![self.store_breaker.status()];
+        breakers.extend(self.alerter.as_ref().map(|alerter| alerter.breaker_status()));
+        breakers
+    }
+
+    /// Backend de persistencia (para registros auxiliares como la blocklist).
+    pub fn store(&self) -> &dyn ProfileStore {
+        self.store.as_ref()
+    }
+
+    /// Claves de todos los perfiles en memoria (export en streaming: los perfiles se
+    /// leen uno a uno después, sin copiar el mapa entero).
+    pub fn profile_keys(&self) -> Vec<ProfileKey> {
+        self.profiles.iter().map(|r| r.key().clone()).collect()
+    }
+
+    /// Inserta (o reemplaza) un perfil exportado de otra instancia y lo persiste.
+    pub async fn import_profile(&self, profile: ClientProfile) -> Result<(), DetectorError> {
+        if profile.tenant_id.trim().is_empty() || profile.client_id.trim().is_empty() {
+            return Err(DetectorError::InvalidEvent("tenant_id and client_id are required".to_string()));
+        }
+        let key = (profile.tenant_id.clone(), profile.client_id.clone());
+        if self.profiles.len() >= self.max_profiles && !self.profiles.contains_key(&key) {
+            return Err(DetectorError::CapacityExceeded { max_profiles: self.max_profiles });
+        }
+        if let Err(e) = self.store.save(&profile).await {
+            log::warn!("[SECURITY] No se pudo persistir el perfil importado {}:{}: {}", profile.tenant_id, profile.client_id, e);
+        }
+        self.profiles.insert(key, profile);
+        Ok(())
+    }
+
+    pub fn has_profile(&self, tenant_id: &str, client_id: &str) -> bool {
+        self.profiles.contains_key(&(tenant_id.to_string(), client_id.to_string()))
+    }
+
     // Helpers
     pub fn get_profile(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile> {
         self.profiles.get(&(tenant_id.to_string(), client_id.to_string())).map(|r| r.value().clone())
     }
 
-    fn default_thresholds() -> HashMap<String, f64> {
+    /// Resumen por tenant en una sola pasada sobre los perfiles, ordenado por tenant_id.
+    /// Incluye los tenants con eventos procesados aunque ya no tengan perfiles en memoria.
+    pub fn tenant_stats(&self) -> Vec<TenantStats> {
+        let mut by_tenant: HashMap<String, TenantStats> = HashMap::new();
+        for entry in self.profiles.iter() {
+            let profile = entry.value();
+            let stats = by_tenant.entry(profile.tenant_id.clone()).or_insert_with(|| TenantStats {
+                tenant_id: profile.tenant_id.clone(),
+                ..TenantStats::default()
+            });
+            stats.profiles += 1;
+            stats.compromised += profile.is_compromised as usize;
+            stats.average_risk += profile.risk_score; // Suma; se divide al final
+            *stats.threat_levels.entry(profile.threat_level).or_insert(0) += 1;
+        }
+
+        for entry in self.tenant_events.iter() {
+            let stats = by_tenant.entry(entry.key().clone()).or_insert_with(|| TenantStats {
+                tenant_id: entry.key().clone(),
+                ..TenantStats::default()
+            });
+            stats.events_processed = entry.value().load(Ordering::Relaxed);
+        }
+
+        let mut stats: Vec<TenantStats> = by_tenant.into_values().collect();
+        for s in stats.iter_mut().filter(|s| s.profiles > 0) {
+            s.average_risk /= s.profiles as f64;
+        }
+        stats.sort_by(|a, b| a.tenant_id.cmp(&b.tenant_id));
+        stats
+    }
+
+    pub fn active_profiles(&self) -> usize {
+        self.profiles.len()
+    }
+
+    /// Perfiles comprometidos (opcionalmente de un solo tenant), ordenados por (tenant, cliente).
+    pub fn compromised_clients(&self, tenant_id: Option<&str>) -> Vec<CompromisedClient> {
+        let mut clients: Vec<CompromisedClient> = self
+            .profiles
+            .iter()
+            .filter(|entry| {
+                let p = entry.value();
+                p.is_compromised && tenant_id.is_none_or(|t| p.tenant_id == t)
+            })
+            .map(|entry| {
+                let p = entry.value();
+                CompromisedClient {
+                    tenant_id: p.tenant_id.clone(),
+                    client_id: p.client_id.clone(),
+                    risk_score: p.risk_score,
+                    last_seen: p.last_seen,
+                    compromised_at: p.compromised_at,
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| (&a.tenant_id, &a.client_id).cmp(&(&b.tenant_id, &b.client_id)));
+        clients
+    }
+
+    pub fn max_profiles(&self) -> usize {
+        self.max_profiles
+    }
+
+    pub fn risk_half_life(&self) -> Duration {
+        self.risk_half_life
+    }
+
+    /// Pesos y umbrales vigentes del motor (incluidos los cambiados en caliente),
+    /// para calcular la versión del ruleset.
+    pub async fn ruleset(&self) -> serde_json::Value {
+        serde_json::json!({
+            "pattern_weights": self.pattern_weights.iter().map(|(p, w)| (p.code(), *w)).collect::<HashMap<_, _>>(),
+            "risk_thresholds": self.risk_thresholds(),
+            "sensitivity": self.sensitivity,
+            "thresholds": *self.thresholds.read().await,
+            "tenant_thresholds": *self.tenant_thresholds.read().await,
+            "disabled_patterns": self.pattern_matcher.disabled_patterns(),
+            "metadata_rules": self.pattern_matcher.all_metadata_rules(),
+        })
+    }
+
+    /// Sustituye las reglas de metadata de un tenant (vacío = ninguna).
+    pub fn set_metadata_rules(&self, tenant_id: &str, rules: Vec<MetadataRule>) -> Result<(), String> {
+        self.pattern_matcher.set_metadata_rules(tenant_id, rules)
+    }
+
+    pub fn metadata_rules(&self, tenant_id: &str) -> Vec<MetadataRule> {
+        self.pattern_matcher.metadata_rules(tenant_id)
+    }
+
+    /// `true` si el tenant declaró reglas: un evento con metadata puede puntuar sin indicadores.
+    pub fn has_metadata_rules(&self, tenant_id: &str) -> bool {
+        self.pattern_matcher.has_metadata_rules(tenant_id)
+    }
+
+    /// Activa o desactiva patrones en caliente, globalmente (`None`) o para un tenant.
+    pub fn set_disabled_patterns(&self, tenant_id: Option<&str>, patterns: HashSet<BehaviorPattern>) {
+        self.pattern_matcher.set_disabled(tenant_id, patterns);
+    }
+
+    /// Patrones que `analyze()` evalúa para un tenant y los que se saltan.
+    pub fn pattern_toggles(&self, tenant_id: &str) -> (Vec<BehaviorPattern>, Vec<BehaviorPattern>) {
+        let disabled = self.pattern_matcher.disabled_for(tenant_id);
+        let mut enabled: Vec<BehaviorPattern> = Vec::new();
+        for signature in self.pattern_matcher.signatures() {
+            if !disabled.contains(&signature.pattern) && !enabled.contains(&signature.pattern) {
+                enabled.push(signature.pattern.clone());
+            }
+        }
+        let mut disabled: Vec<_> = disabled.into_iter().collect();
+        disabled.sort_by_key(|p| p.code());
+        (enabled, disabled)
+    }
+
+    /// Desactivados globales y por tenant (vista de administración).
+    pub fn disabled_patterns(&self) -> (Vec<BehaviorPattern>, BTreeMap<String, Vec<BehaviorPattern>>) {
+        self.pattern_matcher.disabled_patterns()
+    }
+
+    pub fn sensitivity(&self) -> f64 {
+        self.sensitivity
+    }
+
+    pub fn risk_thresholds(&self) -> RiskThresholds {
+        *self.risk_thresholds.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sustituye los cortes de nivel (recarga de configuración). Los perfiles no se tocan:
+    /// su `threat_level` se recalcula con los cortes nuevos en el siguiente evento.
+    pub fn set_risk_thresholds(&self, thresholds: RiskThresholds) -> Result<(), String> {
+        if !thresholds.is_valid() {
+            return Err(format!("risk_thresholds must be strictly increasing within (0, 1] (got {:?})", thresholds));
+        }
+        *self.risk_thresholds.write().unwrap_or_else(|e| e.into_inner()) = thresholds;
+        Ok(())
+    }
+
+    pub async fn threshold(&self, key: &str) -> Option<f64> {
+        self.thresholds.read().await.get(key).copied()
+    }
+
+    /// Umbral efectivo para un tenant: override propio o, si no existe, el global.
+    pub async fn tenant_threshold(&self, tenant_id: &str, key: &str) -> Option<f64> {
+        let overridden = self
+            .tenant_thresholds
+            .read()
+            .await
+            .get(tenant_id)
+            .and_then(|t| t.get(key).copied());
+        match overridden {
+            Some(value) => Some(value),
+            None => self.threshold(key).await,
+        }
+    }
+
+    /// Fija un override de umbral para un tenant. Falla con claves desconocidas
+    /// o valores fuera de rango (ver `validate_threshold`).
+    pub async fn set_tenant_threshold(&self, tenant_id: &str, key: &str, value: f64) -> Result<(), String> {
+        validate_threshold(key, value)?;
+        self.tenant_thresholds
+            .write()
+            .await
+            .entry(tenant_id.to_string())
+            .or_default()
+            .insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Umbrales globales vigentes.
+    pub async fn thresholds(&self) -> HashMap<String, f64> {
+        self.thresholds.read().await.clone()
+    }
+
+    /// Actualiza umbrales globales en caliente. Se valida todo antes de aplicar nada
+    /// y el lock de escritura solo se toma para insertar.
+    pub async fn set_thresholds(&self, updates: &HashMap<String, f64>) -> Result<HashMap<String, f64>, String> {
+        for (key, value) in updates {
+            validate_threshold(key, *value)?;
+        }
+        let mut thresholds = self.thresholds.write().await;
+        thresholds.extend(updates.iter().map(|(k, v)| (k.clone(), *v)));
+        Ok(thresholds.clone())
+    }
+
+    /// Umbrales efectivos de un tenant (globales combinados con sus overrides).
+    pub async fn effective_thresholds(&self, tenant_id: &str) -> HashMap<String, f64> {
+        let mut effective = self.thresholds.read().await.clone();
+        if let Some(overrides) = self.tenant_thresholds.read().await.get(tenant_id) {
+            effective.extend(overrides.iter().map(|(k, v)| (k.clone(), *v)));
+        }
+        effective
+    }
+
+    fn default_thresholds(config: &SecurityConfig) -> HashMap<String, f64> {
         let mut t = HashMap::new();
-        t.insert("rate_limit".to_string(), 100.0);
+        t.insert("rate_limit".to_string(), config.rate_limit_threshold);
+        t.insert("score_multiplier".to_string(), 1.0);
         t
     }
-}
\ No newline at end of file
+}
+
+// Escala y cortes de nivel con que `evaluate` puntúa: los del detector, o los
+// candidatos de un what-if (`simulate_with`)
+#[derive(Clone, Copy)]
+struct Tuning {
+    sensitivity: f64,
+    risk_thresholds: RiskThresholds,
+}
+
+// Normalización inteligente (Acumulación con techo) y escala de sensibilidad.
+// Una inyección es crítica sea cual sea la sensibilidad
+fn combine_score(total: f64, critical: bool, sensitivity: f64) -> f64 {
+    if critical {
+        1.0
+    } else {
+        apply_sensitivity(total.clamp(0.0, 1.0), sensitivity)
+    }
+}
+
+fn validate_event(event: &BehaviorEvent) -> Result<(), DetectorError> {
+    if event.tenant_id.trim().is_empty() || event.client_id.trim().is_empty() {
+        return Err(DetectorError::InvalidEvent("tenant_id and client_id are required".to_string()));
+    }
+    if !event.confidence.is_finite() {
+        return Err(DetectorError::InvalidEvent("confidence is not a finite number".to_string()));
+    }
+    if let Some((key, _)) = event.indicators.iter().find(|(_, v)| !v.is_finite()) {
+        return Err(DetectorError::InvalidEvent(format!("indicator '{}' is not a finite number", key)));
+    }
+    Ok(())
+}
+
+// Acota el timestamp del llamador a [now - max_age, now]: un reloj adelantado o un
+// reintento muy tardío no deben fechar el resultado fuera de rango. None = ya era válido
+fn clamp_timestamp(event: &BehaviorEvent, now: DateTime<Utc>, max_age: Duration) -> Option<BehaviorEvent> {
+    let oldest = now - max_age;
+    let timestamp = event.timestamp.clamp(oldest, now);
+    if timestamp == event.timestamp {
+        return None;
+    }
+    log::warn!(
+        "[SECURITY] Timestamp fuera de rango para {}:{} ({}), ajustado a {}",
+        event.tenant_id, event.client_id, event.timestamp, timestamp
+    );
+    Some(BehaviorEvent { timestamp, ..event.clone() })
+}
+
+fn new_profile(event: &BehaviorEvent) -> ClientProfile {
+    ClientProfile {
+        tenant_id: event.tenant_id.clone(),
+        client_id: event.client_id.clone(),
+        first_seen: Utc::now(),
+        last_seen: Utc::now(),
+        total_events: 0,
+        average_confidence: 0.0,
+        risk_score: 0.0,
+        is_compromised: false,
+        compromised_at: None,
+        incident_count: 0,
+        released_at: None,
+        threat_level: ThreatLevel::Safe,
+        challenge_count: 0,
+        last_challenged_at: None,
+        device_id: String::new(),
+        known_devices: VecDeque::new(),
+        request_intervals_ms: VecDeque::new(),
+        location_history: Vec::new(),
+        recent_events: VecDeque::new(),
+    }
+}
+
+/// Registra `device_id` como último dispositivo del perfil. Devuelve `true` si es un
+/// dispositivo nuevo para un cliente que ya tenía alguno (el primero se aprende sin alertar).
+fn record_device(profile: &mut ClientProfile, device_id: &str) -> bool {
+    profile.device_id = device_id.to_string();
+    if let Some(pos) = profile.known_devices.iter().position(|d| d == device_id) {
+        // Conocido: pasa al final para que la expulsión por capacidad sea LRU
+        if let Some(known) = profile.known_devices.remove(pos) {
+            profile.known_devices.push_back(known);
+        }
+        return false;
+    }
+
+    let is_change = !profile.known_devices.is_empty();
+    if profile.known_devices.len() >= MAX_KNOWN_DEVICES {
+        profile.known_devices.pop_front();
+    }
+    profile.known_devices.push_back(device_id.to_string());
+    is_change
+}
+
+/// Aplica sobre una copia local la marca de compromiso compartida (`ProfileStore::load_compromise`).
+/// Solo cuenta si es posterior al último compromiso y a la última liberación conocidos: una
+/// marca que el store aún no ha hecho caducar no vuelve a bloquear a un cliente ya liberado.
+pub(crate) fn apply_compromise_mark(profile: &mut ClientProfile, at: DateTime<Utc>) {
+    let newer = |known: Option<DateTime<Utc>>| known.is_none_or(|known| at > known);
+    if newer(profile.compromised_at) && newer(profile.released_at) {
+        profile.is_compromised = true;
+        profile.compromised_at = Some(at);
+        profile.threat_level = ThreatLevel::Critical;
+    }
+}
+
+fn release_profile(profile: &mut ClientProfile) {
+    profile.is_compromised = false;
+    profile.compromised_at = None;
+    profile.risk_score = 0.0;
+    profile.threat_level = ThreatLevel::Safe;
+}
+
+/// Desviación típica (ms) de los intervalos entre eventos, o `None` sin muestras suficientes.
+/// La firma de timing attack compara este valor con su umbral en ms.
+fn timing_dispersion(intervals: &VecDeque<f64>) -> Option<f64> {
+    if intervals.len() < TIMING_MIN_SAMPLES {
+        return None;
+    }
+    let n = intervals.len() as f64;
+    let mean = intervals.iter().sum::<f64>() / n;
+    let variance = intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / n;
+    Some(variance.sqrt().max(TIMING_VARIANCE_FLOOR))
+}
+
+/// Decaimiento exponencial: `risk * 0.5^(elapsed / half_life)`.
+/// Con `half_life` nulo o negativo el riesgo no decae.
+pub fn decay_risk(risk: f64, elapsed: Duration, half_life: Duration) -> f64 {
+    let half_life_secs = half_life.num_milliseconds() as f64 / 1000.0;
+    let elapsed_secs = elapsed.num_milliseconds().max(0) as f64 / 1000.0;
+    if half_life_secs <= 0.0 {
+        return risk;
+    }
+    risk * 0.5_f64.powf(elapsed_secs / half_life_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patterns::KEY_INJECTION_SCORE;
+
+    fn event(tenant_id: &str, client_id: &str) -> BehaviorEvent {
+        BehaviorEvent {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            timestamp: Utc::now(),
+            pattern: BehaviorPattern::Normal,
+            confidence: 1.0,
+            indicators: HashMap::new(),
+            metadata: HashMap::new(),
+            device_id: None,
+        }
+    }
+
+    fn injection(tenant_id: &str, client_id: &str) -> BehaviorEvent {
+        let mut event = event(tenant_id, client_id);
+        event.indicators.insert(KEY_INJECTION_SCORE.to_string(), 0.95);
+        event
+    }
+
+    fn vip(tenant_id: &str, client_id: &str) -> VipEntry {
+        VipEntry {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            reason: Some("CFO".to_string()),
+            added_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn flushed_profiles_are_restored_into_a_new_detector() {
+        let store = InMemoryProfileStore::new();
+        let detector = AnomalyDetector::with_store(SecurityConfig::default(), Box::new(store.clone()));
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        // Escritura perdida (p.ej. con el breaker abierto): solo el flush la recupera
+        store.remove("acme", "42").await.unwrap();
+        assert!(store.load("acme", "42").await.is_none());
+
+        assert_eq!(detector.flush().await, 1);
+
+        let restarted = AnomalyDetector::with_store(SecurityConfig::default(), Box::new(store));
+        assert_eq!(restarted.restore().await, 1);
+        let profile = restarted.get_profile("acme", "42").expect("perfil restaurado");
+        assert_eq!(profile.total_events, 2);
+    }
+
+    #[tokio::test]
+    async fn critical_vip_is_not_marked_compromised() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        detector.add_vip(vip("acme", "cfo")).await.unwrap();
+
+        let first = detector.analyze(&injection("acme", "cfo")).await.unwrap();
+        assert_eq!(first.level, ThreatLevel::Critical);
+        assert_eq!(first.recommendation, "REQUIRE_MFA");
+        assert!(!detector.get_profile("acme", "cfo").unwrap().is_compromised);
+        // El siguiente evento limpio se analiza: no hay bloqueo permanente
+        let next = detector.analyze(&event("acme", "cfo")).await.unwrap();
+        assert_ne!(next.reasons, vec![COMPROMISED_REASON.to_string()]);
+
+        // El mismo evento compromete a un cliente normal (y a un homónimo de otro tenant)
+        detector.analyze(&injection("globex", "cfo")).await.unwrap();
+        assert!(detector.get_profile("globex", "cfo").unwrap().is_compromised);
+    }
+
+    #[tokio::test]
+    async fn vips_are_restored_from_the_store() {
+        let store = InMemoryProfileStore::new();
+        let detector = AnomalyDetector::with_store(SecurityConfig::default(), Box::new(store.clone()));
+        detector.add_vip(vip("acme", "cfo")).await.unwrap();
+        detector.add_vip(vip("acme", "ceo")).await.unwrap();
+        assert!(detector.remove_vip("acme", "ceo").await);
+
+        let restarted = AnomalyDetector::with_store(SecurityConfig::default(), Box::new(store));
+        assert_eq!(restarted.restore_vips().await, 1);
+        assert!(restarted.is_vip("acme", "cfo"));
+        assert!(!restarted.is_vip("acme", "ceo"));
+        assert!(!restarted.is_vip("globex", "cfo"));
+    }
+
+    #[tokio::test]
+    async fn sensitivity_scales_detector_scores_monotonically() {
+        let mut scores = Vec::new();
+        for sensitivity in [0.2, 0.8, 1.0] {
+            let detector = AnomalyDetector::with_config(SecurityConfig { sensitivity, ..SecurityConfig::default() });
+            let mut probe = event("acme", "42");
+            probe.indicators.insert("enumeration_score".to_string(), 0.9);
+            scores.push(detector.analyze(&probe).await.unwrap().score);
+        }
+        assert!(scores[0] > 0.0);
+        assert!(scores[0] < scores[1] && scores[1] < scores[2], "not monotonic: {:?}", scores);
+        assert!((scores[0] - scores[1] * 0.25).abs() < 1e-9);
+        assert!((scores[2] - (scores[1] * 1.25).min(1.0)).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn simulate_with_uses_the_candidate_tuning() {
+        let detector = AnomalyDetector::with_config(SecurityConfig { sensitivity: 0.2, ..SecurityConfig::default() });
+        let mut probe = event("acme", "42");
+        probe.indicators.insert("enumeration_score".to_string(), 0.9);
+
+        let current = detector.simulate(&probe).await.unwrap();
+        let candidate = detector.simulate_with(&probe, 1.0, RiskThresholds::default()).await.unwrap();
+        assert!(candidate.score > current.score);
+        assert!(candidate.level > current.level, "{:?} vs {:?}", candidate.level, current.level);
+        // Nada cambia en el detector: ni su sensibilidad ni el perfil
+        assert_eq!(detector.sensitivity(), 0.2);
+        assert!(!detector.has_profile("acme", "42"));
+    }
+
+    #[tokio::test]
+    async fn eviction_drops_the_oldest_profiles_but_keeps_compromised_ones() {
+        let detector = AnomalyDetector::with_config(SecurityConfig { max_active_profiles: 10, ..SecurityConfig::default() });
+        // Perfiles recientes (el barrido de 24h no los toca); c9 es el más antiguo y está comprometido
+        for i in 0..10 {
+            let mut profile = new_profile(&event("acme", &format!("c{}", i)));
+            profile.last_seen = Utc::now() - Duration::minutes(i);
+            profile.is_compromised = i == 9;
+            detector.import_profile(profile).await.unwrap();
+        }
+
+        detector.analyze(&event("acme", "new")).await.unwrap();
+
+        assert_eq!(detector.evictions(), 1);
+        assert!(detector.has_profile("acme", "new"));
+        assert!(detector.has_profile("acme", "c9"), "un perfil comprometido nunca se expulsa");
+        assert!(!detector.has_profile("acme", "c8"));
+        assert_eq!(detector.active_profiles(), 10);
+    }
+
+    #[tokio::test]
+    async fn full_detector_of_compromised_profiles_rejects_new_clients() {
+        let detector = AnomalyDetector::with_config(SecurityConfig { max_active_profiles: 3, ..SecurityConfig::default() });
+        for i in 0..3 {
+            detector.analyze(&injection("acme", &format!("c{}", i))).await.unwrap();
+        }
+
+        let err = detector.analyze(&event("acme", "new")).await.unwrap_err();
+        assert!(matches!(err, DetectorError::CapacityExceeded { max_profiles: 3 }));
+        assert_eq!(detector.evictions(), 0);
+        assert_eq!(detector.compromised_clients(Some("acme")).len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_analyze_on_one_key_loses_no_updates() {
+        const CALLS: u64 = 500;
+        let detector = Arc::new(AnomalyDetector::with_config(SecurityConfig::default()));
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..CALLS {
+            let detector = Arc::clone(&detector);
+            tasks.spawn(async move { detector.analyze(&event("acme", "42")).await });
+        }
+
+        // Un guard retenido a través de un await bloquearía aquí: el timeout lo convierte en fallo
+        let all = async {
+            while let Some(result) = tasks.join_next().await {
+                result.unwrap().unwrap();
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(30), all)
+            .await
+            .expect("analyze() concurrente no terminó (¿deadlock?)");
+        assert_eq!(detector.get_profile("acme", "42").unwrap().total_events, CALLS);
+        assert_eq!(detector.active_profiles(), 1);
+    }
+
+    #[tokio::test]
+    async fn detector_levels_follow_the_configured_risk_thresholds() {
+        let mut probe = event("acme", "42");
+        probe.indicators.insert("enumeration_score".to_string(), 0.9);
+
+        let default = AnomalyDetector::with_config(SecurityConfig::default());
+        let score = default.analyze(&probe).await.unwrap();
+        assert_eq!(score.level, RiskThresholds::default().level(score.score));
+
+        // Mismo score, cortes más bajos: sube de nivel
+        let strict = RiskThresholds { low: 0.05, medium: 0.1, high: 0.15, critical: 0.2 };
+        let config = SecurityConfig { risk_thresholds: strict, ..SecurityConfig::default() };
+        let detector = AnomalyDetector::with_config(config);
+        assert!(score.score >= strict.critical, "{}", score.score);
+        assert_eq!(detector.analyze(&probe).await.unwrap().level, ThreatLevel::Critical);
+        assert_eq!(detector.risk_thresholds().critical, 0.2);
+    }
+
+    #[tokio::test(start_paused = true)]
+        async fn background_sweep_drops_stale_profiles_until_shutdown() {
+        let detector = Arc::new(AnomalyDetector::with_config(SecurityConfig::default()));
+        let stale = Utc::now() - Duration::hours(STALE_PROFILE_HOURS + 1);
+        for (client_id, last_seen, compromised) in [("old", stale, false), ("attacker", stale, true), ("fresh", Utc::now(), false)] {
+            let mut profile = new_profile(&event("acme", client_id));
+            profile.last_seen = last_seen;
+            profile.is_compromised = compromised;
+            detector.import_profile(profile).await.unwrap();
+        }
+
+        let every = std::time::Duration::from_secs(60);
+        let sweeper = detector.spawn_cleanup(every);
+        tokio::task::yield_now().await;
+        // El primer tick inmediato no barre
+        assert!(detector.has_profile("acme", "old"));
+
+        tokio::time::advance(every).await;
+        tokio::task::yield_now().await;
+        // Barrido sin haber llegado al límite de capacidad
+        assert!(!detector.has_profile("acme", "old"));
+        assert!(detector.has_profile("acme", "attacker"));
+        assert!(detector.has_profile("acme", "fresh"));
+
+        detector.shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(1), sweeper)
+            .await
+            .expect("la tarea de barrido no terminó tras shutdown()")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tenant_thresholds_override_the_global_ones() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        let global = detector.threshold("rate_limit").await.unwrap();
+        detector.set_tenant_threshold("acme", "rate_limit", 50.0).await.unwrap();
+
+        assert_eq!(detector.tenant_threshold("acme", "rate_limit").await, Some(50.0));
+        assert_eq!(detector.tenant_threshold("globex", "rate_limit").await, Some(global));
+        // Una clave sin override propio cae al global
+        assert_eq!(detector.tenant_threshold("acme", "score_multiplier").await, detector.threshold("score_multiplier").await);
+        assert_eq!(detector.effective_thresholds("acme").await["rate_limit"], 50.0);
+
+        assert!(detector.set_tenant_threshold("acme", "rate_limit", 0.0).await.is_err());
+        assert!(detector.set_tenant_threshold("acme", "unknown", 1.0).await.is_err());
+        assert_eq!(detector.tenant_threshold("acme", "rate_limit").await, Some(50.0));
+    }
+
+    #[tokio::test]
+    async fn tenant_score_multiplier_scales_only_that_tenant() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        detector.set_tenant_threshold("acme", "score_multiplier", 0.5).await.unwrap();
+        let probe = |tenant_id: &str| {
+            let mut probe = event(tenant_id, "42");
+            probe.indicators.insert("enumeration_score".to_string(), 0.9);
+            probe
+        };
+
+        let damped = detector.analyze(&probe("acme")).await.unwrap().score;
+        let normal = detector.analyze(&probe("globex")).await.unwrap().score;
+        assert!((damped - normal * 0.5).abs() < 1e-9, "{} vs {}", damped, normal);
+    }
+
+    fn timing_attack(score: &AnomalyScore) -> bool {
+        score.detected_patterns.contains(&BehaviorPattern::TimingAttack)
+    }
+
+    #[tokio::test]
+    async fn robotic_fixed_intervals_are_detected_once_enough_samples_exist() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        // Ráfaga a intervalo fijo: el primer evento no aporta intervalo y hacen falta
+        // TIMING_MIN_SAMPLES antes de evaluar la dispersión
+        for i in 0..TIMING_MIN_SAMPLES {
+            let score = detector.analyze(&event("acme", "bot")).await.unwrap();
+            assert!(!timing_attack(&score), "evento {} sin muestras suficientes", i);
+        }
+        let score = detector.analyze(&event("acme", "bot")).await.unwrap();
+        assert!(timing_attack(&score), "{:?}", score.detected_patterns);
+
+        // El buffer queda acotado aunque el cliente siga enviando
+        for _ in 0..TIMING_BUFFER_SIZE {
+            detector.analyze(&event("acme", "bot")).await.unwrap();
+        }
+        assert_eq!(detector.get_profile("acme", "bot").unwrap().request_intervals_ms.len(), TIMING_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn caller_supplied_timing_variance_takes_precedence() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        let mut human = event("acme", "42");
+        human.indicators.insert(KEY_TIMING_VARIANCE.to_string(), 250.0);
+        for _ in 0..=TIMING_MIN_SAMPLES {
+            assert!(!timing_attack(&detector.analyze(&human).await.unwrap()));
+        }
+    }
+
+    #[test]
+    fn timing_dispersion_separates_jitter_from_robots() {
+        let intervals = |values: &[f64]| values.iter().copied().collect::<VecDeque<f64>>();
+        assert_eq!(timing_dispersion(&intervals(&[100.0; TIMING_MIN_SAMPLES - 1])), None);
+        // Intervalo perfectamente fijo: se aplica el suelo, no 0
+        assert_eq!(timing_dispersion(&intervals(&[100.0; TIMING_MIN_SAMPLES])), Some(TIMING_VARIANCE_FLOOR));
+        // Un humano con jitter de cientos de ms queda muy por encima del umbral de la firma
+        let human = timing_dispersion(&intervals(&[200.0, 900.0, 450.0, 1300.0, 300.0, 700.0, 1600.0, 500.0])).unwrap();
+        assert!(human > 100.0, "{}", human);
+    }
+
+    #[test]
+    fn clamp_timestamp_bounds_future_and_stale_events() {
+        let now = Utc::now();
+        let max_age = Duration::hours(1);
+        let at = |timestamp: DateTime<Utc>| BehaviorEvent { timestamp, ..event("acme", "42") };
+
+        assert!(clamp_timestamp(&at(now - Duration::minutes(5)), now, max_age).is_none());
+        assert!(clamp_timestamp(&at(now), now, max_age).is_none());
+        assert!(clamp_timestamp(&at(now - max_age), now, max_age).is_none(), "el borde es válido");
+        assert_eq!(clamp_timestamp(&at(now + Duration::days(3)), now, max_age).unwrap().timestamp, now);
+        assert_eq!(clamp_timestamp(&at(now - Duration::days(400)), now, max_age).unwrap().timestamp, now - max_age);
+    }
+
+    #[tokio::test]
+    async fn skewed_timestamps_never_reach_the_profile_clock() {
+        let config = SecurityConfig { max_event_age_secs: 3600, ..SecurityConfig::default() };
+        let detector = AnomalyDetector::with_config(config);
+        let before = Utc::now();
+
+        let future = BehaviorEvent { timestamp: before + Duration::days(30), ..event("acme", "42") };
+        let result = detector.analyze(&future).await.unwrap();
+        assert!(result.timestamp <= Utc::now(), "{}", result.timestamp);
+
+        let stale = BehaviorEvent { timestamp: before - Duration::days(30), ..event("acme", "42") };
+        let result = detector.analyze(&stale).await.unwrap();
+        assert!(result.timestamp >= before - Duration::hours(1), "{}", result.timestamp);
+
+        // El estado del perfil usa la hora del servidor: ni last_seen en el futuro
+        // ni intervalos negativos por el timestamp del llamador
+        let profile = detector.get_profile("acme", "42").unwrap();
+        assert!(profile.last_seen >= before && profile.last_seen <= Utc::now());
+        assert!(profile.request_intervals_ms.iter().all(|interval| *interval >= 0.0));
+        assert!(profile.risk_score.is_finite());
+    }
+
+    #[tokio::test]
+    async fn low_confidence_events_raise_risk_less() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        let mut risks = Vec::new();
+        for (client, confidence) in [("sensor", 0.3), ("waf", 1.0)] {
+            let mut probe = event("acme", client);
+            probe.confidence = confidence;
+            probe.indicators.insert("enumeration_score".to_string(), 0.9);
+            detector.analyze(&probe).await.unwrap();
+            risks.push(detector.get_profile("acme", client).unwrap().risk_score);
+        }
+        assert!(risks[0] > 0.0 && risks[0] < risks[1], "{:?}", risks);
+    }
+
+    #[tokio::test]
+    async fn average_confidence_is_a_running_mean_of_clamped_values() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+        for confidence in [1.0, 0.5, 3.0, -1.0] {
+            let probe = BehaviorEvent { confidence, ..event("acme", "42") };
+            detector.analyze(&probe).await.unwrap();
+        }
+        // 1.0, 0.5, 1.0 (acotado), 0.0 (acotado)
+        let profile = detector.get_profile("acme", "42").unwrap();
+        assert!((profile.average_confidence - 0.625).abs() < 1e-9, "{}", profile.average_confidence);
+
+        let invalid = BehaviorEvent { confidence: f64::NAN, ..event("acme", "42") };
+        assert!(matches!(detector.analyze(&invalid).await, Err(DetectorError::InvalidEvent(_))));
+        assert_eq!(detector.get_profile("acme", "42").unwrap().total_events, 4);
+    }
+
+    // Simula el paso del tiempo sobre el perfil vivo
+    fn rewind(detector: &AnomalyDetector, client_id: &str, change: impl FnOnce(&mut ClientProfile)) {
+        let key = ("acme".to_string(), client_id.to_string());
+        change(&mut detector.profiles.get_mut(&key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn released_clients_decay_only_to_the_incident_floor_while_watched() {
+        let config = SecurityConfig {
+            compromise_ttl_hours: 1.0,
+            min_risk_after_incident: 0.3,
+            incident_watch_hours: 24.0,
+            ..SecurityConfig::default()
+        };
+        let detector = AnomalyDetector::with_config(config);
+
+        detector.analyze(&injection("acme", "42")).await.unwrap();
+        let burned = detector.get_profile("acme", "42").unwrap();
+        assert!(burned.is_compromised);
+        assert_eq!(burned.incident_count, 1);
+
+        // Vence el TTL del compromiso: el siguiente evento limpio libera al cliente
+        rewind(&detector, "42", |p| p.compromised_at = Some(Utc::now() - Duration::hours(2)));
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        let released = detector.get_profile("acme", "42").unwrap();
+        assert!(!released.is_compromised && released.released_at.is_some());
+        assert!((released.risk_score - 0.3).abs() < 1e-9, "{}", released.risk_score);
+
+        // Un día entero de inactividad: el decay se detiene en el suelo
+        rewind(&detector, "42", |p| {
+            p.risk_score = 0.9;
+            p.last_seen -= Duration::hours(20);
+            p.released_at = p.released_at.map(|at| at - Duration::hours(20));
+        });
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        assert!((detector.get_profile("acme", "42").unwrap().risk_score - 0.3).abs() < 1e-9);
+
+        // Pasada la ventana de vigilancia el riesgo vuelve a decaer hasta cero
+        rewind(&detector, "42", |p| {
+            p.last_seen -= Duration::days(10);
+            p.released_at = p.released_at.map(|at| at - Duration::days(10));
+        });
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        let forgiven = detector.get_profile("acme", "42").unwrap();
+        assert!(forgiven.risk_score < 0.01, "{}", forgiven.risk_score);
+
+        // La reincidencia suma al contador
+        detector.analyze(&injection("acme", "42")).await.unwrap();
+        assert_eq!(detector.get_profile("acme", "42").unwrap().incident_count, 2);
+    }
+
+    #[tokio::test]
+    async fn without_a_floor_released_clients_start_from_zero() {
+        let config = SecurityConfig { compromise_ttl_hours: 1.0, ..SecurityConfig::default() };
+        let detector = AnomalyDetector::with_config(config);
+        detector.analyze(&injection("acme", "42")).await.unwrap();
+        rewind(&detector, "42", |p| p.compromised_at = Some(Utc::now() - Duration::hours(2)));
+        detector.analyze(&event("acme", "42")).await.unwrap();
+        assert_eq!(detector.get_profile("acme", "42").unwrap().risk_score, 0.0);
+    }
+
+    fn from_device(client_id: &str, device_id: &str) -> BehaviorEvent {
+        BehaviorEvent { device_id: Some(device_id.to_string()), ..event("acme", client_id) }
+    }
+
+    fn device_change(score: &AnomalyScore) -> bool {
+        score.detected_patterns.contains(&BehaviorPattern::DeviceChange)
+    }
+
+    #[tokio::test]
+    async fn a_new_device_trips_device_change_but_a_returning_one_does_not() {
+        let detector = AnomalyDetector::with_config(SecurityConfig::default());
+
+        // El primer dispositivo se aprende sin alertar
+        assert!(!device_change(&detector.analyze(&from_device("42", "laptop")).await.unwrap()));
+        assert!(device_change(&detector.analyze(&from_device("42", "phone")).await.unwrap()));
+
+        // Volver a un dispositivo conocido no es un cambio, ni alternar entre ellos
+        for device_id in ["laptop", "phone", "laptop"] {
+            let score = detector.analyze(&from_device("42", device_id)).await.unwrap();
+            assert!(!device_change(&score), "{}: {:?}", device_id, score.detected_patterns);
+        }
+        let profile = detector.get_profile("acme", "42").unwrap();
+        assert_eq!(profile.device_id, "laptop");
+        assert_eq!(profile.known_devices, VecDeque::from(["phone".to_string(), "laptop".to_string()]));
+
+        // Sin device_id no se aprende ni se compara nada
+        assert!(!device_change(&detector.analyze(&event("acme", "42")).await.unwrap()));
+        assert_eq!(detector.get_profile("acme", "42").unwrap().known_devices.len(), 2);
+    }
+
+    #[test]
+    fn known_devices_are_capped_and_the_least_recently_used_is_forgotten() {
+        let mut profile = new_profile(&event("acme", "42"));
+        for n in 0..MAX_KNOWN_DEVICES {
+            record_device(&mut profile, &format!("device-{}", n));
+        }
+        // device-0 vuelve a usarse: el menos reciente pasa a ser device-1
+        assert!(!record_device(&mut profile, "device-0"));
+        assert!(record_device(&mut profile, "device-new"));
+
+        assert_eq!(profile.known_devices.len(), MAX_KNOWN_DEVICES);
+        assert!(!profile.known_devices.contains(&"device-1".to_string()));
+        assert!(profile.known_devices.contains(&"device-0".to_string()));
+        assert_eq!(profile.known_devices.back().map(String::as_str), Some("device-new"));
+        // Un dispositivo olvidado vuelve a contar como nuevo
+        assert!(record_device(&mut profile, "device-1"));
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+// ==========================================
+// ENDPOINTS SENSIBLES (TOMA DE CUENTA)
+// ==========================================
+
+/// Patrones de endpoints privilegiados (`/admin/payroll`, `/api/*/export`...).
+///
+/// `*` equivale a cualquier secuencia (incluidas `/`); un patrón sin comodines cubre
+/// la ruta exacta y todo lo que cuelga de ella (`/admin` → `/admin/users`, no
+/// `/administrator`). Se ignoran la query string y las mayúsculas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SensitiveEndpoints {
+    patterns: Vec<String>,
+}
+
+impl Default for SensitiveEndpoints {
+    fn default() -> Self {
+        Self::new(["/admin"])
+    }
+}
+
+impl SensitiveEndpoints {
+    pub fn new<S: Into<String>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(|p| p.into().trim().to_ascii_lowercase())
+                .filter(|p| !p.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Lista separada por comas (`ANOMALY_SENSITIVE_ENDPOINTS="/admin,/api/*/payroll"`).
+    pub fn parse(raw: &str) -> Self {
+        Self::new(raw.split(','))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Primer patrón que cubre `endpoint`.
+    pub fn matching(&self, endpoint: &str) -> Option<&str> {
+        let path = endpoint.split(['?', '#']).next().unwrap_or_default().to_ascii_lowercase();
+        self.patterns.iter().map(String::as_str).find(|pattern| pattern_matches(pattern, &path))
+    }
+}
+
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('*') {
+        let prefix = pattern.trim_end_matches('/');
+        return path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    }
+    glob_match(pattern.as_bytes(), path.as_bytes())
+}
+
+// Glob con `*` (backtracking sobre el último comodín: lineal en la práctica)
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_patterns_cover_the_path_and_its_children_only() {
+        let sensitive = SensitiveEndpoints::parse("/admin, /finance/payroll/");
+        assert_eq!(sensitive.matching("/admin"), Some("/admin"));
+        assert_eq!(sensitive.matching("/admin/users/7"), Some("/admin"));
+        assert_eq!(sensitive.matching("/ADMIN/Users?page=2"), Some("/admin"));
+        assert_eq!(sensitive.matching("/finance/payroll"), Some("/finance/payroll/"));
+        assert_eq!(sensitive.matching("/administrator"), None);
+        assert_eq!(sensitive.matching("/reports/admin"), None);
+        assert_eq!(sensitive.matching("/login"), None);
+    }
+
+    #[test]
+    fn globs_match_any_sequence_including_slashes() {
+        let sensitive = SensitiveEndpoints::new(["/api/*/export", "*payroll*"]);
+        assert_eq!(sensitive.matching("/api/v1/export"), Some("/api/*/export"));
+        assert_eq!(sensitive.matching("/api/v1/tenants/acme/export#top"), Some("/api/*/export"));
+        assert_eq!(sensitive.matching("/api/v1/export/csv"), None, "el glob no es prefijo");
+        assert_eq!(sensitive.matching("/hr/PayRoll/2026"), Some("*payroll*"));
+        assert_eq!(sensitive.matching("/api/v1/import"), None);
+    }
+
+    #[test]
+    fn blank_entries_are_ignored() {
+        assert!(SensitiveEndpoints::parse(" , ,").is_empty());
+        assert!(!SensitiveEndpoints::default().is_empty());
+        assert_eq!(SensitiveEndpoints::parse("").matching("/admin"), None);
+    }
+}
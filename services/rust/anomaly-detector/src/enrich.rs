@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+
+use crate::models::BehaviorEvent;
+use crate::patterns::KEY_IP_REPUTATION;
+
+// ==========================================
+// ENRIQUECIMIENTO ASÍNCRONO DE EVENTOS
+// ==========================================
+
+/// Clave de `BehaviorEvent.metadata` con la IP de origen (la rellena la API HTTP).
+pub const META_IP_ADDRESS: &str = "ip_address";
+
+/// Fuente externa (reputación de IP, threat intel...) que añade indicadores a un
+/// evento antes del scoring. `enrich` recibe una copia propia del evento: solo se
+/// conservan las claves nuevas de `indicators`/`metadata`, nunca se pisan las que
+/// trae el llamador.
+#[async_trait]
+pub trait EventEnricher: Send + Sync {
+    /// Nombre para los logs.
+    fn name(&self) -> &str;
+    async fn enrich(&self, event: &mut BehaviorEvent);
+}
+
+/// Ejecuta `enrichers` a la vez sobre `event` con un plazo total de `timeout`.
+/// Al vencer se sigue con lo que haya terminado; el resto se cancela. Si dos
+/// enriquecedores aportan la misma clave gana el primero en terminar.
+pub async fn run_enrichers(enrichers: &[Arc<dyn EventEnricher>], event: &mut BehaviorEvent, timeout: Duration) {
+    if enrichers.is_empty() {
+        return;
+    }
+    let mut tasks = JoinSet::new();
+    for enricher in enrichers {
+        let enricher = Arc::clone(enricher);
+        let mut copy = event.clone();
+        tasks.spawn(async move {
+            enricher.enrich(&mut copy).await;
+            (enricher.name().to_string(), copy)
+        });
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let base = event.clone();
+    loop {
+        match tokio::time::timeout_at(deadline, tasks.join_next()).await {
+            Ok(Some(Ok((name, enriched)))) => {
+                log::debug!("[ENRICH] {} completado", name);
+                merge(event, &base, enriched);
+            }
+            Ok(Some(Err(e))) => log::warn!("[ENRICH] Un enriquecedor falló: {}", e),
+            Ok(None) => break,
+            Err(_) => {
+                log::warn!(
+                    "[ENRICH] Plazo de {} ms agotado: {} enriquecedor(es) sin terminar",
+                    timeout.as_millis(),
+                    tasks.len()
+                );
+                tasks.abort_all();
+                break;
+            }
+        }
+    }
+}
+
+// Claves que `enriched` añadió respecto a `base` (las ya presentes en `event` se respetan)
+fn merge(event: &mut BehaviorEvent, base: &BehaviorEvent, enriched: BehaviorEvent) {
+    for (key, value) in enriched.indicators {
+        if !base.indicators.contains_key(&key) && value.is_finite() {
+            event.indicators.entry(key).or_insert(value);
+        }
+    }
+    for (key, value) in enriched.metadata {
+        if !base.metadata.contains_key(&key) {
+            event.metadata.entry(key).or_insert(value);
+        }
+    }
+}
+
+/// Red con reputación conocida (0 = limpia, 1 = maliciosa).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReputationEntry {
+    pub network: IpNet,
+    pub score: f64,
+}
+
+/// Reputación estática por CIDR (`SecurityConfig::ip_reputation`): fija
+/// `ip_reputation` con la red más específica que contenga la IP del evento.
+pub struct StaticReputation {
+    entries: Vec<ReputationEntry>,
+}
+
+impl StaticReputation {
+    pub fn new(mut entries: Vec<ReputationEntry>) -> Self {
+        // Prefijo más largo primero: la primera coincidencia es la más específica
+        entries.sort_by_key(|e| std::cmp::Reverse(e.network.prefix_len()));
+        Self { entries }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<f64> {
+        self.entries.iter().find(|e| e.network.contains(&ip)).map(|e| e.score)
+    }
+}
+
+#[async_trait]
+impl EventEnricher for StaticReputation {
+    fn name(&self) -> &str {
+        "static_reputation"
+    }
+
+    async fn enrich(&self, event: &mut BehaviorEvent) {
+        let ip = event.metadata.get(META_IP_ADDRESS).and_then(|raw| raw.trim().parse().ok());
+        if let Some(score) = ip.and_then(|ip| self.lookup(ip)) {
+            event.indicators.insert(KEY_IP_REPUTATION.to_string(), score);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+
+// ==========================================
+// ERRORES DEL DETECTOR
+// ==========================================
+
+/// Errores de `AnomalyDetector::analyze` / `simulate`, tipados para que el
+/// llamador decida (p.ej. el código HTTP) sin comparar cadenas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetectorError {
+    /// El ProfileStore falló en una operación imprescindible para el resultado.
+    StoreUnavailable(String),
+    /// El evento no se puede analizar (identificadores vacíos, indicadores no finitos...).
+    InvalidEvent(String),
+    /// No cabe un perfil nuevo ni tras la purga (todos los perfiles están comprometidos).
+    CapacityExceeded { max_profiles: usize },
+}
+
+impl fmt::Display for DetectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DetectorError::StoreUnavailable(e) => write!(f, "profile store unavailable: {}", e),
+            DetectorError::InvalidEvent(e) => write!(f, "invalid event: {}", e),
+            DetectorError::CapacityExceeded { max_profiles } => {
+                write!(f, "profile capacity exceeded ({} active profiles)", max_profiles)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DetectorError {}
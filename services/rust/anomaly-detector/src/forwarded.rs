@@ -0,0 +1,64 @@
+use std::net::{IpAddr, SocketAddr};
+
+// ==========================================
+// IP DEL CLIENTE DETRÁS DE PROXIES DE CONFIANZA
+// ==========================================
+
+/// Tope de `SecurityConfig::trust_proxy` (ninguna topología real encadena más).
+pub const MAX_TRUSTED_PROXIES: usize = 16;
+
+/// IP del cliente según `X-Forwarded-For` o `Forwarded` cuando delante del servicio
+/// hay `trusted_proxies` proxies de confianza (0 = no se leen cabeceras).
+///
+/// Cada proxy añade a la derecha la dirección de quien le conectó: solo las
+/// `trusted_proxies` entradas más a la derecha son fiables, todo lo anterior lo
+/// escribe el cliente. El cliente es la entrada `trusted_proxies` contando desde
+/// la derecha. Varias cabeceras del mismo nombre se concatenan en orden.
+///
+/// Devuelve `None` (el llamador usa la IP del cuerpo) si la cadena es más corta
+/// que el número de proxies, si esa entrada no es una IP, o si ambas cabeceras
+/// están presentes y no coinciden.
+pub fn client_ip<'a>(
+    forwarded_for: impl IntoIterator<Item = &'a str>,
+    forwarded: impl IntoIterator<Item = &'a str>,
+    trusted_proxies: usize,
+) -> Option<IpAddr> {
+    if trusted_proxies == 0 {
+        return None;
+    }
+    let xff: Vec<&str> = forwarded_for.into_iter().flat_map(|h| h.split(',')).map(str::trim).collect();
+    let fwd: Vec<&str> = forwarded
+        .into_iter()
+        .flat_map(|h| h.split(','))
+        .map(|element| forwarded_for_param(element).unwrap_or(""))
+        .collect();
+
+    let pick = |chain: &[&str]| -> Option<Option<IpAddr>> {
+        let hop = chain.len().checked_sub(trusted_proxies)?;
+        Some(parse_node(chain[hop]))
+    };
+    let from_xff = (!xff.iter().all(|e| e.is_empty())).then(|| pick(&xff));
+    let from_fwd = (!fwd.is_empty()).then(|| pick(&fwd));
+    match (from_xff, from_fwd) {
+        (Some(a), Some(b)) if a != b => None,
+        (Some(ip), _) | (None, Some(ip)) => ip.flatten(),
+        (None, None) => None,
+    }
+}
+
+// Valor de `for=` de un elemento de `Forwarded` (RFC 7239): `for=192.0.2.60;proto=http`
+fn forwarded_for_param(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("for").then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// "1.2.3.4", "1.2.3.4:8080", "2001:db8::1" o "[2001:db8::1]:8080". Los identificadores
+// ofuscados de RFC 7239 ("unknown", "_hidden") no son una IP
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
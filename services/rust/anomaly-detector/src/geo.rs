@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+// ==========================================
+// GEOGRAFÍA: CENTROIDES Y VIAJE IMPOSIBLE
+// ==========================================
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Centroides aproximados (lat, lon) por código ISO-3166 alpha-2.
+// Es una heurística gruesa: basta para distinguir "París → Madrid" de "París → Sídney".
+const COUNTRY_CENTROIDS: &[(&str, f64, f64)] = &[
+    ("AR", -38.4, -63.6), ("AU", -25.3, 133.8), ("AT", 47.5, 14.6), ("BE", 50.5, 4.5),
+    ("BR", -14.2, -51.9), ("CA", 56.1, -106.3), ("CH", 46.8, 8.2), ("CL", -35.7, -71.5),
+    ("CN", 35.9, 104.2), ("CO", 4.6, -74.3), ("CZ", 49.8, 15.5), ("DE", 51.2, 10.5),
+    ("DK", 56.3, 9.5), ("EG", 26.8, 30.8), ("ES", 40.5, -3.7), ("FI", 61.9, 25.7),
+    ("FR", 46.2, 2.2), ("GB", 55.4, -3.4), ("GR", 39.1, 21.8), ("HK", 22.3, 114.2),
+    ("ID", -0.8, 113.9), ("IE", 53.4, -8.2), ("IL", 31.0, 34.9), ("IN", 20.6, 79.0),
+    ("IT", 41.9, 12.6), ("JP", 36.2, 138.3), ("KP", 40.3, 127.5), ("KR", 35.9, 127.8),
+    ("MX", 23.6, -102.6), ("NG", 9.1, 8.7), ("NL", 52.1, 5.3), ("NO", 60.5, 8.5),
+    ("NZ", -40.9, 174.9), ("PE", -9.2, -75.0), ("PL", 51.9, 19.1), ("PT", 39.4, -8.2),
+    ("RO", 45.9, 25.0), ("RU", 61.5, 105.3), ("SA", 23.9, 45.1), ("SE", 60.1, 18.6),
+    ("SG", 1.35, 103.8), ("TR", 39.0, 35.2), ("UA", 48.4, 31.2), ("US", 37.1, -95.7),
+    ("VE", 6.4, -66.6), ("VN", 14.1, 108.3), ("ZA", -30.6, 22.9),
+];
+
+pub fn country_centroid(code: &str) -> Option<(f64, f64)> {
+    COUNTRY_CENTROIDS
+        .iter()
+        .find(|(c, _, _)| c.eq_ignore_ascii_case(code))
+        .map(|&(_, lat, lon)| (lat, lon))
+}
+
+/// Distancia de gran círculo (haversine) entre los centroides de dos países.
+/// `None` si alguno no está en la tabla (LAN, UNKNOWN, países sin centroide).
+pub fn country_distance_km(from: &str, to: &str) -> Option<f64> {
+    let (lat1, lon1) = country_centroid(from)?;
+    let (lat2, lon2) = country_centroid(to)?;
+
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    Some(2.0 * EARTH_RADIUS_KM * a.sqrt().asin())
+}
+
+/// Velocidad (km/h) necesaria para ir de `from` a `to` en `elapsed`.
+/// Un intervalo nulo o negativo se trata como un minuto para no dividir por cero.
+pub fn required_speed_kmh(from: &str, to: &str, elapsed: Duration) -> Option<f64> {
+    let distance = country_distance_km(from, to)?;
+    let hours = (elapsed.num_seconds().max(60)) as f64 / 3600.0;
+    Some(distance / hours)
+}
+
+// ==========================================
+// RIESGO GRADUADO DE UBICACIÓN
+// ==========================================
+
+/// Fracción del peso `location` que suma un país fuera de `typical_countries`.
+///
+/// Un viaje regional (Francia → Bélgica) no debe pesar lo mismo que aparecer en la otra
+/// punta del mundo: se gradúa por la distancia al país típico más cercano, salvo que
+/// `countries` fije el factor del país explícitamente (alto para países de riesgo,
+/// bajo para destinos habituales del tenant).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocationRisk {
+    /// Hasta esta distancia (km) el país se considera vecino.
+    pub neighbor_km: f64,
+    pub neighbor_factor: f32,
+    /// Hasta esta distancia (km), misma región.
+    pub regional_km: f64,
+    pub regional_factor: f32,
+    /// Más lejos, sin países típicos o sin centroide conocido (peso completo histórico).
+    pub distant_factor: f32,
+    /// Factor explícito por código ISO-3166 alpha-2; prevalece sobre la distancia.
+    pub countries: HashMap<String, f32>,
+}
+
+impl Default for LocationRisk {
+    fn default() -> Self {
+        Self {
+            neighbor_km: 1500.0,
+            neighbor_factor: 0.2,
+            regional_km: 4000.0,
+            regional_factor: 0.5,
+            distant_factor: 1.0,
+            countries: [("KP", 1.5), ("IR", 1.5), ("SY", 1.5)]
+                .into_iter()
+                .map(|(code, factor)| (code.to_string(), factor))
+                .collect(),
+        }
+    }
+}
+
+impl LocationRisk {
+    /// Factor de `country` respecto a los países típicos del usuario (0 si ya es típico).
+    pub fn factor<'a, I>(&self, country: &str, typical: I) -> f32
+    where
+        I: IntoIterator<Item = &'a String> + Copy,
+    {
+        if typical.into_iter().any(|t| t.eq_ignore_ascii_case(country)) {
+            return 0.0;
+        }
+        if let Some(factor) = self.countries.get(&country.to_ascii_uppercase()) {
+            return *factor;
+        }
+        let nearest = typical
+            .into_iter()
+            .filter_map(|t| country_distance_km(t, country))
+            .fold(None, |min: Option<f64>, d| Some(min.map_or(d, |m| m.min(d))));
+        match nearest {
+            Some(km) if km <= self.neighbor_km => self.neighbor_factor,
+            Some(km) if km <= self.regional_km => self.regional_factor,
+            _ => self.distant_factor,
+        }
+    }
+
+    /// Sobrescribe `countries` con `ANOMALY_COUNTRY_RISK` ("KP=1.5,BE=0.1").
+    /// Entradas inválidas se ignoran con aviso.
+    pub fn with_env_overrides(mut self) -> Self {
+        let Ok(raw) = std::env::var("ANOMALY_COUNTRY_RISK") else {
+            return self;
+        };
+        for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let parsed = pair
+                .split_once('=')
+                .and_then(|(code, factor)| Some((code.trim(), factor.trim().parse::<f32>().ok()?)))
+                .filter(|(code, factor)| code.len() == 2 && factor.is_finite() && *factor >= 0.0);
+            match parsed {
+                Some((code, factor)) => {
+                    self.countries.insert(code.to_ascii_uppercase(), factor);
+                }
+                None => log::warn!("[CONFIG] ANOMALY_COUNTRY_RISK: entrada inválida {:?} (se esperaba 'PAIS=factor')", pair),
+            }
+        }
+        self
+    }
+
+    /// Factores finitos y no negativos, con `neighbor_km <= regional_km`.
+    pub fn validate(&self) -> Result<(), String> {
+        let factors = [
+            ("neighbor_factor", self.neighbor_factor),
+            ("regional_factor", self.regional_factor),
+            ("distant_factor", self.distant_factor),
+        ];
+        if let Some((name, value)) = factors.iter().find(|(_, v)| !v.is_finite() || *v < 0.0) {
+            return Err(format!("location_risk.{} must be a non-negative number (got {})", name, value));
+        }
+        if let Some((code, value)) = self.countries.iter().find(|(_, v)| !v.is_finite() || **v < 0.0) {
+            return Err(format!("location_risk.countries.{} must be a non-negative number (got {})", code, value));
+        }
+        if !(self.neighbor_km >= 0.0 && self.neighbor_km <= self.regional_km && self.regional_km.is_finite()) {
+            return Err(format!(
+                "location_risk requires 0 <= neighbor_km <= regional_km (got {} / {})",
+                self.neighbor_km, self.regional_km
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn location_factor_reads_the_typical_set_regardless_of_case() {
+        let risk = LocationRisk::default();
+        let typical = BTreeSet::from(["es".to_string(), "US".to_string()]);
+        assert_eq!(risk.factor("ES", &typical), 0.0);
+        assert_eq!(risk.factor("us", &typical), 0.0);
+        // Graduado por el país típico más cercano; la lista explícita prevalece
+        assert_eq!(risk.factor("PT", &typical), risk.neighbor_factor);
+        assert_eq!(risk.factor("JP", &typical), risk.distant_factor);
+        assert_eq!(risk.factor("KP", &typical), 1.5);
+        // Sin países típicos no hay referencia: peso completo
+        assert_eq!(risk.factor("PT", &BTreeSet::new()), risk.distant_factor);
+        // Cualquier colección de referencias sirve
+        assert_eq!(risk.factor("PT", &["ES".to_string()][..]), risk.neighbor_factor);
+    }
+}
@@ -0,0 +1,289 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use maxminddb::{geoip2, Reader};
+
+// ==========================================
+// GEOIP RECARGABLE EN CALIENTE
+// ==========================================
+
+/// Base de datos MaxMind (GeoLite2/GeoIP2 Country o City, `.mmdb`) cargada en memoria.
+///
+/// El árbol de búsqueda de MaxMind ya resuelve redes anidadas (gana la más específica)
+/// y las direcciones IPv4 dentro de una base IPv6.
+pub struct GeoIpDatabase {
+    reader: Reader<Vec<u8>>,
+}
+
+impl std::fmt::Debug for GeoIpDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIpDatabase")
+            .field("database_type", &self.database_type())
+            .field("build_epoch", &self.build_epoch())
+            .finish()
+    }
+}
+
+impl GeoIpDatabase {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self { reader })
+    }
+
+    /// País (ISO 3166-1 alfa-2) de `ip`: el de ubicación, o el de registro de la red si falta.
+    pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let record = self.reader.lookup(ip).ok()?.decode::<geoip2::Country>().ok()??;
+        record
+            .country
+            .iso_code
+            .or(record.registered_country.iso_code)
+            .map(str::to_string)
+    }
+
+    pub fn build_epoch(&self) -> u64 {
+        self.reader.metadata().build_epoch
+    }
+
+    /// Tipo de base de datos (`GeoLite2-Country`, `GeoIP2-City`...).
+    pub fn database_type(&self) -> &str {
+        &self.reader.metadata().database_type
+    }
+}
+
+/// Lector de GeoIP que vigila el fichero y lo recarga al cambiar.
+///
+/// Si una recarga falla (fichero corrupto o a medio rotar) se sigue sirviendo
+/// la versión anterior y solo se registra el error.
+pub struct GeoIpReader {
+    path: String,
+    current: RwLock<Arc<GeoIpDatabase>>,
+    loaded_mtime: Mutex<Option<SystemTime>>,
+}
+
+impl GeoIpReader {
+    /// La carga inicial sí debe funcionar: sin base de datos no hay nada que servir.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mtime = modified_at(path);
+        let db = GeoIpDatabase::load(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            current: RwLock::new(Arc::new(db)),
+            loaded_mtime: Mutex::new(mtime),
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+        self.database().lookup(ip)
+    }
+
+    pub fn build_epoch(&self) -> u64 {
+        self.database().build_epoch()
+    }
+
+    fn database(&self) -> Arc<GeoIpDatabase> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Recarga si el mtime cambió. Devuelve `true` si se cargó una versión nueva.
+    pub fn reload_if_changed(&self) -> bool {
+        let mtime = modified_at(&self.path);
+        let mut loaded = self.loaded_mtime.lock().unwrap_or_else(|e| e.into_inner());
+        if mtime.is_none() || mtime == *loaded {
+            return false;
+        }
+
+        match GeoIpDatabase::load(&self.path) {
+            Ok(db) => {
+                log::info!("[GEOIP] Recargada {} ({}, build {})", self.path, db.database_type(), db.build_epoch());
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(db);
+                *loaded = mtime;
+                true
+            }
+            Err(e) => {
+                // No se actualiza loaded_mtime: se reintenta en el siguiente tick
+                log::error!("[GEOIP] Recarga fallida, se mantiene la versión anterior: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Comprueba el fichero cada `every` en una tarea Tokio.
+    pub fn spawn_watcher(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let reader = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            loop {
+                tick.tick().await;
+                // La lectura del fichero es bloqueante: fuera del runtime async
+                let reader = reader.clone();
+                let _ = tokio::task::spawn_blocking(move || reader.reload_if_changed()).await;
+            }
+        })
+    }
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipnet::Ipv4Net;
+
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    // Escritor mínimo de MaxMind DB (formato 2.0, IPv4, registros de 24 bits): cada red apunta
+    // a {"country": {"iso_code": ISO}}. Las redes anidadas van de la más amplia a la más específica
+    fn mmdb(networks: &[(&str, &str)], build_epoch: u64) -> Vec<u8> {
+        let mut nodes = vec![[Record::Empty, Record::Empty]];
+        let mut data = Vec::new();
+        for (cidr, iso) in networks {
+            let offset = data.len();
+            data.push(0xE1); // map de 1 entrada
+            string(&mut data, "country");
+            data.push(0xE1);
+            string(&mut data, "iso_code");
+            string(&mut data, iso);
+
+            let network: Ipv4Net = cidr.parse().unwrap();
+            let bits = u32::from(network.network());
+            let mut node = 0;
+            for depth in 0..network.prefix_len() {
+                let side = ((bits >> (31 - depth)) & 1) as usize;
+                if depth + 1 == network.prefix_len() {
+                    nodes[node][side] = Record::Data(offset);
+                    break;
+                }
+                node = match nodes[node][side] {
+                    Record::Node(next) => next,
+                    // Una red más específica dentro de otra hereda sus datos en el resto del rango
+                    Record::Data(parent) => push_node(&mut nodes, node, side, [Record::Data(parent), Record::Data(parent)]),
+                    Record::Empty => push_node(&mut nodes, node, side, [Record::Empty, Record::Empty]),
+                };
+            }
+        }
+
+        let node_count = nodes.len();
+        let mut out = Vec::new();
+        for records in &nodes {
+            for record in records {
+                let value = match record {
+                    Record::Empty => node_count,
+                    Record::Node(next) => *next,
+                    Record::Data(offset) => node_count + 16 + offset,
+                };
+                out.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&data);
+
+        out.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        out.push(0xE9); // map de 9 entradas
+        string(&mut out, "binary_format_major_version");
+        out.extend_from_slice(&[0xA2, 0, 2]);
+        string(&mut out, "binary_format_minor_version");
+        out.extend_from_slice(&[0xA2, 0, 0]);
+        string(&mut out, "build_epoch");
+        out.extend_from_slice(&[0x08, 0x02]); // uint64 (tipo extendido 9)
+        out.extend_from_slice(&build_epoch.to_be_bytes());
+        string(&mut out, "database_type");
+        string(&mut out, "GeoLite2-Country");
+        string(&mut out, "description");
+        out.push(0xE0);
+        string(&mut out, "ip_version");
+        out.extend_from_slice(&[0xA2, 0, 4]);
+        string(&mut out, "languages");
+        out.extend_from_slice(&[0x00, 0x04]); // array vacío (tipo extendido 11)
+        string(&mut out, "node_count");
+        out.push(0xC4);
+        out.extend_from_slice(&(node_count as u32).to_be_bytes());
+        string(&mut out, "record_size");
+        out.extend_from_slice(&[0xA2, 0, 24]);
+        out
+    }
+
+    fn push_node(nodes: &mut Vec<[Record; 2]>, parent: usize, side: usize, records: [Record; 2]) -> usize {
+        nodes.push(records);
+        let next = nodes.len() - 1;
+        nodes[parent][side] = Record::Node(next);
+        next
+    }
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.push(0x40 | value.len() as u8);
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn write(path: &std::path::Path, bytes: &[u8], modified: SystemTime) {
+        std::fs::write(path, bytes).unwrap();
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("geoip-{}-{}.mmdb", name, std::process::id()))
+    }
+
+    #[test]
+    fn nested_networks_resolve_to_the_most_specific_one() {
+        let path = temp_path("nested");
+        write(&path, &mmdb(&[("81.2.0.0/16", "GB"), ("81.2.69.0/24", "FR")], 1_700_000_000), SystemTime::now());
+        let reader = GeoIpReader::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(reader.lookup("81.2.69.160".parse().unwrap()).as_deref(), Some("FR"));
+        assert_eq!(reader.lookup("81.2.70.1".parse().unwrap()).as_deref(), Some("GB"));
+        assert_eq!(reader.lookup("8.8.8.8".parse().unwrap()), None);
+        assert_eq!(reader.build_epoch(), 1_700_000_000);
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_previous_database() {
+        let path = temp_path("failed");
+        let started = SystemTime::now() - Duration::from_secs(60);
+        write(&path, &mmdb(&[("81.2.69.0/24", "GB")], 1), started);
+        let reader = GeoIpReader::open(path.to_str().unwrap()).unwrap();
+
+        // Fichero a medio rotar: truncado
+        write(&path, b"\xAB\xCD\xEFMaxMind", started + Duration::from_secs(10));
+        assert!(!reader.reload_if_changed());
+        assert_eq!(reader.lookup("81.2.69.1".parse().unwrap()).as_deref(), Some("GB"));
+        assert_eq!(reader.build_epoch(), 1);
+
+        // Se reintenta en el siguiente tick: el mtime fallido no se da por cargado
+        write(&path, &mmdb(&[("81.2.69.0/24", "IE")], 2), started + Duration::from_secs(10));
+        assert!(reader.reload_if_changed());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.lookup("81.2.69.1".parse().unwrap()).as_deref(), Some("IE"));
+    }
+
+    #[test]
+    fn a_successful_reload_swaps_in_the_new_database() {
+        let path = temp_path("reload");
+        let started = SystemTime::now() - Duration::from_secs(60);
+        write(&path, &mmdb(&[("81.2.69.0/24", "GB")], 1), started);
+        let reader = GeoIpReader::open(path.to_str().unwrap()).unwrap();
+        assert!(!reader.reload_if_changed(), "sin cambios no se recarga");
+
+        write(&path, &mmdb(&[("81.2.69.0/24", "DE"), ("203.0.113.0/24", "JP")], 2), started + Duration::from_secs(10));
+        assert!(reader.reload_if_changed());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.lookup("81.2.69.1".parse().unwrap()).as_deref(), Some("DE"));
+        assert_eq!(reader.lookup("203.0.113.9".parse().unwrap()).as_deref(), Some("JP"));
+        assert_eq!(reader.build_epoch(), 2);
+    }
+
+    #[test]
+    fn a_file_that_is_not_a_maxmind_database_is_rejected() {
+        let path = temp_path("csv");
+        write(&path, b"81.2.69.0/24,GB\n", SystemTime::now());
+        let opened = GeoIpReader::open(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(opened.is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::models::{AnomalyScore, ThreatLevel};
+
+// ==========================================
+// HISTÓRICO DE SCORES (CUMPLIMIENTO)
+// ==========================================
+
+/// Scores en cola entre `analyze()` y el escritor en segundo plano.
+pub const SCORE_QUEUE_CAPACITY: usize = 10_000;
+
+/// Destino duradero de cada `AnomalyScore` ("detecciones Critical del tenant X en 90 días").
+#[async_trait]
+pub trait ScoreSink: Send + Sync {
+    async fn record(&self, score: &AnomalyScore) -> Result<(), String>;
+}
+
+/// Sink JSON Lines (solo append). Esquema: una línea por score con la serialización
+/// de `AnomalyScore` — `tenant_id`, `client_id`, `score` (0–1), `level`
+/// (`Safe`…`Critical`), `detected_patterns`, `reasons`, `pattern_contributions`,
+/// `timestamp` (RFC 3339, UTC) y `recommendation`. Se consulta con `query_jsonl`.
+pub struct JsonlScoreSink {
+    file: Mutex<tokio::fs::File>,
+}
+
+impl JsonlScoreSink {
+    pub async fn open(path: &str) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+#[async_trait]
+impl ScoreSink for JsonlScoreSink {
+    async fn record(&self, score: &AnomalyScore) -> Result<(), String> {
+        let mut line = serde_json::to_vec(score).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        // Una sola escritura por línea: un lector concurrente nunca ve registros mezclados.
+        // tokio::fs::File escribe en diferido: flush confirma que la línea llegó al fichero
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await.map_err(|e| e.to_string())?;
+        file.flush().await.map_err(|e| e.to_string())
+    }
+}
+
+/// Filtro de `query_jsonl`. `level` es el nivel mínimo (Critical = solo Critical).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScoreQuery {
+    pub tenant_id: String,
+    #[serde(default)]
+    pub level: Option<ThreatLevel>,
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Últimos `limit` scores que cumplen `query`, del más reciente al más antiguo.
+/// Lectura bloqueante de todo el fichero: llamar desde `spawn_blocking`.
+/// Las líneas corruptas (p.ej. una escritura cortada) se ignoran.
+pub fn query_jsonl(path: &str, query: &ScoreQuery, limit: usize) -> io::Result<Vec<AnomalyScore>> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut matches = VecDeque::with_capacity(limit.min(1024));
+    for line in reader.lines() {
+        let Ok(score) = serde_json::from_str::<AnomalyScore>(&line?) else {
+            continue;
+        };
+        let wanted = score.tenant_id == query.tenant_id
+            && query.level.is_none_or(|level| score.level >= level)
+            && query.since.is_none_or(|since| score.timestamp >= since)
+            && query.until.is_none_or(|until| score.timestamp <= until);
+        if !wanted || limit == 0 {
+            continue;
+        }
+        // El fichero va en orden cronológico: basta con retener los `limit` últimos
+        if matches.len() == limit {
+            matches.pop_front();
+        }
+        matches.push_back(score);
+    }
+    Ok(matches.into_iter().rev().collect())
+}
+
+/// Cola hacia un `ScoreSink` con escritor en segundo plano: `record` nunca espera,
+/// así la latencia del scoring no depende del sink. Con la cola llena el score se
+/// descarta y se cuenta en `dropped`.
+pub struct ScoreRecorder {
+    tx: mpsc::Sender<AnomalyScore>,
+    dropped: AtomicU64,
+}
+
+impl ScoreRecorder {
+    /// Lanza el escritor. Debe invocarse dentro de un runtime de tokio.
+    pub fn spawn(sink: Arc<dyn ScoreSink>, capacity: usize) -> Self {
+        let (tx, mut rx) = mpsc::channel::<AnomalyScore>(capacity.max(1));
+        tokio::spawn(async move {
+            while let Some(score) = rx.recv().await {
+                if let Err(e) = sink.record(&score).await {
+                    log::warn!("[HISTORY] No se pudo registrar el score de {}:{}: {}", score.tenant_id, score.client_id, e);
+                }
+            }
+        });
+        Self {
+            tx,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, score: &AnomalyScore) {
+        if self.tx.try_send(score.clone()).is_err() {
+            // Solo se avisa en potencias de 2 para no inundar el log si el sink se atasca
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                log::warn!("[HISTORY] Cola de scores llena: {} descartados", dropped);
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Espera a que el escritor vacíe la cola (apagado). Con un sink caído no termina:
+    /// acotarlo con un timeout.
+    pub async fn flush(&self) {
+        while self.tx.capacity() < self.tx.max_capacity() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
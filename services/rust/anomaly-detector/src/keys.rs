@@ -0,0 +1,69 @@
+// ==========================================
+// CLAVES COMPUESTAS TENANT + CLIENTE
+// ==========================================
+
+/// Clave "tenant:cliente" sin ambigüedad para mapas, stores y caché.
+///
+/// `%` y `:` se escapan en cada parte, así el único ':' literal es el separador:
+/// el tenant `acme:prod` con el cliente `5` ya no coincide con el tenant `acme` y el
+/// cliente `prod:5`. Sin esos caracteres la clave es idéntica al formato anterior,
+/// de modo que los datos ya persistidos siguen siendo válidos.
+pub fn composite_key(tenant_id: &str, client_id: &str) -> String {
+    format!("{}:{}", escape(tenant_id), escape(client_id))
+}
+
+/// Inversa de `composite_key`. `None` si la clave no tiene exactamente un separador.
+pub fn split_composite_key(key: &str) -> Option<(String, String)> {
+    let (tenant, client) = key.split_once(':')?;
+    if client.contains(':') {
+        return None;
+    }
+    Some((unescape(tenant), unescape(client)))
+}
+
+fn escape(part: &str) -> String {
+    if !part.contains([':', '%']) {
+        return part.to_string();
+    }
+    part.replace('%', "%25").replace(':', "%3A")
+}
+
+fn unescape(part: &str) -> String {
+    if !part.contains('%') {
+        return part.to_string();
+    }
+    part.replace("%3A", ":").replace("%3a", ":").replace("%25", "%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_and_client_separators_no_longer_collide() {
+        let a = composite_key("tenant:a", "b");
+        let b = composite_key("tenant", "a:b");
+        assert_ne!(a, b);
+        assert_eq!(split_composite_key(&a), Some(("tenant:a".to_string(), "b".to_string())));
+        assert_eq!(split_composite_key(&b), Some(("tenant".to_string(), "a:b".to_string())));
+    }
+
+    #[test]
+    fn plain_keys_keep_the_legacy_format() {
+        assert_eq!(composite_key("acme", "42"), "acme:42");
+        assert_eq!(split_composite_key("acme:42"), Some(("acme".to_string(), "42".to_string())));
+        assert_eq!(split_composite_key("acme"), None);
+        assert_eq!(split_composite_key("acme:a:b"), None);
+    }
+
+    #[test]
+    fn escaped_percent_roundtrips() {
+        // Partes que ya parecen escapadas no se decodifican de más
+        for (tenant, client) in [("100%", "a%b"), ("%3A", "%25"), ("%:", ":%"), ("a%253Ab", "%3a")] {
+            let key = composite_key(tenant, client);
+            assert_eq!(key.matches(':').count(), 1, "{}", key);
+            assert_eq!(split_composite_key(&key), Some((tenant.to_string(), client.to_string())), "{}", key);
+        }
+        assert_ne!(composite_key("%3A", "x"), composite_key(":", "x"));
+    }
+}
@@ -6,16 +6,65 @@
 pub mod models;
 pub mod detector;
 pub mod patterns;
+pub mod geo;
+pub mod geoip;
+pub mod keys;
+pub mod history;
+pub mod log_throttle;
 pub mod storage; 
-pub mod api;
+pub mod alerts;
+pub mod allowlist;
+pub mod audit;
+pub mod auth;
+pub mod breaker;
+pub mod coalesce;
+pub mod dedup;
+pub mod endpoints;
+pub mod enrich;
+pub mod error;
+pub mod forwarded;
+pub mod outcomes;
+pub mod rate_limit;
+pub mod scoring;
+pub mod session;
+pub mod spray;
+pub mod telemetry;
+pub mod timezone;
+pub mod trends;
+pub mod tls;
+pub mod user_agent;
 
 // Re-exportaciones públicas (API Pública)
 // Solo exponemos lo que el consumidor de la librería necesita ver
 pub use detector::AnomalyDetector;
-pub use models::{BehaviorEvent, ThreatLevel, AnomalyScore, BehaviorPattern};
+pub use models::{Action, BehaviorEvent, ClientProfile, ColdStartAction, CompromisedClient, EventSummary, ThreatLevel, AnomalyScore, BehaviorPattern, DetectionResult, HealthCheck, MetadataOperator, MetadataRule, TenantStats, ThreatSignature, VipEntry};
 pub use patterns::PatternMatcher;
+pub use storage::{InMemoryProfileStore, ProfileStore};
+pub use rate_limit::SlidingWindowLimiter;
+pub use outcomes::LoginOutcomeTracker;
+pub use log_throttle::LogThrottle;
+pub use spray::SprayTracker;
+pub use session::{SessionJump, SessionTracker};
+pub use audit::AuditLog;
+pub use auth::ApiKeySet;
+pub use error::DetectorError;
+pub use coalesce::{Coalesce, WriteCoalescer};
+pub use dedup::ResponseCache;
+pub use endpoints::SensitiveEndpoints;
+pub use enrich::{EventEnricher, ReputationEntry, StaticReputation};
+pub use alerts::WebhookAlerter;
+pub use breaker::{BreakerSettings, BreakerState, BreakerStatus, CircuitBreaker};
+pub use scoring::{ActionThresholds, ChallengeTypes, RiskThresholds, ScoringWeights, WorkHours};
+pub use allowlist::IpAllowlist;
+pub use geoip::GeoIpReader;
+pub use keys::{composite_key, split_composite_key};
+pub use history::{JsonlScoreSink, ScoreQuery, ScoreSink};
+pub use trends::{TrendBucket, TrendCounter, TREND_RING_HOURS};
+pub use tls::TlsSettings;
+pub use user_agent::{normalize_user_agent, UaNormalization};
 
 use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 
 // ==========================================
 // CONFIGURACIÓN CENTRALIZADA
@@ -23,11 +72,93 @@ use std::sync::Arc;
 
 /// Configuración para el motor de seguridad.
 /// Permite ajustar la sensibilidad sin tocar el código fuente.
-#[derive(Debug, Clone)]
+///
+/// Se puede cargar desde un fichero JSON (`from_file`); los campos ausentes
+/// toman su valor por defecto.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SecurityConfig {
     pub max_active_profiles: usize,
     pub rate_limit_threshold: f64,
-    pub sensitivity: f64, // 0.0 a 1.0
+    /// Escala de todos los scores, 0.0 a 1.0 (ver `scoring::apply_sensitivity`; 0.8 = neutro).
+    pub sensitivity: f64,
+    /// Horas de inactividad tras las cuales el risk_score de un perfil se reduce a la mitad.
+    pub risk_half_life_hours: f64,
+    /// Horas tras las cuales un perfil comprometido se libera automáticamente (0 = permanente).
+    pub compromise_ttl_hours: f64,
+    /// Riesgo residual (0–1) de un cliente liberado por `compromise_ttl_hours`: durante
+    /// `incident_watch_hours` su risk_score decae solo hasta este suelo (0 = sin suelo).
+    pub min_risk_after_incident: f64,
+    /// Horas de vigilancia tras la liberación automática (0 = sin suelo).
+    pub incident_watch_hours: f64,
+    /// Fichero JSON con las `ThreatSignature` a usar (None = umbrales por defecto).
+    pub signatures_path: Option<String>,
+    /// URL a la que se envía un POST JSON cuando un perfil pasa a Critical.
+    pub alert_webhook_url: Option<String>,
+    /// Minutos mínimos entre dos alertas para el mismo cliente.
+    pub alert_debounce_minutes: i64,
+    /// Redes de confianza (CIDR IPv4/IPv6) que saltan el scoring.
+    pub allowlist: Vec<ipnet::IpNet>,
+    /// Modo observación: se puntúa todo pero la acción devuelta es siempre ALLOW.
+    pub shadow_mode: bool,
+    /// Horas en modo observación de cada tenant desde su primer evento; después se
+    /// aplican las decisiones sin intervención manual (0 = sin rampa).
+    pub tenant_ramp_hours: f64,
+    /// Acción para usuarios sin baseline (ALLOW = fail-open, CHALLENGE = fail-secure).
+    pub cold_start_action: ColdStartAction,
+    /// Acción máxima de los clientes VIP (`VipEntry`): CHALLENGE o ALLOW, nunca BLOCK.
+    pub vip_max_action: Action,
+    /// Pesos del scoring aditivo del servicio HTTP (baseline por usuario).
+    pub scoring_weights: scoring::ScoringWeights,
+    /// Peso base de cada patrón en el score del detector (ver `scoring::default_pattern_weights`).
+    pub pattern_weights: std::collections::HashMap<BehaviorPattern, f64>,
+    /// Patrones que no se evalúan nunca (p.ej. `TimingAttack` con integraciones automáticas).
+    pub disabled_patterns: Vec<BehaviorPattern>,
+    /// Patrones desactivados solo para ciertos tenants (se suman a los globales).
+    pub tenant_disabled_patterns: std::collections::HashMap<String, Vec<BehaviorPattern>>,
+    /// Reglas de cada tenant sobre `BehaviorEvent.metadata` (ver `MetadataRule`).
+    pub metadata_rules: std::collections::HashMap<String, Vec<MetadataRule>>,
+    /// Cortes de nivel de amenaza (escala 0–1) compartidos por detector y API HTTP.
+    pub risk_thresholds: scoring::RiskThresholds,
+    /// Cortes de score para CHALLENGE y BLOCK en la API HTTP (None = los de `risk_thresholds`).
+    pub action_thresholds: Option<scoring::ActionThresholds>,
+    /// Paso a presentar en cada nivel de riesgo cuando la acción es CHALLENGE.
+    pub challenge_types: scoring::ChallengeTypes,
+    /// Challenges previos que suben un escalón la recomendación (THROTTLE → MFA → ISOLATE).
+    pub challenge_escalation_step: u32,
+    /// Horas sin challenges tras las cuales `challenge_count` vuelve a cero.
+    pub challenge_reset_hours: f64,
+    /// Antigüedad máxima (segundos) de `BehaviorEvent.timestamp`; fuera de
+    /// `[ahora - max, ahora]` se acota y se avisa en el log (0 = siempre la hora del servidor).
+    pub max_event_age_secs: u64,
+    /// Eventos recientes que guarda cada perfil para revisión forense (0 = ninguno).
+    pub event_history_size: usize,
+    /// Fichero JSON Lines donde se añade cada score de `analyze()` (None = sin histórico).
+    pub score_history_path: Option<String>,
+    /// URL de Redis (`redis://host:6379/`) del store compartido entre réplicas: perfiles,
+    /// VIP, blocklist y baselines sobreviven a reinicios. Requiere la feature `redis`
+    /// (None = en memoria, se pierde todo al reiniciar).
+    pub redis_url: Option<String>,
+    /// Minutos entre barridos de perfiles obsoletos en segundo plano (0 = solo al llegar al límite).
+    pub cleanup_interval_minutes: u64,
+    /// Proxies de confianza delante de la API HTTP: con N > 0 la IP del cliente sale de
+    /// `X-Forwarded-For`/`Forwarded` (N saltos desde la derecha) en lugar del cuerpo.
+    pub trust_proxy: usize,
+    /// Circuit breaker de las dependencias externas (store de perfiles, webhook de alertas).
+    pub circuit_breaker: breaker::BreakerSettings,
+    /// Reputación estática por red (0 = limpia, 1 = maliciosa): alimenta `BadReputation`.
+    pub ip_reputation: Vec<enrich::ReputationEntry>,
+    /// Presupuesto (ms) de cada decisión de `/detect`: al agotarse se responde ALLOW con
+    /// `degraded: true` en lugar de retrasar el login (0 = sin límite).
+    pub scoring_timeout_ms: u64,
+    /// Plazo total (ms) de los enriquecedores de cada evento; al vencer se puntúa con lo obtenido.
+    pub enrichment_timeout_ms: u64,
+    /// Sal secreta (mínimo `MIN_IP_SALT_LEN` bytes) de los tenants que seudonimizan IPs:
+    /// la API HTTP sustituye `ip_address` por HMAC-SHA256(sal, ip) al recibir el evento y
+    /// ningún baseline, perfil ni auditoría guarda la IP real. Sin IP no hay geolocalización:
+    /// para esos tenants quedan desactivados GeoIP (país UNKNOWN), viaje imposible, sesiones
+    /// entre redes, reputación por CIDR, y allowlist/blocklist por IP literal.
+    pub tenant_ip_salts: std::collections::HashMap<String, String>,
 }
 
 impl Default for SecurityConfig {
@@ -36,10 +167,147 @@ impl Default for SecurityConfig {
             max_active_profiles: 100_000,
             rate_limit_threshold: 100.0,
             sensitivity: 0.8,
+            risk_half_life_hours: 6.0,
+            compromise_ttl_hours: 24.0,
+            min_risk_after_incident: 0.0,
+            incident_watch_hours: 24.0 * 7.0,
+            signatures_path: None,
+            alert_webhook_url: None,
+            alert_debounce_minutes: 15,
+            allowlist: Vec::new(),
+            shadow_mode: false,
+            tenant_ramp_hours: 0.0,
+            cold_start_action: ColdStartAction::Allow,
+            vip_max_action: Action::Challenge,
+            scoring_weights: scoring::ScoringWeights::default(),
+            pattern_weights: scoring::default_pattern_weights(),
+            disabled_patterns: Vec::new(),
+            tenant_disabled_patterns: std::collections::HashMap::new(),
+            metadata_rules: std::collections::HashMap::new(),
+            risk_thresholds: scoring::RiskThresholds::default(),
+            action_thresholds: None,
+            challenge_types: scoring::ChallengeTypes::default(),
+            challenge_escalation_step: 3,
+            challenge_reset_hours: 24.0,
+            event_history_size: 10,
+            max_event_age_secs: 24 * 3600,
+            score_history_path: None,
+            redis_url: None,
+            cleanup_interval_minutes: 10,
+            trust_proxy: 0,
+            circuit_breaker: breaker::BreakerSettings::default(),
+            ip_reputation: Vec::new(),
+            scoring_timeout_ms: 50,
+            enrichment_timeout_ms: 30,
+            tenant_ip_salts: std::collections::HashMap::new(),
         }
     }
 }
 
+// Tope del historial forense por perfil (se multiplica por max_active_profiles)
+const MAX_EVENT_HISTORY: usize = 1000;
+
+/// Longitud mínima de cada sal de `tenant_ip_salts`: con menos, el espacio IPv4
+/// (2^32) más la sal sería abordable por fuerza bruta.
+pub const MIN_IP_SALT_LEN: usize = 16;
+
+/// Tope de `max_event_age_secs` (un año): más allá el acotado deja de tener sentido.
+pub const MAX_EVENT_AGE_SECS: u64 = 365 * 24 * 3600;
+
+impl SecurityConfig {
+    /// Lee la configuración de un fichero JSON y valida sus rangos.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("No se pudo leer la configuración {}: {}", path, e))?;
+        let config: Self = serde_json::from_str(&raw)
+            .map_err(|e| format!("Configuración inválida {}: {}", path, e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Configuración inválida {}: {}", path, e))?;
+        Ok(config)
+    }
+
+    /// Fichero de `ANOMALY_CONFIG_PATH` si está definido; si no, los valores por defecto.
+    pub fn load() -> Result<Self, String> {
+        match std::env::var("ANOMALY_CONFIG_PATH") {
+            Ok(path) => Self::from_file(&path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Comprueba que cada valor está en su rango.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_active_profiles == 0 {
+            return Err("max_active_profiles must be greater than 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.sensitivity) {
+            return Err(format!("sensitivity must be within 0–1 (got {})", self.sensitivity));
+        }
+        let non_negative = [
+            ("rate_limit_threshold", self.rate_limit_threshold),
+            ("risk_half_life_hours", self.risk_half_life_hours),
+            ("compromise_ttl_hours", self.compromise_ttl_hours),
+            ("incident_watch_hours", self.incident_watch_hours),
+            ("challenge_reset_hours", self.challenge_reset_hours),
+            ("tenant_ramp_hours", self.tenant_ramp_hours),
+        ];
+        if let Some((name, value)) = non_negative.iter().find(|(_, v)| !v.is_finite() || *v < 0.0) {
+            return Err(format!("{} must be a non-negative number (got {})", name, value));
+        }
+        if !(0.0..=1.0).contains(&self.min_risk_after_incident) {
+            return Err(format!("min_risk_after_incident must be within 0–1 (got {})", self.min_risk_after_incident));
+        }
+        if self.alert_debounce_minutes < 0 {
+            return Err(format!("alert_debounce_minutes must be >= 0 (got {})", self.alert_debounce_minutes));
+        }
+        if self.event_history_size > MAX_EVENT_HISTORY {
+            return Err(format!("event_history_size must be at most {} (got {})", MAX_EVENT_HISTORY, self.event_history_size));
+        }
+        if self.max_event_age_secs > MAX_EVENT_AGE_SECS {
+            return Err(format!("max_event_age_secs must be at most {} (got {})", MAX_EVENT_AGE_SECS, self.max_event_age_secs));
+        }
+        if self.trust_proxy > forwarded::MAX_TRUSTED_PROXIES {
+            return Err(format!("trust_proxy must be at most {} (got {})", forwarded::MAX_TRUSTED_PROXIES, self.trust_proxy));
+        }
+        if let Some(tenant) = self.tenant_ip_salts.iter().find(|(_, salt)| salt.len() < MIN_IP_SALT_LEN).map(|(t, _)| t) {
+            return Err(format!("tenant_ip_salts[{}] must be at least {} bytes", tenant, MIN_IP_SALT_LEN));
+        }
+        if self.redis_url.is_some() && !cfg!(feature = "redis") {
+            return Err("redis_url requires building with the `redis` feature".to_string());
+        }
+        if self.vip_max_action == Action::Block {
+            return Err("vip_max_action must be ALLOW or CHALLENGE".to_string());
+        }
+        if self.challenge_escalation_step == 0 {
+            return Err("challenge_escalation_step must be at least 1".to_string());
+        }
+        if !self.risk_thresholds.is_valid() {
+            return Err(format!(
+                "risk_thresholds must be strictly increasing within (0, 1] (got {:?})",
+                self.risk_thresholds
+            ));
+        }
+        if let Some(actions) = self.action_thresholds.filter(|a| !a.is_valid()) {
+            return Err(format!("action_thresholds must satisfy 0 < challenge <= block <= 1 (got {:?})", actions));
+        }
+        if let Some((pattern, weight)) = self.pattern_weights.iter().find(|(_, w)| !w.is_finite() || **w < 0.0) {
+            return Err(format!("pattern_weights.{:?} must be a non-negative number (got {})", pattern, weight));
+        }
+        if self.enrichment_timeout_ms == 0 {
+            return Err("enrichment_timeout_ms must be greater than 0".to_string());
+        }
+        if let Some(entry) = self.ip_reputation.iter().find(|e| !(0.0..=1.0).contains(&e.score)) {
+            return Err(format!("ip_reputation.{} score must be within 0–1 (got {})", entry.network, entry.score));
+        }
+        for (tenant_id, rules) in &self.metadata_rules {
+            patterns::validate_metadata_rules(rules).map_err(|e| format!("metadata_rules.{}: {}", tenant_id, e))?;
+        }
+        self.challenge_types.validate()?;
+        self.circuit_breaker.validate()?;
+        self.scoring_weights.validate()
+    }
+}
+
 // ==========================================
 // INICIALIZACIÓN DEL SISTEMA
 // ==========================================
@@ -62,21 +330,138 @@ impl Default for SecurityConfig {
 ///    HttpServer::new(move || App::new().app_data(Data::new(security_engine.clone()))...
 ///
 pub async fn initialize(config: Option<SecurityConfig>) -> Result<Arc<AnomalyDetector>, Box<dyn std::error::Error>> {
-    // 1. Cargar configuración (o usar defaults seguros)
-    let _cfg = config.unwrap_or_default();
+    // 1. Cargar configuración (o el fichero de ANOMALY_CONFIG_PATH, o defaults seguros)
+    let cfg = match config {
+        Some(cfg) => cfg,
+        None => SecurityConfig::load()?,
+    };
+    cfg.validate()?;
 
-    // 2. Instanciar el detector (Usando la lógica corregida del paso anterior)
-    let detector = AnomalyDetector::new().await;
+    // 2. Cargar firmas de amenaza externas (si se configuró un fichero)
+    let signatures = match &cfg.signatures_path {
+        Some(path) => Some(patterns::load_signatures(path)?),
+        None => None,
+    };
 
-    // Aquí podrías aplicar la configuración _cfg al detector si expusieras métodos para ello.
-    // Por ejemplo: detector.set_max_profiles(_cfg.max_active_profiles);
+    // 3. Instanciar el detector aplicando los límites de la configuración
+    let cleanup_interval = cfg.cleanup_interval_minutes;
+    let history_path = cfg.score_history_path.clone();
+    let disabled_patterns = (cfg.disabled_patterns.clone(), cfg.tenant_disabled_patterns.clone());
+    let reputation = cfg.ip_reputation.clone();
+    let metadata_rules = cfg.metadata_rules.clone();
+    let mut detector = match cfg.redis_url.clone() {
+        Some(url) => {
+            let store = open_store(&url, cfg.compromise_ttl_hours).await?;
+            println!("[SECURITY] Profile store: Redis (shared, durable).");
+            AnomalyDetector::with_store(cfg, store)
+        }
+        None => {
+            println!("[SECURITY] Profile store: in-memory (state is lost on restart).");
+            AnomalyDetector::with_config(cfg)
+        }
+    };
+    let mut matcher = match signatures {
+        Some(signatures) => {
+            println!("[SECURITY] Loaded {} threat signatures from file.", signatures.len());
+            PatternMatcher::with_signatures(signatures)
+        }
+        None => PatternMatcher::new(),
+    };
+    if !disabled_patterns.0.is_empty() || !disabled_patterns.1.is_empty() {
+        println!("[SECURITY] Disabled patterns: {:?} (global), {} tenant overrides.", disabled_patterns.0, disabled_patterns.1.len());
+    }
+    matcher = matcher.with_disabled(disabled_patterns.0, disabled_patterns.1);
+    if !metadata_rules.is_empty() {
+        println!("[SECURITY] Metadata rules declared for {} tenants.", metadata_rules.len());
+    }
+    matcher = matcher.with_metadata_rules(metadata_rules);
+    detector = detector.with_pattern_matcher(matcher);
+    if !reputation.is_empty() {
+        println!("[SECURITY] IP reputation: {} networks.", reputation.len());
+        detector = detector.with_enricher(Arc::new(enrich::StaticReputation::new(reputation)));
+    }
+    if let Some(path) = &history_path {
+        println!("[SECURITY] Score history: appending to {}.", path);
+        detector = detector.with_score_sink(Arc::new(history::JsonlScoreSink::open(path).await?));
+    }
 
-    // 3. Log de arranque (Vital para auditoría)
+    // 4. Log de arranque (Vital para auditoría)
     println!("[SECURITY] WorkChain Threat Engine Initialized.");
     println!("[SECURITY] Mode: Zero Trust / Multi-Tenant Isolation");
     
-    // 4. Retornar Puntero Compartido (Arc)
+    // 5. Retornar Puntero Compartido (Arc)
     // Esto garantiza que todos los hilos del servidor web vean la misma memoria
     // y bloqueen a los atacantes globalmente.
-    Ok(Arc::new(detector))
-}
\ No newline at end of file
+    let detector = Arc::new(detector);
+
+    // 6. Rehidratar perfiles y VIP antes de servir la primera petición
+    let restored = detector.restore().await;
+    let vips = detector.restore_vips().await;
+    if restored > 0 || vips > 0 {
+        println!(
+            "[SECURITY] Restored {} profiles and {} VIP clients (VIP actions capped at {}).",
+            restored, vips, detector.vip_max_action()
+        );
+    }
+
+    // 7. Barrido periódico de perfiles obsoletos (termina con `detector.shutdown()`)
+    if cleanup_interval > 0 {
+        detector.spawn_cleanup(std::time::Duration::from_secs(cleanup_interval * 60));
+    }
+
+    Ok(detector)
+}
+
+// Backend durable de `redis_url`; sin la feature `redis` la configuración ya lo rechaza
+#[cfg(feature = "redis")]
+async fn open_store(url: &str, compromise_ttl_hours: f64) -> Result<Box<dyn ProfileStore>, String> {
+    let compromise_ttl = (compromise_ttl_hours > 0.0)
+        .then(|| chrono::Duration::seconds((compromise_ttl_hours * 3600.0) as i64));
+    let store = storage::RedisProfileStore::connect(url).await?.with_compromise_ttl(compromise_ttl);
+    Ok(Box::new(store))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn open_store(_url: &str, _compromise_ttl_hours: f64) -> Result<Box<dyn ProfileStore>, String> {
+    Err("redis_url requires building with the `redis` feature".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redis_url_needs_the_redis_feature() {
+        let config = SecurityConfig { redis_url: Some("redis://127.0.0.1/".to_string()), ..SecurityConfig::default() };
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "redis"));
+    }
+
+    #[tokio::test]
+    async fn configured_max_profiles_reaches_the_detector() {
+        let path = std::env::temp_dir().join(format!("anomaly-config-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "max_active_profiles": 2, "cleanup_interval_minutes": 0 }"#).unwrap();
+        let config = SecurityConfig::from_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let detector = initialize(Some(config.unwrap())).await.unwrap();
+        assert_eq!(detector.max_profiles(), 2);
+
+        // El tope se aplica: con dos clientes comprometidos no cabe un tercero
+        let event = |client_id: &str, injection: f64| BehaviorEvent {
+            tenant_id: "acme".to_string(),
+            client_id: client_id.to_string(),
+            timestamp: chrono::Utc::now(),
+            pattern: BehaviorPattern::Normal,
+            confidence: 1.0,
+            indicators: std::collections::HashMap::from([(patterns::KEY_INJECTION_SCORE.to_string(), injection)]),
+            metadata: std::collections::HashMap::new(),
+            device_id: None,
+        };
+        detector.analyze(&event("a", 0.95)).await.unwrap();
+        detector.analyze(&event("b", 0.95)).await.unwrap();
+        assert!(matches!(
+            detector.analyze(&event("c", 0.0)).await,
+            Err(DetectorError::CapacityExceeded { max_profiles: 2 })
+        ));
+    }
+}
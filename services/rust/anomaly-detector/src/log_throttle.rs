@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// ==========================================
+// LOGS LIMITADOS POR CLAVE (ANTI-INUNDACIÓN)
+// ==========================================
+
+/// Como mucho una línea de log por clave cada `interval`.
+///
+/// Durante un ataque sostenido cada petición repetiría la misma línea y saturaría
+/// la ingesta de logs: las suprimidas se cuentan y se informan en la siguiente
+/// línea permitida (o en `purge_idle` si la clave deja de aparecer).
+pub struct LogThrottle {
+    // Clave -> (última línea emitida, líneas suprimidas desde entonces)
+    last: DashMap<String, (Instant, u64)>,
+    interval: Duration,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            last: DashMap::new(),
+            interval,
+        }
+    }
+
+    /// `Some(n)` si se puede registrar ahora (`n` = líneas suprimidas desde la anterior),
+    /// `None` si la línea debe omitirse.
+    pub fn allow(&self, key: &str) -> Option<u64> {
+        let now = Instant::now();
+        match self.last.get_mut(key) {
+            Some(mut slot) if now.duration_since(slot.0) < self.interval => {
+                slot.1 += 1;
+                None
+            }
+            Some(mut slot) => {
+                let suppressed = slot.1;
+                *slot = (now, 0);
+                Some(suppressed)
+            }
+            None => {
+                self.last.insert(key.to_string(), (now, 0));
+                Some(0)
+            }
+        }
+    }
+
+    /// Olvida las claves sin actividad reciente, resumiendo lo que quedó suprimido.
+    pub fn purge_idle(&self) {
+        self.last.retain(|key, (at, suppressed)| {
+            if at.elapsed() < self.interval {
+                return true;
+            }
+            if *suppressed > 0 {
+                log::info!("[LOG] {} líneas similares suprimidas para {}", suppressed, key);
+            }
+            false
+        });
+    }
+}
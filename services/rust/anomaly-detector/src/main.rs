@@ -1,10 +1,37 @@
-use actix_web::{web, App, HttpServer, HttpResponse, HttpRequest, middleware, Responder};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_http::{error::DispatchError, HttpService, Protocol};
+use actix_service::{fn_service, map_config, IntoServiceFactory, ServiceFactoryExt};
+use actix_web::{web, App, HttpServer, HttpResponse, HttpRequest, middleware};
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web_actors::ws;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use dashmap::DashMap; // MEJORA: Reemplazo de Mutex<HashMap> para alto rendimiento
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use log::{info, warn, error};
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{debug, info, warn};
 use dotenv::dotenv;
-use chrono::{DateTime, Utc, Timelike};
+use chrono::{DateTime, Utc};
+use anomaly_detector::detector::{validate_threshold, COMPROMISED_REASON};
+use anomaly_detector::enrich::META_IP_ADDRESS;
+use anomaly_detector::patterns::{
+    KEY_ENUMERATION_SCORE, KEY_FAILURE_RATE, KEY_LOCATION_RISK, KEY_SPRAY_SCORE,
+};
+use anomaly_detector::telemetry::{self, DetectSpan, TraceContext};
+use anomaly_detector::timezone;
+use anomaly_detector::{
+    composite_key, forwarded, geo, history, normalize_user_agent, split_composite_key, scoring::{apply_sensitivity, maturity_confidence}, Action, ActionThresholds, AnomalyDetector, ApiKeySet, BreakerState, BreakerStatus, GeoIpReader, AuditLog, BehaviorEvent, BehaviorPattern, ClientProfile, MetadataRule, ColdStartAction, ProfileStore, DetectorError, HealthCheck, IpAllowlist, LogThrottle, LoginOutcomeTracker,
+    ChallengeTypes, Coalesce, ResponseCache, RiskThresholds, ScoreQuery, ScoringWeights, SecurityConfig, SessionJump, SessionTracker, SlidingWindowLimiter, SprayTracker,
+    ThreatLevel, TlsSettings, TrendCounter, VipEntry, WorkHours, TREND_RING_HOURS, WriteCoalescer,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use ring::hmac;
 
 /**
  * WorkChain ERP - Anomaly Detection Service (Optimized)
@@ -23,256 +50,4961 @@ use chrono::{DateTime, Utc, Timelike};
 struct AppState {
     // DashMap permite acceso concurrente. Clave: "tenant_id:user_id"
     baselines: Arc<DashMap<String, UserBaseline>>,
-    api_key: String,
+    // Base GeoIP recargable (None = sin base de datos configurada)
+    geoip: Option<Arc<GeoIpReader>>,
+    // Zonas IANA (cacheadas) y zona por defecto de cada tenant; sin zona se usa UTC
+    tenant_timezones: Arc<HashMap<String, String>>,
+    // Horario laboral declarado por tenant (ver WorkHours); editable por API
+    work_hours: Arc<DashMap<String, WorkHours>>,
+    // Keys aceptadas simultáneamente (permite rotar sin cortar clientes)
+    api_keys: Arc<ApiKeySet>,
+    // Contadores lock-free para /metrics
+    metrics: Arc<Metrics>,
+    // Decisiones por tenant, hora y acción para /api/v1/trends
+    trends: Arc<TrendCounter>,
+    // Motor de perfiles (compartido por todos los workers)
+    detector: Arc<AnomalyDetector>,
+    // Freno duro anti-DoS por "tenant_id:user_id"
+    rate_limiter: Arc<SlidingWindowLimiter>,
+    // Ventana de éxitos/fallos de login para calcular failure_rate
+    login_outcomes: Arc<LoginOutcomeTracker>,
+    // Usuarios distintos atacados por IP (credential spray)
+    spray: Arc<SprayTracker>,
+    // Última IP/país de cada sesión (cookie reutilizada desde otra red)
+    sessions: Arc<SessionTracker>,
+    // Auditoría estructurada (SOC-2)
+    audit: Arc<AuditLog>,
+    // Logs por petición limitados por clave (un ataque no inunda la ingesta)
+    log_throttle: Arc<LogThrottle>,
+    // Pesos, cortes de acción y allowlist vigentes; SIGHUP los sustituye (ver reload_config)
+    live_config: Arc<std::sync::RwLock<Arc<LiveConfig>>>,
+    // Blocklist manual (IPs y "tenant_id:user_id"), persistida en el ProfileStore
+    blocklist: Arc<DashMap<(BlockKind, String), BlocklistEntry>>,
+    // Difusión en vivo de detecciones hacia /api/v1/stream (JSON ya serializado)
+    stream_tx: broadcast::Sender<String>,
+    stream_min_score: f32,
+    // Observar sin aplicar (ver SecurityConfig::shadow_mode)
+    shadow_mode: bool,
+    // Presupuesto de cada decisión en vivo (ver SecurityConfig::scoring_timeout_ms)
+    scoring_timeout: Option<std::time::Duration>,
+    // Rampa observar → aplicar de cada tenant nuevo (ver SecurityConfig::tenant_ramp_hours)
+    tenant_ramp: Option<chrono::Duration>,
+    // Primer evento en vivo de cada tenant, persistido en el ProfileStore
+    tenant_first_seen: Arc<DashMap<String, DateTime<Utc>>>,
+    // Decisión para usuarios sin baseline (ver SecurityConfig::cold_start_action)
+    cold_start_action: ColdStartAction,
+    // Proxies de confianza delante del servicio (ver SecurityConfig::trust_proxy)
+    trust_proxy: usize,
+    // Clave HMAC de los tenants que seudonimizan IPs (ver SecurityConfig::tenant_ip_salts)
+    ip_salts: Arc<HashMap<String, hmac::Key>>,
+    // Huella de pesos y umbrales vigentes; cambia con cada ajuste en caliente
+    ruleset_version: Arc<std::sync::RwLock<String>>,
+    // Paso concreto de cada CHALLENGE según el nivel de riesgo
+    challenge_types: Arc<ChallengeTypes>,
+    // BLOCK como 429 + cabeceras en lugar de 200 (integraciones nuevas)
+    block_as_429: bool,
+    // Respuestas recientes por "tenant_id:event_id" (entrega at-least-once)
+    responses: Arc<ResponseCache<AnomalyResponse>>,
+    // Escrituras de /baseline agrupadas por "tenant_id:user_id" (ráfagas)
+    baseline_writes: Arc<WriteCoalescer<PendingBaseline>>,
+    // Arranque del proceso (uptime en /health)
+    started_at: std::time::Instant,
+    // Fichero JSONL del histórico de scores (None = /history desactivado)
+    score_history_path: Option<String>,
+}
+
+// Parte de la configuración recargable en caliente. Cada petición toma un snapshot
+// al empezar: una recarga a mitad de scoring no mezcla pesos viejos y nuevos
+struct LiveConfig {
+    // Pesos de calculate_anomaly_score
+    weights: ScoringWeights,
+    // Cortes de score para CHALLENGE/BLOCK (ver SecurityConfig::action_thresholds)
+    action_thresholds: ActionThresholds,
+    // Redes de confianza que nunca se puntúan
+    allowlist: IpAllowlist,
+}
+
+impl LiveConfig {
+    fn from_config(config: &SecurityConfig) -> Self {
+        Self {
+            weights: config.scoring_weights.clone(),
+            action_thresholds: config.action_thresholds.unwrap_or_default(),
+            allowlist: IpAllowlist::new(config.allowlist.clone()),
+        }
+    }
+}
+
+impl AppState {
+    fn live_config(&self) -> Arc<LiveConfig> {
+        self.live_config.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+// Como mucho una línea de log por cliente (y tipo de evento) cada LOG_THROTTLE_SECS
+const LOG_THROTTLE_SECS: u64 = 10;
+
+// Ventana del rate limiting: rate_limit_threshold peticiones por minuto
+const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+const DEFAULT_RATE_LIMIT: f64 = 100.0;
+// Claves (tenant:usuario) seguidas a la vez por el rate limiter
+const MAX_RATE_LIMIT_KEYS: usize = 200_000;
+
+// Capacidad del canal de streaming: un dashboard más lento que esto pierde mensajes
+const STREAM_CHANNEL_CAPACITY: usize = 1024;
+// Score mínimo para publicar una detección en el stream (riesgo "medium")
+const DEFAULT_STREAM_MIN_SCORE: f32 = 0.5;
+
+// Namespace de la blocklist en el ProfileStore
+const BLOCKLIST_NAMESPACE: &str = "blocklist";
+// Namespace de los horarios laborales por tenant (clave tenant_id)
+const WORK_HOURS_NAMESPACE: &str = "work_hours";
+// Namespace del primer evento de cada tenant (rampa observar → aplicar)
+const TENANT_FIRST_SEEN_NAMESPACE: &str = "tenant_first_seen";
+// Namespace de los baselines aprendidos (clave "tenant_id:user_id")
+const BASELINE_NAMESPACE: &str = "baselines";
+
+// Dirección de escucha si no se define ANOMALY_BIND_ADDR
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3001";
+
+// Cada cuánto se comprueba si el fichero GeoIP cambió
+const GEOIP_RELOAD_SECS: u64 = 60;
+
+// /health: espera máxima al store y ocupación de perfiles que se reporta como "degraded"
+const HEALTH_STORE_TIMEOUT_SECS: u64 = 2;
+const HEALTH_PROFILES_WARN_RATIO: f64 = 0.9;
+
+// Tiempo máximo para persistir perfiles al apagar el servicio
+const SHUTDOWN_FLUSH_SECS: u64 = 5;
+
+// Ventana de resultados de login: últimos 20 intentos en 15 minutos,
+// con al menos 5 para que la tasa de fallo sea significativa
+const LOGIN_OUTCOME_SAMPLES: usize = 20;
+const LOGIN_OUTCOME_MIN_SAMPLES: usize = 5;
+const LOGIN_OUTCOME_WINDOW_SECS: u64 = 15 * 60;
+
+// Credential spray: usuarios distintos por IP y tenant antes de marcar la IP
+const SPRAY_MAX_USERS: usize = 10;
+const SPRAY_WINDOW_SECS: u64 = 10 * 60;
+
+// Sesiones seguidas: caducan pronto (una sesión vive minutos) y el mapa está acotado
+const SESSION_TTL_SECS: u64 = 15 * 60;
+const MAX_TRACKED_SESSIONS: usize = 200_000;
+
+// Tenants con tendencias por hora (un ring de TREND_RING_HOURS cada uno)
+const MAX_TREND_TENANTS: usize = 10_000;
+
+// Ventana y tamaño de la caché de idempotencia por event_id
+const EVENT_DEDUP_TTL_SECS: u64 = 5 * 60;
+const EVENT_DEDUP_CAPACITY: usize = 100_000;
+
+// Como máximo una escritura de baseline por clave en esta ventana; el resto se agrupa.
+// Un lote con tantas observaciones distintas se aplica sin esperar a la ventana
+const BASELINE_COALESCE_MS: u64 = 100;
+const BASELINE_COALESCE_MAX: usize = 64;
+
+// Topes de memoria por baseline: países aprendidos y user-agents recordados
+const MAX_TYPICAL_COUNTRIES: usize = 5;
+const MAX_KNOWN_USER_AGENTS: usize = 10;
+
+// Frecuencia de horas: decaimiento por observación, mínimo para ser "habitual"
+// y valor por debajo del cual la hora se olvida
+const HOUR_DECAY: f64 = 0.97;
+const HOUR_TYPICAL_MIN: f64 = 0.5;
+const HOUR_PRUNE: f64 = 0.05;
+
+// Paginación de /api/v1/stats
+const DEFAULT_STATS_PAGE: usize = 100;
+const MAX_STATS_PAGE: usize = 1000;
+
+// Tamaño máximo de un body JSON (un lote de MAX_BATCH_SIZE eventos cabe con holgura)
+const JSON_BODY_LIMIT: usize = 1024 * 1024;
+
+// Versión del motor en cada respuesta (la del ruleset se calcula en caliente)
+const ENGINE_VERSION: &str = concat!("anomaly-detector/", env!("CARGO_PKG_VERSION"));
+
+// Endpoints distintos que recuerda cada baseline (además de la ventana por antigüedad)
+const MAX_ENDPOINT_HISTORY: usize = 50;
+
+// Máximo de eventos por petición en /detect/batch
+const MAX_BATCH_SIZE: usize = 500;
+
+// Máximo de eventos de muestra en /whatif (cada uno se puntúa dos veces)
+const MAX_WHATIF_EVENTS: usize = 5_000;
+
+// /baseline/import: logs históricos en bloque, con su propio límite de body
+const MAX_IMPORT_SIZE: usize = 20_000;
+const IMPORT_BODY_LIMIT: usize = 8 * 1024 * 1024;
+
+// location_risk puenteado para UNUSUAL_LOCATION con el peso completo: por encima del
+// umbral de la firma (0.8). Un país vecino lo escala con su factor y queda por debajo
+const BRIDGED_LOCATION_RISK: f64 = 0.9;
+
+// /export y /import (NDJSON en streaming): sin límite de body, pero sí por línea
+const MAX_SNAPSHOT_LINE: usize = 1024 * 1024;
+// Errores de línea que se devuelven en detalle (el resto solo se cuentan)
+const MAX_SNAPSHOT_ERRORS: usize = 20;
+
+#[derive(Default)]
+struct Metrics {
+    // Límites superiores de los buckets del histograma (cortes medium/high/critical).
+    // Se sustituyen al recargar los cortes (ver set_score_bounds)
+    score_bounds: std::sync::RwLock<[f32; 3]>,
+    detections_total: AtomicU64,
+    action_allow: AtomicU64,
+    action_challenge: AtomicU64,
+    action_block: AtomicU64,
+    // Conteos no acumulados por bucket (el último es +Inf)
+    score_buckets: [AtomicU64; 4],
+    // Suma de scores en milésimas para no necesitar un AtomicF64
+    score_sum_milli: AtomicU64,
+    // Mensajes del stream descartados por consumidores lentos
+    stream_dropped: AtomicU64,
+    // Decisiones fail-open por agotar el presupuesto de scoring
+    degraded_total: AtomicU64,
+    // Decisiones que shadow mode habría aplicado
+    shadow_allow: AtomicU64,
+    shadow_challenge: AtomicU64,
+    shadow_block: AtomicU64,
+}
+
+impl Metrics {
+    fn new(thresholds: &RiskThresholds) -> Self {
+        Self {
+            score_bounds: std::sync::RwLock::new(Self::bounds(thresholds)),
+            ..Self::default()
+        }
+    }
+
+    fn bounds(thresholds: &RiskThresholds) -> [f32; 3] {
+        [thresholds.medium as f32, thresholds.high as f32, thresholds.critical as f32]
+    }
+
+    // Con otros cortes los conteos anteriores dejan de ser comparables: el histograma
+    // vuelve a cero (para Prometheus, un reinicio del contador)
+    fn set_score_bounds(&self, thresholds: &RiskThresholds) {
+        let mut bounds = self.score_bounds.write().unwrap_or_else(|e| e.into_inner());
+        let updated = Self::bounds(thresholds);
+        if *bounds == updated {
+            return;
+        }
+        *bounds = updated;
+        for bucket in &self.score_buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.score_sum_milli.store(0, Ordering::Relaxed);
+    }
+
+    fn record(&self, score: f32, action: Action) {
+        self.detections_total.fetch_add(1, Ordering::Relaxed);
+        match action {
+            Action::Block => &self.action_block,
+            Action::Challenge => &self.action_challenge,
+            Action::Allow => &self.action_allow,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+
+        // Lectura tomada durante el registro: un cambio de cortes no se intercala a medias
+        let bounds = self.score_bounds.read().unwrap_or_else(|e| e.into_inner());
+        let idx = bounds.iter().position(|&le| score <= le).unwrap_or(bounds.len());
+        self.score_buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.score_sum_milli.fetch_add((score.max(0.0) * 1000.0) as u64, Ordering::Relaxed);
+    }
+
+    fn record_shadow(&self, action: Action) {
+        match action {
+            Action::Block => &self.shadow_block,
+            Action::Challenge => &self.shadow_challenge,
+            Action::Allow => &self.shadow_allow,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Serializa en formato de texto de Prometheus (exposition format 0.0.4).
+    fn render(&self, active_baselines: usize, events_processed: u64, profile_evictions: u64) -> String {
+        let mut out = String::new();
+        let total = self.detections_total.load(Ordering::Relaxed);
+
+        out.push_str("# HELP anomaly_detections_total Total de peticiones evaluadas por /detect.\n");
+        out.push_str("# TYPE anomaly_detections_total counter\n");
+        out.push_str(&format!("anomaly_detections_total {}\n", total));
+
+        out.push_str("# HELP anomaly_events_processed_total Eventos procesados (todas las evaluaciones en vivo).\n");
+        out.push_str("# TYPE anomaly_events_processed_total counter\n");
+        out.push_str(&format!("anomaly_events_processed_total {}\n", events_processed));
+
+        out.push_str("# HELP anomaly_active_baselines Baselines de usuario en memoria.\n");
+        out.push_str("# TYPE anomaly_active_baselines gauge\n");
+        out.push_str(&format!("anomaly_active_baselines {}\n", active_baselines));
+
+        out.push_str("# HELP anomaly_profile_evictions_total Perfiles expulsados por capacidad (LRU).\n");
+        out.push_str("# TYPE anomaly_profile_evictions_total counter\n");
+        out.push_str(&format!("anomaly_profile_evictions_total {}\n", profile_evictions));
+
+        out.push_str("# HELP anomaly_actions_total Decisiones emitidas por acción.\n");
+        out.push_str("# TYPE anomaly_actions_total counter\n");
+        for (action, counter) in [
+            ("ALLOW", &self.action_allow),
+            ("CHALLENGE", &self.action_challenge),
+            ("BLOCK", &self.action_block),
+        ] {
+            out.push_str(&format!("anomaly_actions_total{{action=\"{}\"}} {}\n", action, counter.load(Ordering::Relaxed)));
+        }
+
+        out.push_str("# HELP anomaly_shadow_actions_total Acciones que shadow mode habría aplicado.\n");
+        out.push_str("# TYPE anomaly_shadow_actions_total counter\n");
+        for (action, counter) in [
+            ("ALLOW", &self.shadow_allow),
+            ("CHALLENGE", &self.shadow_challenge),
+            ("BLOCK", &self.shadow_block),
+        ] {
+            out.push_str(&format!("anomaly_shadow_actions_total{{action=\"{}\"}} {}\n", action, counter.load(Ordering::Relaxed)));
+        }
+
+        out.push_str("# HELP anomaly_stream_dropped_total Mensajes de /api/v1/stream descartados por consumidores lentos.\n");
+        out.push_str("# TYPE anomaly_stream_dropped_total counter\n");
+        out.push_str(&format!("anomaly_stream_dropped_total {}\n", self.stream_dropped.load(Ordering::Relaxed)));
+
+        out.push_str("# HELP anomaly_degraded_decisions_total Decisiones ALLOW por agotar el presupuesto de scoring.\n");
+        out.push_str("# TYPE anomaly_degraded_decisions_total counter\n");
+        out.push_str(&format!("anomaly_degraded_decisions_total {}\n", self.degraded_total.load(Ordering::Relaxed)));
+
+        out.push_str("# HELP anomaly_score Distribución de anomaly_score por umbral de riesgo.\n");
+        out.push_str("# TYPE anomaly_score histogram\n");
+        let bounds = *self.score_bounds.read().unwrap_or_else(|e| e.into_inner());
+        let mut cumulative = 0;
+        for (i, le) in bounds.iter().enumerate() {
+            cumulative += self.score_buckets[i].load(Ordering::Relaxed);
+            out.push_str(&format!("anomaly_score_bucket{{le=\"{}\"}} {}\n", le, cumulative));
+        }
+        cumulative += self.score_buckets[bounds.len()].load(Ordering::Relaxed);
+        out.push_str(&format!("anomaly_score_bucket{{le=\"+Inf\"}} {}\n", cumulative));
+        out.push_str(&format!(
+            "anomaly_score_sum {}\n",
+            self.score_sum_milli.load(Ordering::Relaxed) as f64 / 1000.0
+        ));
+        out.push_str(&format!("anomaly_score_count {}\n", cumulative));
+
+        out
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct UserBaseline {
-    user_id: i32,
+    // Uno de los dos: el seudónimo del tenant (hashed_id) sustituye al numérico
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hashed_id: Option<String>,
     tenant_id: String,
-    typical_countries: Vec<String>,
-    typical_hours: Vec<u32>,
-    known_user_agents: Vec<String>,
-    endpoints_history: Vec<String>, // Renombrado para claridad
+    // Conjunto acotado a MAX_TYPICAL_COUNTRIES (los primeros aprendidos)
+    typical_countries: BTreeSet<String>,
+    // Frecuencia decaída por hora local: las horas que dejan de usarse se desvanecen
+    #[serde(deserialize_with = "deserialize_hours")]
+    typical_hours: HashMap<u32, f64>,
+    // Zona IANA del usuario ("Europe/Madrid"); None = la del tenant o UTC
+    #[serde(default)]
+    timezone: Option<String>,
+    // Últimos MAX_KNOWN_USER_AGENTS distintos, el usado más recientemente al final
+    known_user_agents: VecDeque<String>,
+    // Endpoints usados y cuándo por última vez (el más reciente al final);
+    // se olvidan al salir de la ventana de ScoringWeights::endpoint_history_days
+    #[serde(deserialize_with = "deserialize_endpoints")]
+    endpoints_history: VecDeque<(DateTime<Utc>, String)>,
     last_updated: DateTime<Utc>,
+    // Último login observado (para detectar viaje imposible)
+    #[serde(default)]
+    last_country: Option<String>,
+    #[serde(default)]
+    last_login_at: Option<DateTime<Utc>>,
+    // Eventos aprendidos: mide la madurez del baseline (confidence)
+    #[serde(default)]
+    observations: u64,
+    // Endpoints nuevos (distintos) vistos recientemente: detecta escaneos en ráfaga
+    #[serde(default)]
+    recent_new_endpoints: VecDeque<(DateTime<Utc>, String)>,
+}
+
+// Observación de /baseline ya resuelta (país por GeoIP, UA normalizado)
+struct BaselineObservation {
+    at: DateTime<Utc>,
+    // Hora local de `at` en la zona del usuario
+    hour: u32,
+    country: String,
+    user_agent: String,
+    endpoint: String,
+    // Repeticiones idénticas agrupadas en la misma hora
+    count: u32,
+}
+
+impl BaselineObservation {
+    fn same_as(&self, other: &Self) -> bool {
+        self.hour == other.hour
+            && self.country == other.country
+            && self.user_agent == other.user_agent
+            && self.endpoint == other.endpoint
+    }
+}
+
+// Observaciones pendientes de una clave. Las repetidas se deduplican (sumando
+// count) pero ningún país ni UA observado se pierde
+struct PendingBaseline {
+    user_id: Option<i32>,
+    hashed_id: Option<String>,
+    tenant_id: String,
+    // Zona enviada explícitamente (la más reciente gana)
+    timezone: Option<String>,
+    // En orden de llegada: la última define last_country/last_login_at
+    observations: Vec<BaselineObservation>,
+}
+
+impl Coalesce for PendingBaseline {
+    fn absorb(&mut self, other: Self) {
+        if other.timezone.is_some() {
+            self.timezone = other.timezone;
+        }
+        for obs in other.observations {
+            match self.observations.iter().position(|o| o.same_as(&obs)) {
+                Some(idx) => {
+                    let mut existing = self.observations.remove(idx);
+                    existing.count += obs.count;
+                    existing.at = existing.at.max(obs.at);
+                    self.observations.push(existing);
+                }
+                None => self.observations.push(obs),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.observations.len()
+    }
 }
 
+// Longitudes máximas por campo: los textos acaban en baselines y perfiles,
+// así que un valor enorme se multiplicaría en memoria (se rechaza con 400)
+const MAX_TENANT_ID_LEN: usize = 128;
+const MAX_IP_LEN: usize = 64;
+const MAX_USER_AGENT_LEN: usize = 512;
+const MAX_ENDPOINT_LEN: usize = 2048;
+const MAX_ID_LEN: usize = 256;
+const MAX_TIMEZONE_LEN: usize = 64;
+const MAX_METADATA_ENTRIES: usize = 32;
+
 #[derive(Deserialize, Serialize, Debug)]
 struct AnomalyRequest {
-    user_id: i32,
+    // Usuario numérico o, en tenants que no comparten identificadores reales, un
+    // seudónimo opaco (hashed_id) que lo sustituye como clave (ver subject)
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_ID_LEN>")]
+    hashed_id: Option<String>,
+    #[serde(deserialize_with = "required::<_, MAX_TENANT_ID_LEN>")]
     tenant_id: String,
+    #[serde(deserialize_with = "required::<_, MAX_IP_LEN>")]
     ip_address: String,
+    #[serde(deserialize_with = "required::<_, MAX_USER_AGENT_LEN>")]
     user_agent: String,
+    #[serde(deserialize_with = "required::<_, MAX_ENDPOINT_LEN>")]
     endpoint: String,
+    // Resultado del login (opcional): alimenta la detección de fuerza bruta
+    #[serde(default)]
+    login_success: Option<bool>,
+    // Identificador idempotente: un reintento devuelve la respuesta cacheada
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_ID_LEN>")]
+    event_id: Option<String>,
+    // Huella estable del dispositivo (distinta del user-agent): detecta DeviceChange
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_ID_LEN>")]
+    device_id: Option<String>,
+    // Identificador de la sesión (cookie/token): la misma sesión desde otra red es robo
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_ID_LEN>")]
+    session_id: Option<String>,
+    // Señales propias del tenant (p.ej. "auth_method"): las evalúan sus MetadataRule
+    #[serde(default, deserialize_with = "bounded_metadata")]
+    metadata: HashMap<String, String>,
+    // Zona IANA del usuario (solo /baseline): las horas típicas se aprenden en hora local
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_TIMEZONE_LEN>")]
+    timezone: Option<String>,
+}
+
+impl AnomalyRequest {
+    // Clave del usuario: el seudónimo si viene, si no el numérico (validado por check_subject)
+    fn subject(&self) -> String {
+        subject_id(self.user_id, self.hashed_id.as_deref()).unwrap_or_default()
+    }
+
+    fn check_subject(&self) -> Result<(), DetectorError> {
+        match subject_id(self.user_id, self.hashed_id.as_deref()) {
+            Some(subject) if !subject.trim().is_empty() => Ok(()),
+            _ => Err(DetectorError::InvalidEvent("user_id or a non-empty hashed_id is required".to_string())),
+        }
+    }
+}
+
+// Evento histórico para /baseline/import: el mismo payload de /detect más su marca de tiempo
+#[derive(Deserialize)]
+struct ImportEvent {
+    #[serde(flatten)]
+    request: AnomalyRequest,
+    timestamp: DateTime<Utc>,
 }
 
 #[derive(Deserialize)]
 struct ResetRequest {
-    user_id: i32,
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    hashed_id: Option<String>,
     tenant_id: String,
 }
 
-#[derive(Serialize)]
-struct AnomalyResponse {
-    anomaly_score: f32,
-    anomalies: Vec<String>,
-    risk_level: String,
-    action: String, // ALLOW, CHALLENGE, BLOCK
+#[derive(Deserialize)]
+struct TenantResetRequest {
+    tenant_id: String,
 }
 
-// ==========================================
-// CONFIGURACIÓN Y MAIN
-// ==========================================
-
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    dotenv().ok();
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+#[derive(Deserialize)]
+struct UnblockRequest {
+    tenant_id: String,
+    client_id: String,
+}
 
-    let api_key = std::env::var("ANOMALY_API_KEY").unwrap_or_else(|_| "change_me_in_production".to_string());
-    
-    let app_state = AppState {
-        baselines: Arc::new(DashMap::new()),
-        api_key,
-    };
+#[derive(Deserialize)]
+struct ProfileQuery {
+    tenant_id: String,
+    client_id: String,
+}
 
-    info!("🚀 Anomaly Detection Service started on port 3001");
-    info!("🔒 Concurrency mode: DashMap (Lock-free reading)");
+#[derive(Deserialize)]
+struct BaselineQuery {
+    tenant_id: String,
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    hashed_id: Option<String>,
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(app_state.clone()))
-            .wrap(middleware::Logger::default())
-            // Middleware de seguridad simple
-            .wrap(middleware::NormalizePath::trim())
-            .route("/health", web::get().to(health))
-            .service(
-                web::scope("/api/v1")
-                    .route("/detect", web::post().to(detect_anomaly))
-                    .route("/baseline", web::post().to(update_baseline))
-                    .route("/reset", web::post().to(reset_baseline))
-            )
-    })
-    .bind("0.0.0.0:3001")?
-    .run()
-    .await
+#[derive(Deserialize)]
+struct TrendsQuery {
+    tenant_id: String,
+    #[serde(default = "default_trend_hours")]
+    hours: usize,
 }
 
-async fn health() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({ "status": "healthy", "engine": "rust-dashmap" }))
+fn default_trend_hours() -> usize {
+    24
 }
 
-// Helper para validar API Key
-fn is_authorized(req: &HttpRequest, state: &web::Data<AppState>) -> bool {
-    match req.headers().get("X-API-KEY") {
-        Some(key) => key.to_str().unwrap_or("") == state.api_key,
-        None => false,
-    }
+#[derive(Deserialize)]
+struct StatsQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
 }
 
-// ==========================================
-// HANDLERS
-// ==========================================
+// Rampa observar → aplicar de un tenant (en /api/v1/stats)
+#[derive(Debug, Clone, Serialize)]
+struct TenantRamp {
+    first_observed: DateTime<Utc>,
+    enforce_at: DateTime<Utc>,
+    // Hasta enforce_at la acción devuelta es ALLOW y la real viaja en shadow_action
+    observing: bool,
+}
 
-async fn detect_anomaly(
-    req: HttpRequest,
-    state: web::Data<AppState>,
-    body: web::Json<AnomalyRequest>,
-) -> HttpResponse {
-    if !is_authorized(&req, &state) {
-        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+impl TenantRamp {
+    fn new(first_observed: DateTime<Utc>, ramp: chrono::Duration, now: DateTime<Utc>) -> Self {
+        let enforce_at = first_observed + ramp;
+        Self { first_observed, enforce_at, observing: now < enforce_at }
     }
+}
 
-    // Generar clave compuesta para aislamiento Multi-Tenant estricto
-    let key = format!("{}:{}", body.tenant_id, body.user_id);
+#[derive(Deserialize)]
+struct CompromisedQuery {
+    tenant_id: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
 
-    // DashMap permite obtener una referencia de lectura sin bloquear todo el mapa
-    let baseline_ref = state.baselines.get(&key);
+// Una línea del snapshot de /export (NDJSON): {"kind":"profile","profile":{...}}
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum SnapshotRecord {
+    Profile { profile: ClientProfile },
+    Baseline { baseline: UserBaseline },
+}
 
-    let (score, anomalies) = match baseline_ref {
-        Some(entry) => calculate_anomaly_score(&body, entry.value()),
-        None => (0.0, vec!["New user profile created".to_string()]), // Cold start
-    };
+#[derive(Deserialize)]
+struct HistoryQuery {
+    tenant_id: String,
+    // Nivel mínimo: "Critical" devuelve solo detecciones críticas
+    level: Option<ThreatLevel>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
 
-    let risk_level = determine_risk_level(score);
-    let action = match risk_level.as_str() {
-        "critical" => "BLOCK",
-        "high" => "CHALLENGE",
-        _ => "ALLOW",
-    };
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum BlockKind {
+    Ip,
+    Client, // "tenant_id:user_id"
+}
 
-    if score > 0.0 {
-        info!("⚠️ Anomaly detected [Tenant: {} User: {}]: Score: {}, Risk: {}", body.tenant_id, body.user_id, score, risk_level);
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BlocklistEntry {
+    kind: BlockKind,
+    value: String,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+}
 
-    HttpResponse::Ok().json(AnomalyResponse {
-        anomaly_score: score,
-        anomalies,
-        risk_level,
-        action: action.to_string(),
-    })
+#[derive(Deserialize)]
+struct VipRequest {
+    #[serde(deserialize_with = "required::<_, MAX_ID_LEN>")]
+    client_id: String,
+    #[serde(default, deserialize_with = "bounded_opt::<_, MAX_ID_LEN>")]
+    reason: Option<String>,
 }
 
-async fn update_baseline(
-    req: HttpRequest,
-    state: web::Data<AppState>,
-    body: web::Json<AnomalyRequest>,
-) -> HttpResponse {
-    if !is_authorized(&req, &state) {
-        return HttpResponse::Unauthorized().finish();
-    }
+#[derive(Deserialize)]
+struct BlocklistRequest {
+    kind: BlockKind,
+    value: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
 
-    let key = format!("{}:{}", body.tenant_id, body.user_id);
-    let now = Utc::now();
-    let country = extract_country(&body.ip_address);
-    let hour = now.hour();
+// Body de /whatif: cada campo presente sustituye al vigente; scoring_weights puede ser
+// parcial ({"behavioral": 4.0}) y se aplica sobre los pesos actuales
+#[derive(Deserialize)]
+struct WhatIfRequest {
+    #[serde(default)]
+    scoring_weights: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    action_thresholds: Option<ActionThresholds>,
+    #[serde(default)]
+    risk_thresholds: Option<RiskThresholds>,
+    #[serde(default)]
+    sensitivity: Option<f64>,
+    events: Vec<AnomalyRequest>,
+}
 
-    // DashMap: Operación atómica de escritura/actualización
-    state.baselines.entry(key).and_modify(|b| {
-        // Actualizar datos existentes con límites de memoria
-        if !b.typical_countries.contains(&country) {
-            if b.typical_countries.len() < 5 { b.typical_countries.push(country.clone()); }
-        }
-        if !b.typical_hours.contains(&hour) {
-            b.typical_hours.push(hour);
-        }
-        if !b.known_user_agents.contains(&body.user_agent) {
-            // Límite anti-DoS: Solo guardar últimos 10 UAs
-            if b.known_user_agents.len() >= 10 { b.known_user_agents.remove(0); }
-            b.known_user_agents.push(body.user_agent.clone());
-        }
-        
-        // Sliding window para endpoints (Límite 50)
-        b.endpoints_history.push(body.endpoint.clone());
-        if b.endpoints_history.len() > 50 {
-            b.endpoints_history.remove(0);
-        }
-        
-        b.last_updated = now;
-    }).or_insert(UserBaseline {
-        user_id: body.user_id,
-        tenant_id: body.tenant_id.clone(),
-        typical_countries: vec![country],
-        typical_hours: vec![hour],
-        known_user_agents: vec![body.user_agent.clone()],
-        endpoints_history: vec![body.endpoint.clone()],
-        last_updated: now,
-    });
+impl WhatIfRequest {
+    // Configuración candidata validada con las mismas reglas que la de arranque
+    fn candidate(&self, current: &ScoringSetup) -> Result<ScoringSetup, String> {
+        let weights = match &self.scoring_weights {
+            None => current.live.weights.clone(),
+            Some(overrides) => {
+                let mut merged = serde_json::to_value(&current.live.weights).map_err(|e| e.to_string())?;
+                if let Some(fields) = merged.as_object_mut() {
+                    fields.extend(overrides.clone());
+                }
+                let weights: ScoringWeights =
+                    serde_json::from_value(merged).map_err(|e| format!("scoring_weights: {}", e))?;
+                weights.validate()?;
+                weights
+            }
+        };
+        let action_thresholds = self.action_thresholds.unwrap_or(current.live.action_thresholds);
+        if !action_thresholds.is_valid() {
+            return Err(format!("action_thresholds must satisfy 0 < challenge <= block <= 1 (got {:?})", action_thresholds));
+        }
+        let risk_thresholds = self.risk_thresholds.unwrap_or(current.risk_thresholds);
+        if !risk_thresholds.is_valid() {
+            return Err(format!("risk_thresholds must be strictly increasing within (0, 1] (got {:?})", risk_thresholds));
+        }
+        let sensitivity = self.sensitivity.unwrap_or(current.sensitivity);
+        if !(0.0..=1.0).contains(&sensitivity) {
+            return Err(format!("sensitivity must be within 0–1 (got {})", sensitivity));
+        }
+        Ok(ScoringSetup {
+            live: Arc::new(LiveConfig {
+                weights,
+                action_thresholds,
+                allowlist: current.live.allowlist.clone(),
+            }),
+            risk_thresholds,
+            sensitivity,
+        })
+    }
+}
 
-    HttpResponse::Ok().json(serde_json::json!({ "status": "updated" }))
+// Distribución de acciones de /whatif con la configuración vigente y la candidata
+#[derive(Serialize, Default)]
+struct WhatIfReport {
+    evaluated: usize,
+    failed: usize,
+    current: BTreeMap<&'static str, usize>,
+    candidate: BTreeMap<&'static str, usize>,
+    // Decisiones distintas, por transición ("ALLOW->BLOCK")
+    changed: usize,
+    transitions: BTreeMap<String, usize>,
+    // Fracción de eventos evaluados (candidata - vigente): 0.04 = 4 puntos más de BLOCK
+    block_rate_delta: f64,
+    challenge_rate_delta: f64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
 }
 
-async fn reset_baseline(
-    req: HttpRequest,
-    state: web::Data<AppState>,
-    body: web::Json<ResetRequest>, // Uso de Struct tipado en lugar de JSON genérico
-) -> HttpResponse {
-    if !is_authorized(&req, &state) {
-        return HttpResponse::Unauthorized().finish();
+impl WhatIfReport {
+    fn record(&mut self, before: Action, after: Action) {
+        self.evaluated += 1;
+        *self.current.entry(before.as_str()).or_default() += 1;
+        *self.candidate.entry(after.as_str()).or_default() += 1;
+        if before != after {
+            self.changed += 1;
+            *self.transitions.entry(format!("{}->{}", before, after)).or_default() += 1;
+        }
     }
 
-    let key = format!("{}:{}", body.tenant_id, body.user_id);
-    
-    // Eliminación atómica
-    if state.baselines.remove(&key).is_some() {
-        info!("Baseline reset for user {}", key);
-        HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
-    } else {
-        HttpResponse::NotFound().json(serde_json::json!({ "error": "Profile not found" }))
+    fn reject(&mut self, err: DetectorError) {
+        self.failed += 1;
+        if self.errors.len() < MAX_SNAPSHOT_ERRORS {
+            self.errors.push(err.to_string());
+        }
+    }
+
+    fn finish(&mut self) {
+        // Las tres acciones siempre presentes: el dashboard no tiene que suponer ceros
+        for action in [Action::Allow, Action::Challenge, Action::Block] {
+            self.current.entry(action.as_str()).or_default();
+            self.candidate.entry(action.as_str()).or_default();
+        }
+        if self.evaluated > 0 {
+            let rate = |counts: &BTreeMap<&'static str, usize>, action: Action| {
+                counts[action.as_str()] as f64 / self.evaluated as f64
+            };
+            self.block_rate_delta = rate(&self.candidate, Action::Block) - rate(&self.current, Action::Block);
+            self.challenge_rate_delta = rate(&self.candidate, Action::Challenge) - rate(&self.current, Action::Challenge);
+        }
     }
 }
 
-// ==========================================
-// LOGICA DE NEGOCIO Y CALCULOS
-// ==========================================
+// Resultado por elemento de /detect/batch (mismo orden que la entrada)
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Scored(AnomalyResponse),
+    Failed { error: String },
+}
 
-fn calculate_anomaly_score(req: &AnomalyRequest, baseline: &UserBaseline) -> (f32, Vec<String>) {
-    let mut score: f32 = 0.0;
-    let mut anomalies = Vec::new();
+// Registro de auditoría: una línea JSON por decisión
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: DateTime<Utc>,
+    tenant_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hashed_id: Option<&'a str>,
+    ip_address: &'a str,
+    action: Action,
+    risk_level: &'a str,
+    anomaly_score: f32,
+    anomalies: &'a [AnomalyReason],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow_action: Option<Action>,
+    ruleset_version: &'a str,
+}
 
-    // 1. Geo Check
-    let current_country = extract_country(&req.ip_address);
-    if !baseline.typical_countries.contains(&current_country) {
-        score += 3.0;
-        anomalies.push(format!("Unusual Location: {}", current_country));
+// Motivo de una detección: código estable para el frontend/gateway, peso aportado
+// al score aditivo (0 = informativo) y detalle opcional
+#[derive(Clone, Debug, Serialize)]
+struct AnomalyReason {
+    code: &'static str,
+    severity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    // Texto histórico en inglés (ver to_strings)
+    #[serde(skip)]
+    label: &'static str,
+}
+
+impl AnomalyReason {
+    fn new(code: &'static str, label: &'static str, severity: f32) -> Self {
+        Self { code, severity, detail: None, label }
     }
 
-    // 2. Time Check
-    let current_hour = Utc::now().hour();
-    if !baseline.typical_hours.contains(&current_hour) {
-        score += 1.5; // Bajamos peso, puede ser trabajo nocturno
-        anomalies.push("Unusual Time".to_string());
+    fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
     }
 
-    // 3. User Agent Check
-    if !baseline.known_user_agents.contains(&req.user_agent) {
-        score += 2.0;
-        anomalies.push("New Device/Browser".to_string());
+    fn pattern(pattern: &BehaviorPattern, severity: f32) -> Self {
+        Self::new(pattern.code(), pattern.human_reason(), severity)
     }
 
-    // 4. Endpoint Enumeration (Heurística simple)
-    // Si el usuario está tocando muchos endpoints nuevos rápidamente
-    if !baseline.endpoints_history.contains(&req.endpoint) {
-        score += 0.5; // Pequeña penalización por exploración normal
+    // Texto plano anterior a los códigos ("Unusual Location: FR")
+    fn to_strings(reasons: &[Self]) -> Vec<String> {
+        reasons
+            .iter()
+            .map(|r| match &r.detail {
+                Some(detail) => format!("{}: {}", r.label, detail),
+                None => r.label.to_string(),
+            })
+            .collect()
     }
+}
 
-    (score, anomalies)
+// Un factor evaluado al puntuar, haya aportado o no (vista de /explain). `observed` y
+// `threshold` solo aparecen en los factores que comparan una medida con un corte
+#[derive(Clone, Debug, Serialize)]
+struct ScoreFactor {
+    factor: &'static str,
+    value: String,
+    contribution: f32,
+    in_baseline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    observed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold: Option<f64>,
 }
 
-fn extract_country(ip: &str) -> String {
-    // Placeholder para integración real con GeoIP (MaxMind)
-    // En producción usarías una crate como `maxminddb`
-    if ip.starts_with("10.") || ip.starts_with("192.") {
-        return "LAN".to_string();
+impl ScoreFactor {
+    fn new(factor: &'static str, value: impl Into<String>, contribution: f32, in_baseline: bool) -> Self {
+        Self { factor, value: value.into(), contribution, in_baseline, observed: None, threshold: None }
+    }
+
+    fn compared(mut self, observed: f64, threshold: f64) -> Self {
+        self.observed = Some(observed);
+        self.threshold = Some(threshold);
+        self
     }
-    "US".to_string() 
 }
 
-fn determine_risk_level(score: f32) -> String {
-    match score {
-        x if x < 2.0 => "low".to_string(),
-        x if x < 4.5 => "medium".to_string(),
-        x if x < 7.0 => "high".to_string(),
-        _ => "critical".to_string(),
+// Resultado de calculate_anomaly_score: score aditivo, motivos y el desglose por factor
+struct ScoreBreakdown {
+    score: f32,
+    anomalies: Vec<AnomalyReason>,
+    factors: Vec<ScoreFactor>,
+}
+
+// Desglose que acompaña a una evaluación sin efectos (nunca a /detect)
+#[derive(Clone)]
+struct Explanation {
+    raw_score: f32,
+    factors: Vec<ScoreFactor>,
+}
+
+#[derive(Clone, Serialize)]
+struct AnomalyResponse {
+    // Binario y configuración de scoring que produjeron la respuesta (auditoría de cambios)
+    engine_version: &'static str,
+    ruleset_version: String,
+    anomaly_score: f32,
+    anomalies: Vec<AnomalyReason>,
+    risk_level: String,
+    action: Action, // ALLOW, CHALLENGE, BLOCK
+    // Solo con CHALLENGE: qué presentar (CAPTCHA, MFA, EMAIL_VERIFICATION...)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recommendation: Option<String>,
+    // Madurez de los datos (0–1): con valores bajos el gateway puede optar por fail-open
+    confidence: f32,
+    // Segundos hasta que se levante un BLOCK temporal (solo viaja como cabecera Retry-After)
+    #[serde(skip)]
+    retry_after_secs: Option<u64>,
+    // Solo en shadow mode: la acción que se habría aplicado
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow_action: Option<Action>,
+    // Solo si se agotó el presupuesto de scoring: ALLOW sin evaluar (fail-open)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    degraded: bool,
+    processing_time_ms: u64,
+    // Solo en simulación: lo consume /explain
+    #[serde(skip)]
+    explanation: Option<Explanation>,
+}
+
+// Respuesta de /explain: la decisión, cada factor con su aporte y los cortes con que se comparó
+#[derive(Serialize)]
+struct ExplainResponse {
+    engine_version: &'static str,
+    ruleset_version: String,
+    anomaly_score: f32,
+    // Suma de aportes antes de normalizar a 0–1 y aplicar la sensibilidad
+    raw_score: f32,
+    sensitivity: f64,
+    risk_level: String,
+    risk_thresholds: RiskThresholds,
+    action: Action,
+    action_thresholds: ActionThresholds,
+    factors: Vec<ScoreFactor>,
+    anomalies: Vec<AnomalyReason>,
+}
+
+// ==========================================
+// CONFIGURACIÓN Y MAIN
+// ==========================================
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    dotenv().ok();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    // OTEL_EXPORTER_OTLP_ENDPOINT=http://collector:4318 exporta los spans (feature "otel")
+    let tracing = telemetry::init_tracing("anomaly-detector").map_err(std::io::Error::other)?;
+    if tracing.exporting() {
+        info!("📡 OTLP trace export enabled");
+    }
+
+    // ANOMALY_API_KEYS="nueva,antigua" durante una rotación; ANOMALY_API_KEY sigue funcionando
+    let api_keys = std::env::var("ANOMALY_API_KEYS")
+        .or_else(|_| std::env::var("ANOMALY_API_KEY"))
+        .unwrap_or_else(|_| "change_me_in_production".to_string());
+    let api_keys = ApiKeySet::parse(&api_keys);
+    if api_keys.is_empty() {
+        return Err(std::io::Error::other("ANOMALY_API_KEYS does not contain any key"));
+    }
+    info!("🔑 {} API key(s) accepted", api_keys.len());
+    
+    // Base: fichero JSON de ANOMALY_CONFIG_PATH (o defaults); cada variable de entorno
+    // definida tiene prioridad sobre el valor del fichero
+    let base = SecurityConfig::load().map_err(std::io::Error::other)?;
+    if let Ok(path) = std::env::var("ANOMALY_CONFIG_PATH") {
+        info!("⚙️ Security config loaded from {}", path);
+    }
+
+    let security_config = resolve_config(base)?;
+    let cold_start_action = security_config.cold_start_action;
+    let trust_proxy = security_config.trust_proxy;
+    if trust_proxy > 0 {
+        info!("🔀 Client IP taken from X-Forwarded-For/Forwarded ({} trusted proxies)", trust_proxy);
     }
-}
\ No newline at end of file
+    let ip_salts: HashMap<String, hmac::Key> = security_config
+        .tenant_ip_salts
+        .iter()
+        .map(|(tenant, salt)| (tenant.clone(), hmac::Key::new(hmac::HMAC_SHA256, salt.as_bytes())))
+        .collect();
+    if !ip_salts.is_empty() {
+        info!("🕶️ IP addresses pseudonymized for {} tenant(s): GeoIP checks disabled for them", ip_salts.len());
+    }
+    if cold_start_action == ColdStartAction::Challenge {
+        info!("🧊 Cold start: requests for users without a baseline are challenged");
+    }
+    let live_config = LiveConfig::from_config(&security_config);
+    let challenge_types = Arc::new(security_config.challenge_types.clone());
+    let score_history_path = security_config.score_history_path.clone();
+    let shadow_mode = security_config.shadow_mode;
+    if shadow_mode {
+        warn!("👻 Shadow mode enabled: decisions are logged but never enforced");
+    }
+    let scoring_timeout = (security_config.scoring_timeout_ms > 0)
+        .then(|| std::time::Duration::from_millis(security_config.scoring_timeout_ms));
+    let tenant_ramp = (security_config.tenant_ramp_hours > 0.0)
+        .then(|| chrono::Duration::seconds((security_config.tenant_ramp_hours * 3600.0) as i64));
+    if tenant_ramp.is_some() {
+        info!("🐣 Tenant ramp: new tenants are observed for {}h before enforcing", security_config.tenant_ramp_hours);
+    }
+    if !live_config.allowlist.is_empty() {
+        info!("✅ Allowlist loaded: {} trusted networks", live_config.allowlist.len());
+    }
+    let detector = anomaly_detector::initialize(Some(security_config))
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    // Limpieza periódica de ventanas inactivas para acotar el número de claves
+    let rate_limiter = Arc::new(SlidingWindowLimiter::new(MAX_RATE_LIMIT_KEYS, std::time::Duration::from_secs(RATE_LIMIT_WINDOW_SECS)));
+    let login_outcomes = Arc::new(LoginOutcomeTracker::new(
+        LOGIN_OUTCOME_SAMPLES,
+        LOGIN_OUTCOME_MIN_SAMPLES,
+        std::time::Duration::from_secs(LOGIN_OUTCOME_WINDOW_SECS),
+    ));
+    let responses = Arc::new(ResponseCache::new(
+        std::time::Duration::from_secs(EVENT_DEDUP_TTL_SECS),
+        EVENT_DEDUP_CAPACITY,
+    ));
+    let spray = Arc::new(SprayTracker::new(SPRAY_MAX_USERS, std::time::Duration::from_secs(SPRAY_WINDOW_SECS)));
+    let sessions = Arc::new(SessionTracker::new(MAX_TRACKED_SESSIONS, std::time::Duration::from_secs(SESSION_TTL_SECS)));
+    let log_throttle = Arc::new(LogThrottle::new(std::time::Duration::from_secs(LOG_THROTTLE_SECS)));
+    let trends = Arc::new(TrendCounter::new(MAX_TREND_TENANTS));
+    let (limiter, outcomes, sprays, tracked_sessions, cached, throttle, trend_rings) = (
+        rate_limiter.clone(),
+        login_outcomes.clone(),
+        spray.clone(),
+        sessions.clone(),
+        responses.clone(),
+        log_throttle.clone(),
+        trends.clone(),
+    );
+    actix_web::rt::spawn(async move {
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(RATE_LIMIT_WINDOW_SECS));
+        loop {
+            tick.tick().await;
+            limiter.purge_idle();
+            outcomes.purge_idle();
+            sprays.purge_idle();
+            tracked_sessions.purge_idle();
+            cached.purge_expired();
+            throttle.purge_idle();
+            trend_rings.purge_idle(Utc::now());
+        }
+    });
+
+    // Auditoría: ANOMALY_AUDIT_LOG=/ruta/audit.jsonl, o stdout si no está definida
+    let audit_path = std::env::var("ANOMALY_AUDIT_LOG").ok();
+    let audit = Arc::new(AuditLog::open(audit_path.as_deref())?);
+
+    // Blocklist: se rehidrata desde el store para sobrevivir a reinicios
+    if !detector.store().is_durable() {
+        warn!("💾 No durable store (ANOMALY_REDIS_URL unset): blocklist, baselines and VIPs are lost on restart");
+    }
+    let blocklist = Arc::new(restore_blocklist(detector.store()).await);
+
+    // GeoIP: ANOMALY_GEOIP_PATH=/ruta/GeoLite2-Country.mmdb, se recarga en caliente al rotarlo
+    let geoip = match std::env::var("ANOMALY_GEOIP_PATH") {
+        Ok(path) => {
+            let reader = Arc::new(GeoIpReader::open(&path).map_err(std::io::Error::other)?);
+            info!("🌍 GeoIP database loaded from {} (build {})", path, reader.build_epoch());
+            reader.spawn_watcher(std::time::Duration::from_secs(GEOIP_RELOAD_SECS));
+            Some(reader)
+        }
+        Err(_) => None,
+    };
+
+    // Zonas por tenant: ANOMALY_TENANT_TIMEZONES="acme=Europe/Madrid,globex=America/Bogota"
+    let tenant_timezones = match std::env::var("ANOMALY_TENANT_TIMEZONES") {
+        Ok(raw) => parse_tenant_timezones(&raw)?,
+        Err(_) => HashMap::new(),
+    };
+    if !tenant_timezones.is_empty() {
+        info!("🕐 Tenant timezones: {:?}", tenant_timezones);
+    }
+
+    // Horarios laborales: ANOMALY_TENANT_WORK_HOURS="acme=8-18,globex=22-6" como base;
+    // los fijados por API (persistidos) tienen prioridad
+    let work_hours = Arc::new(DashMap::new());
+    if let Ok(raw) = std::env::var("ANOMALY_TENANT_WORK_HOURS") {
+        for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (tenant, hours) = pair.split_once('=').ok_or_else(|| {
+                std::io::Error::other(format!("ANOMALY_TENANT_WORK_HOURS: expected 'tenant=8-18', got {:?}", pair))
+            })?;
+            let hours = hours.parse::<WorkHours>().map_err(std::io::Error::other)?;
+            work_hours.insert(tenant.trim().to_string(), hours);
+        }
+    }
+    for (tenant, raw) in detector.store().load_records(WORK_HOURS_NAMESPACE).await {
+        match serde_json::from_str::<WorkHours>(&raw) {
+            Ok(hours) if hours.validate().is_ok() => {
+                work_hours.insert(tenant, hours);
+            }
+            _ => warn!("Ignoring corrupt work hours record for tenant {}", tenant),
+        }
+    }
+    if !work_hours.is_empty() {
+        info!("🗓️ Work hours declared for {} tenants", work_hours.len());
+    }
+
+    // Primer evento de cada tenant: sin él, un reinicio volvería a poner a todos en observación
+    let tenant_first_seen = Arc::new(DashMap::new());
+    for (tenant, raw) in detector.store().load_records(TENANT_FIRST_SEEN_NAMESPACE).await {
+        match serde_json::from_str::<DateTime<Utc>>(&raw) {
+            Ok(first_seen) => {
+                tenant_first_seen.insert(tenant, first_seen);
+            }
+            Err(e) => warn!("Ignoring corrupt first-seen record for tenant {}: {}", tenant, e),
+        }
+    }
+
+    // Baselines: se rehidratan para que un deploy no obligue a reaprender a cada usuario
+    let baselines = Arc::new(restore_baselines(detector.store()).await);
+    if !baselines.is_empty() {
+        info!("📚 Restored {} user baselines", baselines.len());
+    }
+
+    let stream_min_score = std::env::var("ANOMALY_STREAM_MIN_SCORE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_STREAM_MIN_SCORE);
+    let (stream_tx, _) = broadcast::channel(STREAM_CHANNEL_CAPACITY);
+
+    let ruleset_version = ruleset_version(&detector, &live_config).await;
+    info!("📐 Engine {} with ruleset {}", ENGINE_VERSION, ruleset_version);
+
+    let app_state = AppState {
+        baselines,
+        api_keys: Arc::new(api_keys),
+        geoip,
+        tenant_timezones: Arc::new(tenant_timezones),
+        work_hours,
+        metrics: Arc::new(Metrics::new(&detector.risk_thresholds())),
+        trends,
+        detector,
+        rate_limiter,
+        login_outcomes,
+        spray,
+        sessions,
+        audit,
+        log_throttle,
+        live_config: Arc::new(std::sync::RwLock::new(Arc::new(live_config))),
+        blocklist,
+        stream_tx,
+        stream_min_score,
+        shadow_mode,
+        scoring_timeout,
+        tenant_ramp,
+        tenant_first_seen,
+        cold_start_action,
+        trust_proxy,
+        ip_salts: Arc::new(ip_salts),
+        ruleset_version: Arc::new(std::sync::RwLock::new(ruleset_version)),
+        challenge_types,
+        block_as_429: std::env::var("ANOMALY_BLOCK_AS_429").is_ok_and(|v| v == "true" || v == "1"),
+        responses,
+        baseline_writes: Arc::new(WriteCoalescer::new(
+            std::time::Duration::from_millis(BASELINE_COALESCE_MS),
+            BASELINE_COALESCE_MAX,
+        )),
+        started_at: std::time::Instant::now(),
+        score_history_path,
+    };
+
+    // Aplica los lotes de /baseline que quedaron pendientes al acabar la ráfaga
+    let flusher_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(std::time::Duration::from_millis(BASELINE_COALESCE_MS));
+        loop {
+            tick.tick().await;
+            for (key, batch) in flusher_state.baseline_writes.drain_due() {
+                apply_baseline(&flusher_state, &key, batch).await;
+            }
+        }
+    });
+
+    // ANOMALY_BIND_ADDR="0.0.0.0:3001" o varias separadas por comas ("0.0.0.0:3001,[::1]:3001")
+    let bind_addrs = parse_bind_addrs(&std::env::var("ANOMALY_BIND_ADDR").unwrap_or_else(|_| DEFAULT_BIND_ADDR.to_string()))?;
+    info!("🚀 Anomaly Detection Service listening on {:?}", bind_addrs);
+    info!("🔒 Concurrency mode: DashMap (Lock-free reading)");
+
+    // kill -HUP <pid> recarga la configuración sin perder baselines ni perfiles
+    spawn_reload_on_sighup(app_state.clone())?;
+
+    // Referencia para el flush final (app_state se mueve al closure del servidor)
+    let shutdown_state = app_state.clone();
+
+    // ANOMALY_TLS_CERT + ANOMALY_TLS_KEY activan TLS en todas las direcciones
+    // (y ANOMALY_TLS_CLIENT_CA exige certificado de cliente); sin certificado, en claro
+    let tls = TlsSettings::from_env().map_err(std::io::Error::other)?;
+    match tls {
+        Some(settings) => {
+            let config = settings.server_config()?;
+            info!(
+                "🔐 TLS enabled ({})",
+                if settings.client_ca_path.is_some() { "mutual TLS: client certificates required" } else { "server certificate only" }
+            );
+            serve_tls(app_state, &bind_addrs, config).await?;
+        }
+        None => {
+            let mut server = HttpServer::new(move || build_app(app_state.clone()));
+            for addr in &bind_addrs {
+                server = server.bind(addr)?;
+            }
+            // Actix atiende SIGTERM/SIGINT: deja de aceptar conexiones y drena las activas
+            server.run().await?;
+        }
+    }
+
+    flush_on_shutdown(&shutdown_state).await;
+    tracing.shutdown().await;
+    Ok(())
+}
+
+// Rutas y middleware del servicio (compartidos por el servidor en claro y el TLS)
+fn build_app(
+    app_state: AppState,
+) -> App<
+    impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error, InitError = ()>,
+> {
+    App::new()
+        .app_data(web::Data::new(app_state.clone()))
+        .app_data(
+            web::JsonConfig::default()
+                .limit(JSON_BODY_LIMIT)
+                .error_handler(json_error_handler),
+        )
+        // Mismo tope para cualquier body que no pase por el extractor JSON
+        .app_data(web::PayloadConfig::new(JSON_BODY_LIMIT))
+        // gzip/brotli/zstd según Accept-Encoding (export, stats, compromised...).
+        // El upgrade 101 de /stream no se comprime: los frames WebSocket pasan intactos
+        .wrap(middleware::Compress::default())
+        .wrap(middleware::Logger::default())
+        // Middleware de seguridad simple
+        .wrap(middleware::NormalizePath::trim())
+        .route("/health", web::get().to(health))
+        .route("/metrics", web::get().to(metrics))
+        .service(
+            web::scope("/api/v1")
+                .route("/stream", web::get().to(stream_detections))
+                .route("/detect", web::post().to(detect_anomaly))
+                .route("/detect/batch", web::post().to(detect_batch))
+                .route("/simulate", web::post().to(simulate_detection))
+                .route("/explain", web::post().to(explain_detection))
+                .service(
+                    web::resource("/whatif")
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(IMPORT_BODY_LIMIT)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::post().to(whatif_thresholds)),
+                )
+                .route("/baseline", web::get().to(get_baseline))
+                .route("/baseline", web::post().to(update_baseline))
+                .service(
+                    web::resource("/baseline/import")
+                        .app_data(
+                            web::JsonConfig::default()
+                                .limit(IMPORT_BODY_LIMIT)
+                                .error_handler(json_error_handler),
+                        )
+                        .route(web::post().to(import_baselines)),
+                )
+                .route("/reset", web::post().to(reset_baseline))
+                .route("/reset/tenant", web::post().to(reset_tenant))
+                .route("/unblock", web::post().to(unblock_client))
+                .route("/profile", web::get().to(get_profile))
+                .route("/stats", web::get().to(tenant_stats))
+                .route("/trends", web::get().to(detection_trends))
+                .route("/compromised", web::get().to(list_compromised))
+                .route("/history", web::get().to(score_history))
+                .route("/export", web::get().to(export_snapshot))
+                .route("/import", web::post().to(import_snapshot))
+                .route("/blocklist", web::get().to(list_blocklist))
+                .route("/blocklist", web::post().to(add_blocklist))
+                .route("/blocklist", web::delete().to(remove_blocklist))
+                .route("/thresholds", web::get().to(get_thresholds))
+                .route("/thresholds", web::put().to(set_thresholds))
+                .route("/patterns", web::get().to(get_patterns))
+                .route("/patterns", web::put().to(set_patterns))
+                .route("/tenants/{id}/thresholds", web::put().to(set_tenant_thresholds))
+                .route("/tenants/{id}/patterns", web::get().to(get_tenant_patterns))
+                .route("/tenants/{id}/patterns", web::put().to(set_tenant_patterns))
+                .route("/tenants/{id}/metadata-rules", web::get().to(get_metadata_rules))
+                .route("/tenants/{id}/metadata-rules", web::put().to(set_metadata_rules))
+                .route("/tenants/{id}/vips", web::get().to(list_vips))
+                .route("/tenants/{id}/vips", web::post().to(add_vip))
+                .route("/tenants/{id}/vips/{client_id}", web::delete().to(remove_vip))
+                .route("/tenants/{id}/work-hours", web::get().to(get_work_hours))
+                .route("/tenants/{id}/work-hours", web::put().to(set_work_hours))
+                .route("/tenants/{id}/work-hours", web::delete().to(delete_work_hours))
+        )
+}
+
+// Handshake TLS máximo antes de cerrar la conexión (un cliente lento no retiene el socket)
+const TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+// HttpServer solo habla TLS con actix-tls: aquí actix-server acepta el TCP, tokio-rustls
+// hace el handshake y actix-http sirve la misma App sobre el stream cifrado.
+// Mismo manejo de señales que HttpServer (SIGTERM/SIGINT drenan las conexiones activas)
+async fn serve_tls(app_state: AppState, bind_addrs: &[SocketAddr], config: Arc<rustls::ServerConfig>) -> std::io::Result<()> {
+    let acceptor = tokio_rustls::TlsAcceptor::from(config);
+    let mut builder = actix_server::Server::build();
+    for &addr in bind_addrs {
+        let (state, acceptor) = (app_state.clone(), acceptor.clone());
+        builder = builder.bind(format!("anomaly-detector-tls-{}", addr), addr, move || {
+            let acceptor = acceptor.clone();
+            let handshake = fn_service(move |io: actix_web::rt::net::TcpStream| {
+                let acceptor = acceptor.clone();
+                async move {
+                    let peer = io.peer_addr().ok();
+                    let timeout = std::time::Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SECS);
+                    let stream = tokio::time::timeout(timeout, acceptor.accept(io))
+                        .await
+                        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))
+                        .and_then(|accepted| accepted)
+                        .map_err(|e| {
+                            debug!("TLS handshake failed from {:?}: {}", peer, e);
+                            DispatchError::Io(e)
+                        })?;
+                    Ok((stream, Protocol::Http1, peer))
+                }
+            });
+            let app = build_app(state.clone()).into_factory().map_err(|err| err.error_response());
+            handshake.and_then(
+                HttpService::build()
+                    .local_addr(addr)
+                    .finish(map_config(app, |_| AppConfig::default())),
+            )
+        })?;
+    }
+    builder.run().await
+}
+
+// Fichero de configuración + variables de entorno (cada variable definida tiene prioridad).
+// Se usa al arrancar y en cada recarga por SIGHUP
+fn resolve_config(base: SecurityConfig) -> std::io::Result<SecurityConfig> {
+    // Allowlist: ANOMALY_ALLOWLIST="10.0.0.0/8,2001:db8::/32" (se relee al arrancar y con SIGHUP)
+    let allowlist = match std::env::var("ANOMALY_ALLOWLIST") {
+        Ok(raw) => IpAllowlist::parse_networks(&raw).map_err(std::io::Error::other)?,
+        Err(_) => base.allowlist.clone(),
+    };
+
+    // ANOMALY_COLD_START_ACTION=ALLOW|CHALLENGE (un valor inválido aborta el arranque o la recarga)
+    let cold_start_action = match std::env::var("ANOMALY_COLD_START_ACTION") {
+        Ok(raw) => raw.parse::<ColdStartAction>().map_err(std::io::Error::other)?,
+        Err(_) => base.cold_start_action,
+    };
+
+    let risk_thresholds = RiskThresholds::from_env_or(base.risk_thresholds);
+    let config = SecurityConfig {
+        signatures_path: std::env::var("ANOMALY_SIGNATURES_PATH").ok().or(base.signatures_path.clone()),
+        alert_webhook_url: std::env::var("ANOMALY_ALERT_WEBHOOK").ok().or(base.alert_webhook_url.clone()),
+        allowlist,
+        // ANOMALY_SENSITIVITY=0.0–1.0 escala todos los scores (0.8 = neutro)
+        sensitivity: std::env::var("ANOMALY_SENSITIVITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.sensitivity),
+        shadow_mode: std::env::var("ANOMALY_SHADOW_MODE").map_or(base.shadow_mode, |v| v == "true" || v == "1"),
+        // ANOMALY_SCORING_TIMEOUT_MS=50 (0 = esperar siempre al scoring completo)
+        scoring_timeout_ms: std::env::var("ANOMALY_SCORING_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.scoring_timeout_ms),
+        // ANOMALY_TENANT_RAMP_HOURS=48 observa cada tenant nuevo dos días antes de aplicar
+        tenant_ramp_hours: std::env::var("ANOMALY_TENANT_RAMP_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.tenant_ramp_hours),
+        risk_thresholds,
+        // ANOMALY_ACTION_{CHALLENGE,BLOCK}=0.7/0.95; sin configurar siguen a high/critical
+        action_thresholds: Some(ActionThresholds::from_env_or(
+            base.action_thresholds.unwrap_or_else(|| ActionThresholds::from_risk(&risk_thresholds)),
+        )),
+        cold_start_action,
+        scoring_weights: ScoringWeights::from_env_or(base.scoring_weights.clone()),
+        pattern_weights: anomaly_detector::scoring::pattern_weights_from_env(base.pattern_weights.clone()),
+        // ANOMALY_EVENT_HISTORY=25 amplía la línea de tiempo de /api/v1/profile
+        event_history_size: std::env::var("ANOMALY_EVENT_HISTORY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.event_history_size),
+        // ANOMALY_MAX_EVENT_AGE_SECS=3600 acota antes los timestamps antiguos
+        max_event_age_secs: std::env::var("ANOMALY_MAX_EVENT_AGE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.max_event_age_secs),
+        // ANOMALY_SCORE_HISTORY_PATH=/var/lib/anomaly/scores.jsonl (consultable en /api/v1/history)
+        score_history_path: std::env::var("ANOMALY_SCORE_HISTORY_PATH").ok().or(base.score_history_path.clone()),
+        // ANOMALY_CHALLENGE_TYPES="medium=CAPTCHA,high=MFA"
+        challenge_types: ChallengeTypes::from_env_or(base.challenge_types.clone()),
+        // ANOMALY_REDIS_URL=redis://redis:6379/ comparte perfiles y registros entre réplicas
+        redis_url: std::env::var("ANOMALY_REDIS_URL").ok().or(base.redis_url.clone()),
+        // ANOMALY_TRUST_PROXY=1 detrás de un único reverse proxy
+        trust_proxy: std::env::var("ANOMALY_TRUST_PROXY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(base.trust_proxy),
+        ..base
+    };
+    config.validate().map_err(std::io::Error::other)?;
+    Ok(config)
+}
+
+// SIGHUP: relee ANOMALY_CONFIG_PATH y sustituye pesos, cortes de nivel y de acción y la
+// allowlist. Baselines y perfiles se conservan; el resto de ajustes requiere reiniciar.
+// Una configuración inválida se descarta entera y sigue vigente la anterior
+async fn reload_config(state: &AppState) -> std::io::Result<()> {
+    let config = resolve_config(SecurityConfig::load().map_err(std::io::Error::other)?)?;
+    apply_config(state, &config).await
+}
+
+// Sustituye la configuración viva por `config` (ya leída y resuelta)
+async fn apply_config(state: &AppState, config: &SecurityConfig) -> std::io::Result<()> {
+    state.detector.set_risk_thresholds(config.risk_thresholds).map_err(std::io::Error::other)?;
+    // El histograma de /metrics sigue los cortes vigentes
+    state.metrics.set_score_bounds(&config.risk_thresholds);
+    let live = LiveConfig::from_config(config);
+    let networks = live.allowlist.len();
+    *state.live_config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(live);
+    refresh_ruleset_version(state).await;
+    info!("🔄 Configuration reloaded ({} trusted networks)", networks);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn spawn_reload_on_sighup(state: AppState) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reload_config(&state).await {
+                warn!("❌ Configuration reload failed, keeping the current one: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_reload_on_sighup(_state: AppState) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Huella de todo lo que decide un score: pesos y umbrales del motor, pesos aditivos y cortes de acción
+async fn ruleset_version(detector: &AnomalyDetector, live: &LiveConfig) -> String {
+    anomaly_detector::scoring::ruleset_fingerprint(&serde_json::json!({
+        "engine": detector.ruleset().await,
+        "scoring_weights": live.weights,
+        "action_thresholds": live.action_thresholds,
+    }))
+}
+
+// Tras un ajuste en caliente de umbrales: las respuestas siguientes llevan la versión nueva
+async fn refresh_ruleset_version(state: &AppState) {
+    let version = ruleset_version(&state.detector, &state.live_config()).await;
+    if let Ok(mut current) = state.ruleset_version.write() {
+        if *current != version {
+            info!("📐 Ruleset version {} -> {}", current, version);
+            *current = version;
+        }
+    }
+}
+
+// Clave de baselines, rate limiting y coalescencia (ver composite_key)
+fn baseline_key(tenant_id: &str, subject: &str) -> String {
+    composite_key(tenant_id, subject)
+}
+
+// Identificador del usuario en claves, perfiles y blocklist: hashed_id tiene prioridad.
+// Un tenant debe usar siempre el mismo: "42" como seudónimo y el user_id 42 coinciden
+fn subject_id(user_id: Option<i32>, hashed_id: Option<&str>) -> Option<String> {
+    hashed_id.map(str::to_string).or_else(|| user_id.map(|id| id.to_string()))
+}
+
+// Pares "tenant=Zona/IANA" separados por comas; una zona desconocida aborta el arranque
+fn parse_tenant_timezones(raw: &str) -> std::io::Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (tenant, zone) = pair
+            .split_once('=')
+            .map(|(t, z)| (t.trim(), z.trim()))
+            .ok_or_else(|| std::io::Error::other(format!("ANOMALY_TENANT_TIMEZONES: se esperaba 'tenant=zona' en {:?}", pair)))?;
+        if !timezone::is_known(zone) {
+            return Err(std::io::Error::other(format!("ANOMALY_TENANT_TIMEZONES: zona desconocida {:?}", zone)));
+        }
+        map.insert(tenant.to_string(), zone.to_string());
+    }
+    Ok(map)
+}
+
+// Direcciones IP:puerto literales; un valor inválido aborta el arranque con un error claro
+fn parse_bind_addrs(raw: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let addrs = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry.parse::<SocketAddr>().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid ANOMALY_BIND_ADDR entry {:?} (expected IP:PORT, e.g. 0.0.0.0:3001 or [::]:3001)", entry),
+                )
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "ANOMALY_BIND_ADDR is empty"));
+    }
+    Ok(addrs)
+}
+
+// Persiste el estado en memoria antes de salir, acotado a SHUTDOWN_FLUSH_SECS
+// para no bloquear al orquestador si el store está caído
+async fn flush_on_shutdown(state: &AppState) {
+    state.detector.shutdown();
+    let budget = std::time::Duration::from_secs(SHUTDOWN_FLUSH_SECS);
+    let flush = async {
+        for (key, batch) in state.baseline_writes.drain_all() {
+            apply_baseline(state, &key, batch).await;
+        }
+        state.detector.flush().await
+    };
+    match tokio::time::timeout(budget, flush).await {
+        Ok(saved) => info!("💾 Shutdown flush: {} profiles persisted", saved),
+        Err(_) => warn!("⏱️ Shutdown flush aborted after {}s", SHUTDOWN_FLUSH_SECS),
+    }
+}
+
+// Errores del extractor JSON con el mismo sobre {"error": ...} que el resto de la API,
+// más un "code" estable para que el gateway no tenga que parsear el mensaje
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let (status, code) = match &err {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+        }
+        JsonPayloadError::ContentType => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_content_type"),
+        JsonPayloadError::Deserialize(e) if e.is_data() => (StatusCode::BAD_REQUEST, "invalid_field"),
+        JsonPayloadError::Deserialize(_) => (StatusCode::BAD_REQUEST, "malformed_json"),
+        _ => (StatusCode::BAD_REQUEST, "invalid_payload"),
+    };
+    let response = HttpResponse::build(status).json(serde_json::json!({
+        "error": err.to_string(),
+        "code": code,
+    }));
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    #[serde(flatten)]
+    check: HealthCheck,
+    engine: &'static str,
+    store: &'static str,
+    geoip_build_epoch: Option<u64>,
+    // Un breaker abierto = dependencia caída, se sigue sin ella (estado "degraded")
+    circuit_breakers: Vec<BreakerStatus>,
+}
+
+// Readiness real: 503 si el store no responde, "degraded" cerca del tope de perfiles
+// o con algún circuit breaker sin cerrar
+async fn health(state: web::Data<AppState>) -> HttpResponse {
+    let budget = std::time::Duration::from_secs(HEALTH_STORE_TIMEOUT_SECS);
+    let store_ok = match tokio::time::timeout(budget, state.detector.store().ping()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("Health check: profile store unreachable: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!("Health check: profile store did not answer within {}s", HEALTH_STORE_TIMEOUT_SECS);
+            false
+        }
+    };
+
+    let active_profiles = state.detector.active_profiles();
+    let near_cap = active_profiles as f64 >= state.detector.max_profiles() as f64 * HEALTH_PROFILES_WARN_RATIO;
+    let circuit_breakers = state.detector.breakers();
+    let tripped = circuit_breakers.iter().any(|b| b.state != BreakerState::Closed);
+    let status = match (store_ok, near_cap || tripped) {
+        (false, _) => "unhealthy",
+        (true, true) => "degraded",
+        (true, false) => "healthy",
+    };
+
+    let body = HealthResponse {
+        check: HealthCheck {
+            status: status.to_string(),
+            uptime_seconds: state.started_at.elapsed().as_secs(),
+            events_processed: state.detector.events_processed(),
+            active_profiles: active_profiles as u64,
+            memory_usage_mb: resident_memory_mb().unwrap_or(0),
+        },
+        engine: "rust-dashmap",
+        store: if store_ok { "ok" } else { "unreachable" },
+        geoip_build_epoch: state.geoip.as_ref().map(|g| g.build_epoch()),
+        circuit_breakers,
+    };
+
+    if store_ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+// RSS del proceso (VmRSS de /proc, solo Linux)
+fn resident_memory_mb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+async fn metrics(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(state.metrics.render(
+            state.baselines.len(),
+            state.detector.events_processed(),
+            state.detector.evictions(),
+        ))
+}
+
+// Helper para validar API Key (cabecera X-API-KEY, comparación en tiempo constante)
+fn is_authorized(req: &HttpRequest, state: &web::Data<AppState>) -> bool {
+    match req.headers().get("X-API-KEY") {
+        Some(key) => state.api_keys.accepts(key.to_str().unwrap_or("")),
+        None => false,
+    }
+}
+
+// ==========================================
+// HANDLERS
+// ==========================================
+
+async fn detect_anomaly(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut body: web::Json<AnomalyRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+    apply_forwarded_ip(&req, &state, &mut body);
+    pseudonymize_ip(&state, &mut body);
+
+    // Continúa la traza del llamador (cabecera W3C traceparent) si la hay
+    let parent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::parse);
+    let span = DetectSpan::start(&body.tenant_id, parent.as_ref());
+    let response = match span.run(evaluate_request(&state, &body)).await {
+        Ok(response) => response,
+        Err(e) => return detector_error_response(&e),
+    };
+    span.record_action(response.action.as_str());
+
+    // Opcional: los intermediarios HTTP pueden cortar el tráfico bloqueado sin leer el body
+    if state.block_as_429 && response.action == Action::Block {
+        let mut blocked = HttpResponse::TooManyRequests();
+        blocked.insert_header(("X-Anomaly-Score", response.anomaly_score.to_string()));
+        if let Some(secs) = response.retry_after_secs {
+            blocked.insert_header((actix_web::http::header::RETRY_AFTER, secs.to_string()));
+        }
+        return blocked.json(response);
+    }
+
+    HttpResponse::Ok().json(response)
+}
+
+async fn detect_batch(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<Vec<serde_json::Value>>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+
+    if body.len() > MAX_BATCH_SIZE {
+        return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": format!("Batch too large: {} events (max {})", body.len(), MAX_BATCH_SIZE)
+        }));
+    }
+
+    // Cada elemento se evalúa por separado y en orden: un evento malformado
+    // produce un objeto de error en su posición sin invalidar el resto del lote
+    let mut results = Vec::with_capacity(body.len());
+    for raw in body.into_inner() {
+        let item = match serde_json::from_value::<AnomalyRequest>(raw) {
+            Ok(mut event) => {
+                // Como en /detect: la IP del cliente puede venir de los proxies de confianza
+                apply_forwarded_ip(&req, &state, &mut event);
+                pseudonymize_ip(&state, &mut event);
+                match evaluate_request(&state, &event).await {
+                    Ok(response) => BatchItem::Scored(response),
+                    Err(e) => BatchItem::Failed { error: e.to_string() },
+                }
+            }
+            Err(e) => BatchItem::Failed { error: format!("Invalid event: {}", e) },
+        };
+        results.push(item);
+    }
+
+    HttpResponse::Ok().json(results)
+}
+
+// Live aplica la decisión (perfiles, contadores, auditoría); Simulate solo la calcula
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EvalMode {
+    Live,
+    Simulate,
+}
+
+// "¿Qué score tendría este evento?" contra los baselines reales, sin mutar nada
+async fn simulate_detection(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut body: web::Json<AnomalyRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+    apply_forwarded_ip(&req, &state, &mut body);
+    pseudonymize_ip(&state, &mut body);
+
+    match score_request(&state, &body, EvalMode::Simulate).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => detector_error_response(&e),
+    }
+}
+
+// Desglose de la decisión automatizada (divulgación ECOA/GDPR). Igual que /simulate,
+// sin efectos: mismos baselines, pero nada se aprende ni se registra
+async fn explain_detection(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut body: web::Json<AnomalyRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+    apply_forwarded_ip(&req, &state, &mut body);
+    pseudonymize_ip(&state, &mut body);
+
+    let response = match score_request(&state, &body, EvalMode::Simulate).await {
+        Ok(response) => response,
+        Err(e) => return detector_error_response(&e),
+    };
+    let explanation = response.explanation.unwrap_or(Explanation { raw_score: 0.0, factors: Vec::new() });
+    HttpResponse::Ok().json(ExplainResponse {
+        engine_version: response.engine_version,
+        ruleset_version: response.ruleset_version,
+        anomaly_score: response.anomaly_score,
+        raw_score: explanation.raw_score,
+        sensitivity: state.detector.sensitivity(),
+        risk_level: response.risk_level,
+        risk_thresholds: state.detector.risk_thresholds(),
+        action: response.action,
+        action_thresholds: state.live_config().action_thresholds,
+        factors: explanation.factors,
+        anomalies: response.anomalies,
+    })
+}
+
+// Impacto de una configuración candidata sobre una muestra de eventos: cada evento se
+// puntúa como en /simulate con la vigente y con la candidata (sin mutar nada) y se
+// compara la distribución de acciones. Los eventos no se influyen entre sí (el
+// failure_rate de uno no cuenta para el siguiente)
+async fn whatif_thresholds(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<WhatIfRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+
+    if body.events.len() > MAX_WHATIF_EVENTS {
+        return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": format!("Sample too large: {} events (max {})", body.events.len(), MAX_WHATIF_EVENTS)
+        }));
+    }
+
+    let current = ScoringSetup::current(&state);
+    let candidate = match body.candidate(&current) {
+        Ok(candidate) => candidate,
+        Err(e) => return HttpResponse::BadRequest().json(serde_json::json!({ "error": e, "code": "invalid_field" })),
+    };
+
+    let WhatIfRequest { events, .. } = body.into_inner();
+    let mut report = WhatIfReport::default();
+    for mut event in events {
+        pseudonymize_ip(&state, &mut event);
+        let before = score_with(&state, &event, EvalMode::Simulate, &current).await;
+        let after = score_with(&state, &event, EvalMode::Simulate, &candidate).await;
+        match (before, after) {
+            (Ok(before), Ok(after)) => report.record(decided_action(&before), decided_action(&after)),
+            (Err(e), _) | (_, Err(e)) => report.reject(e),
+        }
+    }
+    report.finish();
+
+    info!(
+        "🔮 What-if on {} events: {} decisions would change ({:+.2}% BLOCK)",
+        report.evaluated,
+        report.changed,
+        report.block_rate_delta * 100.0
+    );
+    HttpResponse::Ok().json(report)
+}
+
+// La acción que se aplicaría: en shadow mode o durante la rampa, la que viaja en shadow_action
+fn decided_action(response: &AnomalyResponse) -> Action {
+    response.shadow_action.unwrap_or(response.action)
+}
+
+// Con trust_proxy, la IP del cliente sale de las cabeceras que añaden los proxies de
+// confianza; si no son concluyentes (cadena corta, cabeceras contradictorias) se
+// mantiene la del cuerpo
+fn apply_forwarded_ip(req: &HttpRequest, state: &AppState, body: &mut AnomalyRequest) {
+    if state.trust_proxy == 0 {
+        return;
+    }
+    let headers = req.headers();
+    let values = |name: &'static str| headers.get_all(name).filter_map(|v| v.to_str().ok());
+    if let Some(ip) = forwarded::client_ip(values("x-forwarded-for"), values("forwarded"), state.trust_proxy) {
+        let ip = ip.to_string();
+        if ip != body.ip_address {
+            debug!("🔀 Client IP {} from forwarded headers (body said {}) [Tenant: {}]", ip, body.ip_address, body.tenant_id);
+        }
+        body.ip_address = ip;
+    }
+}
+
+// Tenants con sal (ver SecurityConfig::tenant_ip_salts): la IP se sustituye por
+// "ip:" + HMAC-SHA256 antes de evaluar nada. Al no ser una IP, extract_country devuelve
+// UNKNOWN y GeoIP, viaje imposible, sesiones y reputación no se aplican
+fn pseudonymize_ip(state: &AppState, body: &mut AnomalyRequest) {
+    if let Some(key) = state.ip_salts.get(&body.tenant_id) {
+        body.ip_address = hashed_ip(key, &body.ip_address);
+    }
+}
+
+// Forma canónica antes del HMAC: "::FFFF:1.2.3.4" y "::ffff:1.2.3.4" dan el mismo hash
+fn hashed_ip(key: &hmac::Key, ip: &str) -> String {
+    let ip = ip.trim();
+    let canonical = ip.parse::<IpAddr>().map(|addr| addr.to_string()).unwrap_or_else(|_| ip.to_string());
+    let tag = hmac::sign(key, canonical.as_bytes());
+    // 128 bits bastan para que dos IPs no colisionen
+    let hex: String = tag.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("ip:{}", hex)
+}
+
+// Cada variante con su código: evento inválido = culpa del cliente, el resto = servicio no disponible
+fn detector_error_response(err: &DetectorError) -> HttpResponse {
+    let status = match err {
+        DetectorError::InvalidEvent(_) => StatusCode::BAD_REQUEST,
+        DetectorError::StoreUnavailable(_) | DetectorError::CapacityExceeded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    if status.is_server_error() {
+        warn!("Detector unavailable: {}", err);
+    }
+    HttpResponse::build(status).json(serde_json::json!({ "error": err.to_string() }))
+}
+
+// Núcleo de /detect: compartido por la ruta individual y la de lote.
+// Con event_id, un reintento dentro de la ventana devuelve la respuesta original
+// sin tocar rate limit, perfiles ni métricas
+// (los errores no se cachean: un reintento vuelve a evaluarse)
+async fn evaluate_request(state: &AppState, body: &AnomalyRequest) -> Result<AnomalyResponse, DetectorError> {
+    let Some(event_id) = &body.event_id else {
+        return score_within_budget(state, body).await;
+    };
+
+    let cache_key = composite_key(&body.tenant_id, event_id);
+    if let Some(cached) = state.responses.get(&cache_key) {
+        debug!("♻️ Duplicate event {} [Tenant: {}]: returning cached response", event_id, body.tenant_id);
+        return Ok(cached);
+    }
+
+    let response = score_within_budget(state, body).await?;
+    // Una respuesta degradada no se cachea: el reintento merece una evaluación completa
+    if !response.degraded {
+        state.responses.insert(cache_key, response.clone());
+    }
+    Ok(response)
+}
+
+// Latencia del login acotada: pasado `scoring_timeout` se responde ALLOW (fail-open).
+// El corte llega en el siguiente punto de espera (store, enriquecedores, umbrales); lo ya
+// aplicado en memoria se conserva y lo pendiente de persistir lo recoge `flush()`
+async fn score_within_budget(state: &AppState, body: &AnomalyRequest) -> Result<AnomalyResponse, DetectorError> {
+    let Some(budget) = state.scoring_timeout else {
+        return score_request(state, body, EvalMode::Live).await;
+    };
+    let started = std::time::Instant::now();
+    match tokio::time::timeout(budget, score_request(state, body, EvalMode::Live)).await {
+        Ok(result) => result,
+        Err(_) => {
+            state.metrics.degraded_total.fetch_add(1, Ordering::Relaxed);
+            if let Some(suppressed) = log_allowed(state, "degraded", false) {
+                warn!(
+                    "⏱️ Scoring exceeded {} ms [Tenant: {} User: {}]: failing open{}",
+                    budget.as_millis(), body.tenant_id, body.subject(), suppressed
+                );
+            }
+            Ok(AnomalyResponse {
+                engine_version: ENGINE_VERSION,
+                ruleset_version: state.ruleset_version.read().map(|v| v.clone()).unwrap_or_default(),
+                anomaly_score: 0.0,
+                anomalies: vec![AnomalyReason::new("SCORING_TIMEOUT", "Scoring timed out", 0.0)],
+                risk_level: determine_risk_level(0.0, &state.detector.risk_thresholds()),
+                action: Action::Allow,
+                recommendation: None,
+                confidence: 0.0,
+                retry_after_secs: None,
+                shadow_action: None,
+                degraded: true,
+                processing_time_ms: started.elapsed().as_millis() as u64,
+                explanation: None,
+            })
+        }
+    }
+}
+
+async fn score_request(state: &AppState, body: &AnomalyRequest, mode: EvalMode) -> Result<AnomalyResponse, DetectorError> {
+    score_with(state, body, mode, &ScoringSetup::current(state)).await
+}
+
+// Pesos y cortes con los que se decide: los vigentes o los candidatos de /whatif
+struct ScoringSetup {
+    live: Arc<LiveConfig>,
+    risk_thresholds: RiskThresholds,
+    sensitivity: f64,
+}
+
+impl ScoringSetup {
+    // Snapshot de pesos/cortes/allowlist: una recarga por SIGHUP aplica a la siguiente petición
+    fn current(state: &AppState) -> Self {
+        Self {
+            live: state.live_config(),
+            risk_thresholds: state.detector.risk_thresholds(),
+            sensitivity: state.detector.sensitivity(),
+        }
+    }
+}
+
+async fn score_with(
+    state: &AppState,
+    body: &AnomalyRequest,
+    mode: EvalMode,
+    setup: &ScoringSetup,
+) -> Result<AnomalyResponse, DetectorError> {
+    let started = std::time::Instant::now();
+    let live = mode == EvalMode::Live;
+    let cfg = &setup.live;
+    let risk_thresholds = setup.risk_thresholds;
+
+    body.check_subject()?;
+    let user = body.subject();
+
+    // Generar clave compuesta para aislamiento Multi-Tenant estricto
+    let key = baseline_key(&body.tenant_id, &user);
+
+    // Rate limiting: se evalúa antes de tocar el baseline (sin guards abiertos en el await)
+    let rate_limit = state.detector.tenant_threshold(&body.tenant_id, "rate_limit").await.unwrap_or(DEFAULT_RATE_LIMIT);
+    let rate_limited = match mode {
+        EvalMode::Live => state.rate_limiter.check(&key, rate_limit as usize),
+        EvalMode::Simulate => state.rate_limiter.peek(&key, rate_limit as usize),
+    };
+
+    // Blocklist manual: bloquea antes de ejecutar el scoring
+    let blocked = blocklist_match(state, body);
+
+    // DashMap bloquea solo el shard de esta clave (el scoring registra la ráfaga de endpoints).
+    // En simulación se puntúa sobre una copia del baseline
+    let work_hours = state.work_hours.get(&body.tenant_id).map(|entry| *entry.value());
+    let score_baseline = |baseline: &mut UserBaseline| {
+        let hour = local_hour(state, baseline.timezone.as_deref(), &body.tenant_id, Utc::now());
+        let breakdown = calculate_anomaly_score(body, baseline, &cfg.weights, state.geoip.as_deref(), hour, work_hours);
+        (breakdown.score, breakdown.anomalies, maturity_confidence(baseline.observations), breakdown.factors)
+    };
+    let scored = match (&blocked, mode) {
+        (Some(_), _) => None,
+        (None, EvalMode::Live) => state.baselines.get_mut(&key).map(|mut entry| score_baseline(entry.value_mut())),
+        (None, EvalMode::Simulate) => {
+            let copy = state.baselines.get(&key).map(|entry| entry.value().clone());
+            copy.map(|mut baseline| score_baseline(&mut baseline))
+        }
+    };
+    let cold_start = blocked.is_none() && scored.is_none();
+    let (mut raw_score, mut anomalies, mut confidence, mut factors) = match (&blocked, scored) {
+        (Some(reason), _) => {
+            let blocked_factor = ScoreFactor::new("blocklist", reason.detail.clone().unwrap_or_default(), 0.0, false);
+            (0.0, vec![reason.clone()], 1.0, vec![blocked_factor]) // Decisión manual: certeza total
+        }
+        (None, Some(scored)) => scored,
+        (None, None) => (
+            0.0,
+            vec![AnomalyReason::new("NEW_PROFILE", "New user profile created", 0.0)],
+            0.0,
+            vec![ScoreFactor::new("baseline", "none", 0.0, false)],
+        ), // Cold start
+    };
+
+    // Sin baseline no hay horas aprendidas, pero el horario declarado ya es una referencia
+    if cold_start {
+        let hour = local_hour(state, body.timezone.as_deref(), &body.tenant_id, Utc::now());
+        if let Some(hours) = work_hours.filter(|hours| !hours.contains(hour)) {
+            raw_score += cfg.weights.time;
+            anomalies.push(
+                AnomalyReason::new("UNUSUAL_TIME", "Unusual Time", cfg.weights.time).with_detail(format!("outside {}", hours)),
+            );
+            factors.push(ScoreFactor::new("time", format!("{:02}:00", hour), cfg.weights.time, false));
+        }
+    }
+
+    // Misma sesión desde otro país (u otra red) antes de caducar: cookie robada
+    if let (None, Some(session_id)) = (&blocked, body.session_id.as_deref()) {
+        if let Some((jump, ip, country)) = session_jump(state, body, session_id, mode) {
+            let before = jump.previous_country.as_deref().unwrap_or(UNKNOWN_COUNTRY);
+            let after = country.as_deref().unwrap_or(UNKNOWN_COUNTRY);
+            let detail = format!("{} ({}) -> {} ({})", jump.previous_ip, before, ip, after);
+            raw_score += cfg.weights.session_hijack;
+            anomalies.push(
+                AnomalyReason::new("SESSION_ANOMALY", "Session Anomaly", cfg.weights.session_hijack).with_detail(detail.clone()),
+            );
+            factors.push(ScoreFactor::new("session", detail, cfg.weights.session_hijack, false));
+            if let Some(suppressed) = live.then(|| log_allowed(state, &format!("session:{}", key), false)).flatten() {
+                warn!(
+                    "🍪 Session reused from another network [Tenant: {} User: {}]: {} -> {}{}",
+                    body.tenant_id, user, jump.previous_ip, ip, suppressed
+                );
+            }
+        }
+    }
+
+    // El historial del motor de perfiles también respalda el score
+    if blocked.is_none() {
+        if let Some(profile) = state.detector.get_profile(&body.tenant_id, &user) {
+            confidence = confidence.max(maturity_confidence(profile.total_events));
+        }
+    }
+
+    // Resultado de login: alimenta failure_rate (RapidFailures) y la correlación
+    // por IP (CredentialSpray) del motor de patrones.
+    // Sin login_success el comportamiento es el de siempre
+    let mut indicators = HashMap::new();
+    if let (None, Some(success)) = (&blocked, body.login_success) {
+        let ip = body.ip_address.trim();
+        let (failure_rate, sprayed) = match mode {
+            EvalMode::Live => (
+                state.login_outcomes.record(&key, success),
+                state.spray.record(&body.tenant_id, ip, &user),
+            ),
+            EvalMode::Simulate => (
+                state.login_outcomes.peek(&key, success),
+                state.spray.peek(&body.tenant_id, ip, &user),
+            ),
+        };
+
+        if let Some(failure_rate) = failure_rate {
+            indicators.insert(KEY_FAILURE_RATE.to_string(), failure_rate);
+        }
+        // La correlación ya decidió: el indicador va saturado para que la firma lo traduzca
+        if sprayed {
+            indicators.insert(KEY_SPRAY_SCORE.to_string(), 1.0);
+            if let Some(suppressed) = live.then(|| log_allowed(state, &format!("spray:{}:{}", body.tenant_id, ip), false)).flatten() {
+                warn!("🌧️ Credential spray from {} [Tenant: {}]: more than {} users targeted{}", ip, body.tenant_id, SPRAY_MAX_USERS, suppressed);
+            }
+        }
+    }
+
+    // Puente hacia el motor de perfiles: lo que el baseline ya detectó se traduce a los
+    // indicadores de sus firmas, así analyze() también ve la petición
+    let bridged = match blocked {
+        None => bridge_indicators(&cfg.weights, &anomalies, &mut indicators),
+        Some(_) => Vec::new(),
+    };
+
+    // Con device_id el motor de perfiles compara contra los dispositivos conocidos (DeviceChange)
+    // Escalón del motor de perfiles para reincidentes (THROTTLE → MFA → ISOLATE)
+    let mut engine_recommendation = None;
+    // Un cliente que el motor ya sigue (p.ej. marcado por una inyección) se analiza siempre
+    let use_engine = blocked.is_none()
+        && (!indicators.is_empty()
+            || body.device_id.is_some()
+            // Con enriquecedores (reputación de IP) cualquier petición puede ganar indicadores
+            || state.detector.has_enrichers()
+            // Las reglas de metadata del tenant también puntúan sin indicadores
+            || state.detector.has_metadata_rules(&body.tenant_id)
+            || state.detector.has_profile(&body.tenant_id, &user));
+    // analyze() cuenta su propio evento; el resto de evaluaciones en vivo se cuentan aquí
+    if live && !use_engine {
+        state.detector.count_event(&body.tenant_id);
+    }
+    if use_engine {
+        let event = behavior_event(body, indicators);
+        let pattern_score = match mode {
+            EvalMode::Live => state.detector.analyze(&event).await?,
+            // La sensibilidad y los cortes de `setup` (los candidatos en /whatif) también en el motor
+            EvalMode::Simulate => state.detector.simulate_with(&event, setup.sensitivity, risk_thresholds).await?,
+        };
+        // Los patrones puenteados ya sumaron en el baseline: no se cuentan dos veces
+        let behavioral = state.detector.score_without(&pattern_score, &bridged);
+        raw_score += behavioral as f32 * cfg.weights.behavioral;
+        engine_recommendation = Some(pattern_score.recommendation.clone());
+        if pattern_score.detected_patterns.is_empty() && !pattern_score.reasons.is_empty() {
+            // Perfil ya comprometido: el detector no analiza patrones
+            let weight = pattern_score.score as f32 * cfg.weights.behavioral;
+            anomalies.push(AnomalyReason::new("CLIENT_COMPROMISED", COMPROMISED_REASON, weight));
+            factors.push(ScoreFactor::new("profile", "compromised", weight, false));
+        }
+        for pattern in pattern_score.detected_patterns.iter().filter(|p| !bridged.contains(p)) {
+            let contribution = pattern_score.pattern_contributions.get(pattern).copied().unwrap_or(0.0);
+            anomalies.push(AnomalyReason::pattern(pattern, contribution as f32 * cfg.weights.behavioral));
+            factors.push(ScoreFactor::new("behavior_pattern", pattern.code(), contribution as f32 * cfg.weights.behavioral, false));
+        }
+    }
+
+    // Una sola escala: el aporte del motor entra sin sensibilidad (score_without) y el
+    // score aditivo se normaliza a 0–1 y se escala aquí una única vez
+    let mut score = apply_sensitivity(cfg.weights.normalize(raw_score) as f64, setup.sensitivity) as f32;
+    let mut risk_level = determine_risk_level(score, &risk_thresholds);
+    // La acción sale del score con sus propios cortes, no de la etiqueta de riesgo
+    let mut action = cfg.action_thresholds.action(score as f64);
+
+    if blocked.is_some() {
+        risk_level = "critical".to_string();
+        action = Action::Block;
+    }
+
+    // Fail-secure: sin baseline no hay con qué comparar, se pide un challenge
+    if cold_start && state.cold_start_action == ColdStartAction::Challenge && action == Action::Allow {
+        action = Action::Challenge;
+        anomalies.push(AnomalyReason::new("COLD_START", "Cold start: no baseline for this user", 0.0));
+    }
+
+    // VIP (confianza por identidad, no por red): la acción por score no pasa del tope.
+    // Score y anomalías se conservan y la acción que habría sido queda en la auditoría
+    let vip_cap = state.detector.vip_max_action();
+    if blocked.is_none() && action > vip_cap && state.detector.is_vip(&body.tenant_id, &user) {
+        if let Some(suppressed) = live.then(|| log_allowed(state, &format!("vip:{}", key), false)).flatten() {
+            info!(
+                "⭐ VIP capped [Tenant: {} User: {}]: would have been {} (score {}){}",
+                body.tenant_id, user, action, score, suppressed
+            );
+        }
+        anomalies.push(AnomalyReason::new("VIP_CAPPED", "VIP action capped", 0.0).with_detail(format!("would have been {}", action)));
+        factors.push(ScoreFactor::new("vip", action.as_str(), 0.0, true));
+        action = vip_cap;
+    }
+
+    // El límite de peticiones bloquea sin importar el score de comportamiento
+    if rate_limited {
+        action = Action::Block;
+        anomalies.push(AnomalyReason::new("RATE_LIMITED", "Rate limit exceeded", 0.0));
+        factors.push(ScoreFactor::new("rate_limit", format!("{}s window", RATE_LIMIT_WINDOW_SECS), 0.0, false));
+        if let Some(suppressed) = live.then(|| log_allowed(state, &format!("rate:{}", key), false)).flatten() {
+            warn!("🛑 Rate limit exceeded [Tenant: {} User: {}]{}", body.tenant_id, user, suppressed);
+        }
+    }
+
+    // Redes de confianza: se permite siempre (salvo blocklist manual),
+    // pero dejamos constancia de lo suprimido
+    if blocked.is_none() && cfg.allowlist.contains(&body.ip_address) {
+        let suppressed = (score > 0.0 || action != Action::Allow)
+            .then(|| log_allowed(state, &format!("allowlist:{}", key), false))
+            .flatten();
+        if let Some(suppressed) = suppressed {
+            info!(
+                "✅ Allowlist suppressed [Tenant: {} User: {} IP: {}]: would have been {} (score {}, {:?}){}",
+                body.tenant_id, user, body.ip_address, action, score, AnomalyReason::to_strings(&anomalies), suppressed
+            );
+        }
+        score = 0.0;
+        anomalies.clear();
+        // Los factores se conservan: /explain muestra lo que la allowlist anuló
+        factors.push(ScoreFactor::new("allowlist", body.ip_address.clone(), 0.0, true));
+        risk_level = determine_risk_level(score, &risk_thresholds);
+        action = Action::Allow;
+    }
+
+    // Shadow mode (global o durante la rampa del tenant): se calcula todo, pero nunca
+    // se aplica. La decisión real viaja como "shadow_action" para medir falsos positivos
+    let mut shadow_action = None;
+    let ramp = tenant_ramp(state, &body.tenant_id, live, Utc::now()).await;
+    if state.shadow_mode || ramp.as_ref().is_some_and(|r| r.observing) {
+        let suppressed = (live && action != Action::Allow)
+            .then(|| log_allowed(state, &format!("shadow:{}", key), risk_level == "critical"))
+            .flatten();
+        if let Some(suppressed) = suppressed {
+            let mode = if state.shadow_mode { "Shadow mode" } else { "Tenant ramp" };
+            info!(
+                "👻 {} [Tenant: {} User: {}]: would have returned {} (score {}){}",
+                mode, body.tenant_id, user, action, score, suppressed
+            );
+        }
+        if live {
+            state.metrics.record_shadow(action);
+        }
+        shadow_action = Some(action);
+        action = Action::Allow;
+    }
+
+    // Un reincidente al que el motor ya exige MFA (o aislar) no baja a un CAPTCHA
+    let recommendation = (action == Action::Challenge).then(|| {
+        let escalated = matches!(engine_recommendation.as_deref(), Some("REQUIRE_MFA" | "ISOLATE_SESSION"));
+        let level = match risk_level.as_str() {
+            "low" | "medium" if escalated => "high",
+            level => level,
+        };
+        state.challenge_types.for_level(level).to_string()
+    });
+
+    let response = AnomalyResponse {
+        engine_version: ENGINE_VERSION,
+        ruleset_version: state.ruleset_version.read().map(|v| v.clone()).unwrap_or_default(),
+        anomaly_score: score,
+        anomalies,
+        risk_level,
+        action,
+        recommendation,
+        confidence: confidence as f32,
+        retry_after_secs: (action == Action::Block && rate_limited)
+            .then(|| state.rate_limiter.retry_after(&key).map_or(RATE_LIMIT_WINDOW_SECS, |d| d.as_secs() + 1)),
+        shadow_action,
+        degraded: false,
+        processing_time_ms: started.elapsed().as_millis() as u64,
+        explanation: (!live).then_some(Explanation { raw_score, factors }),
+    };
+
+    // Una simulación no deja rastro: ni métricas, ni auditoría, ni stream
+    if !live {
+        return Ok(response);
+    }
+
+    state.metrics.record(score, action);
+    state.trends.record(&body.tenant_id, action, Utc::now());
+
+    let record = AuditRecord {
+        timestamp: Utc::now(),
+        tenant_id: &body.tenant_id,
+        user_id: body.user_id,
+        hashed_id: body.hashed_id.as_deref(),
+        ip_address: &body.ip_address,
+        action,
+        risk_level: &response.risk_level,
+        anomaly_score: score,
+        anomalies: &response.anomalies,
+        shadow_action,
+        ruleset_version: &response.ruleset_version,
+    };
+    state.audit.write(&record);
+
+    // send() nunca bloquea; sin suscriptores simplemente devuelve Err
+    if score >= state.stream_min_score && state.stream_tx.receiver_count() > 0 {
+        if let Ok(json) = serde_json::to_string(&record) {
+            let _ = state.stream_tx.send(json);
+        }
+    }
+
+    // Los críticos se registran siempre; el resto, como mucho uno por cliente y ventana
+    let suppressed = (score > 0.0)
+        .then(|| log_allowed(state, &format!("anomaly:{}", key), response.risk_level == "critical"))
+        .flatten();
+    if let Some(suppressed) = suppressed {
+        info!(
+            "⚠️ Anomaly detected [Tenant: {} User: {}]: Score: {}, Risk: {}{}",
+            body.tenant_id, user, score, response.risk_level, suppressed
+        );
+    }
+
+    Ok(response)
+}
+
+async fn update_baseline(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut body: web::Json<AnomalyRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    apply_forwarded_ip(&req, &state, &mut body);
+    pseudonymize_ip(&state, &mut body);
+
+    if let Some(zone) = body.timezone.as_deref().filter(|zone| !timezone::is_known(zone)) {
+        return unknown_timezone_response(zone);
+    }
+
+    if let Err(e) = body.check_subject() {
+        return detector_error_response(&e);
+    }
+
+    let key = baseline_key(&body.tenant_id, &body.subject());
+    let now = Utc::now();
+    let timezone = body.timezone.clone().or_else(|| stored_timezone(&state, &key));
+    let pending = PendingBaseline {
+        user_id: body.user_id,
+        hashed_id: body.hashed_id.clone(),
+        tenant_id: body.tenant_id.clone(),
+        timezone: body.timezone.clone(),
+        observations: vec![BaselineObservation {
+            at: now,
+            hour: local_hour(&state, timezone.as_deref(), &body.tenant_id, now),
+            country: extract_country(&body.ip_address, state.geoip.as_deref()),
+            user_agent: normalize_user_agent(&body.user_agent, state.live_config().weights.user_agent_mode),
+            endpoint: body.endpoint.clone(),
+            count: 1,
+        }],
+    };
+
+    // En ráfaga solo una petición por ventana escribe; el resto lo aplica el flusher
+    match state.baseline_writes.push(&key, pending) {
+        Some(batch) => {
+            apply_baseline(&state, &key, batch).await;
+            HttpResponse::Ok().json(serde_json::json!({ "status": "updated" }))
+        }
+        None => HttpResponse::Ok().json(serde_json::json!({ "status": "queued" })),
+    }
+}
+
+// Warm start de un tenant nuevo: aprende baselines de logs históricos sin puntuar
+// ni bloquear nada (mismos límites de memoria que /baseline)
+async fn import_baselines(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<Vec<ImportEvent>>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"}));
+    }
+
+    if body.len() > MAX_IMPORT_SIZE {
+        return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+            "error": format!("Import too large: {} events (max {})", body.len(), MAX_IMPORT_SIZE)
+        }));
+    }
+
+    if let Some(zone) = body
+        .iter()
+        .filter_map(|event| event.request.timezone.as_deref())
+        .find(|zone| !timezone::is_known(zone))
+    {
+        return unknown_timezone_response(zone);
+    }
+    if body.iter().any(|event| event.request.check_subject().is_err()) {
+        return missing_subject_response();
+    }
+
+    // Agrupar por usuario y ordenar por tiempo: el último evento define last_country
+    let now = Utc::now();
+    let mut skipped = 0;
+    let mut by_key: HashMap<String, PendingBaseline> = HashMap::new();
+    for ImportEvent { mut request, timestamp } in body.into_inner() {
+        // Un evento "futuro" falsearía last_login_at y el viaje imposible
+        if timestamp > now {
+            skipped += 1;
+            continue;
+        }
+        pseudonymize_ip(&state, &mut request);
+        let key = baseline_key(&request.tenant_id, &request.subject());
+        let pending = by_key.entry(key.clone()).or_insert_with(|| PendingBaseline {
+            user_id: request.user_id,
+            hashed_id: request.hashed_id.clone(),
+            tenant_id: request.tenant_id.clone(),
+            timezone: None,
+            observations: Vec::new(),
+        });
+        if request.timezone.is_some() {
+            pending.timezone = request.timezone.clone();
+        }
+        let timezone = pending.timezone.clone().or_else(|| stored_timezone(&state, &key));
+        pending.observations.push(BaselineObservation {
+            at: timestamp,
+            hour: local_hour(&state, timezone.as_deref(), &request.tenant_id, timestamp),
+            country: extract_country(&request.ip_address, state.geoip.as_deref()),
+            user_agent: normalize_user_agent(&request.user_agent, state.live_config().weights.user_agent_mode),
+            endpoint: request.endpoint,
+            count: 1,
+        });
+    }
+
+    let mut imported = 0;
+    let baselines = by_key.len();
+    for (key, mut pending) in by_key {
+        pending.observations.sort_by_key(|obs| obs.at);
+        imported += pending.observations.len();
+        apply_baseline(&state, &key, pending).await;
+    }
+
+    info!("📥 Imported {} historical events into {} baselines ({} skipped)", imported, baselines, skipped);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "imported",
+        "events": imported,
+        "baselines": baselines,
+        "skipped": skipped,
+    }))
+}
+
+// Aplica un lote de observaciones al baseline y lo persiste una sola vez
+async fn apply_baseline(state: &AppState, key: &str, batch: PendingBaseline) {
+    let PendingBaseline { user_id, hashed_id, tenant_id, timezone, observations } = batch;
+
+    // DashMap: Operación atómica de escritura/actualización
+    let mut entry = state.baselines.entry(key.to_string()).or_insert_with(|| UserBaseline {
+        user_id,
+        hashed_id,
+        tenant_id,
+        typical_countries: BTreeSet::new(),
+        typical_hours: HashMap::new(),
+        timezone: None,
+        known_user_agents: VecDeque::new(),
+        endpoints_history: VecDeque::new(),
+        last_updated: Utc::now(),
+        last_login_at: None,
+        last_country: None,
+        observations: 0,
+        recent_new_endpoints: VecDeque::new(),
+    });
+    // Las horas aprendidas en otra zona ya no son comparables: se reaprenden
+    if timezone.is_some() && entry.timezone != timezone {
+        entry.typical_hours.clear();
+        entry.timezone = timezone;
+    }
+    let window = state.live_config().weights.endpoint_history_window();
+    for obs in &observations {
+        record_observation(entry.value_mut(), obs, window);
+    }
+
+    // Persistir sin mantener el guard del DashMap durante el await
+    let snapshot = serde_json::to_string(entry.value());
+    drop(entry);
+    match snapshot {
+        Ok(raw) => {
+            if let Err(e) = state.detector.store().save_record(BASELINE_NAMESPACE, key, &raw).await {
+                warn!("Baseline not persisted for {}: {}", key, e);
+            }
+        }
+        Err(e) => warn!("Baseline not serializable for {}: {}", key, e),
+    }
+}
+
+fn record_observation(b: &mut UserBaseline, obs: &BaselineObservation, endpoint_window: chrono::Duration) {
+    // Actualizar datos existentes con límites de memoria
+    // Nunca se guardan valores vacíos: uno solo haría "conocido" a cualquier evento vacío
+    if b.typical_countries.len() < MAX_TYPICAL_COUNTRIES && !obs.country.trim().is_empty() {
+        b.typical_countries.insert(obs.country.clone());
+    }
+    // Horas: mapa de a lo sumo 24 entradas, las que dejan de usarse se podan al decaer
+    for _ in 0..obs.count {
+        record_hour(&mut b.typical_hours, obs.hour);
+    }
+    // Límite anti-DoS: solo los últimos UAs distintos; uno repetido pasa al final
+    // para que el navegador en uso no sea el primero en olvidarse
+    if !obs.user_agent.trim().is_empty() {
+        if let Some(idx) = b.known_user_agents.iter().position(|ua| *ua == obs.user_agent) {
+            b.known_user_agents.remove(idx);
+        }
+        b.known_user_agents.push_back(obs.user_agent.clone());
+        while b.known_user_agents.len() > MAX_KNOWN_USER_AGENTS {
+            b.known_user_agents.pop_front();
+        }
+    }
+
+    // Sliding window para endpoints: por antigüedad y con tope de MAX_ENDPOINT_HISTORY.
+    // Un endpoint repetido solo refresca su fecha (no desplaza a los demás)
+    if !obs.endpoint.trim().is_empty() {
+        let seen_at = match b.endpoints_history.iter().position(|(_, endpoint)| *endpoint == obs.endpoint) {
+            Some(idx) => b.endpoints_history.remove(idx).map_or(obs.at, |(at, _)| at.max(obs.at)),
+            None => obs.at,
+        };
+        let idx = b.endpoints_history.partition_point(|(at, _)| *at <= seen_at);
+        b.endpoints_history.insert(idx, (seen_at, obs.endpoint.clone()));
+        while b.endpoints_history.len() > MAX_ENDPOINT_HISTORY {
+            b.endpoints_history.pop_front();
+        }
+    }
+    prune_endpoints(b, Utc::now(), endpoint_window);
+
+    b.last_updated = b.last_updated.max(obs.at);
+    b.observations += u64::from(obs.count);
+    // Un evento más antiguo (import histórico) no reemplaza el último login conocido
+    if obs.country != UNKNOWN_COUNTRY && b.last_login_at.is_none_or(|last| obs.at >= last) {
+        b.last_country = Some(obs.country.clone());
+        b.last_login_at = Some(obs.at);
+    }
+}
+
+// Lo aprendido para un usuario: sirve para explicar falsos positivos
+async fn get_baseline(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<BaselineQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let Some(subject) = subject_id(query.user_id, query.hashed_id.as_deref()) else {
+        return missing_subject_response();
+    };
+    let key = baseline_key(&query.tenant_id, &subject);
+    match state.baselines.get(&key) {
+        Some(entry) => HttpResponse::Ok().json(entry.value()),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "error": "Profile not found" })),
+    }
+}
+
+async fn reset_baseline(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<ResetRequest>, // Uso de Struct tipado en lugar de JSON genérico
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let Some(subject) = subject_id(body.user_id, body.hashed_id.as_deref()) else {
+        return missing_subject_response();
+    };
+    let key = baseline_key(&body.tenant_id, &subject);
+
+    // Eliminación atómica
+    if state.baselines.remove(&key).is_some() {
+        if let Err(e) = state.detector.store().remove_record(BASELINE_NAMESPACE, &key).await {
+            warn!("Baseline removal not persisted for {}: {}", key, e);
+        }
+        info!("Baseline reset for user {}", key);
+        HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+    } else {
+        HttpResponse::NotFound().json(serde_json::json!({ "error": "Profile not found" }))
+    }
+}
+
+// Baja de un tenant (GDPR): borra todos sus baselines y perfiles, en memoria y persistidos
+async fn reset_tenant(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<TenantResetRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let tenant_id = body.tenant_id.as_str();
+    // Se compara el tenant completo de la clave: "acme" no arrastra las claves de "acme:eu"
+    let owned = |key: &str| split_composite_key(key).is_some_and(|(tenant, _)| tenant == tenant_id);
+
+    // Primero lo pendiente: un lote coalescido no debe recrear el baseline tras el borrado
+    state.baseline_writes.discard(owned);
+    let mut removed = BTreeSet::new();
+    state.baselines.retain(|key, _| {
+        if owned(key) {
+            removed.insert(key.clone());
+            return false;
+        }
+        true
+    });
+    for (key, _) in state.detector.store().load_records(BASELINE_NAMESPACE).await {
+        if owned(&key) {
+            removed.insert(key);
+        }
+    }
+    for key in &removed {
+        if let Err(e) = state.detector.store().remove_record(BASELINE_NAMESPACE, key).await {
+            warn!("Baseline removal not persisted for {}: {}", key, e);
+        }
+    }
+
+    let profiles = state.detector.purge_tenant(tenant_id).await;
+    state.trends.remove(tenant_id);
+    info!("🧹 Tenant {} reset: {} baselines, {} profiles deleted", tenant_id, removed.len(), profiles);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "deleted",
+        "tenant_id": tenant_id,
+        "baselines": removed.len(),
+        "profiles": profiles,
+    }))
+}
+
+async fn unblock_client(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<UnblockRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if state.detector.unblock(&body.tenant_id, &body.client_id).await {
+        HttpResponse::Ok().json(serde_json::json!({ "status": "unblocked" }))
+    } else {
+        HttpResponse::NotFound().json(serde_json::json!({ "error": "Profile not found" }))
+    }
+}
+
+async fn get_profile(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ProfileQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match state.detector.get_profile(&query.tenant_id, &query.client_id) {
+        Some(profile) => HttpResponse::Ok().json(profile),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "error": "Profile not found" })),
+    }
+}
+
+// Sparkline del dashboard: decisiones por hora y acción, la hora más antigua primero
+async fn detection_trends(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<TrendsQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if !(1..=TREND_RING_HOURS).contains(&query.hours) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("hours must be within 1–{} (got {})", TREND_RING_HOURS, query.hours),
+            "code": "invalid_field",
+        }));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "tenant_id": query.tenant_id,
+        "hours": query.hours,
+        "buckets": state.trends.buckets(&query.tenant_id, query.hours, Utc::now()),
+    }))
+}
+
+async fn tenant_stats(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<StatsQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let stats = state.detector.tenant_stats();
+    let limit = query.limit.unwrap_or(DEFAULT_STATS_PAGE).clamp(1, MAX_STATS_PAGE);
+    let now = Utc::now();
+    // Con rampa configurada cada tenant muestra si sigue observando y hasta cuándo
+    let page: Vec<serde_json::Value> = stats
+        .iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|s| {
+            let mut entry = serde_json::to_value(s).unwrap_or_default();
+            let first_observed = state.tenant_first_seen.get(&s.tenant_id).map(|e| *e.value());
+            if let (Some(ramp), Some(first_observed), Some(fields)) = (state.tenant_ramp, first_observed, entry.as_object_mut()) {
+                let ramp = TenantRamp::new(first_observed, ramp, now);
+                fields.insert("ramp".to_string(), serde_json::to_value(ramp).unwrap_or_default());
+            }
+            entry
+        })
+        .collect();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "total_tenants": stats.len(),
+        "offset": query.offset,
+        "limit": limit,
+        "tenants": page,
+    }))
+}
+
+// Respuesta a incidentes: todos los clientes comprometidos ahora mismo
+async fn list_compromised(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<CompromisedQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let clients = state.detector.compromised_clients(query.tenant_id.as_deref());
+    let limit = query.limit.unwrap_or(DEFAULT_STATS_PAGE).clamp(1, MAX_STATS_PAGE);
+    let page: Vec<_> = clients.iter().skip(query.offset).take(limit).collect();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "total_compromised": clients.len(),
+        "offset": query.offset,
+        "limit": limit,
+        "clients": page,
+    }))
+}
+
+// Consulta de cumplimiento ("detecciones Critical del tenant X en 90 días") sobre el histórico
+async fn score_history(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<HistoryQuery>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let Some(path) = state.score_history_path.clone() else {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Score history not configured" }));
+    };
+
+    let HistoryQuery { tenant_id, level, since, until, limit } = query.into_inner();
+    let limit = limit.unwrap_or(DEFAULT_STATS_PAGE).clamp(1, MAX_STATS_PAGE);
+    let filter = ScoreQuery { tenant_id, level, since, until };
+    // El fichero se recorre entero: fuera del runtime async
+    let result = web::block(move || history::query_jsonl(&path, &filter, limit)).await;
+    match result {
+        Ok(Ok(scores)) => HttpResponse::Ok().json(serde_json::json!({
+            "limit": limit,
+            "scores": scores,
+        })),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            HttpResponse::Ok().json(serde_json::json!({ "limit": limit, "scores": [] }))
+        }
+        Ok(Err(e)) => {
+            warn!("Score history query failed: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Score history unavailable" }))
+        }
+        Err(e) => {
+            warn!("Score history query aborted: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Score history unavailable" }))
+        }
+    }
+}
+
+// Snapshot completo (perfiles + baselines) en NDJSON para DR o migración de región.
+// Solo se copian las claves; cada entrada se serializa al enviarla, así la memoria
+// no crece con el tamaño del mapa. Las entradas borradas durante el export se omiten
+async fn export_snapshot(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let detector = state.detector.clone();
+    let profiles = tokio_stream::iter(detector.profile_keys()).filter_map(move |(tenant_id, client_id)| {
+        let profile = detector.get_profile(&tenant_id, &client_id)?;
+        snapshot_line(&SnapshotRecord::Profile { profile })
+    });
+    let baselines_map = state.baselines.clone();
+    let baseline_keys: Vec<String> = state.baselines.iter().map(|r| r.key().clone()).collect();
+    let baselines = tokio_stream::iter(baseline_keys).filter_map(move |key| {
+        let baseline = baselines_map.get(&key)?.value().clone();
+        snapshot_line(&SnapshotRecord::Baseline { baseline })
+    });
+
+    info!("📤 Snapshot export started");
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(profiles.chain(baselines).map(Ok::<_, actix_web::Error>))
+}
+
+fn snapshot_line(record: &SnapshotRecord) -> Option<web::Bytes> {
+    match serde_json::to_vec(record) {
+        Ok(mut line) => {
+            line.push(b'\n');
+            Some(web::Bytes::from(line))
+        }
+        Err(e) => {
+            warn!("Snapshot record not serializable: {}", e);
+            None
+        }
+    }
+}
+
+// Ingiere un snapshot de /export leyendo el body por trozos (nunca entero en memoria).
+// Las líneas inválidas se saltan y se informan; el resto se aplica y persiste
+async fn import_snapshot(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut counts = SnapshotCounts::default();
+    let mut line_no = 0;
+    loop {
+        let chunk = match payload.next().await {
+            Some(Ok(chunk)) => Some(chunk),
+            Some(Err(e)) => {
+                warn!("Snapshot import aborted at line {}: {}", line_no, e);
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Payload error after line {}: {}", line_no, e),
+                    "profiles": counts.profiles,
+                    "baselines": counts.baselines,
+                }));
+            }
+            None => None,
+        };
+        let Some(chunk) = chunk else {
+            // Última línea sin '\n' final
+            if !buffer.is_empty() {
+                line_no += 1;
+                import_snapshot_line(&state, &buffer, line_no, &mut counts).await;
+            }
+            break;
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            line_no += 1;
+            import_snapshot_line(&state, &line[..end], line_no, &mut counts).await;
+        }
+        if buffer.len() > MAX_SNAPSHOT_LINE {
+            return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                "error": format!("Line {} exceeds {} bytes", line_no + 1, MAX_SNAPSHOT_LINE),
+                "profiles": counts.profiles,
+                "baselines": counts.baselines,
+            }));
+        }
+    }
+
+    info!(
+        "📥 Snapshot import: {} profiles, {} baselines, {} invalid lines",
+        counts.profiles, counts.baselines, counts.invalid
+    );
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "imported",
+        "profiles": counts.profiles,
+        "baselines": counts.baselines,
+        "invalid": counts.invalid,
+        "errors": counts.errors,
+    }))
+}
+
+#[derive(Default)]
+struct SnapshotCounts {
+    profiles: usize,
+    baselines: usize,
+    invalid: usize,
+    errors: Vec<String>,
+}
+
+impl SnapshotCounts {
+    fn reject(&mut self, line_no: usize, reason: impl std::fmt::Display) {
+        self.invalid += 1;
+        if self.errors.len() < MAX_SNAPSHOT_ERRORS {
+            self.errors.push(format!("line {}: {}", line_no, reason));
+        }
+    }
+}
+
+async fn import_snapshot_line(state: &AppState, line: &[u8], line_no: usize, counts: &mut SnapshotCounts) {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return;
+    }
+    match serde_json::from_slice::<SnapshotRecord>(line) {
+        Ok(SnapshotRecord::Profile { profile }) => match state.detector.import_profile(profile).await {
+            Ok(()) => counts.profiles += 1,
+            Err(e) => counts.reject(line_no, e),
+        },
+        Ok(SnapshotRecord::Baseline { mut baseline }) => {
+            drop_blank_entries(&mut baseline);
+            let Some(subject) = subject_id(baseline.user_id, baseline.hashed_id.as_deref()) else {
+                counts.reject(line_no, "baseline without user_id or hashed_id");
+                return;
+            };
+            let key = baseline_key(&baseline.tenant_id, &subject);
+            let raw = serde_json::to_string(&baseline);
+            state.baselines.insert(key.clone(), baseline);
+            match raw {
+                Ok(raw) => {
+                    if let Err(e) = state.detector.store().save_record(BASELINE_NAMESPACE, &key, &raw).await {
+                        warn!("Baseline not persisted for {}: {}", key, e);
+                    }
+                }
+                Err(e) => warn!("Baseline not serializable for {}: {}", key, e),
+            }
+            counts.baselines += 1;
+        }
+        Err(e) => counts.reject(line_no, e),
+    }
+}
+
+async fn list_blocklist(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let entries: Vec<BlocklistEntry> = state.blocklist.iter().map(|r| r.value().clone()).collect();
+    HttpResponse::Ok().json(entries)
+}
+
+async fn add_blocklist(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<BlocklistRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let value = body.value.trim().to_string();
+    if value.is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Empty blocklist value" }));
+    }
+
+    let entry = BlocklistEntry {
+        kind: body.kind,
+        value,
+        reason: body.reason.clone(),
+        created_at: Utc::now(),
+    };
+
+    // Persistir primero: si el store no la acepta no se da por buena (solo un store
+    // durable, ver ANOMALY_REDIS_URL, la conserva tras un reinicio)
+    let raw = match serde_json::to_string(&entry) {
+        Ok(raw) => raw,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() })),
+    };
+    if let Err(e) = state.detector.store().save_record(BLOCKLIST_NAMESPACE, &blocklist_record_key(entry.kind, &entry.value), &raw).await {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": format!("Store unavailable: {}", e) }));
+    }
+
+    info!("⛔ Blocklist add {:?} {}", entry.kind, entry.value);
+    state.blocklist.insert((entry.kind, entry.value.clone()), entry);
+    HttpResponse::Ok().json(serde_json::json!({ "status": "added" }))
+}
+
+async fn remove_blocklist(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<BlocklistRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let value = body.value.trim().to_string();
+    if state.blocklist.remove(&(body.kind, value.clone())).is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Entry not found" }));
+    }
+    if let Err(e) = state.detector.store().remove_record(BLOCKLIST_NAMESPACE, &blocklist_record_key(body.kind, &value)).await {
+        warn!("Blocklist removal not persisted for {}: {}", value, e);
+    }
+
+    info!("Blocklist remove {:?} {}", body.kind, value);
+    HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+}
+
+async fn get_thresholds(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(state.detector.thresholds().await)
+}
+
+// Umbrales globales en caliente (p.ej. endurecer rate_limit durante una campaña).
+// Body: {"rate_limit": 30}. Las claves omitidas conservan su valor
+async fn set_thresholds(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<HashMap<String, f64>>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match state.detector.set_thresholds(&body).await {
+        Ok(thresholds) => {
+            warn!("🎚️ Global thresholds updated at runtime: {:?}", body.0);
+            refresh_ruleset_version(&state).await;
+            HttpResponse::Ok().json(thresholds)
+        }
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({ "error": e, "code": "invalid_field" })),
+    }
+}
+
+// Body: {"rate_limit": 50, "score_multiplier": 1.5}. Se valida todo antes de aplicar nada
+async fn set_tenant_thresholds(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<HashMap<String, f64>>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let tenant_id = path.into_inner();
+    if let Some(e) = body.iter().find_map(|(key, value)| validate_threshold(key, *value).err()) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": e, "code": "invalid_field" }));
+    }
+
+    for (key, value) in body.iter() {
+        if let Err(e) = state.detector.set_tenant_threshold(&tenant_id, key, *value).await {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e }));
+        }
+    }
+
+    info!("Thresholds updated for tenant {}: {:?}", tenant_id, body.0);
+    refresh_ruleset_version(&state).await;
+    HttpResponse::Ok().json(state.detector.effective_thresholds(&tenant_id).await)
+}
+
+// Body de PUT /patterns y /tenants/{id}/patterns: {"disabled": ["TimingAttack"]}
+#[derive(Deserialize)]
+struct PatternToggles {
+    disabled: HashSet<BehaviorPattern>,
+}
+
+async fn get_patterns(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (disabled, tenants) = state.detector.disabled_patterns();
+    HttpResponse::Ok().json(serde_json::json!({ "disabled": disabled, "tenants": tenants }))
+}
+
+async fn set_patterns(req: HttpRequest, state: web::Data<AppState>, body: web::Json<PatternToggles>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    warn!("🧩 Globally disabled patterns updated at runtime: {:?}", body.disabled);
+    state.detector.set_disabled_patterns(None, body.into_inner().disabled);
+    refresh_ruleset_version(&state).await;
+    let (disabled, tenants) = state.detector.disabled_patterns();
+    HttpResponse::Ok().json(serde_json::json!({ "disabled": disabled, "tenants": tenants }))
+}
+
+// Efectivo para el tenant: los globales más los suyos
+async fn get_tenant_patterns(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (enabled, disabled) = state.detector.pattern_toggles(&path);
+    HttpResponse::Ok().json(serde_json::json!({ "enabled": enabled, "disabled": disabled }))
+}
+
+// {"disabled": []} devuelve el tenant a la configuración global
+async fn set_tenant_patterns(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<PatternToggles>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let tenant_id = path.into_inner();
+    info!("🧩 Disabled patterns for tenant {}: {:?}", tenant_id, body.disabled);
+    state.detector.set_disabled_patterns(Some(&tenant_id), body.into_inner().disabled);
+    refresh_ruleset_version(&state).await;
+    let (enabled, disabled) = state.detector.pattern_toggles(&tenant_id);
+    HttpResponse::Ok().json(serde_json::json!({ "enabled": enabled, "disabled": disabled }))
+}
+
+#[derive(Deserialize)]
+struct MetadataRules {
+    rules: Vec<MetadataRule>,
+}
+
+async fn get_metadata_rules(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "rules": state.detector.metadata_rules(&path) }))
+}
+
+// {"rules": []} elimina las reglas del tenant
+async fn set_metadata_rules(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<MetadataRules>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let tenant_id = path.into_inner();
+    let rules = body.into_inner().rules;
+    let count = rules.len();
+    if let Err(e) = state.detector.set_metadata_rules(&tenant_id, rules) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": e, "code": "invalid_field" }));
+    }
+    info!("🧩 Metadata rules for tenant {}: {} declared", tenant_id, count);
+    refresh_ruleset_version(&state).await;
+    HttpResponse::Ok().json(serde_json::json!({ "rules": state.detector.metadata_rules(&tenant_id) }))
+}
+
+async fn list_vips(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "max_action": state.detector.vip_max_action(),
+        "vips": state.detector.vips(&path),
+    }))
+}
+
+// Body: {"client_id": "42", "reason": "CFO"}; client_id es el user_id (o hashed_id) de /detect
+async fn add_vip(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<VipRequest>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let client_id = body.client_id.trim().to_string();
+
+    let entry = VipEntry {
+        tenant_id: path.into_inner(),
+        client_id,
+        reason: body.reason.clone(),
+        added_at: Utc::now(),
+    };
+    match state.detector.add_vip(entry.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(entry),
+        Err(e) => detector_error_response(&e),
+    }
+}
+
+async fn remove_vip(req: HttpRequest, state: web::Data<AppState>, path: web::Path<(String, String)>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let (tenant_id, client_id) = path.into_inner();
+    if !state.detector.remove_vip(&tenant_id, &client_id).await {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": "Not a VIP" }));
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+}
+
+async fn get_work_hours(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match state.work_hours.get(path.as_str()) {
+        Some(hours) => HttpResponse::Ok().json(*hours.value()),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "error": "No work hours declared" })),
+    }
+}
+
+// Body: {"start": 8, "end": 18} en hora local del usuario (o del tenant)
+async fn set_work_hours(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<WorkHours>,
+) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": e, "code": "invalid_field" }));
+    }
+
+    let tenant_id = path.into_inner();
+    let raw = match serde_json::to_string(&body.0) {
+        Ok(raw) => raw,
+        Err(e) => return HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() })),
+    };
+    if let Err(e) = state.detector.store().save_record(WORK_HOURS_NAMESPACE, &tenant_id, &raw).await {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({ "error": format!("Store unavailable: {}", e) }));
+    }
+
+    info!("🗓️ Work hours for tenant {}: {}", tenant_id, body.0);
+    state.work_hours.insert(tenant_id, body.0);
+    HttpResponse::Ok().json(body.0)
+}
+
+async fn delete_work_hours(req: HttpRequest, state: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    if !is_authorized(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let tenant_id = path.into_inner();
+    if state.work_hours.remove(&tenant_id).is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": "No work hours declared" }));
+    }
+    if let Err(e) = state.detector.store().remove_record(WORK_HOURS_NAMESPACE, &tenant_id).await {
+        warn!("Work hours removal not persisted for {}: {}", tenant_id, e);
+    }
+    info!("🗓️ Work hours removed for tenant {}", tenant_id);
+    HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+}
+
+// Registra el primer evento en vivo del tenant (se persiste una sola vez) y devuelve su
+// rampa vista en `now`; None sin rampa configurada. Una simulación de un tenant nuevo no deja rastro
+async fn tenant_ramp(state: &AppState, tenant_id: &str, live: bool, now: DateTime<Utc>) -> Option<TenantRamp> {
+    let known = state.tenant_first_seen.get(tenant_id).map(|entry| *entry.value());
+    let first_observed = match known {
+        Some(first_observed) => first_observed,
+        None if live => {
+            let mut inserted = false;
+            let first_observed = *state.tenant_first_seen.entry(tenant_id.to_string()).or_insert_with(|| {
+                inserted = true;
+                now
+            });
+            if inserted {
+                let raw = serde_json::to_string(&first_observed).unwrap_or_default();
+                if let Err(e) = state.detector.store().save_record(TENANT_FIRST_SEEN_NAMESPACE, tenant_id, &raw).await {
+                    warn!("First-seen time for tenant {} not persisted: {}", tenant_id, e);
+                }
+            }
+            first_observed
+        }
+        None => now,
+    };
+    Some(TenantRamp::new(first_observed, state.tenant_ramp?, now))
+}
+
+// Baselines persistidos en el store; migra las claves en formato antiguo y descarta
+// los registros corruptos
+async fn restore_baselines(store: &dyn ProfileStore) -> DashMap<String, UserBaseline> {
+    let baselines = DashMap::new();
+    for (stored_key, raw) in store.load_records(BASELINE_NAMESPACE).await {
+        match serde_json::from_str::<UserBaseline>(&raw) {
+            Ok(mut baseline) => {
+                if drop_blank_entries(&mut baseline) {
+                    info!("🧹 Blank entries dropped from baseline {}", stored_key);
+                }
+                // La clave sale del propio registro: los de tenants con ':' o '%' guardados
+                // con el formato sin escapar se migran a la clave nueva
+                let Some(subject) = subject_id(baseline.user_id, baseline.hashed_id.as_deref()) else {
+                    warn!("Ignoring baseline record {} without user_id or hashed_id", stored_key);
+                    continue;
+                };
+                let key = baseline_key(&baseline.tenant_id, &subject);
+                if key != stored_key {
+                    match store.save_record(BASELINE_NAMESPACE, &key, &raw).await {
+                        Ok(()) => {
+                            if let Err(e) = store.remove_record(BASELINE_NAMESPACE, &stored_key).await {
+                                warn!("Legacy baseline key {} not removed: {}", stored_key, e);
+                            }
+                            info!("🔑 Baseline key migrated: {} -> {}", stored_key, key);
+                        }
+                        Err(e) => warn!("Baseline key migration failed for {}: {}", stored_key, e),
+                    }
+                }
+                baselines.insert(key, baseline);
+            }
+            Err(e) => warn!("Ignoring corrupt baseline record {}: {}", stored_key, e),
+        }
+    }
+    baselines
+}
+
+// Entradas de la blocklist persistidas en el store (las corruptas se descartan)
+async fn restore_blocklist(store: &dyn ProfileStore) -> DashMap<(BlockKind, String), BlocklistEntry> {
+    let blocklist = DashMap::new();
+    for (_, raw) in store.load_records(BLOCKLIST_NAMESPACE).await {
+        match serde_json::from_str::<BlocklistEntry>(&raw) {
+            Ok(entry) => {
+                blocklist.insert((entry.kind, entry.value.clone()), entry);
+            }
+            Err(e) => warn!("Ignoring corrupt blocklist record: {}", e),
+        }
+    }
+    blocklist
+}
+
+fn blocklist_record_key(kind: BlockKind, value: &str) -> String {
+    match kind {
+        BlockKind::Ip => format!("ip:{}", value),
+        BlockKind::Client => format!("client:{}", value),
+    }
+}
+
+// Devuelve la razón del bloqueo si la IP o el cliente están en la blocklist
+// Los clientes se bloquean como "tenant:user" literal (user = user_id o hashed_id)
+fn blocklist_match(state: &AppState, body: &AnomalyRequest) -> Option<AnomalyReason> {
+    if state.blocklist.contains_key(&(BlockKind::Ip, body.ip_address.trim().to_string())) {
+        return Some(AnomalyReason::new("BLOCKLISTED_IP", "Blocklisted IP", 0.0));
+    }
+    if state.blocklist.contains_key(&(BlockKind::Client, format!("{}:{}", body.tenant_id, body.subject()))) {
+        return Some(AnomalyReason::new("BLOCKLISTED_CLIENT", "Blocklisted Client", 0.0));
+    }
+    None
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    api_key: String,
+}
+
+// Los navegadores no pueden enviar cabeceras en el handshake WebSocket:
+// la API key viaja como query param
+async fn stream_detections(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<StreamQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !state.api_keys.accepts(&query.api_key) {
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API Key"})));
+    }
+
+    let session = StreamSession {
+        rx: Some(state.stream_tx.subscribe()),
+        metrics: state.metrics.clone(),
+    };
+    ws::start(session, &req, payload)
+}
+
+// Sesión WebSocket de un dashboard: reenvía cada detección difundida
+struct StreamSession {
+    rx: Option<broadcast::Receiver<String>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Actor for StreamSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(rx) = self.rx.take() {
+            ctx.add_stream(BroadcastStream::new(rx));
+        }
+    }
+}
+
+impl StreamHandler<Result<String, BroadcastStreamRecvError>> for StreamSession {
+    fn handle(&mut self, item: Result<String, BroadcastStreamRecvError>, ctx: &mut Self::Context) {
+        match item {
+            Ok(json) => ctx.text(json),
+            // El consumidor se quedó atrás: se descartan mensajes en vez de frenar al detector
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                self.metrics.stream_dropped.fetch_add(skipped, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for StreamSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => {}
+        }
+    }
+}
+
+// ==========================================
+// LOGICA DE NEGOCIO Y CALCULOS
+// ==========================================
+
+// Traduce una petición HTTP al evento del motor de patrones
+fn behavior_event(req: &AnomalyRequest, indicators: HashMap<String, f64>) -> BehaviorEvent {
+    BehaviorEvent {
+        tenant_id: req.tenant_id.clone(),
+        client_id: req.subject(),
+        timestamp: Utc::now(),
+        pattern: BehaviorPattern::Normal,
+        confidence: 1.0,
+        indicators,
+        // La del llamador más la IP de origen para los enriquecedores (reputación)
+        metadata: {
+            let mut metadata = req.metadata.clone();
+            metadata.insert(META_IP_ADDRESS.to_string(), req.ip_address.trim().to_string());
+            metadata
+        },
+        device_id: req.device_id.clone(),
+    }
+}
+
+// Indicadores derivados para analyze() (sin pisar los explícitos). Devuelve los patrones
+// cuyo peso ya está en el score del baseline (ubicación, enumeración)
+fn bridge_indicators(
+    weights: &ScoringWeights,
+    anomalies: &[AnomalyReason],
+    indicators: &mut HashMap<String, f64>,
+) -> Vec<BehaviorPattern> {
+    let has = |code: &str| anomalies.iter().any(|a| a.code == code);
+    let mut derived = Vec::new();
+    let mut bridged = Vec::new();
+
+    let location_risk = if has("IMPOSSIBLE_TRAVEL") {
+        Some(1.0)
+    } else if let Some(unusual) = anomalies.iter().find(|a| a.code == "UNUSUAL_LOCATION") {
+        let factor = if weights.location > 0.0 { (unusual.severity / weights.location) as f64 } else { 1.0 };
+        Some((BRIDGED_LOCATION_RISK * factor).min(1.0))
+    } else {
+        None
+    };
+    if let Some(risk) = location_risk {
+        derived.push((KEY_LOCATION_RISK, risk));
+        bridged.push(BehaviorPattern::AnomalousLocation);
+    }
+    if has("ENDPOINT_ENUMERATION") {
+        derived.push((KEY_ENUMERATION_SCORE, 1.0));
+        bridged.push(BehaviorPattern::Enumeration);
+    }
+    // Sin puente de inyección: buscar fragmentos ("/*", "../", "$(") en el endpoint o el
+    // user-agent marcaba rutas legítimas como PayloadInjection (crítico, 24h de bloqueo).
+    // La firma sigue activa para los BehaviorEvent con un `injection_score` calculado
+    // por quien sí inspecciona el payload
+
+    for (key, value) in derived {
+        indicators.entry(key.to_string()).or_insert(value);
+    }
+    bridged
+}
+
+fn calculate_anomaly_score(
+    req: &AnomalyRequest,
+    baseline: &mut UserBaseline,
+    weights: &ScoringWeights,
+    geoip: Option<&GeoIpReader>,
+    local_hour: u32,
+    work_hours: Option<WorkHours>,
+) -> ScoreBreakdown {
+    let mut score: f32 = 0.0;
+    let mut anomalies = Vec::new();
+    let mut factors = Vec::new();
+
+    // 1. Geo Check
+    // Una IP no parseable ("UNKNOWN") no aporta señal geográfica: sin penalización
+    let current_country = extract_country(&req.ip_address, geoip);
+    // Graduado: un país vecino suma una fracción de `location`, uno lejano o de riesgo el peso completo o más
+    let location_factor = weights.location_risk.factor(&current_country, &baseline.typical_countries);
+    let in_baseline = baseline.typical_countries.iter().any(|c| c.eq_ignore_ascii_case(&current_country));
+    if current_country != UNKNOWN_COUNTRY && location_factor > 0.0 {
+        let weight = weights.location * location_factor;
+        score += weight;
+        anomalies.push(AnomalyReason::new("UNUSUAL_LOCATION", "Unusual Location", weight).with_detail(current_country.clone()));
+        factors.push(ScoreFactor::new("location", current_country.clone(), weight, in_baseline));
+    } else {
+        factors.push(ScoreFactor::new("location", current_country.clone(), 0.0, in_baseline));
+    }
+
+    // 1b. Impossible Travel: mismo usuario, otro país, sin tiempo físico para llegar
+    if let (Some(last_country), Some(last_login)) = (&baseline.last_country, baseline.last_login_at) {
+        if *last_country != current_country {
+            let elapsed = Utc::now() - last_login;
+            if let Some(speed) = geo::required_speed_kmh(last_country, &current_country, elapsed) {
+                let route = format!("{} -> {}", last_country, current_country);
+                let impossible = speed > weights.max_travel_speed_kmh;
+                if impossible {
+                    score += weights.impossible_travel;
+                    anomalies.push(
+                        AnomalyReason::new("IMPOSSIBLE_TRAVEL", "Impossible Travel", weights.impossible_travel)
+                            .with_detail(route.clone()),
+                    );
+                }
+                let contribution = if impossible { weights.impossible_travel } else { 0.0 };
+                factors.push(
+                    ScoreFactor::new("travel_speed_kmh", route, contribution, false)
+                        .compared(speed, weights.max_travel_speed_kmh),
+                );
+            }
+        }
+    }
+
+    // 2. Time Check (en la zona del usuario: un 9-17 local no se desplaza con el offset UTC).
+    // Típicas = las aprendidas más el horario laboral declarado por el tenant
+    let declared = work_hours.is_some_and(|hours| hours.contains(local_hour));
+    let hour_weight = baseline.typical_hours.get(&local_hour).copied().unwrap_or(0.0);
+    let unusual_time = !declared && hour_weight < HOUR_TYPICAL_MIN;
+    if unusual_time {
+        score += weights.time;
+        anomalies.push(AnomalyReason::new("UNUSUAL_TIME", "Unusual Time", weights.time));
+    }
+    factors.push(
+        ScoreFactor::new("time", format!("{:02}:00", local_hour), if unusual_time { weights.time } else { 0.0 }, !unusual_time)
+            .compared(hour_weight, HOUR_TYPICAL_MIN),
+    );
+
+    // 3. User Agent Check
+    // Se comparan UAs normalizados: una actualización menor del navegador no es un dispositivo nuevo
+    let user_agent = normalize_user_agent(&req.user_agent, weights.user_agent_mode);
+    let known_agent = baseline.known_user_agents.contains(&user_agent);
+    if !known_agent {
+        score += weights.user_agent;
+        anomalies.push(AnomalyReason::new("NEW_USER_AGENT", "New Device/Browser", weights.user_agent));
+    }
+    factors.push(ScoreFactor::new("user_agent", user_agent, if known_agent { 0.0 } else { weights.user_agent }, known_agent));
+
+    // 4. Endpoint Enumeration
+    // Unos pocos endpoints nuevos espaciados son exploración normal; muchos
+    // distintos dentro de la ventana son un escaneo
+    prune_endpoints(baseline, Utc::now(), weights.endpoint_history_window());
+    let known_endpoint = baseline.endpoints_history.iter().any(|(_, endpoint)| *endpoint == req.endpoint);
+    factors.push(ScoreFactor::new(
+        "endpoint",
+        req.endpoint.clone(),
+        if known_endpoint { 0.0 } else { weights.new_endpoint },
+        known_endpoint,
+    ));
+    if !known_endpoint {
+        score += weights.new_endpoint;
+
+        // Primer acceso a un endpoint privilegiado: señal propia, distinta de la enumeración
+        if let Some(pattern) = weights.sensitive_endpoints.matching(&req.endpoint) {
+            score += weights.sensitive_endpoint;
+            anomalies.push(
+                AnomalyReason::new("SENSITIVE_ENDPOINT", "Sensitive Endpoint Access", weights.sensitive_endpoint)
+                    .with_detail(pattern.to_string()),
+            );
+            factors.push(ScoreFactor::new("sensitive_endpoint", pattern.to_string(), weights.sensitive_endpoint, false));
+        }
+
+        let now = Utc::now();
+        let window = chrono::Duration::seconds(weights.enumeration_window_secs as i64);
+        let recent = &mut baseline.recent_new_endpoints;
+        while recent.front().is_some_and(|(at, _)| now - *at > window) {
+            recent.pop_front();
+        }
+        // Memoria acotada: basta con recordar hasta el umbral
+        if !recent.iter().any(|(_, endpoint)| *endpoint == req.endpoint)
+            && recent.len() < weights.enumeration_threshold as usize
+        {
+            recent.push_back((now, req.endpoint.clone()));
+        }
+        let enumerating = weights.enumeration_threshold > 0 && recent.len() >= weights.enumeration_threshold as usize;
+        if enumerating {
+            score += weights.endpoint_enumeration;
+            anomalies.push(
+                AnomalyReason::new("ENDPOINT_ENUMERATION", "Endpoint Enumeration", weights.endpoint_enumeration)
+                    .with_detail(format!("{} new endpoints in {}s", recent.len(), weights.enumeration_window_secs)),
+            );
+        }
+        factors.push(
+            ScoreFactor::new(
+                "new_endpoints_in_window",
+                format!("{}s", weights.enumeration_window_secs),
+                if enumerating { weights.endpoint_enumeration } else { 0.0 },
+                false,
+            )
+            .compared(recent.len() as f64, weights.enumeration_threshold as f64),
+        );
+    }
+
+    ScoreBreakdown { score, anomalies, factors }
+}
+
+// Cada observación envejece todas las horas y refuerza la actual
+fn record_hour(hours: &mut HashMap<u32, f64>, hour: u32) {
+    for weight in hours.values_mut() {
+        *weight *= HOUR_DECAY;
+    }
+    *hours.entry(hour).or_insert(0.0) += 1.0;
+    hours.retain(|_, weight| *weight >= HOUR_PRUNE);
+}
+
+// Texto con longitud máxima (en bytes): el error de serde acaba como "invalid_field"
+fn bounded<'de, D, const MAX: usize>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    if value.len() > MAX {
+        return Err(serde::de::Error::custom(format!("field exceeds {} bytes", MAX)));
+    }
+    Ok(value)
+}
+
+// Como bounded, pero un campo obligatorio vacío o solo con espacios es un 400: aceptarlo
+// metería "" en los conjuntos del baseline y lo daría por conocido en adelante
+fn required<'de, D, const MAX: usize>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = bounded::<D, MAX>(deserializer)?;
+    if value.trim().is_empty() {
+        return Err(serde::de::Error::custom("field must not be empty"));
+    }
+    Ok(value)
+}
+
+fn bounded_metadata<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let metadata = HashMap::<String, String>::deserialize(deserializer)?;
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(serde::de::Error::custom(format!("metadata exceeds {} entries", MAX_METADATA_ENTRIES)));
+    }
+    if metadata.iter().any(|(k, v)| k.len() > MAX_ID_LEN || v.len() > MAX_ID_LEN) {
+        return Err(serde::de::Error::custom(format!("metadata entries exceed {} bytes", MAX_ID_LEN)));
+    }
+    Ok(metadata)
+}
+
+fn bounded_opt<'de, D, const MAX: usize>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // Vacío o solo espacios cuenta como ausente
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if value.len() > MAX => Err(serde::de::Error::custom(format!("field exceeds {} bytes", MAX))),
+        Some(value) if value.trim().is_empty() => Ok(None),
+        value => Ok(value),
+    }
+}
+
+// Quita los valores vacíos que guardaban versiones anteriores; true si había alguno
+fn drop_blank_entries(b: &mut UserBaseline) -> bool {
+    let before = b.typical_countries.len() + b.known_user_agents.len() + b.endpoints_history.len();
+    b.typical_countries.retain(|country| !country.trim().is_empty());
+    b.known_user_agents.retain(|ua| !ua.trim().is_empty());
+    b.endpoints_history.retain(|(_, endpoint)| !endpoint.trim().is_empty());
+    before != b.typical_countries.len() + b.known_user_agents.len() + b.endpoints_history.len()
+}
+
+// Olvida los endpoints no usados dentro de la ventana (van ordenados por fecha)
+fn prune_endpoints(b: &mut UserBaseline, now: DateTime<Utc>, window: chrono::Duration) {
+    while b.endpoints_history.front().is_some_and(|(at, _)| now - *at > window) {
+        b.endpoints_history.pop_front();
+    }
+}
+
+// Acepta el formato antiguo (lista de endpoints sin fecha) al restaurar baselines:
+// se fechan ahora y caducan con la ventana como cualquier otro
+fn deserialize_endpoints<'de, D>(deserializer: D) -> Result<VecDeque<(DateTime<Utc>, String)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Endpoints {
+        Dated(VecDeque<(DateTime<Utc>, String)>),
+        Legacy(Vec<String>),
+    }
+
+    Ok(match Endpoints::deserialize(deserializer)? {
+        Endpoints::Dated(list) => list,
+        Endpoints::Legacy(list) => {
+            let now = Utc::now();
+            let mut dated = VecDeque::with_capacity(list.len());
+            for endpoint in list {
+                if !dated.iter().any(|(_, seen)| *seen == endpoint) {
+                    dated.push_back((now, endpoint));
+                }
+            }
+            dated
+        }
+    })
+}
+
+// Acepta el formato antiguo (lista de horas) al restaurar baselines persistidos.
+// Las claves llegan como texto: untagged no aplica la conversión de claves de serde_json
+fn deserialize_hours<'de, D>(deserializer: D) -> Result<HashMap<u32, f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hours {
+        Weighted(HashMap<String, f64>),
+        Legacy(Vec<u32>),
+    }
+
+    Ok(match Hours::deserialize(deserializer)? {
+        Hours::Weighted(map) => map
+            .into_iter()
+            .filter_map(|(hour, weight)| Some((hour.parse().ok()?, weight)))
+            .collect(),
+        Hours::Legacy(list) => list.into_iter().map(|hour| (hour, 1.0)).collect(),
+    })
+}
+
+const LAN_COUNTRY: &str = "LAN";
+const UNKNOWN_COUNTRY: &str = "UNKNOWN";
+
+// Hora local en la zona del usuario, la de su tenant o UTC
+fn local_hour(state: &AppState, user_timezone: Option<&str>, tenant_id: &str, at: DateTime<Utc>) -> u32 {
+    let zone = user_timezone.or_else(|| state.tenant_timezones.get(tenant_id).map(String::as_str));
+    timezone::local_hour(zone, at)
+}
+
+fn stored_timezone(state: &AppState, key: &str) -> Option<String> {
+    state.baselines.get(key).and_then(|baseline| baseline.timezone.clone())
+}
+
+fn unknown_timezone_response(zone: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": format!("Unknown IANA timezone: {}", zone),
+        "code": "invalid_field",
+    }))
+}
+
+fn missing_subject_response() -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": "user_id or hashed_id is required",
+        "code": "invalid_field",
+    }))
+}
+
+// Sufijo para la línea de log si se puede emitir (None = suprimida por LogThrottle)
+fn log_allowed(state: &AppState, key: &str, always: bool) -> Option<String> {
+    if always {
+        return Some(String::new());
+    }
+    state.log_throttle.allow(key).map(|suppressed| match suppressed {
+        0 => String::new(),
+        n => format!(" ({} similar lines suppressed)", n),
+    })
+}
+
+// Registra (o consulta, en simulación) la sesión y devuelve el salto junto a la IP y el país
+// actuales. Sin base GeoIP el país no se usa: el placeholder haría iguales a todas las IPs
+fn session_jump(
+    state: &AppState,
+    req: &AnomalyRequest,
+    session_id: &str,
+    mode: EvalMode,
+) -> Option<(SessionJump, IpAddr, Option<String>)> {
+    let ip: IpAddr = req.ip_address.trim().parse().ok()?;
+    let country = state
+        .geoip
+        .as_deref()
+        .map(|geoip| extract_country(&req.ip_address, Some(geoip)))
+        .filter(|country| country != UNKNOWN_COUNTRY && country != LAN_COUNTRY);
+    let jump = match mode {
+        EvalMode::Live => state.sessions.record(&req.tenant_id, session_id, ip, country.as_deref()),
+        EvalMode::Simulate => state.sessions.peek(&req.tenant_id, session_id, ip, country.as_deref()),
+    }?;
+    Some((jump, ip, country))
+}
+
+fn extract_country(ip: &str, geoip: Option<&GeoIpReader>) -> String {
+    let addr: IpAddr = match ip.trim().parse() {
+        Ok(addr) => addr,
+        Err(_) => return UNKNOWN_COUNTRY.to_string(),
+    };
+
+    if is_internal_ip(&addr) {
+        return LAN_COUNTRY.to_string();
+    }
+
+    // Sin base de datos configurada se mantiene el placeholder histórico
+    match geoip {
+        Some(reader) => reader.lookup(addr).unwrap_or_else(|| UNKNOWN_COUNTRY.to_string()),
+        None => "US".to_string(),
+    }
+}
+
+// Rangos privados, loopback y link-local (IPv4 e IPv6)
+fn is_internal_ip(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => is_internal_ipv4(v4),
+        IpAddr::V6(v6) => {
+            // IPv4 mapeada (::ffff:10.0.0.1) se evalúa como IPv4
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_internal_ipv4(&v4);
+            }
+            let first = v6.segments()[0];
+            v6.is_loopback()                  // ::1
+                || (first & 0xfe00) == 0xfc00 // fc00::/7 (ULA)
+                || (first & 0xffc0) == 0xfe80 // fe80::/10 (link-local)
+        }
+    }
+}
+
+fn is_internal_ipv4(v4: &Ipv4Addr) -> bool {
+    // is_private cubre 10.0.0.0/8, 172.16.0.0/12 y 192.168.0.0/16
+    v4.is_private() || v4.is_loopback() || v4.is_link_local()
+}
+
+// Mismos cortes que AnomalyDetector: el score ya llega normalizado a 0–1
+fn determine_risk_level(score: f32, thresholds: &RiskThresholds) -> String {
+    thresholds.risk_level(score as f64).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use anomaly_detector::{BreakerSettings, InMemoryProfileStore, UaNormalization};
+
+    const API_KEY: &str = "test-key";
+
+    // Estado como el de main() sobre `store`; dos estados con el mismo store simulan un reinicio
+    async fn test_state(config: SecurityConfig, store: impl ProfileStore + 'static) -> AppState {
+        let live_config = LiveConfig::from_config(&config);
+        let detector = Arc::new(AnomalyDetector::with_store(config.clone(), Box::new(store)));
+        detector.restore().await;
+        detector.restore_vips().await;
+        let ruleset_version = ruleset_version(&detector, &live_config).await;
+        AppState {
+            baselines: Arc::new(restore_baselines(detector.store()).await),
+            api_keys: Arc::new(ApiKeySet::parse(API_KEY)),
+            geoip: None,
+            tenant_timezones: Arc::new(HashMap::new()),
+            work_hours: Arc::new(DashMap::new()),
+            metrics: Arc::new(Metrics::new(&detector.risk_thresholds())),
+            trends: Arc::new(TrendCounter::new(MAX_TREND_TENANTS)),
+            blocklist: Arc::new(restore_blocklist(detector.store()).await),
+            detector,
+            rate_limiter: Arc::new(SlidingWindowLimiter::new(MAX_RATE_LIMIT_KEYS, std::time::Duration::from_secs(RATE_LIMIT_WINDOW_SECS))),
+            login_outcomes: Arc::new(LoginOutcomeTracker::new(
+                LOGIN_OUTCOME_SAMPLES,
+                LOGIN_OUTCOME_MIN_SAMPLES,
+                std::time::Duration::from_secs(LOGIN_OUTCOME_WINDOW_SECS),
+            )),
+            spray: Arc::new(SprayTracker::new(SPRAY_MAX_USERS, std::time::Duration::from_secs(SPRAY_WINDOW_SECS))),
+            sessions: Arc::new(SessionTracker::new(MAX_TRACKED_SESSIONS, std::time::Duration::from_secs(SESSION_TTL_SECS))),
+            audit: Arc::new(AuditLog::open(Some("/dev/null")).unwrap()),
+            log_throttle: Arc::new(LogThrottle::new(std::time::Duration::from_secs(LOG_THROTTLE_SECS))),
+            live_config: Arc::new(std::sync::RwLock::new(Arc::new(live_config))),
+            stream_tx: broadcast::channel(STREAM_CHANNEL_CAPACITY).0,
+            stream_min_score: DEFAULT_STREAM_MIN_SCORE,
+            shadow_mode: config.shadow_mode,
+            scoring_timeout: None,
+            tenant_ramp: None,
+            tenant_first_seen: Arc::new(DashMap::new()),
+            cold_start_action: config.cold_start_action,
+            trust_proxy: config.trust_proxy,
+            ip_salts: Arc::new(HashMap::new()),
+            ruleset_version: Arc::new(std::sync::RwLock::new(ruleset_version)),
+            challenge_types: Arc::new(config.challenge_types.clone()),
+            block_as_429: false,
+            responses: Arc::new(ResponseCache::new(
+                std::time::Duration::from_secs(EVENT_DEDUP_TTL_SECS),
+                EVENT_DEDUP_CAPACITY,
+            )),
+            baseline_writes: Arc::new(WriteCoalescer::new(
+                std::time::Duration::from_millis(BASELINE_COALESCE_MS),
+                BASELINE_COALESCE_MAX,
+            )),
+            started_at: std::time::Instant::now(),
+            score_history_path: None,
+        }
+    }
+
+    // Backend caído: no responde a nada
+    struct UnreachableStore;
+
+    #[async_trait::async_trait]
+    impl ProfileStore for UnreachableStore {
+        async fn load_all(&self) -> Vec<ClientProfile> {
+            Vec::new()
+        }
+        async fn load(&self, _: &str, _: &str) -> Option<ClientProfile> {
+            None
+        }
+        async fn save(&self, _: &ClientProfile) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+        async fn remove(&self, _: &str, _: &str) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+        async fn load_records(&self, _: &str) -> Vec<(String, String)> {
+            Vec::new()
+        }
+        async fn save_record(&self, _: &str, _: &str, _: &str) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+        async fn remove_record(&self, _: &str, _: &str) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+        async fn ping(&self) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+        fn is_durable(&self) -> bool {
+            true
+        }
+    }
+
+    fn post(uri: &str, body: serde_json::Value) -> actix_http::Request {
+        actix_test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("X-API-KEY", API_KEY))
+            .set_json(body)
+            .to_request()
+    }
+
+    fn get(uri: &str) -> actix_http::Request {
+        actix_test::TestRequest::get().uri(uri).insert_header(("X-API-KEY", API_KEY)).to_request()
+    }
+
+    fn login(user_id: i32, ip: &str) -> serde_json::Value {
+        serde_json::json!({
+            "user_id": user_id,
+            "tenant_id": "acme",
+            "ip_address": ip,
+            "user_agent": "Mozilla/5.0 (X11; Linux x86_64) Firefox/120.0",
+            "endpoint": "/login",
+        })
+    }
+
+    #[actix_web::test]
+    async fn blocklist_entries_survive_a_restart_on_a_durable_store() {
+        let store = InMemoryProfileStore::new();
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), store.clone()).await)).await;
+        let added = actix_test::call_service(&app, post("/api/v1/blocklist", serde_json::json!({ "kind": "ip", "value": "203.0.113.9" }))).await;
+        assert_eq!(added.status(), StatusCode::OK);
+
+        let restarted = actix_test::init_service(build_app(test_state(SecurityConfig::default(), store).await)).await;
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&restarted, post("/api/v1/detect", login(42, "203.0.113.9"))).await;
+        assert_eq!(body["action"], "BLOCK");
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&restarted, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert_ne!(body["action"], "BLOCK");
+    }
+
+    #[actix_web::test]
+    async fn baselines_survive_a_restart_on_a_durable_store() {
+        let store = InMemoryProfileStore::new();
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), store.clone()).await)).await;
+        let learned = actix_test::call_service(&app, post("/api/v1/baseline", login(42, "198.51.100.7"))).await;
+        assert_eq!(learned.status(), StatusCode::OK);
+
+        let restarted = actix_test::init_service(build_app(test_state(SecurityConfig::default(), store).await)).await;
+        let baseline: serde_json::Value =
+            actix_test::call_and_read_body_json(&restarted, get("/api/v1/baseline?tenant_id=acme&user_id=42")).await;
+        assert_eq!(baseline["observations"], 1);
+        assert_eq!(baseline["known_user_agents"].as_array().map(Vec::len), Some(1));
+        assert_eq!(baseline["endpoints_history"][0][1], "/login");
+    }
+
+    #[actix_web::test]
+    async fn health_is_503_when_the_store_is_unreachable() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), UnreachableStore).await)).await;
+        let response = actix_test::call_service(&app, actix_test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = actix_test::read_body_json(response).await;
+        assert_eq!(body["status"], "unhealthy");
+        assert_eq!(body["store"], "unreachable");
+
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let response = actix_test::call_service(&app, actix_test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Regla de metadata del tenant "acme": un login con auth_method=legacy_basic suma `weight`
+    // en el motor de patrones (el único aporte de un login sin baseline)
+    fn legacy_auth_rule(state: &AppState, weight: f64) {
+        let rule = MetadataRule {
+            id: "legacy_auth".to_string(),
+            field: "auth_method".to_string(),
+            operator: anomaly_detector::MetadataOperator::Equals,
+            value: Some("legacy_basic".to_string()),
+            weight,
+        };
+        state.detector.set_metadata_rules("acme", vec![rule]).unwrap();
+    }
+
+    fn legacy_login(user_id: i32, ip: &str) -> serde_json::Value {
+        let mut body = login(user_id, ip);
+        body["metadata"] = serde_json::json!({ "auth_method": "legacy_basic" });
+        body
+    }
+
+    // /explain de un login que solo puntúa por una regla de metadata del tenant (motor de patrones)
+    async fn explain_legacy_login(sensitivity: f64) -> serde_json::Value {
+        let config = SecurityConfig { sensitivity, ..SecurityConfig::default() };
+        let state = test_state(config, InMemoryProfileStore::new()).await;
+        legacy_auth_rule(&state, 0.5);
+        let app = actix_test::init_service(build_app(state)).await;
+        actix_test::call_and_read_body_json(&app, post("/api/v1/explain", legacy_login(42, "198.51.100.7"))).await
+    }
+
+    #[actix_web::test]
+    async fn sensitivity_scales_the_engine_contribution_once() {
+        let weights = ScoringWeights::default();
+        let mut scores = Vec::new();
+        for sensitivity in [0.2, 0.8, 1.0] {
+            let explained = explain_legacy_login(sensitivity).await;
+            let raw = explained["raw_score"].as_f64().unwrap();
+            // El aporte del motor llega sin escalar: el raw no depende de la sensibilidad
+            assert!((raw - 0.5 * weights.behavioral as f64).abs() < 1e-6, "raw {} at {}", raw, sensitivity);
+            let expected = apply_sensitivity(weights.normalize(raw as f32) as f64, sensitivity);
+            let score = explained["anomaly_score"].as_f64().unwrap();
+            assert!((score - expected).abs() < 1e-4, "score {} != {} at {}", score, expected, sensitivity);
+            scores.push(score);
+        }
+        assert!(scores[0] < scores[1] && scores[1] < scores[2], "not monotonic: {:?}", scores);
+        // ×0.25 y ×1.25 del neutro (0.8), no ×0.0625 y ×1.5625
+        assert!((scores[0] / scores[1] - 0.25).abs() < 1e-3);
+        assert!((scores[2] / scores[1] - 1.25).abs() < 1e-3);
+    }
+
+    #[actix_web::test]
+    async fn whatif_candidate_sensitivity_changes_pattern_driven_decisions() {
+        let state = test_state(SecurityConfig { sensitivity: 0.2, ..SecurityConfig::default() }, InMemoryProfileStore::new()).await;
+        legacy_auth_rule(&state, 1.0);
+        let app = actix_test::init_service(build_app(state)).await;
+        let request = serde_json::json!({ "sensitivity": 1.0, "events": [legacy_login(42, "198.51.100.7"), login(43, "198.51.100.8")] });
+
+        let report: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/whatif", request)).await;
+        assert_eq!(report["evaluated"], 2);
+        assert_eq!(report["current"]["ALLOW"], 2);
+        assert_eq!(report["candidate"]["BLOCK"], 1);
+        assert_eq!(report["transitions"]["ALLOW->BLOCK"], 1);
+        assert_eq!(report["changed"], 1);
+    }
+
+    #[actix_web::test]
+    async fn batch_preserves_order_and_isolates_a_malformed_element() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        actix_test::call_service(&app, post("/api/v1/blocklist", serde_json::json!({ "kind": "ip", "value": "203.0.113.9" }))).await;
+
+        let batch = serde_json::json!([
+            login(1, "198.51.100.7"),
+            { "user_id": "not-a-number", "tenant_id": "acme" },
+            login(2, "203.0.113.9"),
+            login(3, "198.51.100.8"),
+        ]);
+        let resp = actix_test::call_service(&app, post("/api/v1/detect/batch", batch)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Vec<serde_json::Value> = actix_test::read_body_json(resp).await;
+
+        assert_eq!(body.len(), 4);
+        assert_ne!(body[0]["action"], "BLOCK");
+        assert!(body[1]["error"].as_str().unwrap().starts_with("Invalid event"), "{}", body[1]);
+        assert!(body[1].get("action").is_none());
+        assert_eq!(body[2]["action"], "BLOCK");
+        assert_ne!(body[3]["action"], "BLOCK");
+    }
+
+    #[actix_web::test]
+    async fn batch_takes_the_client_ip_from_trusted_proxies() {
+        let config = SecurityConfig { trust_proxy: 1, ..SecurityConfig::default() };
+        let app = actix_test::init_service(build_app(test_state(config, InMemoryProfileStore::new()).await)).await;
+        actix_test::call_service(&app, post("/api/v1/blocklist", serde_json::json!({ "kind": "ip", "value": "203.0.113.9" }))).await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/v1/detect/batch")
+            .insert_header(("X-API-KEY", API_KEY))
+            .insert_header(("X-Forwarded-For", "203.0.113.9"))
+            .set_json(serde_json::json!([login(1, "198.51.100.7"), login(2, "198.51.100.8")]))
+            .to_request();
+        let body: Vec<serde_json::Value> = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.len(), 2);
+        assert!(body.iter().all(|item| item["action"] == "BLOCK"), "{:?}", body);
+    }
+
+    fn internal(ip: &str) -> bool {
+        is_internal_ip(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_private_range_boundaries() {
+        // (primera y última dirección del rango, vecinos justo fuera)
+        for (first, last, below, above) in [
+            ("10.0.0.0", "10.255.255.255", "9.255.255.255", "11.0.0.0"),
+            ("172.16.0.0", "172.31.255.255", "172.15.255.255", "172.32.0.0"),
+            ("192.168.0.0", "192.168.255.255", "192.167.255.255", "192.169.0.0"),
+            ("127.0.0.0", "127.255.255.255", "126.255.255.255", "128.0.0.0"),
+            ("169.254.0.0", "169.254.255.255", "169.253.255.255", "169.255.0.0"),
+        ] {
+            assert!(internal(first), "{}", first);
+            assert!(internal(last), "{}", last);
+            assert!(!internal(below), "{}", below);
+            assert!(!internal(above), "{}", above);
+        }
+        // El prefijo textual "192." ya no basta para ser LAN
+        assert!(!internal("192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv6_private_range_boundaries() {
+        for (first, last, below, above) in [
+            ("fc00::", "fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "fbff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "fe00::"),
+            ("fe80::", "febf:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "fe7f:ffff:ffff:ffff:ffff:ffff:ffff:ffff", "fec0::"),
+        ] {
+            assert!(internal(first), "{}", first);
+            assert!(internal(last), "{}", last);
+            assert!(!internal(below), "{}", below);
+            assert!(!internal(above), "{}", above);
+        }
+        assert!(internal("::1"));
+        assert!(!internal("::2"));
+        assert!(!internal("2001:db8::1"));
+        // IPv4 mapeada: se aplican los rangos IPv4
+        assert!(internal("::ffff:10.0.0.1"));
+        assert!(internal("::ffff:172.31.255.255"));
+        assert!(!internal("::ffff:172.32.0.0"));
+    }
+
+    #[test]
+    fn unparseable_ips_are_unknown_not_lan() {
+        assert_eq!(extract_country("10.0.0.1", None), LAN_COUNTRY);
+        assert_eq!(extract_country(" fd00::1 ", None), LAN_COUNTRY);
+        assert_eq!(extract_country("10.0.0", None), UNKNOWN_COUNTRY);
+        assert_eq!(extract_country("192.not.an.ip", None), UNKNOWN_COUNTRY);
+        assert_eq!(extract_country("", None), UNKNOWN_COUNTRY);
+    }
+
+    fn parse_request(overrides: serde_json::Value) -> Result<AnomalyRequest, serde_json::Error> {
+        let mut request = login(42, "198.51.100.7");
+        request.as_object_mut().unwrap().remove("user_id");
+        for (field, value) in overrides.as_object().unwrap() {
+            request[field] = value.clone();
+        }
+        serde_json::from_value(request)
+    }
+
+    #[test]
+    fn check_subject_rejects_blank_ids_and_bounds_long_ones() {
+        for blank in ["", "   ", "\t\n"] {
+            let request = parse_request(serde_json::json!({ "hashed_id": blank })).unwrap();
+            assert!(request.check_subject().is_err(), "{:?}", blank);
+            // Un seudónimo en blanco cuenta como ausente: se usa el numérico
+            let request = parse_request(serde_json::json!({ "hashed_id": blank, "user_id": 7 })).unwrap();
+            assert!(request.check_subject().is_ok());
+            assert_eq!(request.subject(), "7");
+        }
+        assert!(parse_request(serde_json::json!({})).unwrap().check_subject().is_err());
+
+        let longest = "h".repeat(MAX_ID_LEN);
+        let request = parse_request(serde_json::json!({ "hashed_id": longest })).unwrap();
+        assert!(request.check_subject().is_ok());
+        assert_eq!(request.subject(), longest);
+        let err = parse_request(serde_json::json!({ "hashed_id": "h".repeat(MAX_ID_LEN + 1) })).err().unwrap();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+    }
+
+    #[test]
+    fn required_fields_reject_blank_and_oversized_values() {
+        for (field, max) in [
+            ("tenant_id", MAX_TENANT_ID_LEN),
+            ("ip_address", MAX_IP_LEN),
+            ("user_agent", MAX_USER_AGENT_LEN),
+            ("endpoint", MAX_ENDPOINT_LEN),
+        ] {
+            for blank in ["", "  \t "] {
+                let err = parse_request(serde_json::json!({ field: blank, "user_id": 1 })).err().unwrap();
+                assert!(err.to_string().contains("must not be empty"), "{}: {}", field, err);
+            }
+            assert!(parse_request(serde_json::json!({ field: "x".repeat(max), "user_id": 1 })).is_ok(), "{}", field);
+            let err = parse_request(serde_json::json!({ field: "x".repeat(max + 1), "user_id": 1 })).err().unwrap();
+            assert!(err.to_string().contains("exceeds"), "{}: {}", field, err);
+        }
+    }
+
+    fn empty_baseline() -> UserBaseline {
+        UserBaseline {
+            user_id: Some(42),
+            hashed_id: None,
+            tenant_id: "acme".to_string(),
+            typical_countries: BTreeSet::new(),
+            typical_hours: HashMap::new(),
+            timezone: None,
+            known_user_agents: VecDeque::new(),
+            endpoints_history: VecDeque::new(),
+            last_updated: Utc::now(),
+            last_country: None,
+            last_login_at: None,
+            observations: 0,
+            recent_new_endpoints: VecDeque::new(),
+        }
+    }
+
+    fn observation(country: &str, user_agent: &str, endpoint: &str) -> BaselineObservation {
+        BaselineObservation {
+            at: Utc::now(),
+            hour: 10,
+            country: country.to_string(),
+            user_agent: user_agent.to_string(),
+            endpoint: endpoint.to_string(),
+            count: 1,
+        }
+    }
+
+    #[test]
+    fn record_observation_never_stores_blank_values() {
+        let window = chrono::Duration::days(30);
+        let mut baseline = empty_baseline();
+        record_observation(&mut baseline, &observation("", "", ""), window);
+        record_observation(&mut baseline, &observation("   ", " \t ", "\n"), window);
+
+        assert!(baseline.typical_countries.is_empty());
+        assert!(baseline.known_user_agents.is_empty());
+        assert!(baseline.endpoints_history.is_empty());
+        // La observación cuenta (hora y madurez) aunque no aporte valores
+        assert_eq!(baseline.observations, 2);
+        assert!(baseline.typical_hours.contains_key(&10));
+    }
+
+    #[test]
+    fn record_observation_keeps_maximum_length_values_intact() {
+        let window = chrono::Duration::days(30);
+        let mut baseline = empty_baseline();
+        let user_agent = "U".repeat(MAX_USER_AGENT_LEN);
+        let endpoint = format!("/{}", "e".repeat(MAX_ENDPOINT_LEN - 1));
+        record_observation(&mut baseline, &observation("ES", &user_agent, &endpoint), window);
+        record_observation(&mut baseline, &observation("ES", &user_agent, &endpoint), window);
+
+        assert_eq!(baseline.known_user_agents, VecDeque::from([user_agent]));
+        assert_eq!(baseline.endpoints_history.len(), 1);
+        assert_eq!(baseline.endpoints_history[0].1, endpoint);
+        assert_eq!(baseline.typical_countries.len(), 1);
+    }
+
+    #[test]
+    fn scoring_weights_drive_the_additive_score() {
+        let request = parse_request(serde_json::json!({ "user_id": 42, "endpoint": "/reports" })).unwrap();
+        let defaults = ScoringWeights::default();
+        let base = calculate_anomaly_score(&request, &mut empty_baseline(), &defaults, None, 10, None);
+        // País, hora, UA y endpoint nuevos: cada señal aporta su peso
+        let codes: Vec<&str> = base.anomalies.iter().map(|a| a.code).collect();
+        assert!(codes.contains(&"UNUSUAL_TIME") && codes.contains(&"NEW_USER_AGENT"), "{:?}", codes);
+
+        let doubled = ScoringWeights {
+            location: defaults.location * 2.0,
+            time: defaults.time * 2.0,
+            user_agent: defaults.user_agent * 2.0,
+            new_endpoint: defaults.new_endpoint * 2.0,
+            ..defaults.clone()
+        };
+        let scaled = calculate_anomaly_score(&request, &mut empty_baseline(), &doubled, None, 10, None);
+        assert!((scaled.score - base.score * 2.0).abs() < 1e-4, "{} vs {}", scaled.score, base.score);
+
+        let silent = ScoringWeights { time: 0.0, user_agent: 0.0, ..defaults.clone() };
+        let quieter = calculate_anomaly_score(&request, &mut empty_baseline(), &silent, None, 10, None);
+        assert!((base.score - quieter.score - defaults.time - defaults.user_agent).abs() < 1e-4);
+    }
+
+    #[test]
+    fn user_agent_mode_decides_what_counts_as_a_new_device() {
+        let chrome = |version: &str| format!("Mozilla/5.0 (Windows NT 10.0) Chrome/{} Safari/537.36", version);
+        let weights = |mode: UaNormalization| ScoringWeights { user_agent_mode: mode, ..ScoringWeights::default() };
+        let window = chrono::Duration::days(30);
+        let new_device = |mode: UaNormalization, version: &str| {
+            let mut baseline = empty_baseline();
+            let learned = normalize_user_agent(&chrome("120.0.6099.110"), mode);
+            record_observation(&mut baseline, &observation("ES", &learned, "/login"), window);
+            let request = parse_request(serde_json::json!({ "user_id": 42, "user_agent": chrome(version) })).unwrap();
+            let breakdown = calculate_anomaly_score(&request, &mut baseline, &weights(mode), None, 10, None);
+            breakdown.anomalies.iter().any(|a| a.code == "NEW_USER_AGENT")
+        };
+
+        assert!(new_device(UaNormalization::Exact, "120.0.6099.217"));
+        assert!(!new_device(UaNormalization::MajorVersion, "120.0.6099.217"));
+        assert!(new_device(UaNormalization::MajorVersion, "121.0.6167.85"));
+        assert!(!new_device(UaNormalization::FamilyOnly, "121.0.6167.85"));
+    }
+
+    fn enumerating(request: &AnomalyRequest, baseline: &mut UserBaseline, weights: &ScoringWeights) -> bool {
+        calculate_anomaly_score(request, baseline, weights, None, 10, None)
+            .anomalies
+            .iter()
+            .any(|a| a.code == "ENDPOINT_ENUMERATION")
+    }
+
+    #[test]
+    fn a_burst_of_new_endpoints_is_enumeration_but_spread_exploration_is_not() {
+        let weights = ScoringWeights { enumeration_threshold: 5, enumeration_window_secs: 10, ..ScoringWeights::default() };
+        let probe = |i: usize| parse_request(serde_json::json!({ "user_id": 42, "endpoint": format!("/scan/{}", i) })).unwrap();
+
+        // Escaneo simulado: el quinto endpoint nuevo dentro de la ventana dispara la señal
+        let mut baseline = empty_baseline();
+        let flags: Vec<bool> = (0..8).map(|i| enumerating(&probe(i), &mut baseline, &weights)).collect();
+        assert_eq!(flags, [false, false, false, false, true, true, true, true]);
+        // Repetir un endpoint ya contado no infla la ráfaga
+        let mut baseline = empty_baseline();
+        for _ in 0..10 {
+            assert!(!enumerating(&probe(0), &mut baseline, &weights));
+        }
+
+        // Exploración espaciada: los accesos previos quedaron fuera de la ventana
+        let mut baseline = empty_baseline();
+        for i in 0..8 {
+            assert!(!enumerating(&probe(i), &mut baseline, &weights), "acceso {}", i);
+            for (at, _) in baseline.recent_new_endpoints.iter_mut() {
+                *at -= chrono::Duration::seconds(11);
+            }
+        }
+
+        // Umbral 0: la detección queda desactivada
+        let disabled = ScoringWeights { enumeration_threshold: 0, ..weights.clone() };
+        let mut baseline = empty_baseline();
+        assert!((0..8).all(|i| !enumerating(&probe(i), &mut baseline, &disabled)));
+    }
+
+    #[actix_web::test]
+    async fn malformed_bodies_get_the_json_error_envelope() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let raw = |content_type: &str, body: Vec<u8>| {
+            actix_test::TestRequest::post()
+                .uri("/api/v1/detect")
+                .insert_header(("X-API-KEY", API_KEY))
+                .insert_header(("Content-Type", content_type.to_string()))
+                .set_payload(body)
+                .to_request()
+        };
+        let mut wrong_type = login(1, "198.51.100.7");
+        wrong_type["user_id"] = serde_json::json!("not-a-number");
+        let cases = [
+            (raw("application/json", b"{\"user_id\": 1,".to_vec()), StatusCode::BAD_REQUEST, "malformed_json"),
+            (raw("application/json", wrong_type.to_string().into_bytes()), StatusCode::BAD_REQUEST, "invalid_field"),
+            (raw("text/plain", login(1, "198.51.100.7").to_string().into_bytes()), StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_content_type"),
+            (raw("application/json", vec![b' '; JSON_BODY_LIMIT + 1]), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+        ];
+
+        for (request, status, code) in cases {
+            let response = actix_test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "{}", code);
+            assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+            let body: serde_json::Value = actix_test::read_body_json(response).await;
+            assert_eq!(body["code"], code);
+            assert!(body["error"].as_str().is_some_and(|e| !e.is_empty()), "{}", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn every_rotating_api_key_is_accepted() {
+        let state = AppState {
+            api_keys: Arc::new(ApiKeySet::parse("key-nueva,key-antigua")),
+            ..test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await
+        };
+        let app = actix_test::init_service(build_app(state)).await;
+        let stats = |key: Option<&str>| {
+            let request = actix_test::TestRequest::get().uri("/api/v1/stats?tenant_id=acme");
+            match key {
+                Some(key) => request.insert_header(("X-API-KEY", key.to_string())),
+                None => request,
+            }
+            .to_request()
+        };
+
+        for key in ["key-nueva", "key-antigua"] {
+            assert!(actix_test::call_service(&app, stats(Some(key))).await.status().is_success(), "{}", key);
+        }
+        for rejected in [Some("key-retirada"), Some(""), None] {
+            assert_eq!(actix_test::call_service(&app, stats(rejected)).await.status(), StatusCode::UNAUTHORIZED, "{:?}", rejected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_logins_from_one_ip_against_many_users_raise_credential_spray() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let attempt = |user_id: i32| {
+            let mut body = login(user_id, "198.51.100.7");
+            body["login_success"] = serde_json::json!(false);
+            post("/api/v1/detect", body)
+        };
+        let spray_flagged = |response: &serde_json::Value| {
+            response["anomalies"].as_array().unwrap().iter().any(|a| a["code"] == "CREDENTIAL_SPRAY")
+        };
+
+        for user_id in 1..=SPRAY_MAX_USERS as i32 {
+            let response: serde_json::Value = actix_test::call_and_read_body_json(&app, attempt(user_id)).await;
+            assert!(!spray_flagged(&response), "usuario {}: {}", user_id, response);
+        }
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, attempt(SPRAY_MAX_USERS as i32 + 1)).await;
+        assert!(spray_flagged(&response), "{}", response);
+    }
+
+    fn codes(response: &serde_json::Value) -> Vec<String> {
+        response["anomalies"].as_array().unwrap().iter().map(|a| a["code"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[actix_web::test]
+    async fn cold_start_allows_by_default() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert_eq!(response["action"], "ALLOW");
+        assert_eq!(codes(&response), ["NEW_PROFILE"]);
+    }
+
+    #[actix_web::test]
+    async fn fail_secure_cold_start_challenges_until_a_baseline_exists() {
+        let config = SecurityConfig { cold_start_action: ColdStartAction::Challenge, ..SecurityConfig::default() };
+        let app = actix_test::init_service(build_app(test_state(config, InMemoryProfileStore::new()).await)).await;
+
+        let first: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert_eq!(first["action"], "CHALLENGE");
+        assert_eq!(codes(&first), ["NEW_PROFILE", "COLD_START"]);
+
+        actix_test::call_service(&app, post("/api/v1/baseline", login(42, "198.51.100.7"))).await;
+        let known: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert!(!codes(&known).iter().any(|code| code == "COLD_START" || code == "NEW_PROFILE"), "{}", known);
+    }
+
+    #[test]
+    fn first_access_to_a_sensitive_endpoint_is_its_own_signal() {
+        let weights = ScoringWeights::default();
+        let window = chrono::Duration::days(30);
+        let sensitive_hit = |baseline: &mut UserBaseline, endpoint: &str| {
+            let request = parse_request(serde_json::json!({ "user_id": 42, "endpoint": endpoint })).unwrap();
+            let breakdown = calculate_anomaly_score(&request, baseline, &weights, None, 10, None);
+            breakdown.anomalies.iter().find(|a| a.code == "SENSITIVE_ENDPOINT").map(|a| a.severity)
+        };
+
+        let mut baseline = empty_baseline();
+        assert_eq!(sensitive_hit(&mut baseline, "/admin/payroll"), Some(weights.sensitive_endpoint));
+        assert_eq!(sensitive_hit(&mut baseline, "/reports/monthly"), None);
+
+        // Un admin habitual no dispara la señal en sus endpoints conocidos
+        record_observation(&mut baseline, &observation("ES", "Firefox/120", "/admin/payroll"), window);
+        assert_eq!(sensitive_hit(&mut baseline, "/admin/payroll"), None);
+    }
+
+    fn observed_at(endpoint: &str, days_ago: i64) -> BaselineObservation {
+        BaselineObservation { at: Utc::now() - chrono::Duration::days(days_ago), ..observation("ES", "Firefox/120", endpoint) }
+    }
+
+    #[test]
+    fn endpoint_history_drops_entries_older_than_the_window() {
+        let window = chrono::Duration::days(30);
+        let mut baseline = empty_baseline();
+        for (endpoint, days_ago) in [("/old/reports", 90), ("/old/export", 31), ("/invoices", 20), ("/login", 0)] {
+            record_observation(&mut baseline, &observed_at(endpoint, days_ago), window);
+        }
+        let kept: Vec<&str> = baseline.endpoints_history.iter().map(|(_, endpoint)| endpoint.as_str()).collect();
+        assert_eq!(kept, ["/invoices", "/login"]);
+
+        // Volver a usar un endpoint renueva su fecha y lo mantiene en la ventana
+        record_observation(&mut baseline, &observed_at("/invoices", 0), window);
+        assert_eq!(baseline.endpoints_history.back().unwrap().1, "/invoices");
+
+        // El tope por número sigue vigente dentro de la ventana
+        for i in 0..MAX_ENDPOINT_HISTORY + 10 {
+            record_observation(&mut baseline, &observed_at(&format!("/page/{}", i), 0), window);
+        }
+        assert_eq!(baseline.endpoints_history.len(), MAX_ENDPOINT_HISTORY);
+    }
+
+    #[test]
+    fn endpoints_that_aged_out_count_as_new_when_scoring() {
+        let weights = ScoringWeights { endpoint_history_days: 30, ..ScoringWeights::default() };
+        let mut baseline = empty_baseline();
+        // Aprendido dentro de una ventana más amplia (p.ej. antes de reducirla)
+        record_observation(&mut baseline, &observed_at("/reports", 45), chrono::Duration::days(90));
+        assert_eq!(baseline.endpoints_history.len(), 1);
+
+        let request = parse_request(serde_json::json!({ "user_id": 42, "endpoint": "/reports" })).unwrap();
+        let breakdown = calculate_anomaly_score(&request, &mut baseline, &weights, None, 10, None);
+        let endpoint = breakdown.factors.iter().find(|f| f.factor == "endpoint").unwrap();
+        assert!(!endpoint.in_baseline);
+        assert!(baseline.endpoints_history.is_empty());
+    }
+
+    #[test]
+    fn declared_work_hours_are_a_prior_combined_with_learned_hours() {
+        let weights = ScoringWeights::default();
+        let office = Some(WorkHours { start: 8, end: 18 });
+        let request = parse_request(serde_json::json!({ "user_id": 42 })).unwrap();
+        let unusual = |baseline: &mut UserBaseline, hour: u32, hours: Option<WorkHours>| {
+            let breakdown = calculate_anomaly_score(&request, baseline, &weights, None, hour, hours);
+            breakdown.anomalies.iter().any(|a| a.code == "UNUSUAL_TIME")
+        };
+
+        // Perfil nuevo: dentro del horario nunca es inusual, fuera sí
+        assert!(!unusual(&mut empty_baseline(), 8, office));
+        assert!(!unusual(&mut empty_baseline(), 17, office));
+        assert!(unusual(&mut empty_baseline(), 18, office));
+        assert!(unusual(&mut empty_baseline(), 3, office));
+
+        // Unión con lo aprendido: una hora habitual fuera del horario no dispara
+        let mut night_owl = empty_baseline();
+        for _ in 0..5 {
+            record_observation(&mut night_owl, &BaselineObservation { hour: 22, ..observation("ES", "Firefox/120", "/login") }, chrono::Duration::days(30));
+        }
+        assert!(!unusual(&mut night_owl, 22, office));
+        assert!(unusual(&mut night_owl, 3, office));
+    }
+
+    #[actix_web::test]
+    async fn work_hours_api_validates_and_round_trips() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let uri = "/api/v1/tenants/acme/work-hours";
+        let put = |body: serde_json::Value| {
+            actix_test::TestRequest::put().uri(uri).insert_header(("X-API-KEY", API_KEY)).set_json(body).to_request()
+        };
+
+        let rejected = actix_test::call_service(&app, put(serde_json::json!({ "start": 9, "end": 9 }))).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(actix_test::call_service(&app, get(uri)).await.status(), StatusCode::NOT_FOUND);
+
+        let stored = actix_test::call_service(&app, put(serde_json::json!({ "start": 8, "end": 18 }))).await;
+        assert_eq!(stored.status(), StatusCode::OK);
+        let declared: serde_json::Value = actix_test::call_and_read_body_json(&app, get(uri)).await;
+        assert_eq!(declared, serde_json::json!({ "start": 8, "end": 18 }));
+
+        let delete = actix_test::TestRequest::delete().uri(uri).insert_header(("X-API-KEY", API_KEY)).to_request();
+        assert_eq!(actix_test::call_service(&app, delete).await.status(), StatusCode::OK);
+        assert_eq!(actix_test::call_service(&app, get(uri)).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    // Responde al ping pero no persiste perfiles
+    struct FailingSaves(InMemoryProfileStore);
+
+    #[async_trait::async_trait]
+    impl ProfileStore for FailingSaves {
+        async fn load_all(&self) -> Vec<ClientProfile> {
+            self.0.load_all().await
+        }
+        async fn load(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile> {
+            self.0.load(tenant_id, client_id).await
+        }
+        async fn save(&self, _: &ClientProfile) -> Result<(), String> {
+            Err("write failed".to_string())
+        }
+        async fn remove(&self, tenant_id: &str, client_id: &str) -> Result<(), String> {
+            self.0.remove(tenant_id, client_id).await
+        }
+        async fn load_records(&self, namespace: &str) -> Vec<(String, String)> {
+            self.0.load_records(namespace).await
+        }
+        async fn save_record(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+            self.0.save_record(namespace, key, value).await
+        }
+        async fn remove_record(&self, namespace: &str, key: &str) -> Result<(), String> {
+            self.0.remove_record(namespace, key).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn health_reports_an_open_store_breaker_as_degraded() {
+        let config = SecurityConfig {
+            circuit_breaker: BreakerSettings { failure_threshold: 2, cooldown_secs: 60, ..BreakerSettings::default() },
+            ..SecurityConfig::default()
+        };
+        let app = actix_test::init_service(build_app(test_state(config, FailingSaves(InMemoryProfileStore::new())).await)).await;
+        let health = || actix_test::TestRequest::get().uri("/health").to_request();
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, health()).await;
+        assert_eq!(body["status"], "healthy");
+        assert_eq!(body["circuit_breakers"][0]["name"], "profile_store");
+        assert_eq!(body["circuit_breakers"][0]["state"], "closed");
+
+        // La detección sigue respondiendo mientras el store falla (device_id: pasa por el motor)
+        for _ in 0..3 {
+            let mut body = login(42, "198.51.100.7");
+            body["device_id"] = serde_json::json!("laptop-1");
+            let response = actix_test::call_service(&app, post("/api/v1/detect", body)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, health()).await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["circuit_breakers"][0]["state"], "open");
+        assert!(body["circuit_breakers"][0]["retry_in_secs"].is_u64());
+    }
+
+    #[actix_web::test]
+    async fn patterns_can_be_disabled_per_tenant_at_runtime() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let put = |disabled: serde_json::Value| {
+            actix_test::TestRequest::put()
+                .uri("/api/v1/tenants/acme/patterns")
+                .insert_header(("X-API-KEY", API_KEY))
+                .set_json(serde_json::json!({ "disabled": disabled }))
+                .to_request()
+        };
+
+        let toggles: serde_json::Value = actix_test::call_and_read_body_json(&app, put(serde_json::json!(["TimingAttack"]))).await;
+        assert_eq!(toggles["disabled"], serde_json::json!(["TimingAttack"]));
+        assert!(!toggles["enabled"].as_array().unwrap().contains(&serde_json::json!("TimingAttack")));
+        let other: serde_json::Value = actix_test::call_and_read_body_json(&app, get("/api/v1/tenants/globex/patterns")).await;
+        assert!(other["enabled"].as_array().unwrap().contains(&serde_json::json!("TimingAttack")));
+
+        let unknown = actix_test::call_service(&app, put(serde_json::json!(["NotAPattern"]))).await;
+        assert_eq!(unknown.status(), StatusCode::BAD_REQUEST);
+        let restored: serde_json::Value = actix_test::call_and_read_body_json(&app, put(serde_json::json!([]))).await;
+        assert_eq!(restored["disabled"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn baseline_sets_respect_their_caps() {
+        let window = chrono::Duration::days(30);
+        let mut baseline = empty_baseline();
+        for country in ["ES", "FR", "PT", "DE", "IT", "NL", "ES", "BE"] {
+            record_observation(&mut baseline, &observation(country, "Firefox/120", "/login"), window);
+        }
+        // Sin duplicados y con los primeros aprendidos
+        assert_eq!(baseline.typical_countries, BTreeSet::from(["DE", "ES", "FR", "IT", "PT"].map(String::from)));
+        assert_eq!(baseline.typical_countries.len(), MAX_TYPICAL_COUNTRIES);
+
+        // Horas: a lo sumo 24 entradas, por muchas observaciones que haya
+        for hour in (0..24).cycle().take(24 * 20) {
+            record_observation(&mut baseline, &BaselineObservation { hour, ..observation("ES", "Firefox/120", "/login") }, window);
+        }
+        assert!(baseline.typical_hours.len() <= 24);
+        assert!(baseline.typical_hours.keys().all(|hour| *hour < 24));
+    }
+
+    #[test]
+    fn known_user_agents_keep_the_most_recently_used() {
+        let window = chrono::Duration::days(30);
+        let mut baseline = empty_baseline();
+        let agent = |i: usize| format!("Agent/{}", i);
+        for i in 0..MAX_KNOWN_USER_AGENTS {
+            record_observation(&mut baseline, &observation("ES", &agent(i), "/login"), window);
+        }
+        // Reusar el más antiguo lo pasa al final: no es el primero en olvidarse
+        record_observation(&mut baseline, &observation("ES", &agent(0), "/login"), window);
+        assert_eq!(baseline.known_user_agents.len(), MAX_KNOWN_USER_AGENTS);
+        assert_eq!(baseline.known_user_agents.back(), Some(&agent(0)));
+
+        record_observation(&mut baseline, &observation("ES", "Agent/new", "/login"), window);
+        assert_eq!(baseline.known_user_agents.len(), MAX_KNOWN_USER_AGENTS);
+        assert!(!baseline.known_user_agents.contains(&agent(1)), "el menos reciente sale");
+        assert!(baseline.known_user_agents.contains(&agent(0)));
+    }
+
+    // Store lento: cada lectura de perfil tarda más que el presupuesto de scoring
+    struct SlowLoads(InMemoryProfileStore);
+
+    #[async_trait::async_trait]
+    impl ProfileStore for SlowLoads {
+        async fn load_all(&self) -> Vec<ClientProfile> {
+            self.0.load_all().await
+        }
+        async fn load(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile> {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            self.0.load(tenant_id, client_id).await
+        }
+        async fn save(&self, profile: &ClientProfile) -> Result<(), String> {
+            self.0.save(profile).await
+        }
+        async fn remove(&self, tenant_id: &str, client_id: &str) -> Result<(), String> {
+            self.0.remove(tenant_id, client_id).await
+        }
+        async fn load_records(&self, namespace: &str) -> Vec<(String, String)> {
+            self.0.load_records(namespace).await
+        }
+        async fn save_record(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+            self.0.save_record(namespace, key, value).await
+        }
+        async fn remove_record(&self, namespace: &str, key: &str) -> Result<(), String> {
+            self.0.remove_record(namespace, key).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn slow_scoring_fails_open_as_a_degraded_allow() {
+        let state = AppState {
+            scoring_timeout: Some(std::time::Duration::from_millis(30)),
+            ..test_state(SecurityConfig::default(), SlowLoads(InMemoryProfileStore::new())).await
+        };
+        let metrics = state.metrics.clone();
+        let app = actix_test::init_service(build_app(state)).await;
+        // Con device_id la petición pasa por el motor de perfiles (y por el store lento)
+        let mut body = login(42, "198.51.100.7");
+        body["device_id"] = serde_json::json!("laptop-1");
+        body["event_id"] = serde_json::json!("evt-1");
+
+        // El store tarda más que el presupuesto: la respuesta sale del timeout, no del scoring
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", body.clone())).await;
+        assert_eq!(response["degraded"], true);
+        assert_eq!(response["action"], "ALLOW");
+        assert_eq!(codes(&response), ["SCORING_TIMEOUT"]);
+        assert_eq!(metrics.degraded_total.load(Ordering::Relaxed), 1);
+
+        // No se cachea: el reintento con el mismo event_id se vuelve a evaluar
+        actix_test::call_service(&app, post("/api/v1/detect", body)).await;
+        assert_eq!(metrics.degraded_total.load(Ordering::Relaxed), 2);
+        let exposition = actix_test::call_and_read_body(&app, actix_test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(String::from_utf8_lossy(&exposition).contains("anomaly_degraded_decisions_total 2"));
+    }
+
+    #[actix_web::test]
+    async fn fast_scoring_within_budget_is_not_degraded() {
+        let state = AppState {
+            scoring_timeout: Some(std::time::Duration::from_secs(5)),
+            ..test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await
+        };
+        let metrics = state.metrics.clone();
+        let app = actix_test::init_service(build_app(state)).await;
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert!(response.get("degraded").is_none(), "solo se serializa si es true: {}", response);
+        assert_eq!(metrics.degraded_total.load(Ordering::Relaxed), 0);
+    }
+
+    fn pseudonymous_login(hashed_id: &str, ip: &str) -> serde_json::Value {
+        let mut body = login(0, ip);
+        body.as_object_mut().unwrap().remove("user_id");
+        body["hashed_id"] = serde_json::json!(hashed_id);
+        body
+    }
+
+    #[test]
+    fn hashed_ips_are_stable_per_salt_and_skip_geoip() {
+        let acme = hmac::Key::new(hmac::HMAC_SHA256, b"acme-salt");
+        let globex = hmac::Key::new(hmac::HMAC_SHA256, b"globex-salt");
+
+        let hashed = hashed_ip(&acme, "198.51.100.7");
+        assert!(hashed.starts_with("ip:") && !hashed.contains("198.51"), "{}", hashed);
+        assert_eq!(hashed, hashed_ip(&acme, " 198.51.100.7 "));
+        assert_ne!(hashed, hashed_ip(&acme, "198.51.100.8"));
+        assert_ne!(hashed, hashed_ip(&globex, "198.51.100.7"), "la sal es por tenant");
+        // Formas equivalentes de la misma IP dan el mismo seudónimo
+        assert_eq!(hashed_ip(&acme, "::FFFF:198.51.100.7"), hashed_ip(&acme, "::ffff:198.51.100.7"));
+
+        assert_eq!(extract_country(&hashed, None), UNKNOWN_COUNTRY);
+    }
+
+    #[actix_web::test]
+    async fn hashed_ids_key_baselines_like_numeric_ids() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let subject = "9f86d081884c7d659a2feaa0c55ad015";
+
+        let first: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", pseudonymous_login(subject, "198.51.100.7"))).await;
+        assert_eq!(codes(&first), ["NEW_PROFILE"]);
+        actix_test::call_service(&app, post("/api/v1/baseline", pseudonymous_login(subject, "198.51.100.7"))).await;
+
+        let baseline: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, get(&format!("/api/v1/baseline?tenant_id=acme&hashed_id={}", subject))).await;
+        assert_eq!(baseline["hashed_id"], subject);
+        assert_eq!(baseline["observations"], 1);
+        let known: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", pseudonymous_login(subject, "198.51.100.7"))).await;
+        assert!(!codes(&known).contains(&"NEW_PROFILE".to_string()), "{}", known);
+
+        // Sin user_id ni hashed_id no hay a quién asignar el evento
+        let mut anonymous = login(0, "198.51.100.7");
+        anonymous.as_object_mut().unwrap().remove("user_id");
+        assert_eq!(actix_test::call_service(&app, post("/api/v1/detect", anonymous)).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn salted_tenants_never_expose_or_geolocate_ips() {
+        let state = AppState {
+            ip_salts: Arc::new(HashMap::from([("acme".to_string(), hmac::Key::new(hmac::HMAC_SHA256, b"acme-salt"))])),
+            ..test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await
+        };
+        let app = actix_test::init_service(build_app(state)).await;
+        let subject = "9f86d081884c7d659a2feaa0c55ad015";
+        actix_test::call_service(&app, post("/api/v1/baseline", pseudonymous_login(subject, "198.51.100.7"))).await;
+
+        let baseline: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, get(&format!("/api/v1/baseline?tenant_id=acme&hashed_id={}", subject))).await;
+        assert!(!baseline.to_string().contains("198.51.100.7"));
+        // Otra IP cualquiera: sin geolocalización no hay señal de ubicación ni de viaje
+        let elsewhere: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", pseudonymous_login(subject, "8.8.8.8"))).await;
+        let codes = codes(&elsewhere);
+        assert!(!codes.iter().any(|code| code == "UNUSUAL_LOCATION" || code == "IMPOSSIBLE_TRAVEL"), "{:?}", codes);
+    }
+
+    #[actix_web::test]
+    async fn reloaded_risk_thresholds_move_the_score_histogram() {
+        let state = test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await;
+        let app = actix_test::init_service(build_app(state.clone())).await;
+        let exposition = || async {
+            let body = actix_test::call_and_read_body(&app, actix_test::TestRequest::get().uri("/metrics").to_request()).await;
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        let defaults = RiskThresholds::default();
+        state.metrics.record(0.5, Action::Allow);
+        assert!(exposition().await.contains(&format!("anomaly_score_bucket{{le=\"{}\"}}", defaults.critical as f32)));
+
+        let reloaded = RiskThresholds { low: 0.1, medium: 0.2, high: 0.4, critical: 0.6 };
+        apply_config(&state, &SecurityConfig { risk_thresholds: reloaded, ..SecurityConfig::default() }).await.unwrap();
+        state.metrics.record(0.5, Action::Allow);
+
+        let text = exposition().await;
+        // Los cortes nuevos, y solo el registro posterior a la recarga
+        assert!(text.contains("anomaly_score_bucket{le=\"0.4\"} 0\n"), "{}", text);
+        assert!(text.contains("anomaly_score_bucket{le=\"0.6\"} 1\n"), "{}", text);
+        assert!(text.contains("anomaly_score_count 1\n"), "{}", text);
+        assert!(!text.contains(&format!("le=\"{}\"", defaults.medium as f32)), "{}", text);
+        assert_eq!(state.detector.risk_thresholds().critical, 0.6);
+
+        // Unos cortes inválidos se rechazan sin tocar el histograma
+        let invalid = RiskThresholds { medium: 0.9, ..reloaded };
+        assert!(apply_config(&state, &SecurityConfig { risk_thresholds: invalid, ..SecurityConfig::default() }).await.is_err());
+        assert!(exposition().await.contains("anomaly_score_bucket{le=\"0.6\"} 1\n"));
+    }
+
+    #[actix_web::test]
+    async fn exceeding_the_rate_limit_blocks_regardless_of_the_score() {
+        let mut state = test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await;
+        state.block_as_429 = true;
+        state.detector.set_tenant_threshold("acme", "rate_limit", 2.0).await.unwrap();
+        let app = actix_test::init_service(build_app(state)).await;
+
+        for _ in 0..2 {
+            let response = actix_test::call_service(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let limited = actix_test::call_service(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = limited.headers().get(actix_web::http::header::RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+        assert!((1..=RATE_LIMIT_WINDOW_SECS).contains(&retry_after));
+        let limited: serde_json::Value = actix_test::read_body_json(limited).await;
+        assert_eq!(limited["action"], "BLOCK");
+        assert!(codes(&limited).contains(&"RATE_LIMITED".to_string()), "{}", limited);
+
+        // Otro usuario del mismo tenant no comparte la ventana
+        let other: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(7, "198.51.100.7"))).await;
+        assert!(!codes(&other).contains(&"RATE_LIMITED".to_string()));
+    }
+
+    // Ráfaga de /baseline para un usuario: países y UAs distintos en observaciones seguidas
+    fn burst_observation(n: usize) -> PendingBaseline {
+        const COUNTRIES: [&str; MAX_TYPICAL_COUNTRIES] = ["ES", "FR", "DE", "PT", "IT"];
+        PendingBaseline {
+            user_id: Some(42),
+            hashed_id: None,
+            tenant_id: "acme".to_string(),
+            timezone: None,
+            observations: vec![BaselineObservation {
+                at: Utc::now(),
+                hour: 10,
+                country: COUNTRIES[n % COUNTRIES.len()].to_string(),
+                user_agent: format!("agent-{}", n),
+                endpoint: "/login".to_string(),
+                count: 1,
+            }],
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_coalesced_burst_keeps_every_country_and_user_agent() {
+        let state = test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await;
+        let key = baseline_key("acme", "42");
+        let burst = MAX_KNOWN_USER_AGENTS;
+
+        // Como update_baseline: solo se aplica lo que devuelve push, el resto queda pendiente
+        let mut applied = 0;
+        for n in 0..burst {
+            if let Some(batch) = state.baseline_writes.push(&key, burst_observation(n)) {
+                apply_baseline(&state, &key, batch).await;
+                applied += 1;
+            }
+        }
+        assert_eq!(applied, 1, "en ráfaga solo escribe la primera petición");
+
+        // El flusher periódico aplica el resto al vencer el intervalo
+        std::thread::sleep(std::time::Duration::from_millis(BASELINE_COALESCE_MS));
+        for (key, batch) in state.baseline_writes.drain_due() {
+            apply_baseline(&state, &key, batch).await;
+        }
+
+        let baseline = state.baselines.get(&key).unwrap().clone();
+        assert_eq!(baseline.observations, burst as u64);
+        assert_eq!(baseline.typical_countries.len(), MAX_TYPICAL_COUNTRIES);
+        let agents: BTreeSet<String> = baseline.known_user_agents.iter().cloned().collect();
+        assert_eq!(agents, (0..burst).map(|n| format!("agent-{}", n)).collect());
+        // La última observación de la ráfaga define el último país
+        assert_eq!(baseline.last_country.as_deref(), Some(["ES", "FR", "DE", "PT", "IT"][(burst - 1) % MAX_TYPICAL_COUNTRIES]));
+    }
+
+    #[actix_web::test]
+    async fn pending_baseline_writes_are_applied_on_shutdown() {
+        let state = test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await;
+        let key = baseline_key("acme", "42");
+        for n in 0..3 {
+            if let Some(batch) = state.baseline_writes.push(&key, burst_observation(n)) {
+                apply_baseline(&state, &key, batch).await;
+            }
+        }
+        flush_on_shutdown(&state).await;
+
+        let baseline = state.baselines.get(&key).unwrap().clone();
+        assert_eq!(baseline.observations, 3);
+        assert_eq!(baseline.typical_countries, BTreeSet::from(["ES".to_string(), "FR".to_string(), "DE".to_string()]));
+        assert_eq!(baseline.known_user_agents.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn iana_timezones_resolve_without_a_system_zoneinfo() {
+        // La imagen alpine no trae tzdata: las zonas salen de la base compilada en el binario
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let mut madrid = login(42, "198.51.100.7");
+        madrid["timezone"] = serde_json::json!("Europe/Madrid");
+        let learned = actix_test::call_service(&app, post("/api/v1/baseline", madrid)).await;
+        assert_eq!(learned.status(), StatusCode::OK);
+        let baseline: serde_json::Value =
+            actix_test::call_and_read_body_json(&app, get("/api/v1/baseline?tenant_id=acme&user_id=42")).await;
+        assert_eq!(baseline["timezone"], "Europe/Madrid");
+
+        let mut atlantis = login(43, "198.51.100.7");
+        atlantis["timezone"] = serde_json::json!("Europe/Atlantis");
+        let rejected = actix_test::call_service(&app, post("/api/v1/baseline", atlantis)).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+
+        let tenants = parse_tenant_timezones("acme=Europe/Madrid, globex=America/New_York").unwrap();
+        assert_eq!(tenants["globex"], "America/New_York");
+        assert!(parse_tenant_timezones("acme=Europe/Atlantis").is_err());
+    }
+
+    #[actix_web::test]
+    async fn injection_like_substrings_do_not_flag_a_legitimate_client() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        actix_test::call_service(&app, post("/api/v1/baseline", login(42, "198.51.100.7"))).await;
+
+        // Rutas y user-agents reales con fragmentos que antes contaban como inyección
+        let requests = [
+            ("/api/v1/files/*", "Mozilla/5.0 (X11; Linux x86_64) Firefox/120.0"),
+            ("/docs/../guide/index.html", "Mozilla/5.0 (X11; Linux x86_64) Firefox/120.0"),
+            ("/search?q=rock%20'n'%20roll%27%20or%20blues", "Mozilla/5.0 (X11; Linux x86_64) Firefox/120.0"),
+            ("/login", "Mozilla/5.0 (X11; Linux x86_64) Firefox/120.0 $(build 42)"),
+        ];
+        for (endpoint, user_agent) in requests {
+            let mut request = login(42, "198.51.100.7");
+            request["endpoint"] = serde_json::json!(endpoint);
+            request["user_agent"] = serde_json::json!(user_agent);
+            let response: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", request)).await;
+            assert!(!codes(&response).contains(&"PAYLOAD_INJECTION".to_string()), "{}: {}", endpoint, response);
+            assert_ne!(response["action"], "BLOCK", "{}: {}", endpoint, response);
+        }
+
+        // Ni el perfil quedó marcado: la petición habitual sigue pasando
+        let response: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "198.51.100.7"))).await;
+        assert_eq!(response["action"], "ALLOW");
+        assert!(!codes(&response).contains(&"CLIENT_COMPROMISED".to_string()));
+    }
+
+    #[test]
+    fn a_ramp_enforces_from_its_deadline_on() {
+        let first_observed = Utc::now();
+        let ramp = chrono::Duration::hours(24);
+        let just_before = TenantRamp::new(first_observed, ramp, first_observed + ramp - chrono::Duration::seconds(1));
+        assert!(just_before.observing);
+        assert_eq!(just_before.enforce_at, first_observed + ramp);
+        assert!(!TenantRamp::new(first_observed, ramp, first_observed + ramp).observing);
+    }
+
+    #[actix_web::test]
+    async fn tenant_ramp_keeps_the_first_live_event_as_the_start() {
+        let store = InMemoryProfileStore::new();
+        let ramp = chrono::Duration::hours(24);
+        let state = AppState { tenant_ramp: Some(ramp), ..test_state(SecurityConfig::default(), store.clone()).await };
+        let start = Utc::now();
+
+        // Una simulación no arranca la rampa
+        assert!(tenant_ramp(&state, "acme", false, start).await.unwrap().observing);
+        assert!(state.tenant_first_seen.is_empty());
+
+        let first = tenant_ramp(&state, "acme", true, start).await.unwrap();
+        assert_eq!((first.first_observed, first.observing), (start, true));
+        let later = tenant_ramp(&state, "acme", true, start + chrono::Duration::hours(12)).await.unwrap();
+        assert_eq!((later.first_observed, later.observing), (start, true));
+        let past = tenant_ramp(&state, "acme", true, start + ramp + chrono::Duration::seconds(1)).await.unwrap();
+        assert_eq!((past.first_observed, past.observing), (start, false));
+
+        // Persistido una sola vez, con el instante del primer evento
+        let records = store.load_records(TENANT_FIRST_SEEN_NAMESPACE).await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(serde_json::from_str::<DateTime<Utc>>(&records[0].1).unwrap(), start);
+
+        let unconfigured = test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await;
+        assert!(tenant_ramp(&unconfigured, "acme", true, start).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn a_new_tenant_only_shadows_decisions_until_its_ramp_ends() {
+        let ramp = chrono::Duration::hours(24);
+        let state = AppState { tenant_ramp: Some(ramp), ..test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await };
+        let first_seen = state.tenant_first_seen.clone();
+        let app = actix_test::init_service(build_app(state)).await;
+        actix_test::call_service(&app, post("/api/v1/blocklist", serde_json::json!({ "kind": "ip", "value": "203.0.113.9" }))).await;
+        let ramp_of = |stats: &serde_json::Value| {
+            stats["tenants"].as_array().unwrap().iter().find(|t| t["tenant_id"] == "acme").unwrap()["ramp"].clone()
+        };
+
+        let observed: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "203.0.113.9"))).await;
+        assert_eq!(observed["action"], "ALLOW");
+        assert_eq!(observed["shadow_action"], "BLOCK");
+        let stats: serde_json::Value = actix_test::call_and_read_body_json(&app, get("/api/v1/stats")).await;
+        let observing = ramp_of(&stats);
+        assert_eq!(observing["observing"], true);
+        let first_observed = *first_seen.get("acme").unwrap();
+        assert_eq!(observing["enforce_at"], serde_json::to_value(first_observed + ramp).unwrap());
+
+        // Pasado el plazo: el primer evento queda una rampa completa en el pasado
+        first_seen.insert("acme".to_string(), first_observed - ramp);
+        let enforced: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(42, "203.0.113.9"))).await;
+        assert_eq!(enforced["action"], "BLOCK");
+        assert!(enforced["shadow_action"].is_null(), "{}", enforced);
+        let stats: serde_json::Value = actix_test::call_and_read_body_json(&app, get("/api/v1/stats")).await;
+        assert_eq!(ramp_of(&stats)["observing"], false);
+    }
+
+    #[actix_web::test]
+    async fn allowlisted_networks_are_always_allowed_unless_blocklisted() {
+        let config = SecurityConfig {
+            sensitivity: 1.0,
+            allowlist: IpAllowlist::parse_networks("203.0.113.0/24, 2001:db8::/32").unwrap(),
+            ..SecurityConfig::default()
+        };
+        let state = test_state(config, InMemoryProfileStore::new()).await;
+        legacy_auth_rule(&state, 1.0);
+        let app = actix_test::init_service(build_app(state)).await;
+
+        let outside: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", legacy_login(1, "198.51.100.7"))).await;
+        assert_eq!(outside["action"], "BLOCK");
+        for ip in ["203.0.113.5", "2001:db8::7"] {
+            let inside: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", legacy_login(2, ip))).await;
+            assert_eq!(inside["action"], "ALLOW", "{}", ip);
+            assert_eq!(inside["anomaly_score"], 0.0);
+        }
+
+        // La blocklist manual prevalece sobre la allowlist
+        actix_test::call_service(&app, post("/api/v1/blocklist", serde_json::json!({ "kind": "ip", "value": "203.0.113.9" }))).await;
+        let blocked: serde_json::Value = actix_test::call_and_read_body_json(&app, post("/api/v1/detect", login(3, "203.0.113.9"))).await;
+        assert_eq!(blocked["action"], "BLOCK");
+    }
+
+    #[actix_web::test]
+    async fn tenant_thresholds_are_validated_before_anything_is_applied() {
+        let app = actix_test::init_service(build_app(test_state(SecurityConfig::default(), InMemoryProfileStore::new()).await)).await;
+        let put = |body: serde_json::Value| {
+            actix_test::TestRequest::put()
+                .uri("/api/v1/tenants/acme/thresholds")
+                .insert_header(("X-API-KEY", API_KEY))
+                .set_json(body)
+                .to_request()
+        };
+
+        let rejected = actix_test::call_service(&app, put(serde_json::json!({ "rate_limit": 50, "score_multiplier": 99 }))).await;
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        let effective: serde_json::Value = actix_test::call_and_read_body_json(&app, put(serde_json::json!({}))).await;
+        assert_ne!(effective["rate_limit"], 50.0, "nada se aplica si una clave es inválida");
+
+        let effective: serde_json::Value = actix_test::call_and_read_body_json(&app, put(serde_json::json!({ "rate_limit": 50 }))).await;
+        assert_eq!(effective["rate_limit"], 50.0);
+        assert!(effective["score_multiplier"].is_number());
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use chrono::{DateTime, Utc};
 
 // ==========================================
@@ -27,6 +27,97 @@ pub enum BehaviorPattern {
     AnomalousLocation,
     DeviceChange,
     CredentialSpray,
+    // Origen con mala reputación según un enriquecedor externo (ver enrich.rs)
+    BadReputation,
+    // Alguna regla del tenant sobre BehaviorEvent.metadata (ver MetadataRule)
+    TenantRule,
+}
+
+impl BehaviorPattern {
+    /// Explicación en inglés para mostrar al usuario final (mismo estilo que
+    /// las razones del scoring de main.rs).
+    pub fn human_reason(&self) -> &'static str {
+        match self {
+            BehaviorPattern::Normal => "Normal Behavior",
+            BehaviorPattern::RapidFailures => "Repeated Failed Logins",
+            BehaviorPattern::Enumeration => "Account or Resource Enumeration",
+            BehaviorPattern::PayloadInjection => "Malicious Payload Detected",
+            BehaviorPattern::TimingAttack => "Automated Request Timing",
+            BehaviorPattern::ResourceAbuse => "Excessive Resource Usage",
+            BehaviorPattern::AnomalousLocation => "Unusual Location",
+            BehaviorPattern::DeviceChange => "New Device/Browser",
+            BehaviorPattern::CredentialSpray => "Credential Spraying",
+            BehaviorPattern::BadReputation => "Source With Bad Reputation",
+            BehaviorPattern::TenantRule => "Tenant Rule Matched",
+        }
+    }
+
+    /// Código estable (para traducir en el frontend o actuar en el gateway).
+    pub fn code(&self) -> &'static str {
+        match self {
+            BehaviorPattern::Normal => "NORMAL",
+            BehaviorPattern::RapidFailures => "RAPID_FAILURES",
+            BehaviorPattern::Enumeration => "ENUMERATION",
+            BehaviorPattern::PayloadInjection => "PAYLOAD_INJECTION",
+            BehaviorPattern::TimingAttack => "TIMING_ATTACK",
+            BehaviorPattern::ResourceAbuse => "RESOURCE_ABUSE",
+            BehaviorPattern::AnomalousLocation => "ANOMALOUS_LOCATION",
+            BehaviorPattern::DeviceChange => "DEVICE_CHANGE",
+            BehaviorPattern::CredentialSpray => "CREDENTIAL_SPRAY",
+            BehaviorPattern::BadReputation => "BAD_REPUTATION",
+            BehaviorPattern::TenantRule => "TENANT_RULE",
+        }
+    }
+}
+
+/// Decisión para un usuario sin baseline (primer evento visto).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ColdStartAction {
+    /// Fail-open: se permite (comportamiento histórico).
+    #[default]
+    Allow,
+    /// Fail-secure: se exige un challenge hasta que exista baseline.
+    Challenge,
+}
+
+impl std::str::FromStr for ColdStartAction {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.trim().to_ascii_uppercase().as_str() {
+            "ALLOW" => Ok(Self::Allow),
+            "CHALLENGE" => Ok(Self::Challenge),
+            other => Err(format!("Unknown cold start action '{}' (expected ALLOW or CHALLENGE)", other)),
+        }
+    }
+}
+
+/// Decisión devuelta al gateway. Se serializa como los strings históricos
+/// (`ALLOW`, `CHALLENGE`, `BLOCK`); el orden permite escalar con `max`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Action {
+    #[default]
+    Allow,
+    Challenge,
+    Block,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "ALLOW",
+            Action::Challenge => "CHALLENGE",
+            Action::Block => "BLOCK",
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 // ==========================================
@@ -40,9 +131,13 @@ pub struct BehaviorEvent {
     pub client_id: String,
     pub timestamp: DateTime<Utc>,
     pub pattern: BehaviorPattern,
+    // Fiabilidad (0–1) que el emisor da al evento: escala su aporte al score
     pub confidence: f64,
     pub indicators: HashMap<String, f64>,
     pub metadata: HashMap<String, String>,
+    // Huella estable del cliente (no el user-agent); alimenta DeviceChange
+    #[serde(default)]
+    pub device_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +148,11 @@ pub struct AnomalyScore {
     pub score: f64,
     pub level: ThreatLevel,
     pub detected_patterns: Vec<BehaviorPattern>,
+    // Razones legibles derivadas de detected_patterns (para el frontend)
+    pub reasons: Vec<String>,
+    // Aporte de cada patrón al score (detalle forense, aunque el total sature en 1.0)
+    #[serde(default)]
+    pub pattern_contributions: HashMap<BehaviorPattern, f64>,
     pub timestamp: DateTime<Utc>,
     pub recommendation: String,
 }
@@ -66,25 +166,100 @@ pub struct ClientProfile {
     pub first_seen: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
     pub total_events: u64,
+    // Media de `BehaviorEvent.confidence` (0–1) de los eventos recibidos
     pub average_confidence: f64,
     pub risk_score: f64,
     pub is_compromised: bool,
+    // Momento en que se marcó como comprometido (para expirar el bloqueo)
+    #[serde(default)]
+    pub compromised_at: Option<DateTime<Utc>>,
+    // Veces que se ha marcado como comprometido y última liberación automática (por TTL)
+    #[serde(default)]
+    pub incident_count: u32,
+    #[serde(default)]
+    pub released_at: Option<DateTime<Utc>>,
+    pub threat_level: ThreatLevel,
+    // Veces que se le ha exigido fricción (THROTTLE / MFA) sin un periodo limpio entre medias
+    #[serde(default)]
+    pub challenge_count: u32,
+    #[serde(default)]
+    pub last_challenged_at: Option<DateTime<Utc>>,
+    // Último dispositivo visto y los conocidos (acotados, el más reciente al final)
     pub device_id: String,
+    #[serde(default)]
+    pub known_devices: VecDeque<String>,
+    // Intervalos recientes entre eventos (ms) para la varianza de tiempos; no se expone
+    #[serde(default, skip_serializing)]
+    pub request_intervals_ms: VecDeque<f64>,
     
     // Nota: La lógica debe limitar el tamaño de este vector para evitar DoS de memoria
     pub location_history: Vec<String>,
+    // Últimos eventos evaluados (línea de tiempo forense, acotada por event_history_size)
+    #[serde(default)]
+    pub recent_events: VecDeque<EventSummary>,
+}
+
+/// Resumen de un evento evaluado, para reconstruir qué llevó a marcar a un cliente.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventSummary {
+    pub timestamp: DateTime<Utc>,
+    pub patterns: Vec<BehaviorPattern>,
+    pub score: f64,
+    pub level: ThreatLevel,
+}
+
+/// Cómo se compara el indicador con el umbral de una firma.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ThresholdOperator {
+    /// Dispara si el indicador supera el umbral.
+    #[default]
+    Above,
+    /// Dispara si el indicador es positivo pero menor al umbral
+    /// (p.ej. varianza de tiempos artificialmente baja).
+    Below,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThreatSignature {
     pub id: String,
     pub pattern: BehaviorPattern,
-    pub threshold: u32,
+    // Clave del mapa `indicators` que evalúa esta firma
+    pub indicator: String,
+    #[serde(default)]
+    pub operator: ThresholdOperator,
+    pub threshold: f64,
     pub time_window_ms: u64,
     pub severity: ThreatLevel,
     pub description: String,
 }
 
+/// Comparación de una `MetadataRule` (texto exacto, sensible a mayúsculas).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataOperator {
+    Equals,
+    NotEquals,
+    /// El valor contiene `value` como subcadena.
+    Contains,
+    /// La clave existe (sin mirar el valor).
+    Present,
+    Absent,
+}
+
+/// Regla declarada por un tenant sobre `BehaviorEvent.metadata`
+/// (`{"id":"legacy_auth","field":"auth_method","operator":"equals","value":"legacy_basic","weight":0.5}`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MetadataRule {
+    pub id: String,
+    pub field: String,
+    pub operator: MetadataOperator,
+    /// Obligatorio salvo en `present`/`absent`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Aporte al score (0–1) si se cumple; las reglas cumplidas se suman.
+    pub weight: f64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DetectionResult {
     pub success: bool,
@@ -93,6 +268,41 @@ pub struct DetectionResult {
     pub processing_time_ms: u64,
 }
 
+/// Postura de amenaza agregada de un tenant (perfiles en memoria).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TenantStats {
+    pub tenant_id: String,
+    pub profiles: usize,
+    pub compromised: usize,
+    pub average_risk: f64,
+    pub threat_levels: BTreeMap<ThreatLevel, usize>,
+    /// Eventos procesados desde el arranque.
+    #[serde(default)]
+    pub events_processed: u64,
+}
+
+/// Cliente de confianza por identidad (directivos, cuentas de servicio). Su acción no
+/// pasa de `SecurityConfig::vip_max_action` y un score Critical no lo marca como
+/// comprometido. A diferencia de la allowlist (por red), sigue puntuándose.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VipEntry {
+    pub tenant_id: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub added_at: DateTime<Utc>,
+}
+
+/// Cliente actualmente comprometido (respuesta a incidentes).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompromisedClient {
+    pub tenant_id: String,
+    pub client_id: String,
+    pub risk_score: f64,
+    pub last_seen: DateTime<Utc>,
+    pub compromised_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HealthCheck {
     pub status: String,
@@ -100,4 +310,28 @@ pub struct HealthCheck {
     pub events_processed: u64,
     pub active_profiles: u64,
     pub memory_usage_mb: u64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cold_start_action_parses_case_insensitively_and_defaults_to_allow() {
+        assert_eq!(" challenge ".parse(), Ok(ColdStartAction::Challenge));
+        assert_eq!("ALLOW".parse(), Ok(ColdStartAction::Allow));
+        assert!("block".parse::<ColdStartAction>().is_err());
+        assert_eq!(ColdStartAction::default(), ColdStartAction::Allow);
+        assert_eq!(serde_json::to_string(&ColdStartAction::Challenge).unwrap(), "\"CHALLENGE\"");
+    }
+
+    #[test]
+    fn action_keeps_the_historic_strings_and_escalates_with_max() {
+        for (action, label) in [(Action::Allow, "ALLOW"), (Action::Challenge, "CHALLENGE"), (Action::Block, "BLOCK")] {
+            assert_eq!(serde_json::to_string(&action).unwrap(), format!("\"{}\"", label));
+            assert_eq!(serde_json::from_str::<Action>(&format!("\"{}\"", label)).unwrap(), action);
+            assert_eq!(action.to_string(), label);
+        }
+        assert_eq!(Action::Challenge.max(Action::Allow), Action::Challenge);
+        assert_eq!(Action::Challenge.max(Action::Block), Action::Block);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// ==========================================
+// SEGUIMIENTO DE RESULTADOS DE LOGIN
+// ==========================================
+
+/// Ventana deslizante de resultados de login (éxito/fallo) por clave,
+/// de la que se deriva el indicador `failure_rate` para `RapidFailures`.
+pub struct LoginOutcomeTracker {
+    outcomes: DashMap<String, VecDeque<(Instant, bool)>>,
+    max_samples: usize,
+    min_samples: usize,
+    window: Duration,
+}
+
+impl LoginOutcomeTracker {
+    /// `max_samples` acota la memoria por clave; `min_samples` evita que un
+    /// único fallo cuente como tasa de fallo del 100%.
+    pub fn new(max_samples: usize, min_samples: usize, window: Duration) -> Self {
+        Self {
+            outcomes: DashMap::new(),
+            max_samples,
+            min_samples,
+            window,
+        }
+    }
+
+    /// Registra un resultado y devuelve la tasa de fallo actual (0.0–1.0),
+    /// o `None` si todavía no hay muestras suficientes.
+    pub fn record(&self, key: &str, success: bool) -> Option<f64> {
+        let now = Instant::now();
+        let mut samples = self.outcomes.entry(key.to_string()).or_default();
+
+        while let Some(&(at, _)) = samples.front() {
+            if now.duration_since(at) > self.window || samples.len() >= self.max_samples {
+                samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        samples.push_back((now, success));
+
+        self.failure_rate(samples.iter().map(|&(_, ok)| ok))
+    }
+
+    /// Tasa de fallo que devolvería `record` con este resultado, sin registrarlo.
+    pub fn peek(&self, key: &str, success: bool) -> Option<f64> {
+        let now = Instant::now();
+        let mut samples: Vec<bool> = self
+            .outcomes
+            .get(key)
+            .map(|s| s.iter().filter(|(at, _)| now.duration_since(*at) <= self.window).map(|&(_, ok)| ok).collect())
+            .unwrap_or_default();
+        if samples.len() >= self.max_samples {
+            samples.drain(..=samples.len() - self.max_samples);
+        }
+        samples.push(success);
+
+        self.failure_rate(samples.into_iter())
+    }
+
+    fn failure_rate(&self, samples: impl ExactSizeIterator<Item = bool>) -> Option<f64> {
+        let total = samples.len();
+        if total < self.min_samples {
+            return None;
+        }
+        let failures = samples.filter(|ok| !ok).count();
+        Some(failures as f64 / total as f64)
+    }
+
+    /// Elimina las claves sin resultados dentro de la ventana.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.outcomes.retain(|_, samples| {
+            samples.back().is_some_and(|&(at, _)| now.duration_since(at) <= self.window)
+        });
+    }
+}
@@ -1,131 +1,559 @@
-use crate::models::{BehaviorEvent, BehaviorPattern};
-use std::collections::HashMap;
+use crate::models::{BehaviorEvent, BehaviorPattern, MetadataOperator, MetadataRule, ThreatLevel, ThreatSignature, ThresholdOperator};
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
 
 // ==========================================
 // CONSTANTES DE CONFIGURACIÓN (THRESHOLDS)
 // ==========================================
 
 // Keys para buscar en el HashMap de indicadores
-const KEY_INJECTION_SCORE: &str = "injection_score";
-const KEY_FAILURE_RATE: &str = "failure_rate";
-const KEY_ENUMERATION_SCORE: &str = "enumeration_score";
-const KEY_TIMING_VARIANCE: &str = "timing_variance";
+pub const KEY_INJECTION_SCORE: &str = "injection_score";
+pub const KEY_FAILURE_RATE: &str = "failure_rate";
+pub const KEY_ENUMERATION_SCORE: &str = "enumeration_score";
+pub const KEY_TIMING_VARIANCE: &str = "timing_variance";
 const KEY_RESOURCE_USAGE: &str = "resource_usage";
-const KEY_SPRAY_SCORE: &str = "spray_score";
-const KEY_LOCATION_RISK: &str = "location_risk"; // Nuevo
+pub const KEY_SPRAY_SCORE: &str = "spray_score";
+pub const KEY_NEW_DEVICE: &str = "new_device";
+pub const KEY_LOCATION_RISK: &str = "location_risk"; // Nuevo
+pub const KEY_IP_REPUTATION: &str = "ip_reputation"; // Lo añade un EventEnricher
 
 // Umbrales de Detección (Ajustados para Login de Organizador)
+// Son los valores por defecto: pueden sobrescribirse con un fichero de firmas
 const THRESHOLD_INJECTION: f64 = 0.8;
 const THRESHOLD_FAILURE_RATE: f64 = 0.4; // Bajado de 0.6 a 0.4 (Más estricto para login)
 const THRESHOLD_ENUMERATION: f64 = 0.7;
 const THRESHOLD_RESOURCE: f64 = 0.85;
 const THRESHOLD_SPRAY: f64 = 0.7;
 const THRESHOLD_LOCATION: f64 = 0.8; // Alta certeza de ubicación anómala
+const THRESHOLD_IP_REPUTATION: f64 = 0.6; // 0 = limpia, 1 = maliciosa conocida
+const THRESHOLD_NEW_DEVICE: f64 = 0.5; // El detector lo fija a 1.0 ante un device_id desconocido
 
 // Para Timing Attacks: Varianza muy baja (comportamiento robótico)
-const TIMING_VARIANCE_MIN: f64 = 0.0;
 const TIMING_VARIANCE_MAX: f64 = 10.0; // ms
 
-pub struct PatternMatcher;
+// Topes de las reglas de metadata por tenant (se evalúan en cada evento)
+pub const MAX_METADATA_RULES: usize = 50;
+const MAX_RULE_TEXT_LEN: usize = 256;
+
+/// Resultado completo de `PatternMatcher::detect_detailed`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Detection {
+    pub patterns: Vec<BehaviorPattern>,
+    /// Suma de los pesos de las reglas de metadata cumplidas (aporte de `TenantRule`).
+    pub rule_weight: f64,
+    /// Ids de esas reglas, en orden de declaración.
+    pub matched_rules: Vec<String>,
+}
+
+pub struct PatternMatcher {
+    signatures: Vec<ThreatSignature>,
+    // Patrones desactivados para todos y por tenant (vacío = todos activos)
+    disabled: RwLock<HashSet<BehaviorPattern>>,
+    tenant_disabled: DashMap<String, HashSet<BehaviorPattern>>,
+    // Reglas sobre BehaviorEvent.metadata por tenant (ver MetadataRule)
+    metadata_rules: DashMap<String, Vec<MetadataRule>>,
+}
+
+impl Default for PatternMatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl PatternMatcher {
     pub fn new() -> Self {
-        Self
+        Self::with_signatures(default_signatures())
     }
 
-    /// Analiza un evento y devuelve una lista de patrones sospechosos detectados.
-    pub fn detect(&self, event: &BehaviorEvent) -> Vec<BehaviorPattern> {
-        let mut patterns = Vec::new();
+    /// Construye el matcher con un conjunto de firmas propio (sustituye a los defaults).
+    pub fn with_signatures(signatures: Vec<ThreatSignature>) -> Self {
+        Self {
+            signatures,
+            disabled: RwLock::new(HashSet::new()),
+            tenant_disabled: DashMap::new(),
+            metadata_rules: DashMap::new(),
+        }
+    }
 
-        // 1. Inyección de Payload (SQLi, XSS) - CRÍTICO
-        if self.detect_payload_injection(&event.indicators) {
-            patterns.push(BehaviorPattern::PayloadInjection);
+    /// Patrones desactivados de partida (ver `SecurityConfig::disabled_patterns`).
+    pub fn with_disabled(
+        self,
+        global: impl IntoIterator<Item = BehaviorPattern>,
+        per_tenant: impl IntoIterator<Item = (String, Vec<BehaviorPattern>)>,
+    ) -> Self {
+        self.set_disabled(None, global.into_iter().collect());
+        for (tenant_id, patterns) in per_tenant {
+            self.set_disabled(Some(&tenant_id), patterns.into_iter().collect());
         }
+        self
+    }
 
-        // 2. Fallos Rápidos (Brute Force) - CRÍTICO PARA LOGIN
-        if self.detect_rapid_failures(&event.indicators) {
-            patterns.push(BehaviorPattern::RapidFailures);
+    /// Sustituye los patrones desactivados globales (`None`) o de un tenant.
+    /// Un conjunto vacío para un tenant lo devuelve a los globales.
+    pub fn set_disabled(&self, tenant_id: Option<&str>, patterns: HashSet<BehaviorPattern>) {
+        match tenant_id {
+            None => *self.disabled.write().unwrap_or_else(|e| e.into_inner()) = patterns,
+            Some(tenant_id) if patterns.is_empty() => {
+                self.tenant_disabled.remove(tenant_id);
+            }
+            Some(tenant_id) => {
+                self.tenant_disabled.insert(tenant_id.to_string(), patterns);
+            }
         }
+    }
 
-        // 3. Enumeración (Escaneo de usuarios/archivos)
-        if self.detect_enumeration(&event.indicators) {
-            patterns.push(BehaviorPattern::Enumeration);
+    /// Desactivados que se aplican a un tenant: los globales más los suyos.
+    pub fn disabled_for(&self, tenant_id: &str) -> HashSet<BehaviorPattern> {
+        let mut disabled = self.disabled.read().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some(own) = self.tenant_disabled.get(tenant_id) {
+            disabled.extend(own.iter().cloned());
         }
+        disabled
+    }
 
-        // 4. Timing Attacks (Side-channel analysis)
-        if self.detect_timing_attack(&event.indicators) {
-            patterns.push(BehaviorPattern::TimingAttack);
+    /// Vista ordenada (estable para la huella del ruleset): globales y por tenant.
+    pub fn disabled_patterns(&self) -> (Vec<BehaviorPattern>, BTreeMap<String, Vec<BehaviorPattern>>) {
+        let sorted = |set: &HashSet<BehaviorPattern>| {
+            let mut patterns: Vec<_> = set.iter().cloned().collect();
+            patterns.sort_by_key(|p| p.code());
+            patterns
+        };
+        let global = sorted(&self.disabled.read().unwrap_or_else(|e| e.into_inner()));
+        let tenants = self.tenant_disabled.iter().map(|entry| (entry.key().clone(), sorted(entry.value()))).collect();
+        (global, tenants)
+    }
+
+    /// Reglas de metadata de partida (ver `SecurityConfig::metadata_rules`), ya validadas.
+    pub fn with_metadata_rules(self, rules: impl IntoIterator<Item = (String, Vec<MetadataRule>)>) -> Self {
+        for (tenant_id, rules) in rules {
+            if !rules.is_empty() {
+                self.metadata_rules.insert(tenant_id, rules);
+            }
         }
+        self
+    }
 
-        // 5. Abuso de Recursos (DoS)
-        if self.detect_resource_abuse(&event.indicators) {
-            patterns.push(BehaviorPattern::ResourceAbuse);
+    /// Sustituye las reglas de un tenant (vacío = sin reglas).
+    pub fn set_metadata_rules(&self, tenant_id: &str, rules: Vec<MetadataRule>) -> Result<(), String> {
+        validate_metadata_rules(&rules)?;
+        if rules.is_empty() {
+            self.metadata_rules.remove(tenant_id);
+        } else {
+            self.metadata_rules.insert(tenant_id.to_string(), rules);
         }
+        Ok(())
+    }
 
-        // 6. Credential Spraying (Probar 1 password en muchos usuarios)
-        if self.detect_credential_spray(&event.indicators) {
-            patterns.push(BehaviorPattern::CredentialSpray);
+    pub fn metadata_rules(&self, tenant_id: &str) -> Vec<MetadataRule> {
+        self.metadata_rules.get(tenant_id).map(|rules| rules.clone()).unwrap_or_default()
+    }
+
+    pub fn has_metadata_rules(&self, tenant_id: &str) -> bool {
+        self.metadata_rules.contains_key(tenant_id)
+    }
+
+    /// Todas las reglas, ordenadas por tenant (huella del ruleset).
+    pub fn all_metadata_rules(&self) -> BTreeMap<String, Vec<MetadataRule>> {
+        self.metadata_rules.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect()
+    }
+
+    pub fn signatures(&self) -> &[ThreatSignature] {
+        &self.signatures
+    }
+
+    /// Analiza un evento y devuelve una lista de patrones sospechosos detectados.
+    /// Las firmas se evalúan en orden; cada patrón aparece como máximo una vez.
+    /// Las firmas de patrones desactivados para el tenant del evento no se evalúan.
+    /// Una firma o regla que entra en pánico se registra y se omite: el resto sigue.
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(name = "anomaly.patterns", skip_all, fields(tenant_id = %event.tenant_id))
+    )]
+    pub fn detect(&self, event: &BehaviorEvent) -> Vec<BehaviorPattern> {
+        self.detect_detailed(event).patterns
+    }
+
+    /// Como `detect`, con el detalle de las reglas de metadata del tenant: si se cumple
+    /// alguna se añade `TenantRule` (salvo que esté desactivado) y su peso sumado.
+    pub fn detect_detailed(&self, event: &BehaviorEvent) -> Detection {
+        let disabled = self.disabled_for(&event.tenant_id);
+        let mut patterns = self.evaluate(&event.indicators, &disabled);
+
+        let mut detection = Detection::default();
+        if !disabled.contains(&BehaviorPattern::TenantRule) {
+            if let Some(rules) = self.metadata_rules.get(&event.tenant_id) {
+                let matched = |rule: &&MetadataRule| isolated(&rule.id, || rule_matches(rule, &event.metadata)).unwrap_or(false);
+                for rule in rules.iter().filter(matched) {
+                    detection.rule_weight += rule.weight;
+                    detection.matched_rules.push(rule.id.clone());
+                }
+            }
+        }
+        if !detection.matched_rules.is_empty() {
+            patterns.push(BehaviorPattern::TenantRule);
         }
+        detection.patterns = patterns;
+        detection
+    }
 
-        // 7. Ubicación Anómala (Geoip Mismatch) - NUEVO
-        if self.detect_anomalous_location(&event.indicators) {
-            patterns.push(BehaviorPattern::AnomalousLocation);
+    fn evaluate(&self, indicators: &HashMap<String, f64>, disabled: &HashSet<BehaviorPattern>) -> Vec<BehaviorPattern> {
+        self.evaluate_with(indicators, disabled, Self::matches)
+    }
+
+    // `evaluate` con la comprobación de cada firma inyectable (los tests la hacen fallar)
+    fn evaluate_with(
+        &self,
+        indicators: &HashMap<String, f64>,
+        disabled: &HashSet<BehaviorPattern>,
+        check: impl Fn(&ThreatSignature, &HashMap<String, f64>) -> bool,
+    ) -> Vec<BehaviorPattern> {
+        let mut patterns = Vec::new();
+
+        for signature in &self.signatures {
+            if patterns.contains(&signature.pattern) || disabled.contains(&signature.pattern) {
+                continue;
+            }
+            if isolated(&signature.id, || check(signature, indicators)).unwrap_or(false) {
+                patterns.push(signature.pattern.clone());
+            }
         }
 
         patterns
     }
 
-    // --- Métodos de Detección Específicos ---
+    fn matches(signature: &ThreatSignature, indicators: &HashMap<String, f64>) -> bool {
+        let Some(&value) = indicators.get(&signature.indicator) else {
+            return false;
+        };
+        match signature.operator {
+            ThresholdOperator::Above => value > signature.threshold,
+            // Detecta varianza artificialmente baja (bots); 0 = sin datos
+            ThresholdOperator::Below => value > 0.0 && value < signature.threshold,
+        }
+    }
+}
+
+// Ejecuta un detector (firma o regla) aislado: si entra en pánico ante un indicador
+// malformado se registra y se omite, y el resto sigue evaluándose. None = pánico
+fn isolated<T>(detector: &str, check: impl FnOnce() -> T) -> Option<T> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(check)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|m| m.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            log::error!("[PATTERNS] El detector '{}' entró en pánico y se omite: {}", detector, message);
+            None
+        }
+    }
+}
+
+// ==========================================
+// REGLAS DE METADATA POR TENANT
+// ==========================================
+
+// Comparaciones de texto exactas: sin regex ni expresiones, el coste es lineal y acotado
+fn rule_matches(rule: &MetadataRule, metadata: &HashMap<String, String>) -> bool {
+    let actual = metadata.get(&rule.field);
+    let expected = rule.value.as_deref().unwrap_or_default();
+    match rule.operator {
+        MetadataOperator::Present => actual.is_some(),
+        MetadataOperator::Absent => actual.is_none(),
+        MetadataOperator::Equals => actual.is_some_and(|v| v == expected),
+        MetadataOperator::NotEquals => actual.is_some_and(|v| v != expected),
+        MetadataOperator::Contains => actual.is_some_and(|v| v.contains(expected)),
+    }
+}
+
+/// Ids únicos y no vacíos, textos acotados, `value` donde el operador lo necesita
+/// y pesos en 0–1.
+pub fn validate_metadata_rules(rules: &[MetadataRule]) -> Result<(), String> {
+    if rules.len() > MAX_METADATA_RULES {
+        return Err(format!("at most {} metadata rules per tenant (got {})", MAX_METADATA_RULES, rules.len()));
+    }
+    let mut ids = HashSet::new();
+    for rule in rules {
+        if rule.id.trim().is_empty() || rule.field.trim().is_empty() {
+            return Err("metadata rules need a non-empty id and field".to_string());
+        }
+        if !ids.insert(rule.id.as_str()) {
+            return Err(format!("duplicate metadata rule id '{}'", rule.id));
+        }
+        let too_long = [Some(&rule.id), Some(&rule.field), rule.value.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|text| text.len() > MAX_RULE_TEXT_LEN);
+        if too_long {
+            return Err(format!("metadata rule '{}': texts are limited to {} bytes", rule.id, MAX_RULE_TEXT_LEN));
+        }
+        let needs_value = !matches!(rule.operator, MetadataOperator::Present | MetadataOperator::Absent);
+        if needs_value && rule.value.is_none() {
+            return Err(format!("metadata rule '{}': operator {:?} needs a value", rule.id, rule.operator));
+        }
+        if !(rule.weight.is_finite() && (0.0..=1.0).contains(&rule.weight)) {
+            return Err(format!("metadata rule '{}': weight must be within 0–1 (got {})", rule.id, rule.weight));
+        }
+    }
+    Ok(())
+}
+
+// ==========================================
+// FIRMAS POR DEFECTO Y CARGA DESDE FICHERO
+// ==========================================
+
+/// Lee un `Vec<ThreatSignature>` desde un fichero JSON.
+pub fn load_signatures(path: &str) -> Result<Vec<ThreatSignature>, String> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("No se pudo leer el fichero de firmas {}: {}", path, e))?;
+    serde_json::from_str(&raw)
+        .map_err(|e| format!("Fichero de firmas inválido {}: {}", path, e))
+}
+
+/// Firmas equivalentes a los umbrales históricos (mismo orden de evaluación).
+pub fn default_signatures() -> Vec<ThreatSignature> {
+    vec![
+        // 1. Inyección de Payload (SQLi, XSS) - CRÍTICO
+        signature("payload_injection", BehaviorPattern::PayloadInjection, KEY_INJECTION_SCORE,
+            ThresholdOperator::Above, THRESHOLD_INJECTION, ThreatLevel::Critical,
+            "Inyección de payload (SQLi, XSS)"),
+        // 2. Fallos Rápidos (Brute Force) - CRÍTICO PARA LOGIN
+        signature("rapid_failures", BehaviorPattern::RapidFailures, KEY_FAILURE_RATE,
+            ThresholdOperator::Above, THRESHOLD_FAILURE_RATE, ThreatLevel::High,
+            "Fallos de login rápidos (fuerza bruta)"),
+        // 3. Enumeración (Escaneo de usuarios/archivos)
+        signature("enumeration", BehaviorPattern::Enumeration, KEY_ENUMERATION_SCORE,
+            ThresholdOperator::Above, THRESHOLD_ENUMERATION, ThreatLevel::High,
+            "Enumeración de usuarios o recursos"),
+        // 4. Timing Attacks (Side-channel analysis)
+        signature("timing_attack", BehaviorPattern::TimingAttack, KEY_TIMING_VARIANCE,
+            ThresholdOperator::Below, TIMING_VARIANCE_MAX, ThreatLevel::Medium,
+            "Varianza de tiempos robótica (timing attack)"),
+        // 5. Abuso de Recursos (DoS)
+        signature("resource_abuse", BehaviorPattern::ResourceAbuse, KEY_RESOURCE_USAGE,
+            ThresholdOperator::Above, THRESHOLD_RESOURCE, ThreatLevel::High,
+            "Abuso de recursos (DoS)"),
+        // 6. Credential Spraying (Probar 1 password en muchos usuarios)
+        signature("credential_spray", BehaviorPattern::CredentialSpray, KEY_SPRAY_SCORE,
+            ThresholdOperator::Above, THRESHOLD_SPRAY, ThreatLevel::High,
+            "Credential spraying"),
+        // 7. Ubicación Anómala (Geoip Mismatch)
+        // Asumimos que upstream calcula 'location_risk' basado en historial vs IP actual
+        signature("anomalous_location", BehaviorPattern::AnomalousLocation, KEY_LOCATION_RISK,
+            ThresholdOperator::Above, THRESHOLD_LOCATION, ThreatLevel::Medium,
+            "Ubicación anómala"),
+        // 8. Dispositivo nuevo (device_id no visto antes para este cliente)
+        signature("device_change", BehaviorPattern::DeviceChange, KEY_NEW_DEVICE,
+            ThresholdOperator::Above, THRESHOLD_NEW_DEVICE, ThreatLevel::Medium,
+            "Dispositivo nuevo"),
+        // 9. Reputación de la IP de origen (threat intel vía EventEnricher)
+        signature("bad_reputation", BehaviorPattern::BadReputation, KEY_IP_REPUTATION,
+            ThresholdOperator::Above, THRESHOLD_IP_REPUTATION, ThreatLevel::High,
+            "IP de origen con mala reputación"),
+    ]
+}
+
+fn signature(
+    id: &str,
+    pattern: BehaviorPattern,
+    indicator: &str,
+    operator: ThresholdOperator,
+    threshold: f64,
+    severity: ThreatLevel,
+    description: &str,
+) -> ThreatSignature {
+    ThreatSignature {
+        id: id.to_string(),
+        pattern,
+        indicator: indicator.to_string(),
+        operator,
+        threshold,
+        time_window_ms: 0,
+        severity,
+        description: description.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn detect_payload_injection(&self, indicators: &HashMap<String, f64>) -> bool {
-        indicators
-            .get(KEY_INJECTION_SCORE)
-            .map(|&score| score > THRESHOLD_INJECTION)
-            .unwrap_or(false)
+    #[test]
+    fn panicking_signature_is_skipped_and_the_rest_still_match() {
+        let matcher = PatternMatcher::new();
+        let indicators = HashMap::from([
+            (KEY_INJECTION_SCORE.to_string(), 0.95),
+            (KEY_ENUMERATION_SCORE.to_string(), 0.9),
+            (KEY_FAILURE_RATE.to_string(), 0.9),
+        ]);
+
+        // Una firma defectuosa (p.ej. de un fichero de firmas) entra en pánico ante el indicador
+        let patterns = matcher.evaluate_with(&indicators, &HashSet::new(), |signature, indicators| {
+            if signature.id == "enumeration" {
+                panic!("indicador malformado");
+            }
+            PatternMatcher::matches(signature, indicators)
+        });
+
+        assert_eq!(patterns, vec![BehaviorPattern::PayloadInjection, BehaviorPattern::RapidFailures]);
     }
 
-    fn detect_rapid_failures(&self, indicators: &HashMap<String, f64>) -> bool {
-        indicators
-            .get(KEY_FAILURE_RATE)
-            .map(|&rate| rate > THRESHOLD_FAILURE_RATE)
-            .unwrap_or(false)
+    #[test]
+    fn panicking_rule_is_isolated() {
+        let rule = MetadataRule {
+            id: "legacy".to_string(),
+            field: "auth_method".to_string(),
+            operator: MetadataOperator::Equals,
+            value: Some("legacy_basic".to_string()),
+            weight: 0.5,
+        };
+        let metadata = HashMap::from([("auth_method".to_string(), "legacy_basic".to_string())]);
+
+        assert_eq!(isolated("broken", || -> bool { panic!("regla rota") }), None);
+        // El pánico no deja estado envenenado: la siguiente regla se evalúa con normalidad
+        assert_eq!(isolated(&rule.id, || rule_matches(&rule, &metadata)), Some(true));
     }
 
-    fn detect_enumeration(&self, indicators: &HashMap<String, f64>) -> bool {
-        indicators
-            .get(KEY_ENUMERATION_SCORE)
-            .map(|&score| score > THRESHOLD_ENUMERATION)
-            .unwrap_or(false)
+    fn robotic(tenant_id: &str) -> BehaviorEvent {
+        BehaviorEvent {
+            tenant_id: tenant_id.to_string(),
+            client_id: "integration".to_string(),
+            timestamp: chrono::Utc::now(),
+            pattern: BehaviorPattern::Normal,
+            confidence: 1.0,
+            // Muy por debajo del umbral de la firma y con otra señal activa
+            indicators: HashMap::from([
+                (KEY_TIMING_VARIANCE.to_string(), 0.5),
+                (KEY_INJECTION_SCORE.to_string(), 0.95),
+            ]),
+            metadata: HashMap::new(),
+            device_id: None,
+        }
     }
 
-    fn detect_timing_attack(&self, indicators: &HashMap<String, f64>) -> bool {
-        if let Some(&variance) = indicators.get(KEY_TIMING_VARIANCE) {
-            // Detecta varianza artificialmente baja (bots)
-            variance > TIMING_VARIANCE_MIN && variance < TIMING_VARIANCE_MAX
-        } else {
-            false
+    #[test]
+    fn disabled_patterns_never_appear_even_above_their_threshold() {
+        let matcher = PatternMatcher::new();
+        assert!(matcher.detect(&robotic("acme")).contains(&BehaviorPattern::TimingAttack));
+
+        matcher.set_disabled(Some("acme"), HashSet::from([BehaviorPattern::TimingAttack]));
+        assert_eq!(matcher.detect(&robotic("acme")), vec![BehaviorPattern::PayloadInjection]);
+        // Solo para ese tenant
+        assert!(matcher.detect(&robotic("globex")).contains(&BehaviorPattern::TimingAttack));
+
+        // Un conjunto vacío devuelve el tenant a los globales
+        matcher.set_disabled(Some("acme"), HashSet::new());
+        matcher.set_disabled(None, HashSet::from([BehaviorPattern::TimingAttack]));
+        for tenant in ["acme", "globex"] {
+            assert!(!matcher.detect(&robotic(tenant)).contains(&BehaviorPattern::TimingAttack), "{}", tenant);
         }
     }
 
-    fn detect_resource_abuse(&self, indicators: &HashMap<String, f64>) -> bool {
-        indicators
-            .get(KEY_RESOURCE_USAGE)
-            .map(|&usage| usage > THRESHOLD_RESOURCE)
-            .unwrap_or(false)
+    #[test]
+    fn initial_toggles_and_listing() {
+        let matcher = PatternMatcher::new().with_disabled(
+            [BehaviorPattern::ResourceAbuse],
+            [("acme".to_string(), vec![BehaviorPattern::TimingAttack])],
+        );
+        let acme = matcher.disabled_for("acme");
+        assert!(acme.contains(&BehaviorPattern::ResourceAbuse) && acme.contains(&BehaviorPattern::TimingAttack));
+        assert_eq!(matcher.disabled_for("globex"), HashSet::from([BehaviorPattern::ResourceAbuse]));
+
+        let (global, tenants) = matcher.disabled_patterns();
+        assert_eq!(global, vec![BehaviorPattern::ResourceAbuse]);
+        assert_eq!(tenants["acme"], vec![BehaviorPattern::TimingAttack]);
     }
 
-    fn detect_credential_spray(&self, indicators: &HashMap<String, f64>) -> bool {
-        indicators
-            .get(KEY_SPRAY_SCORE)
-            .map(|&score| score > THRESHOLD_SPRAY)
-            .unwrap_or(false)
+    fn rule(id: &str, operator: MetadataOperator, value: Option<&str>, weight: f64) -> MetadataRule {
+        MetadataRule {
+            id: id.to_string(),
+            field: "auth_method".to_string(),
+            operator,
+            value: value.map(str::to_string),
+            weight,
+        }
     }
 
-    fn detect_anomalous_location(&self, indicators: &HashMap<String, f64>) -> bool {
-        // Asumimos que upstream calcula 'location_risk' basado en historial vs IP actual
-        indicators
-            .get(KEY_LOCATION_RISK)
-            .map(|&risk| risk > THRESHOLD_LOCATION)
-            .unwrap_or(false)
+    fn with_metadata(tenant_id: &str, metadata: &[(&str, &str)]) -> BehaviorEvent {
+        BehaviorEvent {
+            indicators: HashMap::new(),
+            metadata: metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..robotic(tenant_id)
+        }
+    }
+
+    #[test]
+    fn equality_and_presence_operators() {
+        let legacy = HashMap::from([("auth_method".to_string(), "legacy_basic".to_string())]);
+        let sso = HashMap::from([("auth_method".to_string(), "saml_sso".to_string())]);
+        let none = HashMap::new();
+
+        let equals = rule("legacy", MetadataOperator::Equals, Some("legacy_basic"), 1.0);
+        assert!(rule_matches(&equals, &legacy));
+        assert!(!rule_matches(&equals, &sso));
+        assert!(!rule_matches(&equals, &none));
+
+        let not_equals = rule("not-sso", MetadataOperator::NotEquals, Some("saml_sso"), 1.0);
+        assert!(rule_matches(&not_equals, &legacy));
+        assert!(!rule_matches(&not_equals, &sso));
+        assert!(!rule_matches(&not_equals, &none), "sin el campo no hay valor que comparar");
+
+        let contains = rule("basic", MetadataOperator::Contains, Some("basic"), 1.0);
+        assert!(rule_matches(&contains, &legacy) && !rule_matches(&contains, &sso));
+
+        let present = rule("declared", MetadataOperator::Present, None, 1.0);
+        let absent = rule("undeclared", MetadataOperator::Absent, None, 1.0);
+        assert!(rule_matches(&present, &sso) && !rule_matches(&present, &none));
+        assert!(rule_matches(&absent, &none) && !rule_matches(&absent, &legacy));
+    }
+
+    #[test]
+    fn matched_rules_add_a_tenant_rule_with_their_summed_weight() {
+        let matcher = PatternMatcher::new();
+        matcher
+            .set_metadata_rules("acme", vec![
+                rule("legacy", MetadataOperator::Equals, Some("legacy_basic"), 0.6),
+                rule("declared", MetadataOperator::Present, None, 0.25),
+                rule("sso", MetadataOperator::Equals, Some("saml_sso"), 0.9),
+            ])
+            .unwrap();
+
+        let detection = matcher.detect_detailed(&with_metadata("acme", &[("auth_method", "legacy_basic")]));
+        assert_eq!(detection.patterns, vec![BehaviorPattern::TenantRule]);
+        assert_eq!(detection.matched_rules, ["legacy", "declared"]);
+        assert!((detection.rule_weight - 0.85).abs() < 1e-9);
+
+        // Las reglas son del tenant que las declaró
+        assert!(matcher.detect_detailed(&with_metadata("globex", &[("auth_method", "legacy_basic")])).patterns.is_empty());
+        // Desactivar TenantRule apaga todas las reglas del tenant
+        matcher.set_disabled(Some("acme"), HashSet::from([BehaviorPattern::TenantRule]));
+        assert!(matcher.detect_detailed(&with_metadata("acme", &[("auth_method", "legacy_basic")])).patterns.is_empty());
+    }
+
+    #[test]
+    fn invalid_rule_sets_are_rejected_whole() {
+        let matcher = PatternMatcher::new();
+        let ok = rule("legacy", MetadataOperator::Equals, Some("legacy_basic"), 1.0);
+        let invalid = [
+            vec![rule("missing-value", MetadataOperator::Equals, None, 0.5)],
+            vec![rule("heavy", MetadataOperator::Present, None, 1.5)],
+            vec![rule("nan", MetadataOperator::Present, None, f64::NAN)],
+            vec![rule(" ", MetadataOperator::Present, None, 0.5)],
+            vec![ok.clone(), ok.clone()],
+            vec![rule(&"x".repeat(MAX_RULE_TEXT_LEN + 1), MetadataOperator::Present, None, 0.5)],
+            (0..=MAX_METADATA_RULES).map(|i| rule(&i.to_string(), MetadataOperator::Present, None, 0.1)).collect(),
+        ];
+        for rules in invalid {
+            assert!(matcher.set_metadata_rules("acme", rules).is_err());
+        }
+        assert!(!matcher.has_metadata_rules("acme"));
+
+        matcher.set_metadata_rules("acme", vec![ok]).unwrap();
+        assert!(matcher.has_metadata_rules("acme"));
+        // Una lista vacía retira las reglas
+        matcher.set_metadata_rules("acme", Vec::new()).unwrap();
+        assert!(!matcher.has_metadata_rules("acme"));
     }
-}
\ No newline at end of file
+}
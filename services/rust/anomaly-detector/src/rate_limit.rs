@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// Con el mapa lleno, como mucho una purga por intervalo (no una por clave nueva)
+const FULL_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// ==========================================
+// RATE LIMITING (VENTANA DESLIZANTE)
+// ==========================================
+
+/// Contador de peticiones por clave en una ventana deslizante.
+///
+/// Cada clave guarda como máximo `limit` timestamps: al superar el límite
+/// no se registran más, así un atacante no puede inflar la memoria. El número de
+/// claves también está acotado (`max_keys`): rotar user_id o hashed_id entre dos
+/// purgas no hace crecer el mapa sin límite.
+pub struct SlidingWindowLimiter {
+    windows: DashMap<String, VecDeque<Instant>>,
+    window: Duration,
+    max_keys: usize,
+    last_full_purge: Mutex<Option<Instant>>,
+}
+
+impl SlidingWindowLimiter {
+    pub fn new(max_keys: usize, window: Duration) -> Self {
+        Self {
+            windows: DashMap::new(),
+            window,
+            max_keys,
+            last_full_purge: Mutex::new(None),
+        }
+    }
+
+    /// Registra una petición para `key` y devuelve `true` si ya se alcanzó `limit`
+    /// dentro de la ventana (la petición NO se cuenta en ese caso).
+    ///
+    /// Lleno de claves vivas, una clave nueva no se sigue y no se limita (como en
+    /// `SessionTracker`): bloquearla dejaría a cualquier usuario nuevo a merced de
+    /// quien llene el mapa. Las claves ya seguidas se siguen limitando.
+    pub fn check(&self, key: &str, limit: usize) -> bool {
+        let now = Instant::now();
+        if self.windows.len() >= self.max_keys && !self.windows.contains_key(key) {
+            self.purge_when_full(now);
+            if self.windows.len() >= self.max_keys {
+                return false;
+            }
+        }
+        let mut hits = self.windows.entry(key.to_string()).or_default();
+
+        // Expirar timestamps fuera de la ventana
+        while let Some(&oldest) = hits.front() {
+            if now.duration_since(oldest) > self.window {
+                hits.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if hits.len() >= limit {
+            return true;
+        }
+        hits.push_back(now);
+        false
+    }
+
+    /// Indica si `check` bloquearía ahora mismo, sin registrar la petición.
+    pub fn peek(&self, key: &str, limit: usize) -> bool {
+        let now = Instant::now();
+        self.windows.get(key).is_some_and(|hits| {
+            hits.iter().filter(|&&at| now.duration_since(at) <= self.window).count() >= limit
+        })
+    }
+
+    /// Tiempo hasta que expire el timestamp más antiguo de `key` (se libera un hueco).
+    pub fn retry_after(&self, key: &str) -> Option<Duration> {
+        let hits = self.windows.get(key)?;
+        let oldest = *hits.front()?;
+        Some(self.window.saturating_sub(oldest.elapsed()))
+    }
+
+    /// Elimina las claves cuya ventana ya expiró por completo.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.windows.retain(|_, hits| {
+            hits.back().is_some_and(|&last| now.duration_since(last) <= self.window)
+        });
+    }
+
+    /// Número de claves seguidas.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    // Purga amortizada: con un flujo de claves nuevas y el mapa lleno de claves vivas,
+    // recorrerlo entero en cada petición sería O(max_keys) en la ruta caliente
+    fn purge_when_full(&self, now: Instant) {
+        let Ok(mut last) = self.last_full_purge.try_lock() else {
+            return;
+        };
+        if last.is_some_and(|at| now.duration_since(at) < FULL_PURGE_INTERVAL) {
+            return;
+        }
+        *last = Some(now);
+        drop(last);
+        self.purge_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_once_the_limit_is_reached_within_the_window() {
+        let limiter = SlidingWindowLimiter::new(100, Duration::from_secs(60));
+        assert!(!limiter.check("acme:42", 2));
+        assert!(!limiter.check("acme:42", 2));
+        assert!(limiter.peek("acme:42", 2));
+        assert!(limiter.check("acme:42", 2));
+        assert!(!limiter.check("acme:7", 2), "cada clave tiene su propia ventana");
+        assert!(limiter.retry_after("acme:42").is_some_and(|d| d <= Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rotating_keys_cannot_grow_the_map_past_max_keys() {
+        let limiter = SlidingWindowLimiter::new(3, Duration::from_secs(60));
+        for user in 0..1000 {
+            limiter.check(&format!("acme:{}", user), 1);
+        }
+        assert_eq!(limiter.len(), 3);
+        // Las claves ya seguidas se siguen limitando
+        assert!(limiter.check("acme:0", 1));
+        assert!(!limiter.check("acme:999", 1), "una clave nueva con el mapa lleno no se sigue");
+    }
+
+    #[test]
+    fn idle_keys_make_room_when_the_map_is_full() {
+        let limiter = SlidingWindowLimiter::new(2, Duration::from_millis(1));
+        limiter.check("acme:1", 5);
+        limiter.check("acme:2", 5);
+        std::thread::sleep(Duration::from_millis(5));
+        limiter.check("acme:3", 5);
+        assert_eq!(limiter.len(), 1);
+        assert!(limiter.retry_after("acme:3").is_some());
+    }
+}
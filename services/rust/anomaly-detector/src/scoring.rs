@@ -0,0 +1,664 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::endpoints::SensitiveEndpoints;
+use crate::geo::LocationRisk;
+use crate::models::{Action, BehaviorPattern, ThreatLevel};
+use crate::user_agent::UaNormalization;
+
+// ==========================================
+// PESOS DEL SCORING ADITIVO (SERVICIO HTTP)
+// ==========================================
+
+/// Peso que suma cada señal de `calculate_anomaly_score` cuando no coincide con el baseline.
+///
+/// Un tenant con plantilla remota global puede bajar `location` sin recompilar.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoringWeights {
+    pub location: f32,
+    /// Qué fracción de `location` suma cada país no típico (vecino, regional, lejano).
+    #[serde(default)]
+    pub location_risk: LocationRisk,
+    pub time: f32,
+    pub user_agent: f32,
+    pub new_endpoint: f32,
+    /// Peso de un endpoint sensible que el usuario nunca había usado (posible toma de cuenta).
+    #[serde(default = "default_sensitive_endpoint_weight")]
+    pub sensitive_endpoint: f32,
+    #[serde(default)]
+    pub sensitive_endpoints: SensitiveEndpoints,
+    /// Peso extra cuando los endpoints nuevos llegan en ráfaga (escaneo).
+    pub endpoint_enumeration: f32,
+    /// Ventana (s) y número de endpoints nuevos distintos que cuentan como escaneo.
+    pub enumeration_window_secs: u32,
+    pub enumeration_threshold: u32,
+    /// Días sin usar un endpoint tras los que deja de contar como conocido.
+    #[serde(default = "default_endpoint_history_days")]
+    pub endpoint_history_days: u32,
+    pub impossible_travel: f32,
+    /// Peso de una misma sesión vista desde otro país (o red) en poco tiempo.
+    #[serde(default = "default_session_hijack_weight")]
+    pub session_hijack: f32,
+    /// Multiplica el score (0–1) del motor de patrones al sumarlo al score aditivo.
+    pub behavioral: f32,
+    /// Velocidad (km/h) por encima de la cual dos logins consecutivos son "viaje imposible".
+    pub max_travel_speed_kmh: f64,
+    /// Puntos aditivos que llevan el score normalizado a 0.5 (ver `normalize`).
+    pub half_score_points: f32,
+    /// Cómo se normaliza el User-Agent antes de compararlo con los conocidos.
+    pub user_agent_mode: UaNormalization,
+}
+
+impl Default for ScoringWeights {
+    fn default() -> Self {
+        Self {
+            location: 3.0,
+            location_risk: LocationRisk::default(),
+            time: 1.5, // Peso bajo: puede ser trabajo nocturno
+            user_agent: 2.0,
+            new_endpoint: 0.5, // Pequeña penalización por exploración normal
+            sensitive_endpoint: default_sensitive_endpoint_weight(),
+            sensitive_endpoints: SensitiveEndpoints::default(),
+            endpoint_enumeration: 5.0,
+            enumeration_window_secs: 10,
+            enumeration_threshold: 20,
+            endpoint_history_days: default_endpoint_history_days(),
+            impossible_travel: 5.0,
+            session_hijack: default_session_hijack_weight(),
+            behavioral: 7.0, // Un 1.0 del motor de patrones equivale a riesgo "critical"
+            max_travel_speed_kmh: 1000.0, // ~ avión comercial
+            half_score_points: 2.0,
+            user_agent_mode: UaNormalization::default(),
+        }
+    }
+}
+
+impl ScoringWeights {
+    /// Lee los pesos de `ANOMALY_WEIGHT_{LOCATION,TIME,USER_AGENT,ENDPOINT,SENSITIVE_ENDPOINT,ENUMERATION,TRAVEL,SESSION,BEHAVIORAL}`,
+    /// los endpoints sensibles de `ANOMALY_SENSITIVE_ENDPOINTS`,
+    /// el riesgo por país de `ANOMALY_COUNTRY_RISK`,
+    /// la ráfaga de `ANOMALY_ENUMERATION_{WINDOW_SECS,THRESHOLD}`, la ventana de
+    /// endpoints conocidos de `ANOMALY_ENDPOINT_HISTORY_DAYS`,
+    /// la velocidad máxima de `ANOMALY_MAX_TRAVEL_KMH` y el modo de
+    /// `ANOMALY_UA_NORMALIZATION` (`exact`, `major_version`, `family_only`).
+    /// Las variables ausentes o inválidas conservan el valor por defecto.
+    pub fn from_env() -> Self {
+        Self::from_env_or(Self::default())
+    }
+
+    /// Como `from_env`, pero partiendo de `defaults` (p.ej. los de un fichero de configuración).
+    pub fn from_env_or(defaults: Self) -> Self {
+        Self {
+            location: env_weight("ANOMALY_WEIGHT_LOCATION", defaults.location),
+            location_risk: defaults.location_risk.with_env_overrides(),
+            time: env_weight("ANOMALY_WEIGHT_TIME", defaults.time),
+            user_agent: env_weight("ANOMALY_WEIGHT_USER_AGENT", defaults.user_agent),
+            new_endpoint: env_weight("ANOMALY_WEIGHT_ENDPOINT", defaults.new_endpoint),
+            sensitive_endpoint: env_weight("ANOMALY_WEIGHT_SENSITIVE_ENDPOINT", defaults.sensitive_endpoint),
+            sensitive_endpoints: std::env::var("ANOMALY_SENSITIVE_ENDPOINTS")
+                .map_or(defaults.sensitive_endpoints, |raw| SensitiveEndpoints::parse(&raw)),
+            endpoint_enumeration: env_weight("ANOMALY_WEIGHT_ENUMERATION", defaults.endpoint_enumeration),
+            enumeration_window_secs: env_weight("ANOMALY_ENUMERATION_WINDOW_SECS", defaults.enumeration_window_secs),
+            enumeration_threshold: env_weight("ANOMALY_ENUMERATION_THRESHOLD", defaults.enumeration_threshold),
+            endpoint_history_days: match env_weight("ANOMALY_ENDPOINT_HISTORY_DAYS", defaults.endpoint_history_days) {
+                days if days > 0 => days,
+                _ => defaults.endpoint_history_days,
+            },
+            impossible_travel: env_weight("ANOMALY_WEIGHT_TRAVEL", defaults.impossible_travel),
+            session_hijack: env_weight("ANOMALY_WEIGHT_SESSION", defaults.session_hijack),
+            behavioral: env_weight("ANOMALY_WEIGHT_BEHAVIORAL", defaults.behavioral),
+            max_travel_speed_kmh: env_weight("ANOMALY_MAX_TRAVEL_KMH", defaults.max_travel_speed_kmh),
+            half_score_points: match env_weight("ANOMALY_HALF_SCORE_POINTS", defaults.half_score_points) {
+                points if points > 0.0 => points,
+                _ => defaults.half_score_points,
+            },
+            user_agent_mode: match std::env::var("ANOMALY_UA_NORMALIZATION") {
+                Ok(raw) => raw.parse().unwrap_or_else(|e| {
+                    log::warn!("[CONFIG] {}, usando {:?}", e, defaults.user_agent_mode);
+                    defaults.user_agent_mode
+                }),
+                Err(_) => defaults.user_agent_mode,
+            },
+        }
+    }
+
+    /// Pesos finitos y no negativos, con `half_score_points` positivo.
+    pub fn validate(&self) -> Result<(), String> {
+        let weights = [
+            ("location", self.location),
+            ("time", self.time),
+            ("user_agent", self.user_agent),
+            ("new_endpoint", self.new_endpoint),
+            ("sensitive_endpoint", self.sensitive_endpoint),
+            ("endpoint_enumeration", self.endpoint_enumeration),
+            ("impossible_travel", self.impossible_travel),
+            ("session_hijack", self.session_hijack),
+            ("behavioral", self.behavioral),
+        ];
+        if let Some((name, value)) = weights.iter().find(|(_, v)| !v.is_finite() || *v < 0.0) {
+            return Err(format!("scoring_weights.{} must be a non-negative number (got {})", name, value));
+        }
+        self.location_risk.validate()?;
+        if !(1..=MAX_ENDPOINT_HISTORY_DAYS).contains(&self.endpoint_history_days) {
+            return Err(format!(
+                "scoring_weights.endpoint_history_days must be within 1..={} (got {})",
+                MAX_ENDPOINT_HISTORY_DAYS, self.endpoint_history_days
+            ));
+        }
+        if !(self.half_score_points.is_finite() && self.half_score_points > 0.0) {
+            return Err(format!("scoring_weights.half_score_points must be > 0 (got {})", self.half_score_points));
+        }
+        if !(self.max_travel_speed_kmh.is_finite() && self.max_travel_speed_kmh > 0.0) {
+            return Err(format!("scoring_weights.max_travel_speed_kmh must be > 0 (got {})", self.max_travel_speed_kmh));
+        }
+        Ok(())
+    }
+
+    /// Ventana de `endpoint_history_days` como duración.
+    pub fn endpoint_history_window(&self) -> chrono::Duration {
+        chrono::Duration::days(i64::from(self.endpoint_history_days.min(MAX_ENDPOINT_HISTORY_DAYS)))
+    }
+
+    /// Lleva la suma de pesos (sin techo) a la escala 0–1 del detector.
+    ///
+    /// Saturación exponencial: cada `half_score_points` puntos reducen a la mitad
+    /// la distancia a 1.0. Con los valores por defecto 2.0 → 0.5, 4.5 → 0.79 y 7.0 → 0.91,
+    /// lo que conserva los cortes históricos del servicio HTTP (2.0 / 4.5 / 7.0).
+    pub fn normalize(&self, raw: f32) -> f32 {
+        if raw <= 0.0 {
+            return 0.0;
+        }
+        1.0 - 0.5f32.powf(raw / self.half_score_points)
+    }
+}
+
+// Diez años: muy por debajo del rango de chrono::Duration
+const MAX_ENDPOINT_HISTORY_DAYS: u32 = 3650;
+
+// Un mes: cubre procesos mensuales (cierres, nóminas) sin arrastrar rutas abandonadas
+fn default_endpoint_history_days() -> u32 {
+    30
+}
+
+// Como el viaje imposible: una cookie en dos países a la vez es robo casi seguro
+fn default_session_hijack_weight() -> f32 {
+    5.0
+}
+
+// Entre un dispositivo nuevo y la enumeración: por sí solo lleva el score a "high"
+fn default_sensitive_endpoint_weight() -> f32 {
+    4.0
+}
+
+/// Horario laboral declarado por un tenant (hora local, `start` incluida, `end` excluida).
+/// Un turno nocturno cruza la medianoche (`22-6`). Actúa como conocimiento previo:
+/// dentro del horario nunca hay "Unusual Time"; fuera, solo si no se aprendió esa hora.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl WorkHours {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+
+    /// Horas en 0–23 y distintas (un horario de 24 h no aporta nada: no se configura).
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start > 23 || self.end > 23 || self.start == self.end {
+            return Err(format!(
+                "work hours must be two different hours within 0..=23 (got {}-{})",
+                self.start, self.end
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for WorkHours {
+    type Err = String;
+
+    /// `"8-18"` (de 08:00 a 17:59) o `"22-6"`.
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (start, end) = raw
+            .split_once('-')
+            .and_then(|(s, e)| Some((s.trim().parse().ok()?, e.trim().parse().ok()?)))
+            .ok_or_else(|| format!("Invalid work hours '{}' (expected START-END, e.g. 8-18)", raw))?;
+        let hours = Self { start, end };
+        hours.validate()?;
+        Ok(hours)
+    }
+}
+
+impl std::fmt::Display for WorkHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}-{:02}", self.start, self.end)
+    }
+}
+
+/// Huella estable (FNV-1a de 64 bits, en hex) de la configuración de scoring.
+///
+/// `serde_json::Value` ordena las claves de los objetos, así que el mismo conjunto de
+/// pesos y umbrales da la misma huella en cualquier réplica y en cualquier arranque.
+pub fn ruleset_fingerprint(ruleset: &serde_json::Value) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in ruleset.to_string().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Sensibilidad con la que los scores no cambian (el valor por defecto de `SecurityConfig`).
+pub const NEUTRAL_SENSITIVITY: f64 = 0.8;
+
+/// Aplica `SecurityConfig::sensitivity` a un score normalizado:
+/// `min(1, score × sensitivity / NEUTRAL_SENSITIVITY)`.
+///
+/// Con 0.8 el score queda igual, con 1.0 se multiplica por 1.25 y con 0.2 por 0.25.
+/// Es monótona en ambos argumentos, así que subir la sensibilidad nunca baja un score.
+pub fn apply_sensitivity(score: f64, sensitivity: f64) -> f64 {
+    (score * sensitivity / NEUTRAL_SENSITIVITY).clamp(0.0, 1.0)
+}
+
+/// Observaciones con las que la confianza de un perfil llega a 0.5.
+pub const CONFIDENCE_HALF_OBSERVATIONS: f64 = 20.0;
+
+/// Confianza (0–1) en un score según cuántas observaciones respaldan el perfil.
+/// Un perfil recién creado da ~0 y tiende a 1 con la madurez.
+pub fn maturity_confidence(observations: u64) -> f64 {
+    let n = observations as f64;
+    n / (n + CONFIDENCE_HALF_OBSERVATIONS)
+}
+
+// ==========================================
+// PESOS POR PATRÓN (DETECTOR)
+// ==========================================
+
+/// Peso base de cada patrón en el score del detector, antes de los multiplicadores
+/// por failure_rate y por tenant. Los patrones detectados se suman (con techo 1.0).
+pub fn default_pattern_weights() -> HashMap<BehaviorPattern, f64> {
+    HashMap::from([
+        (BehaviorPattern::PayloadInjection, 1.0),
+        (BehaviorPattern::CredentialSpray, 0.9),
+        (BehaviorPattern::Enumeration, 0.8),
+        (BehaviorPattern::ResourceAbuse, 0.7),
+        (BehaviorPattern::BadReputation, 0.7),
+        (BehaviorPattern::RapidFailures, 0.6),
+        (BehaviorPattern::TimingAttack, 0.5),
+        (BehaviorPattern::DeviceChange, 0.4),
+        (BehaviorPattern::AnomalousLocation, 0.3),
+        (BehaviorPattern::Normal, 0.0),
+    ])
+}
+
+/// `weights` con los cambios de `ANOMALY_PATTERN_WEIGHTS`
+/// (`"DeviceChange=0.2,AnomalousLocation=0.5"`). Las entradas inválidas se ignoran.
+pub fn pattern_weights_from_env(mut weights: HashMap<BehaviorPattern, f64>) -> HashMap<BehaviorPattern, f64> {
+    let raw = std::env::var("ANOMALY_PATTERN_WEIGHTS").unwrap_or_default();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=').and_then(|(name, weight)| {
+            let pattern = serde_json::from_value(serde_json::Value::String(name.trim().to_string())).ok()?;
+            let weight = weight.trim().parse::<f64>().ok().filter(|w| w.is_finite() && *w >= 0.0)?;
+            Some((pattern, weight))
+        });
+        match parsed {
+            Some((pattern, weight)) => {
+                weights.insert(pattern, weight);
+            }
+            None => log::warn!("[CONFIG] ANOMALY_PATTERN_WEIGHTS: entrada inválida {:?}", entry),
+        }
+    }
+    weights
+}
+
+// ==========================================
+// UMBRALES DE RIESGO (ESCALA ÚNICA 0–1)
+// ==========================================
+
+/// Cortes de nivel de amenaza sobre el score normalizado 0–1.
+///
+/// Es la única escala del sistema: el detector la aplica directamente y el servicio HTTP
+/// normaliza su score aditivo con `ScoringWeights::normalize` antes de clasificarlo.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RiskThresholds {
+    pub low: f64,
+    pub medium: f64,
+    pub high: f64,
+    pub critical: f64,
+}
+
+impl Default for RiskThresholds {
+    fn default() -> Self {
+        Self {
+            low: 0.25,
+            medium: 0.5,
+            high: 0.75,
+            critical: 0.9,
+        }
+    }
+}
+
+impl RiskThresholds {
+    /// Lee `ANOMALY_RISK_{LOW,MEDIUM,HIGH,CRITICAL}`. Si los cortes no quedan
+    /// estrictamente crecientes dentro de (0, 1] se usan los valores por defecto.
+    pub fn from_env() -> Self {
+        Self::from_env_or(Self::default())
+    }
+
+    /// Como `from_env`, pero con `defaults` como valores base y de respaldo.
+    pub fn from_env_or(defaults: Self) -> Self {
+        let thresholds = Self {
+            low: env_weight("ANOMALY_RISK_LOW", defaults.low),
+            medium: env_weight("ANOMALY_RISK_MEDIUM", defaults.medium),
+            high: env_weight("ANOMALY_RISK_HIGH", defaults.high),
+            critical: env_weight("ANOMALY_RISK_CRITICAL", defaults.critical),
+        };
+        if thresholds.is_valid() {
+            thresholds
+        } else {
+            log::warn!("[CONFIG] Umbrales de riesgo no crecientes {:?}, usando {:?}", thresholds, defaults);
+            defaults
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        0.0 < self.low && self.low < self.medium && self.medium < self.high && self.high < self.critical && self.critical <= 1.0
+    }
+
+    /// Nivel de amenaza correspondiente a un score normalizado.
+    pub fn level(&self, score: f64) -> ThreatLevel {
+        match score {
+            s if s >= self.critical => ThreatLevel::Critical,
+            s if s >= self.high => ThreatLevel::High,
+            s if s >= self.medium => ThreatLevel::Medium,
+            s if s >= self.low => ThreatLevel::Low,
+            _ => ThreatLevel::Safe,
+        }
+    }
+
+    /// `risk_level` de la API HTTP. Safe y Low se agrupan como "low".
+    pub fn risk_level(&self, score: f64) -> &'static str {
+        match self.level(score) {
+            ThreatLevel::Critical => "critical",
+            ThreatLevel::High => "high",
+            ThreatLevel::Medium => "medium",
+            ThreatLevel::Low | ThreatLevel::Safe => "low",
+        }
+    }
+}
+
+/// Cortes de score (0–1) a partir de los cuales se exige un challenge o se bloquea.
+///
+/// Independientes de las etiquetas de `RiskThresholds`: renombrar o mover un nivel de
+/// riesgo no cambia qué se bloquea. Por defecto coinciden con los cortes "high" y "critical".
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ActionThresholds {
+    pub challenge: f64,
+    pub block: f64,
+}
+
+impl Default for ActionThresholds {
+    fn default() -> Self {
+        Self::from_risk(&RiskThresholds::default())
+    }
+}
+
+impl ActionThresholds {
+    /// Cortes equivalentes al mapeo histórico: "high" → CHALLENGE, "critical" → BLOCK.
+    pub fn from_risk(risk: &RiskThresholds) -> Self {
+        Self {
+            challenge: risk.high,
+            block: risk.critical,
+        }
+    }
+
+    /// Lee `ANOMALY_ACTION_{CHALLENGE,BLOCK}` partiendo de `defaults`; si los cortes
+    /// resultantes no son válidos se usan `defaults`.
+    pub fn from_env_or(defaults: Self) -> Self {
+        let thresholds = Self {
+            challenge: env_weight("ANOMALY_ACTION_CHALLENGE", defaults.challenge),
+            block: env_weight("ANOMALY_ACTION_BLOCK", defaults.block),
+        };
+        if thresholds.is_valid() {
+            thresholds
+        } else {
+            log::warn!("[CONFIG] Cortes de acción inválidos {:?}, usando {:?}", thresholds, defaults);
+            defaults
+        }
+    }
+
+    /// `0 < challenge <= block <= 1` (con `challenge == block` nunca se pide challenge).
+    pub fn is_valid(&self) -> bool {
+        0.0 < self.challenge && self.challenge <= self.block && self.block <= 1.0
+    }
+
+    /// Acción para un score normalizado; cada corte es inclusivo.
+    pub fn action(&self, score: f64) -> Action {
+        match score {
+            s if s >= self.block => Action::Block,
+            s if s >= self.challenge => Action::Challenge,
+            _ => Action::Allow,
+        }
+    }
+}
+
+fn env_weight<T>(var: &str, default: T) -> T
+where
+    T: std::str::FromStr + Into<f64> + Copy + std::fmt::Display,
+{
+    match std::env::var(var) {
+        Ok(raw) => match raw.trim().parse::<T>() {
+            Ok(value) if value.into().is_finite() && value.into() >= 0.0 => value,
+            _ => {
+                log::warn!("[CONFIG] {}={:?} no es un peso válido, usando {}", var, raw, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// ==========================================
+// TIPO DE CHALLENGE POR NIVEL DE RIESGO
+// ==========================================
+
+/// Paso concreto que el gateway debe presentar cuando la acción es CHALLENGE,
+/// según el `risk_level` (p.ej. CAPTCHA en medium, MFA completo en high).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeTypes {
+    pub low: String,
+    pub medium: String,
+    pub high: String,
+    pub critical: String,
+}
+
+impl Default for ChallengeTypes {
+    fn default() -> Self {
+        Self {
+            low: "CAPTCHA".to_string(),
+            medium: "CAPTCHA".to_string(),
+            high: "MFA".to_string(),
+            critical: "MFA".to_string(),
+        }
+    }
+}
+
+impl ChallengeTypes {
+    /// `defaults` con los cambios de `ANOMALY_CHALLENGE_TYPES`
+    /// (`"medium=CAPTCHA,high=EMAIL_VERIFICATION"`). Las entradas inválidas se ignoran.
+    pub fn from_env_or(mut defaults: Self) -> Self {
+        let raw = std::env::var("ANOMALY_CHALLENGE_TYPES").unwrap_or_default();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let parsed = entry
+                .split_once('=')
+                .map(|(level, challenge)| (level.trim(), challenge.trim()))
+                .filter(|(_, challenge)| !challenge.is_empty());
+            let slot = match parsed {
+                Some(("low", _)) => &mut defaults.low,
+                Some(("medium", _)) => &mut defaults.medium,
+                Some(("high", _)) => &mut defaults.high,
+                Some(("critical", _)) => &mut defaults.critical,
+                _ => {
+                    log::warn!("[CONFIG] ANOMALY_CHALLENGE_TYPES: entrada inválida {:?}", entry);
+                    continue;
+                }
+            };
+            if let Some((_, challenge)) = parsed {
+                *slot = challenge.to_uppercase();
+            }
+        }
+        defaults
+    }
+
+    /// Challenge para un `risk_level` de la API ("low" si no se reconoce).
+    pub fn for_level(&self, risk_level: &str) -> &str {
+        match risk_level {
+            "critical" => &self.critical,
+            "high" => &self.high,
+            "medium" => &self.medium,
+            _ => &self.low,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let levels = [("low", &self.low), ("medium", &self.medium), ("high", &self.high), ("critical", &self.critical)];
+        match levels.iter().find(|(_, challenge)| challenge.trim().is_empty()) {
+            Some((level, _)) => Err(format!("challenge_types.{} must not be empty", level)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_keeps_the_historic_cuts() {
+        let weights = ScoringWeights::default();
+        assert_eq!(weights.normalize(0.0), 0.0);
+        assert_eq!(weights.normalize(-3.0), 0.0);
+        assert!((weights.normalize(2.0) - 0.5).abs() < 1e-6);
+        assert!((weights.normalize(4.5) - 0.79).abs() < 0.01);
+        assert!((weights.normalize(7.0) - 0.91).abs() < 0.01);
+        assert!(weights.normalize(1000.0) <= 1.0);
+
+        let steeper = ScoringWeights { half_score_points: 1.0, ..ScoringWeights::default() };
+        assert!((steeper.normalize(2.0) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn validate_rejects_negative_or_non_finite_weights() {
+        assert!(ScoringWeights::default().validate().is_ok());
+        let err = ScoringWeights { time: -1.0, ..ScoringWeights::default() }.validate().unwrap_err();
+        assert!(err.contains("scoring_weights.time"), "{}", err);
+        assert!(ScoringWeights { behavioral: f32::NAN, ..ScoringWeights::default() }.validate().is_err());
+        assert!(ScoringWeights { half_score_points: 0.0, ..ScoringWeights::default() }.validate().is_err());
+        assert!(ScoringWeights { max_travel_speed_kmh: 0.0, ..ScoringWeights::default() }.validate().is_err());
+        // Un peso a cero desactiva la señal: es válido
+        assert!(ScoringWeights { location: 0.0, ..ScoringWeights::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn risk_thresholds_are_inclusive_lower_bounds() {
+        let thresholds = RiskThresholds::default();
+        assert!(thresholds.is_valid());
+        assert_eq!(thresholds.level(0.0), ThreatLevel::Safe);
+        assert_eq!(thresholds.level(0.2499), ThreatLevel::Safe);
+        assert_eq!(thresholds.level(0.25), ThreatLevel::Low);
+        assert_eq!(thresholds.level(0.5), ThreatLevel::Medium);
+        assert_eq!(thresholds.level(0.7499), ThreatLevel::Medium);
+        assert_eq!(thresholds.level(0.75), ThreatLevel::High);
+        assert_eq!(thresholds.level(0.9), ThreatLevel::Critical);
+        assert_eq!(thresholds.level(1.0), ThreatLevel::Critical);
+        // La API HTTP agrupa Safe y Low
+        assert_eq!(thresholds.risk_level(0.1), "low");
+        assert_eq!(thresholds.risk_level(0.3), "low");
+        assert_eq!(thresholds.risk_level(0.6), "medium");
+        assert_eq!(thresholds.risk_level(0.8), "high");
+        assert_eq!(thresholds.risk_level(0.95), "critical");
+    }
+
+    #[test]
+    fn risk_thresholds_must_increase_within_the_unit_interval() {
+        let valid = RiskThresholds { low: 0.1, medium: 0.2, high: 0.3, critical: 1.0 };
+        assert!(valid.is_valid());
+        assert!(!RiskThresholds { medium: 0.1, ..valid }.is_valid());
+        assert!(!RiskThresholds { high: 0.2, ..valid }.is_valid());
+        assert!(!RiskThresholds { low: 0.0, ..valid }.is_valid());
+        assert!(!RiskThresholds { critical: 1.5, ..valid }.is_valid());
+    }
+
+    #[test]
+    fn action_cutoffs_are_inclusive_at_each_boundary() {
+        let cuts = ActionThresholds { challenge: 0.6, block: 0.85 };
+        assert_eq!(cuts.action(0.0), Action::Allow);
+        assert_eq!(cuts.action(0.5999), Action::Allow);
+        assert_eq!(cuts.action(0.6), Action::Challenge);
+        assert_eq!(cuts.action(0.8499), Action::Challenge);
+        assert_eq!(cuts.action(0.85), Action::Block);
+        assert_eq!(cuts.action(1.0), Action::Block);
+
+        // Con challenge == block nunca se pide challenge
+        let no_challenge = ActionThresholds { challenge: 0.7, block: 0.7 };
+        assert_eq!(no_challenge.action(0.69), Action::Allow);
+        assert_eq!(no_challenge.action(0.7), Action::Block);
+    }
+
+    #[test]
+    fn action_cutoffs_are_independent_of_risk_labels() {
+        let defaults = ActionThresholds::default();
+        let risk = RiskThresholds::default();
+        assert_eq!((defaults.challenge, defaults.block), (risk.high, risk.critical));
+        // Mover las etiquetas después de construir los cortes no cambia la acción
+        let relabeled = RiskThresholds { high: 0.2, ..risk };
+        assert_eq!(relabeled.level(0.3), ThreatLevel::High);
+        assert_eq!(defaults.action(0.3), Action::Allow);
+    }
+
+    #[test]
+    fn action_cutoffs_validation() {
+        assert!(ActionThresholds::default().is_valid());
+        assert!(ActionThresholds { challenge: 0.7, block: 0.7 }.is_valid());
+        assert!(!ActionThresholds { challenge: 0.0, block: 0.5 }.is_valid());
+        assert!(!ActionThresholds { challenge: 0.8, block: 0.6 }.is_valid());
+        assert!(!ActionThresholds { challenge: 0.5, block: 1.5 }.is_valid());
+    }
+
+    #[test]
+    fn endpoint_history_window_is_validated_and_capped() {
+        let days = |endpoint_history_days| ScoringWeights { endpoint_history_days, ..ScoringWeights::default() };
+        assert_eq!(days(7).endpoint_history_window(), chrono::Duration::days(7));
+        assert!(days(0).validate().is_err());
+        assert!(days(MAX_ENDPOINT_HISTORY_DAYS + 1).validate().is_err());
+        // Aunque llegue sin validar, la ventana nunca supera el tope
+        assert_eq!(
+            days(u32::MAX).endpoint_history_window(),
+            chrono::Duration::days(i64::from(MAX_ENDPOINT_HISTORY_DAYS))
+        );
+    }
+
+    #[test]
+    fn work_hours_include_start_and_exclude_end() {
+        let office: WorkHours = "8-18".parse().unwrap();
+        assert!(!office.contains(7) && office.contains(8) && office.contains(17) && !office.contains(18));
+        // Turno nocturno: cruza la medianoche
+        let night: WorkHours = " 22 - 6 ".parse().unwrap();
+        assert!(night.contains(22) && night.contains(0) && night.contains(5));
+        assert!(!night.contains(6) && !night.contains(12) && !night.contains(21));
+        assert_eq!(night.to_string(), "22-06");
+    }
+
+    #[test]
+    fn work_hours_reject_invalid_windows() {
+        for raw in ["8", "8-24", "25-3", "9-9", "a-b", ""] {
+            assert!(raw.parse::<WorkHours>().is_err(), "{}", raw);
+        }
+        assert!(WorkHours { start: 8, end: 18 }.validate().is_ok());
+    }
+}
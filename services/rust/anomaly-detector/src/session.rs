@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// Con el mapa lleno, como mucho una purga por intervalo (no una por sesión nueva)
+const FULL_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// ==========================================
+// REUTILIZACIÓN DE SESIÓN ENTRE REDES
+// ==========================================
+
+// Última aparición de una sesión
+struct Sighting {
+    ip: IpAddr,
+    country: Option<String>,
+    at: Instant,
+}
+
+/// Origen anterior de una sesión que saltó de red de forma improbable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionJump {
+    pub previous_ip: IpAddr,
+    pub previous_country: Option<String>,
+}
+
+/// Última IP/país de cada `(tenant_id, session_id)`.
+///
+/// A diferencia del viaje imposible (por usuario, días de historial) una sesión vive
+/// minutos: la misma cookie desde otro país casi siempre es una cookie robada. Las
+/// entradas caducan `ttl` después de su última aparición y el mapa está acotado.
+pub struct SessionTracker {
+    sessions: DashMap<(String, String), Sighting>,
+    ttl: Duration,
+    max_sessions: usize,
+    last_full_purge: Mutex<Option<Instant>>,
+}
+
+impl SessionTracker {
+    pub fn new(max_sessions: usize, ttl: Duration) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            ttl,
+            max_sessions,
+            last_full_purge: Mutex::new(None),
+        }
+    }
+
+    /// Registra la sesión vista desde `ip` (`country` = None si no se conoce) y devuelve
+    /// el origen anterior si el salto es improbable: otro país, o sin países, otra red.
+    pub fn record(&self, tenant_id: &str, session_id: &str, ip: IpAddr, country: Option<&str>) -> Option<SessionJump> {
+        let now = Instant::now();
+        let key = (tenant_id.to_string(), session_id.to_string());
+        // Lleno de sesiones vivas: las nuevas no se siguen (las conocidas sí)
+        if self.sessions.len() >= self.max_sessions && !self.sessions.contains_key(&key) {
+            self.purge_when_full(now);
+            if self.sessions.len() >= self.max_sessions {
+                return None;
+            }
+        }
+
+        let sighting = Sighting { ip, country: country.map(str::to_string), at: now };
+        let previous = self.sessions.insert(key, sighting)?;
+        self.jump(&previous, ip, country, now)
+    }
+
+    /// Igual que `record`, sin registrar la aparición.
+    pub fn peek(&self, tenant_id: &str, session_id: &str, ip: IpAddr, country: Option<&str>) -> Option<SessionJump> {
+        let previous = self.sessions.get(&(tenant_id.to_string(), session_id.to_string()))?;
+        self.jump(&previous, ip, country, Instant::now())
+    }
+
+    /// Elimina las sesiones no vistas dentro de `ttl`.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.sessions.retain(|_, sighting| now.duration_since(sighting.at) <= self.ttl);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // Purga con el mapa lleno, amortizada: si todas las sesiones siguen vivas,
+    // recorrerlo entero por cada sesión nueva sería O(max_sessions) en la ruta caliente
+    fn purge_when_full(&self, now: Instant) {
+        let Ok(mut last) = self.last_full_purge.try_lock() else {
+            return;
+        };
+        if last.is_some_and(|at| now.duration_since(at) < FULL_PURGE_INTERVAL) {
+            return;
+        }
+        *last = Some(now);
+        drop(last);
+        self.purge_idle();
+    }
+
+    fn jump(&self, previous: &Sighting, ip: IpAddr, country: Option<&str>, now: Instant) -> Option<SessionJump> {
+        if now.duration_since(previous.at) > self.ttl || previous.ip == ip {
+            return None;
+        }
+        let improbable = match (previous.country.as_deref(), country) {
+            (Some(before), Some(after)) => before != after,
+            // Sin geolocalización: un cambio dentro de la misma red (DHCP, NAT del operador) es
+            // normal, y uno entre IPv4 e IPv6 (dual-stack) no es concluyente
+            _ => same_network(previous.ip, ip) == Some(false),
+        };
+        improbable.then(|| SessionJump {
+            previous_ip: previous.ip,
+            previous_country: previous.country.clone(),
+        })
+    }
+}
+
+// Misma /16 (IPv4) o /32 (IPv6): mismo operador o red corporativa. None entre familias
+// distintas: un mismo cliente dual-stack alterna entre ambas y no hay prefijo que comparar
+fn same_network(a: IpAddr, b: IpAddr) -> Option<bool> {
+    match (a.to_canonical(), b.to_canonical()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => Some(a.octets()[..2] == b.octets()[..2]),
+        (IpAddr::V6(a), IpAddr::V6(b)) => Some(a.segments()[..2] == b.segments()[..2]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn a_hijacked_session_from_a_second_country_is_a_jump() {
+        let tracker = SessionTracker::new(100, Duration::from_secs(1800));
+        assert_eq!(tracker.record("acme", "sess-1", ip("198.51.100.7"), Some("ES")), None);
+        // Otra IP del mismo país: el usuario cambió de red
+        assert_eq!(tracker.record("acme", "sess-1", ip("203.0.113.9"), Some("ES")), None);
+
+        let expected = SessionJump { previous_ip: ip("203.0.113.9"), previous_country: Some("ES".to_string()) };
+        assert_eq!(tracker.peek("acme", "sess-1", ip("192.0.2.44"), Some("RU")), Some(expected.clone()));
+        assert_eq!(tracker.record("acme", "sess-1", ip("192.0.2.44"), Some("RU")), Some(expected));
+        // La misma cookie en otro tenant es otra sesión
+        assert_eq!(tracker.record("globex", "sess-1", ip("198.51.100.7"), Some("ES")), None);
+    }
+
+    #[test]
+    fn without_countries_only_a_different_network_is_a_jump() {
+        let tracker = SessionTracker::new(100, Duration::from_secs(1800));
+        tracker.record("acme", "sess-1", ip("198.51.100.7"), None);
+        assert_eq!(tracker.record("acme", "sess-1", ip("198.51.7.20"), None), None, "misma /16");
+        assert!(tracker.record("acme", "sess-1", ip("203.0.113.9"), None).is_some());
+
+        tracker.record("acme", "sess-2", ip("2001:db8:1::10"), None);
+        assert_eq!(tracker.record("acme", "sess-2", ip("2001:db8:2::20"), None), None, "misma /32");
+        assert!(tracker.record("acme", "sess-2", ip("2001:db9::1"), None).is_some());
+    }
+
+    #[test]
+    fn a_dual_stack_flip_without_countries_is_inconclusive() {
+        let tracker = SessionTracker::new(100, Duration::from_secs(1800));
+        tracker.record("acme", "sess-1", ip("198.51.100.7"), None);
+        assert_eq!(tracker.record("acme", "sess-1", ip("2001:db8::1"), None), None);
+        assert_eq!(tracker.record("acme", "sess-1", ip("198.51.100.7"), None), None);
+        // Una IPv4 mapeada en IPv6 es la misma familia
+        assert!(tracker.record("acme", "sess-1", ip("::ffff:203.0.113.9"), None).is_some());
+        // Con países sí se compara aunque cambie la familia
+        tracker.record("acme", "sess-2", ip("198.51.100.7"), Some("ES"));
+        assert!(tracker.record("acme", "sess-2", ip("2001:db8::1"), Some("RU")).is_some());
+    }
+
+    #[test]
+    fn an_expired_sighting_is_not_a_jump() {
+        let tracker = SessionTracker::new(100, Duration::from_millis(1));
+        tracker.record("acme", "sess-1", ip("198.51.100.7"), Some("ES"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(tracker.record("acme", "sess-1", ip("192.0.2.44"), Some("RU")), None);
+    }
+
+    #[test]
+    fn a_full_tracker_keeps_following_known_sessions() {
+        let tracker = SessionTracker::new(2, Duration::from_secs(1800));
+        for session in 0..100 {
+            tracker.record("acme", &format!("sess-{}", session), ip("198.51.100.7"), Some("ES"));
+        }
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.record("acme", "sess-0", ip("192.0.2.44"), Some("RU")).is_some());
+        tracker.record("acme", "sess-99", ip("198.51.100.7"), Some("ES"));
+        assert_eq!(tracker.record("acme", "sess-99", ip("192.0.2.44"), Some("RU")), None, "no se sigue");
+    }
+
+    #[test]
+    fn a_full_tracker_purges_idle_sessions_at_most_once_per_interval() {
+        let tracker = SessionTracker::new(1, Duration::from_millis(1));
+        tracker.record("acme", "sess-1", ip("198.51.100.7"), None);
+        std::thread::sleep(Duration::from_millis(5));
+        tracker.record("acme", "sess-2", ip("198.51.100.7"), None);
+        assert!(tracker.peek("acme", "sess-2", ip("203.0.113.9"), None).is_some(), "la purga hizo sitio");
+
+        // sess-2 ya caducó, pero dentro del intervalo no se vuelve a recorrer el mapa
+        std::thread::sleep(Duration::from_millis(5));
+        tracker.record("acme", "sess-3", ip("198.51.100.7"), None);
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.record("acme", "sess-3", ip("203.0.113.9"), None), None);
+    }
+}
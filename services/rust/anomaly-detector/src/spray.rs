@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+
+// ==========================================
+// CREDENTIAL SPRAY (CORRELACIÓN POR IP)
+// ==========================================
+
+/// Usuarios distintos atacados desde una misma IP dentro de un tenant.
+///
+/// A diferencia del resto del sistema, la clave es `(tenant_id, ip)` y no el usuario:
+/// el spray solo es visible correlacionando intentos contra muchas cuentas.
+pub struct SprayTracker {
+    targets: DashMap<(String, String), VecDeque<(Instant, String)>>,
+    window: Duration,
+    // Por encima de este número de usuarios distintos la IP se considera spray
+    max_users: usize,
+}
+
+impl SprayTracker {
+    pub fn new(max_users: usize, window: Duration) -> Self {
+        Self {
+            targets: DashMap::new(),
+            window,
+            max_users,
+        }
+    }
+
+    /// Registra un intento de login de `ip` contra `user_id` y devuelve `true`
+    /// si la IP ya superó `max_users` usuarios distintos en la ventana.
+    pub fn record(&self, tenant_id: &str, ip: &str, user_id: &str) -> bool {
+        let now = Instant::now();
+        let mut seen = self.targets.entry((tenant_id.to_string(), ip.to_string())).or_default();
+        while seen.front().is_some_and(|(at, _)| now.duration_since(*at) > self.window) {
+            seen.pop_front();
+        }
+
+        // Un usuario repetido refresca su marca de tiempo en lugar de duplicarse
+        seen.retain(|(_, user)| user != user_id);
+        seen.push_back((now, user_id.to_string()));
+        // Memoria acotada: basta con recordar un usuario más que el límite
+        while seen.len() > self.max_users + 1 {
+            seen.pop_front();
+        }
+
+        seen.len() > self.max_users
+    }
+
+    /// Igual que `record`, sin registrar el intento.
+    pub fn peek(&self, tenant_id: &str, ip: &str, user_id: &str) -> bool {
+        let now = Instant::now();
+        let distinct = self
+            .targets
+            .get(&(tenant_id.to_string(), ip.to_string()))
+            .map_or(0, |seen| {
+                seen.iter()
+                    .filter(|(at, user)| now.duration_since(*at) <= self.window && user != user_id)
+                    .count()
+            });
+        distinct + 1 > self.max_users
+    }
+
+    /// Elimina las IPs sin intentos dentro de la ventana.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.targets.retain(|_, seen| {
+            seen.back().is_some_and(|(at, _)| now.duration_since(*at) <= self.window)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_ip_against_many_users_is_a_spray() {
+        let tracker = SprayTracker::new(3, Duration::from_secs(60));
+        let flags: Vec<bool> = ["ana", "bob", "carla", "dani", "eva"]
+            .iter()
+            .map(|user| tracker.record("acme", "198.51.100.7", user))
+            .collect();
+        assert_eq!(flags, [false, false, false, true, true]);
+
+        // Otra IP y la misma IP en otro tenant van por separado
+        assert!(!tracker.record("acme", "198.51.100.8", "ana"));
+        assert!(!tracker.record("globex", "198.51.100.7", "ana"));
+    }
+
+    #[test]
+    fn retrying_the_same_users_is_not_a_spray() {
+        let tracker = SprayTracker::new(3, Duration::from_secs(60));
+        for _ in 0..20 {
+            for user in ["ana", "bob", "carla"] {
+                assert!(!tracker.record("acme", "198.51.100.7", user));
+            }
+        }
+    }
+
+    #[test]
+    fn peek_predicts_without_recording() {
+        let tracker = SprayTracker::new(2, Duration::from_secs(60));
+        tracker.record("acme", "198.51.100.7", "ana");
+        tracker.record("acme", "198.51.100.7", "bob");
+        assert!(tracker.peek("acme", "198.51.100.7", "carla"));
+        assert!(!tracker.peek("acme", "198.51.100.7", "bob"), "un usuario ya visto no suma");
+        // El peek no dejó rastro: bob repetido sigue sin disparar
+        assert!(!tracker.record("acme", "198.51.100.7", "bob"));
+        assert!(tracker.record("acme", "198.51.100.7", "carla"));
+    }
+
+    #[test]
+    fn attempts_expire_with_the_window() {
+        let tracker = SprayTracker::new(1, Duration::from_millis(20));
+        tracker.record("acme", "198.51.100.7", "ana");
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!tracker.record("acme", "198.51.100.7", "bob"), "ana ya salió de la ventana");
+
+        std::thread::sleep(Duration::from_millis(40));
+        tracker.purge_idle();
+        assert!(tracker.targets.is_empty());
+    }
+}
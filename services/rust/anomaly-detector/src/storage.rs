@@ -0,0 +1,660 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::detector::ProfileKey;
+use crate::models::ClientProfile;
+
+#[cfg(feature = "redis")]
+pub use redis_backend::RedisProfileStore;
+
+// ==========================================
+// PERSISTENCIA DE PERFILES
+// ==========================================
+
+/// Backend de persistencia para los perfiles del detector.
+///
+/// El detector mantiene su estado caliente en memoria y delega en el store
+/// para que los perfiles (en especial los `is_compromised`) sobrevivan a un reinicio.
+#[async_trait]
+pub trait ProfileStore: Send + Sync {
+    /// Carga todos los perfiles persistidos (se usa al arrancar).
+    async fn load_all(&self) -> Vec<ClientProfile>;
+
+    /// Busca un único perfil (fallback ante un cache miss local).
+    async fn load(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile>;
+
+    /// Inserta o reemplaza un perfil.
+    async fn save(&self, profile: &ClientProfile) -> Result<(), String>;
+
+    /// Elimina el perfil de (tenant_id, client_id).
+    async fn remove(&self, tenant_id: &str, client_id: &str) -> Result<(), String>;
+
+    /// Registros auxiliares ya serializados (blocklist, etc.) agrupados por namespace.
+    /// Devuelve pares (clave, valor).
+    async fn load_records(&self, namespace: &str) -> Vec<(String, String)>;
+
+    async fn save_record(&self, namespace: &str, key: &str, value: &str) -> Result<(), String>;
+
+    async fn remove_record(&self, namespace: &str, key: &str) -> Result<(), String>;
+
+    /// `true` si lo guardado sobrevive a un reinicio del proceso.
+    fn is_durable(&self) -> bool {
+        false
+    }
+
+    /// Instante del último compromiso de (tenant_id, client_id) registrado por cualquier réplica.
+    /// Los stores que no guardan la marca aparte (un solo proceso) devuelven `None`.
+    async fn load_compromise(&self, _tenant_id: &str, _client_id: &str) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    /// Levanta la marca de compromiso (desbloqueo administrativo).
+    async fn clear_compromise(&self, _tenant_id: &str, _client_id: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Comprueba que el backend responde (health check). Un store en memoria siempre está disponible.
+    async fn ping(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// ==========================================
+// IMPLEMENTACIÓN EN MEMORIA (DEFAULT)
+// ==========================================
+
+/// Store por defecto: comparte el mismo `DashMap` que usa el detector,
+/// por lo que no añade persistencia real (se pierde al reiniciar).
+#[derive(Clone, Default)]
+pub struct InMemoryProfileStore {
+    profiles: Arc<DashMap<ProfileKey, ClientProfile>>,
+    // El mapa es el del propio detector (from_map): save no escribe
+    shared: bool,
+    // (namespace, clave) -> valor serializado
+    records: Arc<DashMap<(String, String), String>>,
+}
+
+impl InMemoryProfileStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Envuelve el mapa del propio detector. `save` no escribe en él: el detector ya
+    /// actualizó el perfil en su sitio, y una instantánea que llegue tarde (se persiste
+    /// tras soltar el guard) pisaría los eventos aplicados entretanto.
+    pub fn from_map(profiles: Arc<DashMap<ProfileKey, ClientProfile>>) -> Self {
+        Self {
+            profiles,
+            shared: true,
+            records: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl ProfileStore for InMemoryProfileStore {
+    async fn load_all(&self) -> Vec<ClientProfile> {
+        self.profiles.iter().map(|r| r.value().clone()).collect()
+    }
+
+    async fn load(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile> {
+        self.profiles
+            .get(&(tenant_id.to_string(), client_id.to_string()))
+            .map(|r| r.value().clone())
+    }
+
+    async fn save(&self, profile: &ClientProfile) -> Result<(), String> {
+        if self.shared {
+            return Ok(());
+        }
+        let key = (profile.tenant_id.clone(), profile.client_id.clone());
+        self.profiles.insert(key, profile.clone());
+        Ok(())
+    }
+
+    async fn remove(&self, tenant_id: &str, client_id: &str) -> Result<(), String> {
+        self.profiles.remove(&(tenant_id.to_string(), client_id.to_string()));
+        Ok(())
+    }
+
+    async fn load_records(&self, namespace: &str) -> Vec<(String, String)> {
+        self.records
+            .iter()
+            .filter(|r| r.key().0 == namespace)
+            .map(|r| (r.key().1.clone(), r.value().clone()))
+            .collect()
+    }
+
+    async fn save_record(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+        self.records.insert((namespace.to_string(), key.to_string()), value.to_string());
+        Ok(())
+    }
+
+    async fn remove_record(&self, namespace: &str, key: &str) -> Result<(), String> {
+        self.records.remove(&(namespace.to_string(), key.to_string()));
+        Ok(())
+    }
+}
+
+// ==========================================
+// IMPLEMENTACIÓN REDIS (feature = "redis")
+// ==========================================
+
+#[cfg(feature = "redis")]
+mod redis_backend {
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use redis::aio::ConnectionLike;
+
+    use super::ProfileStore;
+    use crate::detector::STALE_PROFILE_HOURS;
+    use crate::models::ClientProfile;
+
+    const KEY_PREFIX: &str = "profile";
+    const COMPROMISED_PREFIX: &str = "compromised";
+    const RECORDS_PREFIX: &str = "records";
+    const SCAN_BATCH: usize = 500;
+
+    /// Store compartido entre réplicas: cada perfil vive en `profile:{tenant_id}:{client_id}`
+    /// con un TTL igual a la ventana de perfiles obsoletos (24h).
+    ///
+    /// El compromiso se guarda además en `compromised:{tenant_id}:{client_id}` (instante del
+    /// compromiso, con el TTL que le queda o sin TTL si es permanente). Un `save` limpio no
+    /// toca esa marca: la copia local desfasada de otra réplica (o su `flush()` al apagarse)
+    /// no puede borrar un bloqueo, y `load`/`load_all` la vuelven a aplicar sobre el perfil.
+    ///
+    /// Es genérico sobre la conexión para poder usar `ConnectionManager` en producción
+    /// y una conexión simulada en pruebas.
+    #[derive(Clone)]
+    pub struct RedisProfileStore<C> {
+        conn: C,
+        ttl_seconds: u64,
+        // Duración del bloqueo (None = permanente), como SecurityConfig::compromise_ttl_hours
+        compromise_ttl: Option<chrono::Duration>,
+    }
+
+    impl<C> RedisProfileStore<C>
+    where
+        C: ConnectionLike + Clone + Send + Sync + 'static,
+    {
+        pub fn new(conn: C) -> Self {
+            Self {
+                conn,
+                ttl_seconds: (STALE_PROFILE_HOURS * 3600) as u64,
+                compromise_ttl: Some(chrono::Duration::hours(STALE_PROFILE_HOURS)),
+            }
+        }
+
+        /// Duración de un bloqueo (None = permanente). Fija cuánto viven la marca de
+        /// compromiso y el perfil comprometido, que nunca caduca antes que su bloqueo.
+        pub fn with_compromise_ttl(mut self, ttl: Option<chrono::Duration>) -> Self {
+            self.compromise_ttl = ttl;
+            self
+        }
+
+        pub fn compromise_key(tenant_id: &str, client_id: &str) -> String {
+            format!("{}:{}", COMPROMISED_PREFIX, crate::keys::composite_key(tenant_id, client_id))
+        }
+
+        // TTL del perfil: la ventana de obsoletos, o max(ventana, bloqueo) si está comprometido
+        // (None = sin caducidad, bloqueo permanente)
+        fn profile_ttl(&self, profile: &ClientProfile) -> Option<u64> {
+            if !profile.is_compromised {
+                return Some(self.ttl_seconds);
+            }
+            self.compromise_ttl
+                .map(|ttl| self.ttl_seconds.max(ttl.num_seconds().max(0) as u64))
+        }
+
+        // Lo que le queda al bloqueo (None = permanente); Some(0) = ya expirado
+        fn compromise_remaining(&self, compromised_at: Option<DateTime<Utc>>) -> Option<u64> {
+            let ttl = self.compromise_ttl?;
+            let elapsed = compromised_at.map_or(chrono::Duration::zero(), |at| Utc::now() - at);
+            Some((ttl - elapsed).num_seconds().max(0) as u64)
+        }
+
+        async fn set(&self, key: &str, value: &str, ttl_seconds: Option<u64>) -> Result<(), String> {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(value);
+            if let Some(ttl) = ttl_seconds {
+                cmd.arg("EX").arg(ttl);
+            }
+            let mut conn = self.conn.clone();
+            cmd.query_async::<_, ()>(&mut conn).await.map_err(|e| e.to_string())
+        }
+
+        // Aplica la marca compartida sobre un perfil leído (la copia puede venir de una réplica desfasada)
+        async fn with_compromise(&self, mut profile: ClientProfile) -> ClientProfile {
+            if let Some(at) = self.load_compromise(&profile.tenant_id, &profile.client_id).await {
+                crate::detector::apply_compromise_mark(&mut profile, at);
+            }
+            profile
+        }
+
+        // Partes escapadas: un tenant con ':' no puede pisar la clave de otro.
+        // Las claves antiguas ambiguas se siguen leyendo en load_all y caducan por TTL
+        pub fn key(tenant_id: &str, client_id: &str) -> String {
+            format!("{}:{}", KEY_PREFIX, crate::keys::composite_key(tenant_id, client_id))
+        }
+
+        // Un hash de Redis por namespace, sin TTL (p.ej. la blocklist es permanente)
+        fn records_key(namespace: &str) -> String {
+            format!("{}:{}", RECORDS_PREFIX, namespace)
+        }
+
+        async fn get_json(&self, key: &str) -> Option<ClientProfile> {
+            let mut conn = self.conn.clone();
+            let raw: Option<String> = match redis::cmd("GET").arg(key).query_async(&mut conn).await {
+                Ok(raw) => raw,
+                Err(e) => {
+                    log::warn!("[SECURITY] Redis GET {} falló: {}", key, e);
+                    return None;
+                }
+            };
+            raw.and_then(|json| serde_json::from_str(&json).ok())
+        }
+    }
+
+    impl RedisProfileStore<redis::aio::ConnectionManager> {
+        /// Conecta a Redis con reconexión automática (p.ej. `redis://127.0.0.1/`).
+        pub async fn connect(url: &str) -> Result<Self, String> {
+            let client = redis::Client::open(url).map_err(|e| e.to_string())?;
+            let conn = redis::aio::ConnectionManager::new(client)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(Self::new(conn))
+        }
+    }
+
+    #[async_trait]
+    impl<C> ProfileStore for RedisProfileStore<C>
+    where
+        C: ConnectionLike + Clone + Send + Sync + 'static,
+    {
+        async fn load_all(&self) -> Vec<ClientProfile> {
+            let mut conn = self.conn.clone();
+            let pattern = format!("{}:*", KEY_PREFIX);
+            let mut cursor: u64 = 0;
+            let mut keys: Vec<String> = Vec::new();
+
+            loop {
+                let page: Result<(u64, Vec<String>), _> = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH)
+                    .query_async(&mut conn)
+                    .await;
+                match page {
+                    Ok((next, batch)) => {
+                        keys.extend(batch);
+                        if next == 0 {
+                            break;
+                        }
+                        cursor = next;
+                    }
+                    Err(e) => {
+                        log::warn!("[SECURITY] Redis SCAN falló: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            let mut profiles = Vec::with_capacity(keys.len());
+            for key in keys {
+                if let Some(profile) = self.get_json(&key).await {
+                    profiles.push(self.with_compromise(profile).await);
+                }
+            }
+            profiles
+        }
+
+        async fn load(&self, tenant_id: &str, client_id: &str) -> Option<ClientProfile> {
+            let profile = self.get_json(&Self::key(tenant_id, client_id)).await?;
+            Some(self.with_compromise(profile).await)
+        }
+
+        async fn save(&self, profile: &ClientProfile) -> Result<(), String> {
+            let json = serde_json::to_string(profile).map_err(|e| e.to_string())?;
+            self.set(&Self::key(&profile.tenant_id, &profile.client_id), &json, self.profile_ttl(profile))
+                .await?;
+            if !profile.is_compromised {
+                return Ok(());
+            }
+            let remaining = self.compromise_remaining(profile.compromised_at);
+            if remaining == Some(0) {
+                return Ok(());
+            }
+            let at = profile.compromised_at.unwrap_or_else(Utc::now);
+            self.set(&Self::compromise_key(&profile.tenant_id, &profile.client_id), &at.to_rfc3339(), remaining)
+                .await
+        }
+
+        async fn remove(&self, tenant_id: &str, client_id: &str) -> Result<(), String> {
+            let mut conn = self.conn.clone();
+            redis::cmd("DEL")
+                .arg(Self::key(tenant_id, client_id))
+                .arg(Self::compromise_key(tenant_id, client_id))
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        async fn load_compromise(&self, tenant_id: &str, client_id: &str) -> Option<DateTime<Utc>> {
+            let key = Self::compromise_key(tenant_id, client_id);
+            let mut conn = self.conn.clone();
+            let raw: Option<String> = match redis::cmd("GET").arg(&key).query_async(&mut conn).await {
+                Ok(raw) => raw,
+                Err(e) => {
+                    log::warn!("[SECURITY] Redis GET {} falló: {}", key, e);
+                    return None;
+                }
+            };
+            raw.and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+                .map(|at| at.with_timezone(&Utc))
+        }
+
+        async fn clear_compromise(&self, tenant_id: &str, client_id: &str) -> Result<(), String> {
+            let mut conn = self.conn.clone();
+            redis::cmd("DEL")
+                .arg(Self::compromise_key(tenant_id, client_id))
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        async fn load_records(&self, namespace: &str) -> Vec<(String, String)> {
+            let mut conn = self.conn.clone();
+            match redis::cmd("HGETALL")
+                .arg(Self::records_key(namespace))
+                .query_async::<_, Vec<(String, String)>>(&mut conn)
+                .await
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    log::warn!("[SECURITY] Redis HGETALL {} falló: {}", namespace, e);
+                    Vec::new()
+                }
+            }
+        }
+
+        async fn save_record(&self, namespace: &str, key: &str, value: &str) -> Result<(), String> {
+            let mut conn = self.conn.clone();
+            redis::cmd("HSET")
+                .arg(Self::records_key(namespace))
+                .arg(key)
+                .arg(value)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        async fn remove_record(&self, namespace: &str, key: &str) -> Result<(), String> {
+            let mut conn = self.conn.clone();
+            redis::cmd("HDEL")
+                .arg(Self::records_key(namespace))
+                .arg(key)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(|e| e.to_string())
+        }
+
+        fn is_durable(&self) -> bool {
+            true
+        }
+
+        async fn ping(&self) -> Result<(), String> {
+            let mut conn = self.conn.clone();
+            redis::cmd("PING")
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "redis"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+
+    use super::{ProfileStore, RedisProfileStore};
+    use crate::models::{BehaviorEvent, BehaviorPattern, ClientProfile, ThreatLevel};
+    use crate::patterns::KEY_INJECTION_SCORE;
+    use crate::{AnomalyDetector, SecurityConfig};
+
+    // Redis en memoria con los comandos que usa el store (SET/GET/DEL/SCAN/HSET/HGETALL/HDEL/PING)
+    #[derive(Clone, Default)]
+    struct MockConnection {
+        strings: Arc<Mutex<HashMap<String, String>>>,
+        // EX del último SET de cada clave (None = sin caducidad)
+        ttls: Arc<Mutex<HashMap<String, Option<u64>>>>,
+        hashes: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+    }
+
+    impl MockConnection {
+        fn execute(&self, args: &[String]) -> Value {
+            let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+            let mut strings = self.strings.lock().unwrap();
+            let mut hashes = self.hashes.lock().unwrap();
+            match args[0].as_str() {
+                "SET" => {
+                    strings.insert(args[1].clone(), args[2].clone());
+                    let ttl = (args.get(3).map(String::as_str) == Some("EX")).then(|| args[4].parse().unwrap());
+                    self.ttls.lock().unwrap().insert(args[1].clone(), ttl);
+                    Value::Okay
+                }
+                "GET" => strings.get(&args[1]).map_or(Value::Nil, |v| data(v)),
+                "DEL" => Value::Int(args[1..].iter().filter(|k| strings.remove(*k).is_some()).count() as i64),
+                "SCAN" => {
+                    let prefix = args[3].trim_end_matches('*');
+                    let keys = strings.keys().filter(|k| k.starts_with(prefix)).map(|k| data(k)).collect();
+                    Value::Bulk(vec![data("0"), Value::Bulk(keys)])
+                }
+                "HSET" => {
+                    hashes.entry(args[1].clone()).or_default().insert(args[2].clone(), args[3].clone());
+                    Value::Int(1)
+                }
+                "HGETALL" => Value::Bulk(
+                    hashes
+                        .get(&args[1])
+                        .into_iter()
+                        .flatten()
+                        .flat_map(|(k, v)| [data(k), data(v)])
+                        .collect(),
+                ),
+                "HDEL" => Value::Int(hashes.get_mut(&args[1]).and_then(|h| h.remove(&args[2])).map_or(0, |_| 1)),
+                "PING" => Value::Status("PONG".to_string()),
+                other => panic!("comando no simulado: {}", other),
+            }
+        }
+    }
+
+    impl redis::aio::ConnectionLike for MockConnection {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let args: Vec<String> = cmd
+                .args_iter()
+                .map(|arg| match arg {
+                    Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                    Arg::Cursor => "0".to_string(),
+                })
+                .collect();
+            let value = self.execute(&args);
+            Box::pin(async move { Ok(value) })
+        }
+
+        fn req_packed_commands<'a>(&'a mut self, _: &'a Pipeline, _: usize, _: usize) -> RedisFuture<'a, Vec<Value>> {
+            Box::pin(async { Err(RedisError::from((ErrorKind::ClientError, "el store no usa pipelines"))) })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn profile(tenant_id: &str, client_id: &str) -> ClientProfile {
+        let now = chrono::Utc::now();
+        ClientProfile {
+            tenant_id: tenant_id.to_string(),
+            client_id: client_id.to_string(),
+            first_seen: now,
+            last_seen: now,
+            total_events: 3,
+            average_confidence: 1.0,
+            risk_score: 0.9,
+            is_compromised: true,
+            compromised_at: Some(now),
+            incident_count: 1,
+            released_at: None,
+            threat_level: ThreatLevel::Critical,
+            challenge_count: 0,
+            last_challenged_at: None,
+            device_id: String::new(),
+            known_devices: Default::default(),
+            request_intervals_ms: Default::default(),
+            location_history: Vec::new(),
+            recent_events: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn save_load_all_remove_roundtrip() {
+        let conn = MockConnection::default();
+        let store = RedisProfileStore::new(conn.clone());
+
+        store.save(&profile("acme", "42")).await.unwrap();
+        store.save(&profile("acme:eu", "7")).await.unwrap();
+        assert!(conn.strings.lock().unwrap().contains_key(&RedisProfileStore::<MockConnection>::key("acme", "42")));
+
+        let mut loaded: Vec<(String, String, bool)> = store
+            .load_all()
+            .await
+            .into_iter()
+            .map(|p| (p.tenant_id, p.client_id, p.is_compromised))
+            .collect();
+        loaded.sort();
+        assert_eq!(
+            loaded,
+            vec![("acme".into(), "42".into(), true), ("acme:eu".into(), "7".into(), true)]
+        );
+        assert_eq!(store.load("acme", "42").await.map(|p| p.total_events), Some(3));
+
+        store.remove("acme", "42").await.unwrap();
+        assert!(store.load("acme", "42").await.is_none());
+        let remaining: Vec<String> = store.load_all().await.into_iter().map(|p| p.tenant_id).collect();
+        assert_eq!(remaining, vec!["acme:eu".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn records_roundtrip_and_ping() {
+        let store = RedisProfileStore::new(MockConnection::default());
+        store.save_record("blocklist", "ip:1.2.3.4", "{}").await.unwrap();
+        store.save_record("vips", "acme:42", "{\"x\":1}").await.unwrap();
+        assert_eq!(store.load_records("blocklist").await, vec![("ip:1.2.3.4".to_string(), "{}".to_string())]);
+
+        store.remove_record("blocklist", "ip:1.2.3.4").await.unwrap();
+        assert!(store.load_records("blocklist").await.is_empty());
+        assert_eq!(store.load_records("vips").await.len(), 1);
+        assert!(store.ping().await.is_ok());
+    }
+
+    fn ttl(conn: &MockConnection, key: &str) -> Option<u64> {
+        conn.ttls.lock().unwrap().get(key).copied().flatten()
+    }
+
+    fn clean(mut profile: ClientProfile) -> ClientProfile {
+        profile.is_compromised = false;
+        profile.compromised_at = None;
+        profile.threat_level = ThreatLevel::Safe;
+        profile
+    }
+
+    #[tokio::test]
+    async fn a_stale_clean_save_keeps_another_replicas_compromise() {
+        let conn = MockConnection::default();
+        let flagging = RedisProfileStore::new(conn.clone());
+        let stale = RedisProfileStore::new(conn.clone());
+
+        let compromised = profile("acme", "42");
+        flagging.save(&compromised).await.unwrap();
+        // La otra réplica aún tenía la copia limpia y la persiste (evento o flush al apagarse)
+        stale.save(&clean(compromised.clone())).await.unwrap();
+
+        let loaded = stale.load("acme", "42").await.unwrap();
+        assert!(loaded.is_compromised);
+        assert_eq!(loaded.threat_level, ThreatLevel::Critical);
+        assert!(stale.load_all().await[0].is_compromised);
+        assert!(stale.load_compromise("acme", "42").await.is_some());
+
+        // El desbloqueo administrativo sí levanta la marca
+        stale.clear_compromise("acme", "42").await.unwrap();
+        stale.save(&clean(compromised)).await.unwrap();
+        assert!(!stale.load("acme", "42").await.unwrap().is_compromised);
+    }
+
+    #[tokio::test]
+    async fn compromised_profiles_live_as_long_as_their_block() {
+        let conn = MockConnection::default();
+        let profile_key = RedisProfileStore::<MockConnection>::key("acme", "42");
+        let mark_key = RedisProfileStore::<MockConnection>::compromise_key("acme", "42");
+
+        // Bloqueo permanente: ni el perfil ni la marca caducan
+        let permanent = RedisProfileStore::new(conn.clone()).with_compromise_ttl(None);
+        permanent.save(&profile("acme", "42")).await.unwrap();
+        assert_eq!(ttl(&conn, &profile_key), None);
+        assert_eq!(ttl(&conn, &mark_key), None);
+        assert!(conn.strings.lock().unwrap().contains_key(&mark_key));
+
+        // Bloqueo de 48h: el perfil vive max(24h, 48h) y la marca lo que le queda al bloqueo
+        let long = RedisProfileStore::new(conn.clone()).with_compromise_ttl(Some(chrono::Duration::hours(48)));
+        long.save(&profile("acme", "42")).await.unwrap();
+        assert_eq!(ttl(&conn, &profile_key), Some(48 * 3600));
+        assert!(ttl(&conn, &mark_key).is_some_and(|t| t > 47 * 3600 && t <= 48 * 3600));
+
+        // Un perfil limpio caduca con la ventana de obsoletos
+        long.save(&clean(profile("acme", "7"))).await.unwrap();
+        assert_eq!(ttl(&conn, &RedisProfileStore::<MockConnection>::key("acme", "7")), Some(24 * 3600));
+
+        // remove se lleva también la marca
+        long.remove("acme", "42").await.unwrap();
+        assert!(long.load_compromise("acme", "42").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_replica_with_a_clean_local_copy_blocks_after_another_flags_the_client() {
+        let conn = MockConnection::default();
+        let config = || SecurityConfig { cleanup_interval_minutes: 0, ..SecurityConfig::default() };
+        let a = AnomalyDetector::with_store(config(), Box::new(RedisProfileStore::new(conn.clone())));
+        let b = AnomalyDetector::with_store(config(), Box::new(RedisProfileStore::new(conn.clone())));
+        let event = |injection: f64| BehaviorEvent {
+            tenant_id: "acme".to_string(),
+            client_id: "42".to_string(),
+            timestamp: chrono::Utc::now(),
+            pattern: BehaviorPattern::Normal,
+            confidence: 1.0,
+            indicators: HashMap::from([(KEY_INJECTION_SCORE.to_string(), injection)]),
+            metadata: HashMap::new(),
+            device_id: None,
+        };
+
+        // Ambas réplicas tienen ya el perfil limpio en memoria
+        b.analyze(&event(0.0)).await.unwrap();
+        a.analyze(&event(0.0)).await.unwrap();
+        assert_eq!(a.analyze(&event(0.95)).await.unwrap().level, ThreatLevel::Critical);
+
+        // B no tiene cache miss, pero la marca compartida lo bloquea igualmente
+        let blocked = b.analyze(&event(0.0)).await.unwrap();
+        assert_eq!(blocked.recommendation, "BLOCK_PERMANENTLY");
+        assert!(b.get_profile("acme", "42").unwrap().is_compromised);
+
+        // Y su flush no deshace el bloqueo para una tercera réplica que arranque ahora
+        b.flush().await;
+        let c = AnomalyDetector::with_store(config(), Box::new(RedisProfileStore::new(conn)));
+        assert_eq!(c.restore().await, 1);
+        assert!(c.get_profile("acme", "42").unwrap().is_compromised);
+    }
+}
@@ -0,0 +1,223 @@
+use std::future::Future;
+
+// ==========================================
+// TRAZAS DISTRIBUIDAS (feature = "otel")
+// ==========================================
+
+/// Contexto W3C recibido en la cabecera `traceparent`
+/// (`00-<trace_id 32 hex>-<parent_id 16 hex>-<flags 2 hex>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// `None` si la cabecera no es un traceparent válido (se empieza una traza nueva).
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        // Versiones futuras pueden añadir campos; la 00 tiene exactamente cuatro
+        if version == "ff" || !is_hex(version, 2) || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        // Identificadores todo ceros son inválidos según la especificación
+        if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            parent_id: parent_id.to_ascii_lowercase(),
+            sampled: flags & 0x01 == 1,
+        })
+    }
+
+    // Contexto remoto del que cuelga el span raíz (el llamador sigue siendo el padre en OTLP)
+    #[cfg(feature = "otel")]
+    fn remote_context(&self) -> Option<opentelemetry::Context> {
+        use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+        let flags = if self.sampled { TraceFlags::SAMPLED } else { TraceFlags::default() };
+        let span_context = SpanContext::new(
+            TraceId::from_hex(&self.trace_id).ok()?,
+            SpanId::from_hex(&self.parent_id).ok()?,
+            flags,
+            true,
+            TraceState::default(),
+        );
+        Some(opentelemetry::Context::new().with_remote_span_context(span_context))
+    }
+}
+
+/// Exportador OTLP instalado por `init_tracing`; `shutdown` envía los spans pendientes.
+pub struct TracingGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl TracingGuard {
+    /// `true` si los spans se exportan vía OTLP.
+    pub fn exporting(&self) -> bool {
+        #[cfg(feature = "otel")]
+        {
+            self.provider.is_some()
+        }
+        #[cfg(not(feature = "otel"))]
+        false
+    }
+
+    /// Vacía la cola del exportador (llamar al final del apagado).
+    pub async fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider {
+            // shutdown() bloquea hasta que la tarea del batch termina de exportar; se
+            // ejecuta en otro hilo para no bloquear el runtime de un solo hilo de actix
+            let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::warn!("[TELEMETRY] Cierre del exportador OTLP: {}", e),
+                Err(e) => log::warn!("[TELEMETRY] Cierre del exportador OTLP: {}", e),
+            }
+        }
+    }
+}
+
+/// Instala el exportador OTLP/HTTP (protobuf) si `OTEL_EXPORTER_OTLP_ENDPOINT` u
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` están definidas; el resto de variables
+/// `OTEL_EXPORTER_OTLP_*` estándar (cabeceras, timeout) se respetan. Sin ellas, o sin la
+/// feature `otel`, no se instala nada. Debe llamarse dentro de un runtime de tokio.
+pub fn init_tracing(service_name: &str) -> Result<TracingGuard, String> {
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some());
+        if !configured {
+            return Ok(TracingGuard { provider: None });
+        }
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()
+            .map_err(|e| format!("OTLP exporter: {}", e))?;
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+            .with_resource(opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                "service.name",
+                service_name.to_string(),
+            )]))
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name.to_string()));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+            .map_err(|e| format!("tracing subscriber: {}", e))?;
+        Ok(TracingGuard { provider: Some(provider) })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = service_name;
+        Ok(TracingGuard {})
+    }
+}
+
+fn is_hex(raw: &str, len: usize) -> bool {
+    raw.len() == len && raw.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Span raíz de una detección. Sin la feature `otel` todas las operaciones son no-op,
+/// así que los handlers no necesitan `cfg`.
+///
+/// Con `otel` los spans se emiten con `tracing` y, tras `init_tracing`, se exportan vía
+/// OTLP como hijos del `traceparent` del llamador (misma traza de extremo a extremo).
+pub struct DetectSpan {
+    #[cfg(feature = "otel")]
+    span: tracing::Span,
+}
+
+impl DetectSpan {
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub fn start(tenant_id: &str, parent: Option<&TraceContext>) -> Self {
+        #[cfg(feature = "otel")]
+        {
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+            let span = tracing::info_span!("anomaly.detect", tenant_id = %tenant_id, action = tracing::field::Empty);
+            if let Some(context) = parent.and_then(TraceContext::remote_context) {
+                span.set_parent(context);
+            }
+            Self { span }
+        }
+        #[cfg(not(feature = "otel"))]
+        Self {}
+    }
+
+    /// Ejecuta `fut` dentro del span (los spans hijos de `analyze` y `detect` cuelgan de él).
+    pub async fn run<F: Future>(&self, fut: F) -> F::Output {
+        #[cfg(feature = "otel")]
+        {
+            use tracing::Instrument;
+            fut.instrument(self.span.clone()).await
+        }
+        #[cfg(not(feature = "otel"))]
+        fut.await
+    }
+
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub fn record_action(&self, action: &str) {
+        #[cfg(feature = "otel")]
+        self.span.record("action", action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_w3c_traceparent() {
+        let ctx = TraceContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_id, "00f067aa0ba902b7");
+        assert!(ctx.sampled);
+        assert!(!TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap().sampled);
+        for invalid in [
+            "",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn detect_span_continues_the_caller_trace() {
+        use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let parent = TraceContext::parse(TRACEPARENT).unwrap();
+            let span = DetectSpan::start("acme", Some(&parent));
+            let context = span.span.context();
+            let child = context.span().span_context().clone();
+            assert_eq!(child.trace_id().to_string(), parent.trace_id);
+            assert_ne!(child.span_id().to_string(), parent.parent_id);
+
+            // Sin traceparent se empieza una traza nueva
+            let root = DetectSpan::start("acme", None);
+            assert_ne!(root.span.context().span().span_context().trace_id().to_string(), parent.trace_id);
+        });
+    }
+}
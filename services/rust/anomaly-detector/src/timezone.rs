@@ -0,0 +1,92 @@
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+
+// ==========================================
+// ZONAS HORARIAS IANA
+// ==========================================
+
+// La base de datos IANA va compilada en el binario (chrono-tz): no depende del
+// paquete tzdata de la imagen ni lee del disco, y resolver un nombre no reserva
+// memoria, así que un nombre desconocido enviado por la API no deja rastro.
+
+/// Zona IANA (`Europe/Madrid`) por nombre exacto; `None` si no existe.
+pub fn zone(name: &str) -> Option<Tz> {
+    if is_utc(name) {
+        return Some(Tz::UTC);
+    }
+    name.parse().ok()
+}
+
+/// `true` si el nombre es UTC o una zona existente.
+pub fn is_known(name: &str) -> bool {
+    zone(name).is_some()
+}
+
+/// Hora local de `at` en la zona `name` (UTC si no hay zona o no se conoce).
+pub fn local_hour(name: Option<&str>, at: DateTime<Utc>) -> u32 {
+    match name.and_then(zone) {
+        Some(tz) => at.with_timezone(&tz).hour(),
+        None => at.hour(),
+    }
+}
+
+fn is_utc(name: &str) -> bool {
+    matches!(name, "UTC" | "Etc/UTC" | "Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn known_and_unknown_names() {
+        for name in ["UTC", "Etc/UTC", "Z", "Europe/Madrid", "America/New_York", "Asia/Kolkata"] {
+            assert!(is_known(name), "{}", name);
+        }
+        for name in ["", "Europe/Atlantis", "europe/madrid", "../etc/passwd", "/usr/share/zoneinfo/UTC"] {
+            assert!(!is_known(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_or_missing_zones_fall_back_to_utc() {
+        let at = utc(2024, 6, 1, 14, 30);
+        assert_eq!(local_hour(None, at), 14);
+        assert_eq!(local_hour(Some("Europe/Atlantis"), at), 14);
+        assert_eq!(local_hour(Some("Asia/Kolkata"), at), 20);
+    }
+
+    #[test]
+    fn spring_forward_skips_the_missing_hour() {
+        // Madrid: 31-mar-2024 a las 01:00 UTC se pasa de 02:00 CET a 03:00 CEST
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2024, 3, 31, 0, 59)), 1);
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2024, 3, 31, 1, 0)), 3);
+        // Nueva York: 10-mar-2024 a las 07:00 UTC, de 02:00 EST a 03:00 EDT
+        assert_eq!(local_hour(Some("America/New_York"), utc(2024, 3, 10, 6, 59)), 1);
+        assert_eq!(local_hour(Some("America/New_York"), utc(2024, 3, 10, 7, 0)), 3);
+    }
+
+    #[test]
+    fn fall_back_repeats_the_hour() {
+        // Madrid: 27-oct-2024 a las 01:00 UTC se vuelve de 03:00 CEST a 02:00 CET
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2024, 10, 27, 0, 30)), 2);
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2024, 10, 27, 1, 30)), 2);
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2024, 10, 27, 2, 30)), 3);
+        // Sídney (hemisferio sur): 6-abr-2025 a las 16:00 UTC, de 03:00 AEDT a 02:00 AEST
+        assert_eq!(local_hour(Some("Australia/Sydney"), utc(2025, 4, 5, 15, 30)), 2);
+        assert_eq!(local_hour(Some("Australia/Sydney"), utc(2025, 4, 5, 16, 30)), 2);
+    }
+
+    #[test]
+    fn dates_past_the_last_listed_transition_follow_the_current_rule() {
+        // Verano e invierno de 2090: horario de verano europeo y estadounidense vigentes
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2090, 7, 1, 12, 0)), 14);
+        assert_eq!(local_hour(Some("Europe/Madrid"), utc(2090, 1, 1, 12, 0)), 13);
+        assert_eq!(local_hour(Some("America/New_York"), utc(2090, 7, 1, 12, 0)), 8);
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+
+// ==========================================
+// TLS / mTLS NATIVO
+// ==========================================
+
+/// Certificado del servidor y, opcionalmente, la CA con la que se exige
+/// certificado de cliente (mTLS). Sin certificado el servicio escucha en claro.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+}
+
+impl TlsSettings {
+    /// Lee `ANOMALY_TLS_CERT`, `ANOMALY_TLS_KEY` y `ANOMALY_TLS_CLIENT_CA` (PEM).
+    /// `None` si no hay certificado; error si falta la mitad del par o hay CA sin certificado.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let client_ca_path = var("ANOMALY_TLS_CLIENT_CA");
+        match (var("ANOMALY_TLS_CERT"), var("ANOMALY_TLS_KEY")) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(Self { cert_path, key_path, client_ca_path })),
+            (None, None) if client_ca_path.is_none() => Ok(None),
+            (None, None) => Err("ANOMALY_TLS_CLIENT_CA requires ANOMALY_TLS_CERT and ANOMALY_TLS_KEY".to_string()),
+            _ => Err("ANOMALY_TLS_CERT and ANOMALY_TLS_KEY must be set together".to_string()),
+        }
+    }
+
+    /// Configuración rustls (proveedor ring, HTTP/1.1 por ALPN). Con `client_ca_path`
+    /// se rechaza cualquier conexión sin un certificado de cliente firmado por esa CA.
+    pub fn server_config(&self) -> io::Result<Arc<ServerConfig>> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(&self.cert_path, e))?;
+        if certs.is_empty() {
+            return Err(io::Error::other(format!("{}: no certificates found", self.cert_path)));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path).map_err(|e| pem_error(&self.key_path, e))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match &self.client_ca_path {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for ca in CertificateDer::pem_file_iter(ca_path).map_err(|e| pem_error(ca_path, e))? {
+                    roots.add(ca.map_err(|e| pem_error(ca_path, e))?).map_err(io::Error::other)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .map_err(io::Error::other)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key).map_err(io::Error::other)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+fn pem_error(path: &str, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::other(format!("{}: {:?}", path, e))
+}
@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use chrono::{DateTime, TimeZone, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::models::Action;
+
+// ==========================================
+// TENDENCIAS POR HORA (RING DE CONTADORES)
+// ==========================================
+
+/// Horas que recuerda cada tenant (y máximo de `hours` en una consulta).
+pub const TREND_RING_HOURS: usize = 48;
+
+const ACTIONS: [Action; 3] = [Action::Allow, Action::Challenge, Action::Block];
+
+// Una hora del ring: `hour` es la hora-época a la que pertenecen los contadores
+#[derive(Default)]
+struct Bucket {
+    hour: AtomicU64,
+    counts: [AtomicU64; 3],
+}
+
+struct Ring {
+    buckets: [Bucket; TREND_RING_HOURS],
+}
+
+/// Decisiones por hora y acción de las últimas `TREND_RING_HOURS` horas.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrendBucket {
+    pub start: DateTime<Utc>,
+    #[serde(rename = "ALLOW")]
+    pub allow: u64,
+    #[serde(rename = "CHALLENGE")]
+    pub challenge: u64,
+    #[serde(rename = "BLOCK")]
+    pub block: u64,
+}
+
+/// Contadores por `(tenant, hora, acción)` sin locks en el camino caliente: la hora-época
+/// módulo `TREND_RING_HOURS` elige el bucket, y el primero que escribe en una hora nueva
+/// lo reinicia. Un incremento que coincida con ese reinicio puede perderse (aproximado,
+/// como /metrics). Memoria acotada: `max_tenants` rings de tamaño fijo.
+pub struct TrendCounter {
+    tenants: DashMap<String, Arc<Ring>>,
+    max_tenants: usize,
+}
+
+impl TrendCounter {
+    pub fn new(max_tenants: usize) -> Self {
+        Self {
+            tenants: DashMap::new(),
+            max_tenants,
+        }
+    }
+
+    /// Cuenta una decisión de `tenant_id` en la hora de `at`.
+    pub fn record(&self, tenant_id: &str, action: Action, at: DateTime<Utc>) {
+        let Some(ring) = self.ring(tenant_id) else {
+            return;
+        };
+        let hour = epoch_hour(at);
+        let bucket = &ring.buckets[hour as usize % TREND_RING_HOURS];
+        let current = bucket.hour.load(Ordering::Acquire);
+        // Bucket de una vuelta anterior del ring: lo recicla quien gane el intercambio
+        if current < hour
+            && bucket.hour.compare_exchange(current, hour, Ordering::AcqRel, Ordering::Acquire).is_ok()
+        {
+            for count in &bucket.counts {
+                count.store(0, Ordering::Release);
+            }
+        }
+        if bucket.hour.load(Ordering::Acquire) == hour {
+            bucket.counts[action_index(action)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Las `hours` horas que terminan en la de `now` (la más antigua primero); las horas
+    /// sin decisiones van a cero. `hours` se acota a `1..=TREND_RING_HOURS`.
+    pub fn buckets(&self, tenant_id: &str, hours: usize, now: DateTime<Utc>) -> Vec<TrendBucket> {
+        let hours = hours.clamp(1, TREND_RING_HOURS) as u64;
+        let ring = self.tenants.get(tenant_id).map(|entry| Arc::clone(entry.value()));
+        let current = epoch_hour(now);
+        (current + 1 - hours..=current)
+            .map(|hour| {
+                let mut counts = [0; 3];
+                if let Some(bucket) = ring.as_ref().map(|ring| &ring.buckets[hour as usize % TREND_RING_HOURS]) {
+                    if bucket.hour.load(Ordering::Acquire) == hour {
+                        for (slot, count) in counts.iter_mut().zip(&bucket.counts) {
+                            *slot = count.load(Ordering::Relaxed);
+                        }
+                    }
+                }
+                TrendBucket {
+                    start: Utc.timestamp_opt(hour as i64 * 3600, 0).single().unwrap_or(now),
+                    allow: counts[0],
+                    challenge: counts[1],
+                    block: counts[2],
+                }
+            })
+            .collect()
+    }
+
+    /// Olvida un tenant (baja/reset).
+    pub fn remove(&self, tenant_id: &str) {
+        self.tenants.remove(tenant_id);
+    }
+
+    /// Elimina los tenants sin decisiones en todo el ring.
+    pub fn purge_idle(&self, now: DateTime<Utc>) {
+        let oldest = epoch_hour(now).saturating_sub(TREND_RING_HOURS as u64);
+        self.tenants.retain(|_, ring| ring.buckets.iter().any(|b| b.hour.load(Ordering::Acquire) > oldest));
+    }
+
+    // Lleno de tenants: los nuevos no se siguen hasta que purge_idle libere hueco
+    fn ring(&self, tenant_id: &str) -> Option<Arc<Ring>> {
+        if let Some(ring) = self.tenants.get(tenant_id) {
+            return Some(Arc::clone(ring.value()));
+        }
+        if self.tenants.len() >= self.max_tenants {
+            return None;
+        }
+        let ring = self
+            .tenants
+            .entry(tenant_id.to_string())
+            .or_insert_with(|| Arc::new(Ring { buckets: std::array::from_fn(|_| Bucket::default()) }));
+        Some(Arc::clone(ring.value()))
+    }
+}
+
+fn epoch_hour(at: DateTime<Utc>) -> u64 {
+    (at.timestamp().max(0) / 3600) as u64
+}
+
+fn action_index(action: Action) -> usize {
+    ACTIONS.iter().position(|a| *a == action).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+    }
+
+    fn totals(bucket: &TrendBucket) -> (u64, u64, u64) {
+        (bucket.allow, bucket.challenge, bucket.block)
+    }
+
+    #[test]
+    fn decisions_land_in_their_hour_bucket() {
+        let trends = TrendCounter::new(10);
+        trends.record("acme", Action::Allow, at("2026-10-15T10:00:00Z"));
+        trends.record("acme", Action::Block, at("2026-10-15T10:59:59.999Z"));
+        trends.record("acme", Action::Challenge, at("2026-10-15T11:00:00Z"));
+
+        let buckets = trends.buckets("acme", 3, at("2026-10-15T11:30:00Z"));
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets[0].start, at("2026-10-15T09:00:00Z"));
+        assert_eq!(totals(&buckets[0]), (0, 0, 0));
+        assert_eq!(buckets[1].start, at("2026-10-15T10:00:00Z"));
+        assert_eq!(totals(&buckets[1]), (1, 0, 1));
+        assert_eq!(buckets[2].start, at("2026-10-15T11:00:00Z"));
+        assert_eq!(totals(&buckets[2]), (0, 1, 0));
+        // Otro tenant no ve nada
+        assert!(trends.buckets("globex", 3, at("2026-10-15T11:30:00Z")).iter().all(|b| totals(b) == (0, 0, 0)));
+    }
+
+    #[test]
+    fn ring_slot_is_recycled_after_a_full_turn() {
+        let trends = TrendCounter::new(10);
+        let first = at("2026-10-15T10:15:00Z");
+        let wrapped = first + Duration::hours(TREND_RING_HOURS as i64);
+        trends.record("acme", Action::Block, first);
+        trends.record("acme", Action::Block, first);
+        trends.record("acme", Action::Allow, wrapped);
+
+        let buckets = trends.buckets("acme", TREND_RING_HOURS, wrapped);
+        assert_eq!(buckets.len(), TREND_RING_HOURS);
+        // La hora original ya salió de la ventana y su slot solo cuenta la hora nueva
+        assert_eq!(buckets[0].start, at("2026-10-15T11:00:00Z"));
+        assert_eq!(totals(buckets.last().unwrap()), (1, 0, 0));
+        assert_eq!(buckets.iter().map(|b| b.block).sum::<u64>(), 0);
+
+        // Un evento rezagado de la vuelta anterior no pisa el slot reciclado
+        trends.record("acme", Action::Block, first);
+        assert_eq!(totals(trends.buckets("acme", 1, wrapped).last().unwrap()), (1, 0, 0));
+    }
+
+    #[test]
+    fn hours_are_clamped_and_idle_tenants_purged() {
+        let trends = TrendCounter::new(1);
+        let now = at("2026-10-15T10:15:00Z");
+        trends.record("acme", Action::Allow, now);
+        // Lleno: el segundo tenant no se sigue
+        trends.record("globex", Action::Allow, now);
+        assert_eq!(trends.buckets("globex", 1, now)[0].allow, 0);
+
+        assert_eq!(trends.buckets("acme", 0, now).len(), 1);
+        assert_eq!(trends.buckets("acme", 1000, now).len(), TREND_RING_HOURS);
+
+        trends.purge_idle(now + Duration::hours(TREND_RING_HOURS as i64 - 1));
+        assert_eq!(trends.buckets("acme", 1, now)[0].allow, 1);
+        trends.purge_idle(now + Duration::hours(TREND_RING_HOURS as i64 + 1));
+        trends.record("globex", Action::Allow, now);
+        assert_eq!(trends.buckets("globex", 1, now)[0].allow, 1);
+    }
+}
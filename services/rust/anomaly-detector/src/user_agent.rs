@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+// ==========================================
+// NORMALIZACIÓN DE USER-AGENT
+// ==========================================
+
+/// Agresividad con la que se normaliza el User-Agent antes de compararlo con el baseline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UaNormalization {
+    /// Comparación literal (sólo se recortan espacios).
+    Exact,
+    /// Conserva solo la versión mayor: `Chrome/120.0.6099.110` → `Chrome/120`.
+    #[default]
+    MajorVersion,
+    /// Solo navegador y sistema: `Chrome on Windows`.
+    FamilyOnly,
+}
+
+impl std::str::FromStr for UaNormalization {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "exact" => Ok(Self::Exact),
+            "major_version" | "major" => Ok(Self::MajorVersion),
+            "family_only" | "family" => Ok(Self::FamilyOnly),
+            other => Err(format!("Unknown UA normalization '{}'", other)),
+        }
+    }
+}
+
+/// Reduce un User-Agent a la forma comparable según `mode`, para que una
+/// actualización menor del navegador no cuente como "dispositivo nuevo".
+pub fn normalize_user_agent(ua: &str, mode: UaNormalization) -> String {
+    match mode {
+        UaNormalization::Exact => ua.trim().to_string(),
+        UaNormalization::MajorVersion => strip_minor_versions(ua.trim()),
+        UaNormalization::FamilyOnly => format!("{} on {}", browser_family(ua), os_family(ua)),
+    }
+}
+
+// Recorta cada secuencia de versión (`120.0.6099.110`, `10_15_7`) a su primer número
+fn strip_minor_versions(ua: &str) -> String {
+    let chars: Vec<char> = ua.chars().collect();
+    let mut out = String::with_capacity(ua.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        out.push(c);
+        i += 1;
+        if c.is_ascii_digit() && (i == chars.len() || !chars[i].is_ascii_digit()) {
+            // Fin del número mayor: saltar los grupos `.NN` / `_NN` que le siguen
+            while i + 1 < chars.len() && matches!(chars[i], '.' | '_') && chars[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+        }
+    }
+    out
+}
+
+// El orden importa: Edge y Opera también anuncian "Chrome", y Chrome anuncia "Safari"
+fn browser_family(ua: &str) -> &'static str {
+    const FAMILIES: [(&str, &str); 9] = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("CriOS/", "Chrome"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+        ("python-requests/", "python-requests"),
+        ("okhttp/", "okhttp"),
+    ];
+    FAMILIES
+        .iter()
+        .find(|(token, _)| ua.contains(token))
+        .map_or("Other", |(_, family)| family)
+}
+
+fn os_family(ua: &str) -> &'static str {
+    const SYSTEMS: [(&str, &str); 6] = [
+        ("Windows", "Windows"),
+        ("Android", "Android"), // Antes que Linux
+        ("iPhone", "iOS"),
+        ("iPad", "iOS"),
+        ("Mac OS X", "macOS"),
+        ("Linux", "Linux"),
+    ];
+    SYSTEMS
+        .iter()
+        .find(|(token, _)| ua.contains(token))
+        .map_or("Other", |(_, os)| os)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_WIN: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.110 Safari/537.36";
+    const SAFARI_MAC: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.1 Safari/605.1.15";
+
+    #[test]
+    fn major_version_drops_minor_groups_only() {
+        let normalized = normalize_user_agent(CHROME_WIN, UaNormalization::MajorVersion);
+        assert!(normalized.contains("Chrome/120 "), "{}", normalized);
+        assert!(normalized.contains("Windows NT 10;"), "{}", normalized);
+        assert!(normalize_user_agent(SAFARI_MAC, UaNormalization::MajorVersion).contains("Mac OS X 10)"));
+
+        // Un parche menor colapsa al mismo valor; un salto de versión mayor no
+        let patched = CHROME_WIN.replace("120.0.6099.110", "120.0.6099.217");
+        let upgraded = CHROME_WIN.replace("120.0.6099.110", "121.0.6167.85");
+        assert_eq!(normalize_user_agent(&patched, UaNormalization::MajorVersion), normalized);
+        assert_ne!(normalize_user_agent(&upgraded, UaNormalization::MajorVersion), normalized);
+    }
+
+    #[test]
+    fn family_only_respects_token_precedence() {
+        assert_eq!(normalize_user_agent(CHROME_WIN, UaNormalization::FamilyOnly), "Chrome on Windows");
+        assert_eq!(normalize_user_agent(SAFARI_MAC, UaNormalization::FamilyOnly), "Safari on macOS");
+        // Edge anuncia Chrome y Safari; Android anuncia Linux
+        let edge = format!("{} Edg/120.0.2210.91", CHROME_WIN);
+        assert_eq!(normalize_user_agent(&edge, UaNormalization::FamilyOnly), "Edge on Windows");
+        let android = "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 Chrome/120.0.0.0 Mobile Safari/537.36";
+        assert_eq!(normalize_user_agent(android, UaNormalization::FamilyOnly), "Chrome on Android");
+        assert_eq!(normalize_user_agent("curl/8.4.0", UaNormalization::FamilyOnly), "curl on Other");
+        assert_eq!(normalize_user_agent("", UaNormalization::FamilyOnly), "Other on Other");
+    }
+
+    #[test]
+    fn exact_only_trims() {
+        assert_eq!(normalize_user_agent(&format!("  {}\n", CHROME_WIN), UaNormalization::Exact), CHROME_WIN);
+    }
+
+    #[test]
+    fn parses_mode_names_and_aliases() {
+        assert_eq!("exact".parse(), Ok(UaNormalization::Exact));
+        assert_eq!(" Major-Version ".parse(), Ok(UaNormalization::MajorVersion));
+        assert_eq!("major".parse(), Ok(UaNormalization::MajorVersion));
+        assert_eq!("FAMILY".parse(), Ok(UaNormalization::FamilyOnly));
+        assert!("fuzzy".parse::<UaNormalization>().is_err());
+        assert_eq!(UaNormalization::default(), UaNormalization::MajorVersion);
+    }
+}